@@ -130,6 +130,7 @@ fn main() {
         schema: "{ \"properties\": {\"fortune\": {\"type\": \
                  \"string\"} }, \"required\": [\"fortune\"] }"
             .into(),
+        unknown_fields: Default::default(),
     };
 
     let event_definition = EventTypeDefinition {
@@ -146,7 +147,10 @@ fn main() {
             message_size: 500,
             read_parallelism: 16,
             write_parallelism: 16,
+            unknown_fields: Default::default(),
         }),
+        options: None,
+        unknown_fields: Default::default(),
     };
 
     let api_client = ::nakadion::api_client::ConfigBuilder::default()
@@ -164,6 +168,8 @@ fn main() {
         owning_application: "test-suite".into(),
         event_types: vec![EVENT_TYPE_NAME.into()],
         read_from: Some(ReadFrom::Begin),
+        initial_cursors: None,
+        unknown_fields: Default::default(),
     };
 
     let subscription_status = api_client.create_subscription(&request).unwrap();
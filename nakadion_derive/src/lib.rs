@@ -0,0 +1,68 @@
+//! `#[derive(NakadiEvent)]` for `nakadion`
+//!
+//! Use it through the `derive` feature of `nakadion`. The event type
+//! is given with the `nakadi` attribute:
+//!
+//! ```rust,ignore
+//! #[derive(Serialize, Deserialize, NakadiEvent)]
+//! #[nakadi(event_type = "order.created")]
+//! struct OrderCreated {
+//!     order_number: String,
+//! }
+//! ```
+extern crate proc_macro;
+extern crate proc_macro2;
+#[macro_use]
+extern crate quote;
+extern crate syn;
+
+use proc_macro::TokenStream;
+use syn::{DeriveInput, Lit, Meta, NestedMeta};
+
+#[proc_macro_derive(NakadiEvent, attributes(nakadi))]
+pub fn derive_nakadi_event(input: TokenStream) -> TokenStream {
+    let input: DeriveInput = syn::parse(input).expect("Could not parse the input");
+
+    let name = &input.ident;
+    let event_type = match event_type(&input) {
+        Ok(event_type) => event_type,
+        Err(msg) => panic!("#[derive(NakadiEvent)] on {}: {}", name, msg),
+    };
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    let expanded = quote! {
+        impl #impl_generics ::nakadion::events::NakadiEvent for #name #ty_generics #where_clause {
+            fn event_type() -> &'static str {
+                #event_type
+            }
+        }
+    };
+
+    expanded.into()
+}
+
+/// Find `event_type` in `#[nakadi(event_type = "...")]`.
+fn event_type(input: &DeriveInput) -> Result<String, String> {
+    for attr in &input.attrs {
+        let list = match attr.interpret_meta() {
+            Some(Meta::List(ref list)) if list.ident == "nakadi" => list.clone(),
+            _ => continue,
+        };
+
+        for nested in list.nested {
+            match nested {
+                NestedMeta::Meta(Meta::NameValue(ref name_value))
+                    if name_value.ident == "event_type" =>
+                {
+                    match name_value.lit {
+                        Lit::Str(ref event_type) => return Ok(event_type.value()),
+                        _ => return Err("event_type must be a string".to_string()),
+                    }
+                }
+                _ => return Err("Only event_type is supported in #[nakadi(...)]".to_string()),
+            }
+        }
+    }
+
+    Err("Missing #[nakadi(event_type = \"...\")]".to_string())
+}
@@ -1,4 +1,12 @@
 use std::fmt;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use futures::{future, Async, Future};
+use futures::future::{Shared, SharedError, SharedItem};
+use reqwest::{Response, StatusCode};
+
+use nakadi::response_headers::CapturedHeaders;
 
 /// A token used for authentication against `Nakadi`.
 #[derive(Clone, Debug)]
@@ -23,6 +31,243 @@ impl fmt::Display for AccessToken {
 pub trait ProvidesAccessToken {
     /// Get a new `Token`. Return `None` to disable authentication.
     fn get_token(&self) -> Result<Option<AccessToken>, TokenError>;
+
+    /// Called when `Nakadi` rejected the last token with
+    /// `401 Unauthorized`.
+    ///
+    /// Providers caching tokens should drop the cached token so that
+    /// the next call to `get_token` returns a fresh one.
+    fn invalidate(&self) {}
+}
+
+/// A token that is being fetched
+pub type TokenFuture = Box<Future<Item = Option<AccessToken>, Error = TokenError> + Send>;
+
+/// Provides an `AccessToken` without blocking the calling thread.
+///
+/// Meant for providers that fetch tokens over the network, e.g. from an
+/// OAuth server, and run on an event loop. Use `BlockingTokenProvider`
+/// where a `ProvidesAccessToken` is required.
+pub trait AsyncProvidesToken {
+    /// Get a new `Token`. Resolve to `None` to disable authentication.
+    fn get_token_async(&self) -> TokenFuture;
+
+    /// Called when `Nakadi` rejected the last token with
+    /// `401 Unauthorized`.
+    fn invalidate(&self) {}
+}
+
+/// Makes an `AsyncProvidesToken` a `ProvidesAccessToken`
+/// by waiting for the token.
+///
+/// The future must not depend on the thread calling `get_token`
+/// to make progress.
+pub struct BlockingTokenProvider<P>(pub P);
+
+impl<P: AsyncProvidesToken> ProvidesAccessToken for BlockingTokenProvider<P> {
+    fn get_token(&self) -> Result<Option<AccessToken>, TokenError> {
+        self.0.get_token_async().wait()
+    }
+
+    fn invalidate(&self) {
+        self.0.invalidate()
+    }
+}
+
+/// The first retry after fetching a token failed while there is
+/// no token to fall back to
+const FIRST_TOKEN_RETRY_MS: u64 = 250;
+
+/// Keeps the token of an `AsyncProvidesToken` and refreshes it ahead
+/// of time so that getting a token rarely waits for an OAuth call.
+///
+/// The token is fetched again once it is older than `refresh_interval`
+/// and whenever `Nakadi` rejected it. While a refresh is in flight the
+/// last token is handed out. Callers only wait if there is no token at
+/// all. If fetching a token fails the last token is kept unless the error
+/// is permanent. Without a token fetching is retried after a short backoff
+/// which doubles up to `refresh_interval`.
+///
+/// No thread is spawned. The refresh is driven by the futures returned
+/// from `get_token_async`. Wrap it in a `BlockingTokenProvider` where a
+/// `ProvidesAccessToken` is required.
+pub struct PrefetchingTokenProvider<P> {
+    provider: P,
+    refresh_interval: Duration,
+    state: Arc<Mutex<PrefetchState>>,
+}
+
+struct PrefetchState {
+    /// `None` until a token was fetched
+    token: Option<Option<AccessToken>>,
+    last_error: Option<TokenError>,
+    in_flight: Option<Shared<TokenFuture>>,
+    /// `None` if a token should be fetched right away
+    next_fetch_at: Option<Instant>,
+    retry_backoff: Duration,
+    invalidate_provider: bool,
+}
+
+impl<P: AsyncProvidesToken> PrefetchingTokenProvider<P> {
+    pub fn new(provider: P, refresh_interval: Duration) -> PrefetchingTokenProvider<P> {
+        PrefetchingTokenProvider {
+            provider,
+            refresh_interval,
+            state: Arc::new(Mutex::new(PrefetchState {
+                token: None,
+                last_error: None,
+                in_flight: None,
+                next_fetch_at: None,
+                retry_backoff: Duration::from_millis(FIRST_TOKEN_RETRY_MS),
+                invalidate_provider: false,
+            })),
+        }
+    }
+}
+
+impl PrefetchState {
+    fn fetch_due(&self, now: Instant) -> bool {
+        let permanently_failed = self.last_error
+            .as_ref()
+            .map(TokenError::is_permanent)
+            .unwrap_or(false);
+        self.in_flight.is_none() && !permanently_failed
+            && self.next_fetch_at.map(|at| at <= now).unwrap_or(true)
+    }
+
+    fn fetched(
+        &mut self,
+        fetched: Result<Option<AccessToken>, TokenError>,
+        refresh_interval: Duration,
+    ) -> Result<Option<AccessToken>, TokenError> {
+        if self.in_flight.take().is_none() {
+            // Another caller already recorded the outcome of this fetch
+            return match (fetched, &self.token) {
+                (Err(ref err), &Some(ref token)) if !err.is_permanent() => Ok(token.clone()),
+                (fetched, _) => fetched,
+            };
+        }
+
+        let now = Instant::now();
+        match fetched {
+            Ok(token) => {
+                self.token = Some(token.clone());
+                self.last_error = None;
+                self.next_fetch_at = Some(now + refresh_interval);
+                self.retry_backoff = Duration::from_millis(FIRST_TOKEN_RETRY_MS);
+                Ok(token)
+            }
+            Err(ref err) if !err.is_permanent() && self.token.is_some() => {
+                warn!("Could not refresh the token. Keeping the last one: {}", err);
+                self.next_fetch_at = Some(now + refresh_interval);
+                Ok(self.token.clone().and_then(|token| token))
+            }
+            Err(err) => {
+                self.token = None;
+                self.last_error = Some(err.clone());
+                self.next_fetch_at = Some(now + self.retry_backoff);
+                self.retry_backoff = ::std::cmp::min(self.retry_backoff * 2, refresh_interval);
+                Err(err)
+            }
+        }
+    }
+}
+
+fn lock_state(state: &Mutex<PrefetchState>) -> MutexGuard<PrefetchState> {
+    match state.lock() {
+        Ok(state) => state,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+fn shared_result(
+    result: Result<SharedItem<Option<AccessToken>>, SharedError<TokenError>>,
+) -> Result<Option<AccessToken>, TokenError> {
+    match result {
+        Ok(token) => Ok((*token).clone()),
+        Err(err) => Err((*err).clone()),
+    }
+}
+
+impl<P: AsyncProvidesToken> AsyncProvidesToken for PrefetchingTokenProvider<P> {
+    fn get_token_async(&self) -> TokenFuture {
+        let refresh_interval = self.refresh_interval;
+        let (cached, last_error, in_flight) = {
+            let mut state = lock_state(&self.state);
+            if state.fetch_due(Instant::now()) {
+                if state.invalidate_provider {
+                    state.invalidate_provider = false;
+                    self.provider.invalidate();
+                }
+                state.in_flight = Some(self.provider.get_token_async().shared());
+            }
+            (
+                state.token.clone(),
+                state.last_error.clone(),
+                state.in_flight.clone(),
+            )
+        };
+
+        let state = self.state.clone();
+        match (in_flight, cached) {
+            (None, Some(token)) => Box::new(future::ok(token)),
+            (None, None) => Box::new(future::err(last_error.unwrap_or_else(|| {
+                TokenError::Other {
+                    message: "No token has been fetched yet".to_string(),
+                }
+            }))),
+            // Only look whether the refresh is done and hand out
+            // the last token if it is not.
+            (Some(mut in_flight), Some(token)) => Box::new(future::lazy(move || {
+                match in_flight.poll() {
+                    Ok(Async::NotReady) => Ok(token),
+                    Ok(Async::Ready(fetched)) => {
+                        lock_state(&state).fetched(shared_result(Ok(fetched)), refresh_interval)
+                    }
+                    Err(err) => {
+                        lock_state(&state).fetched(shared_result(Err(err)), refresh_interval)
+                    }
+                }
+            })),
+            (Some(in_flight), None) => Box::new(in_flight.then(move |fetched| {
+                lock_state(&state).fetched(shared_result(fetched), refresh_interval)
+            })),
+        }
+    }
+
+    fn invalidate(&self) {
+        let mut state = lock_state(&self.state);
+        state.token = None;
+        state.next_fetch_at = None;
+        state.invalidate_provider = true;
+    }
+}
+
+/// Sends a request and sends it once more if `Nakadi` responds with
+/// `401 Unauthorized`.
+///
+/// Before retrying the token provider gets invalidated. `send` must
+/// get the token from the provider each time it is called.
+pub fn send_with_fresh_token_on_401<F, E>(
+    token_provider: &ProvidesAccessToken,
+    mut send: F,
+) -> Result<Response, E>
+where
+    F: FnMut() -> Result<Response, E>,
+{
+    let response = send()?;
+    trace!(
+        "Nakadi responded with {} {}",
+        response.status(),
+        CapturedHeaders::from_headers(response.headers())
+    );
+    if response.status() != StatusCode::Unauthorized {
+        return Ok(response);
+    }
+
+    warn!("Nakadi rejected the access token. Retrying with a fresh token.");
+    token_provider.invalidate();
+    send()
 }
 
 #[derive(Fail, Debug, Clone)]
@@ -33,4 +278,94 @@ pub enum TokenError {
     Server { message: String },
     #[fail(display = "Other Error: {}", message)]
     Other { message: String },
+    /// The provider will never be able to provide a token again,
+    /// e.g. because the credentials have been revoked.
+    #[fail(display = "Permanent Error: {}", message)]
+    Permanent { message: String },
+}
+
+impl TokenError {
+    /// Returns true if retrying will not get a token.
+    pub fn is_permanent(&self) -> bool {
+        match *self {
+            TokenError::Permanent { .. } => true,
+            _ => false,
+        }
+    }
+}
+
+#[test]
+fn the_blocking_provider_waits_for_the_token() {
+    struct ReadyProvider;
+
+    impl AsyncProvidesToken for ReadyProvider {
+        fn get_token_async(&self) -> TokenFuture {
+            Box::new(future::ok(Some(AccessToken::new("token"))))
+        }
+    }
+
+    let token = BlockingTokenProvider(ReadyProvider).get_token().unwrap();
+    assert_eq!(token.map(|t| t.0), Some("token".to_string()));
+}
+
+#[test]
+fn the_prefetching_provider_fetches_a_new_token_once_invalidated() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    struct CountingProvider(Arc<AtomicUsize>);
+
+    impl AsyncProvidesToken for CountingProvider {
+        fn get_token_async(&self) -> TokenFuture {
+            let n = self.0.fetch_add(1, Ordering::SeqCst);
+            Box::new(future::ok(Some(AccessToken::new(format!("token-{}", n)))))
+        }
+    }
+
+    let fetched = Arc::new(AtomicUsize::new(0));
+    let provider = BlockingTokenProvider(PrefetchingTokenProvider::new(
+        CountingProvider(fetched.clone()),
+        Duration::from_secs(3600),
+    ));
+
+    let token = provider.get_token().unwrap().unwrap();
+    assert_eq!(token.0, "token-0");
+    assert_eq!(provider.get_token().unwrap().unwrap().0, "token-0");
+
+    provider.invalidate();
+    assert_eq!(provider.get_token().unwrap().unwrap().0, "token-1");
+    assert_eq!(fetched.load(Ordering::SeqCst), 2);
+}
+
+#[test]
+fn the_prefetching_provider_retries_soon_while_it_has_no_token() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+
+    struct FailingOnce(Arc<AtomicUsize>);
+
+    impl AsyncProvidesToken for FailingOnce {
+        fn get_token_async(&self) -> TokenFuture {
+            if self.0.fetch_add(1, Ordering::SeqCst) == 0 {
+                Box::new(future::err(TokenError::Server {
+                    message: "unavailable".to_string(),
+                }))
+            } else {
+                Box::new(future::ok(Some(AccessToken::new("token"))))
+            }
+        }
+    }
+
+    let fetched = Arc::new(AtomicUsize::new(0));
+    let provider = BlockingTokenProvider(PrefetchingTokenProvider::new(
+        FailingOnce(fetched.clone()),
+        Duration::from_secs(3600),
+    ));
+
+    assert!(provider.get_token().is_err());
+    assert!(provider.get_token().is_err());
+    assert_eq!(fetched.load(Ordering::SeqCst), 1);
+
+    thread::sleep(Duration::from_millis(FIRST_TOKEN_RETRY_MS + 50));
+    assert_eq!(provider.get_token().unwrap().unwrap().0, "token");
+    assert_eq!(fetched.load(Ordering::SeqCst), 2);
 }
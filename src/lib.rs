@@ -9,6 +9,7 @@ extern crate hyper;
 extern crate log;
 #[macro_use]
 extern crate serde;
+#[macro_use]
 extern crate serde_json;
 
 extern crate reqwest;
@@ -18,22 +19,72 @@ extern crate chrono;
 
 extern crate backoff;
 
+extern crate futures;
+extern crate tokio;
+
 extern crate url;
 
 #[cfg(feature = "metrix")]
 extern crate metrix;
 
+#[cfg(feature = "derive")]
+#[allow(unused_imports)]
+#[macro_use]
+extern crate nakadion_derive;
+#[cfg(feature = "derive")]
+#[doc(hidden)]
+pub use nakadion_derive::*;
+
 pub mod auth;
 
 mod nakadi;
 
 pub use nakadi::handler::*;
 pub use nakadi::consumer;
-pub use nakadi::model::{EventType, FlowId, PartitionId, StreamId, SubscriptionId};
+pub use nakadi::model::{EventType, FlowId, Offset, PartitionId, RawCursor, StreamCursor,
+                      StreamId, SubscriptionId};
 pub use nakadi::streaming_client;
 pub use nakadi::api_client;
-pub use nakadi::{CommitStrategy, Nakadion, NakadionBuilder, NakadionConfig, SubscriptionDiscovery};
+pub use nakadi::{CommitStrategy, CompletionSummary, ConfigSource, Nakadion, NakadionBuilder,
+                 NakadionConfig, ShutdownFuture, SubscriptionDiscovery};
 pub use nakadi::metrics;
+pub use nakadi::introspection;
+pub use nakadi::quota;
+pub use nakadi::scaling;
+pub use nakadi::ordering;
+pub use nakadi::cluster;
+pub use nakadi::response_headers;
+pub use nakadi::wire_debug;
+pub use nakadi::partition_state;
+pub use nakadi::validation;
+pub use nakadi::backfill;
+pub use nakadi::enrichment;
+pub use nakadi::buffer_pool;
+pub use nakadi::mailbox;
+pub use nakadi::worker_pool;
+pub use nakadi::retention;
+pub use nakadi::assignment;
+pub use nakadi::spool;
+pub use nakadi::partitioning;
+pub use nakadi::lifecycle;
+pub use nakadi::gaps;
+pub use nakadi::retry_scheduler;
+pub use nakadi::filtering;
+pub use nakadi::warm_up;
+pub use nakadi::schema_check;
+pub use nakadi::commit_policy;
+pub use nakadi::adaptive_limits;
+pub use nakadi::roundtrip;
+pub use nakadi::failover;
+pub use nakadi::rebalancing;
+pub use nakadi::repartitioning;
+pub use nakadi::leasing;
+pub use nakadi::stage_timing;
+pub use nakadi::batch_stream;
+pub use nakadi::compat;
+#[allow(deprecated)]
+pub use nakadi::compat::{ConnectorSettings, ConnectorSettingsBuilder, HyperClientConnector};
+pub use nakadi::testkit;
 
 pub use nakadi::publisher;
 
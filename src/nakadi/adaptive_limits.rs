@@ -0,0 +1,197 @@
+//! Adapting the limits of the stream to the throughput of the handlers
+//!
+//! `Nakadi` sends at most `max_uncommitted_events` events that have not
+//! been committed yet. Set too low the handlers wait for events, set too
+//! high they can not process the events before the cursors must be
+//! committed.
+//!
+//! In the adaptive mode the consumer measures how many events were
+//! committed per second while a stream was connected. When it reconnects
+//! it requests as many uncommitted events as the handlers can process
+//! within `uncommitted_time` and batches the handlers can process within
+//! `batch_time`. A limit at most doubles or halves per reconnect and
+//! always stays within its bounds. The configured limits are never
+//! exceeded.
+//!
+//! Streams shorter than `min_observation` do not change the limits.
+use std::cmp;
+use std::time::{Duration, Instant};
+
+/// Settings for adapting the limits
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AdaptiveLimitsConfig {
+    pub min_batch_limit: usize,
+    pub max_batch_limit: usize,
+    pub min_max_uncommitted_events: usize,
+    pub max_max_uncommitted_events: usize,
+    /// The time it may take to process all uncommitted events
+    pub uncommitted_time: Duration,
+    /// The time it may take to process a batch
+    pub batch_time: Duration,
+    /// Streams connected for a shorter time do not change the limits
+    pub min_observation: Duration,
+}
+
+impl AdaptiveLimitsConfig {
+    /// Adapt `batch_limit` within `batch_limit` and `max_uncommitted_events`
+    /// within `max_uncommitted_events`.
+    ///
+    /// The uncommitted events may take 20 seconds and a batch 1 second to
+    /// process. Streams must be connected for 30 seconds to change the limits.
+    /// The first stream is connected with the lower bounds.
+    pub fn new(
+        batch_limit: (usize, usize),
+        max_uncommitted_events: (usize, usize),
+    ) -> AdaptiveLimitsConfig {
+        AdaptiveLimitsConfig {
+            min_batch_limit: cmp::max(batch_limit.0, 1),
+            max_batch_limit: cmp::max(batch_limit.0, batch_limit.1),
+            min_max_uncommitted_events: cmp::max(max_uncommitted_events.0, 1),
+            max_max_uncommitted_events: cmp::max(
+                max_uncommitted_events.0,
+                max_uncommitted_events.1,
+            ),
+            uncommitted_time: Duration::from_secs(20),
+            batch_time: Duration::from_secs(1),
+            min_observation: Duration::from_secs(30),
+        }
+    }
+
+    pub fn uncommitted_time(mut self, uncommitted_time: Duration) -> AdaptiveLimitsConfig {
+        self.uncommitted_time = uncommitted_time;
+        self
+    }
+
+    pub fn batch_time(mut self, batch_time: Duration) -> AdaptiveLimitsConfig {
+        self.batch_time = batch_time;
+        self
+    }
+
+    pub fn min_observation(mut self, min_observation: Duration) -> AdaptiveLimitsConfig {
+        self.min_observation = min_observation;
+        self
+    }
+}
+
+/// The limit to use next for a throughput of `events_per_sec`
+/// if processing the events may take `time`.
+pub fn adapted_limit(
+    current: usize,
+    events_per_sec: f64,
+    time: Duration,
+    bounds: (usize, usize),
+) -> usize {
+    let secs = time.as_secs() as f64 + f64::from(time.subsec_nanos()) / 1_000_000_000.0;
+    let target = (events_per_sec * secs).max(0.0) as usize;
+    let stepped = cmp::min(cmp::max(target, current / 2), current.saturating_mul(2));
+    cmp::min(cmp::max(stepped, bounds.0), bounds.1)
+}
+
+/// Tracks the throughput over the connections of a consumer
+#[derive(Debug)]
+pub struct AdaptiveLimits {
+    config: AdaptiveLimitsConfig,
+    batch_limit: usize,
+    max_uncommitted_events: usize,
+    /// When the current stream was connected and the
+    /// number of events committed until then
+    connected: Option<(Instant, u64)>,
+}
+
+impl AdaptiveLimits {
+    pub fn new(config: AdaptiveLimitsConfig) -> AdaptiveLimits {
+        AdaptiveLimits {
+            config,
+            batch_limit: config.min_batch_limit,
+            max_uncommitted_events: config.min_max_uncommitted_events,
+            connected: None,
+        }
+    }
+
+    pub fn config(&self) -> &AdaptiveLimitsConfig {
+        &self.config
+    }
+
+    /// The `batch_limit` and `max_uncommitted_events` to connect with
+    pub fn limits(&self) -> (usize, usize) {
+        (self.batch_limit, self.max_uncommitted_events)
+    }
+
+    /// A stream was connected at `now` when `events_committed`
+    /// events had been committed in total.
+    pub fn connected(&mut self, now: Instant, events_committed: u64) {
+        self.connected = Some((now, events_committed));
+    }
+
+    /// The stream was disconnected at `now` when `events_committed`
+    /// events had been committed in total.
+    ///
+    /// Returns true if the limits changed.
+    pub fn disconnected(&mut self, now: Instant, events_committed: u64) -> bool {
+        let (since, committed_before) = match self.connected.take() {
+            Some(connected) => connected,
+            None => return false,
+        };
+        if now < since + self.config.min_observation {
+            return false;
+        }
+
+        let elapsed = now - since;
+        let elapsed_secs =
+            elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1_000_000_000.0;
+        let events_per_sec =
+            events_committed.saturating_sub(committed_before) as f64 / elapsed_secs;
+
+        let max_uncommitted_events = adapted_limit(
+            self.max_uncommitted_events,
+            events_per_sec,
+            self.config.uncommitted_time,
+            (
+                self.config.min_max_uncommitted_events,
+                self.config.max_max_uncommitted_events,
+            ),
+        );
+        // Nakadi rejects batches larger than the uncommitted events
+        let batch_limit = cmp::min(
+            adapted_limit(
+                self.batch_limit,
+                events_per_sec,
+                self.config.batch_time,
+                (self.config.min_batch_limit, self.config.max_batch_limit),
+            ),
+            max_uncommitted_events,
+        );
+
+        let changed = (batch_limit, max_uncommitted_events) != self.limits();
+        self.batch_limit = batch_limit;
+        self.max_uncommitted_events = max_uncommitted_events;
+        changed
+    }
+}
+
+#[test]
+fn limits_change_at_most_by_factor_two() {
+    let time = Duration::from_secs(10);
+    assert_eq!(adapted_limit(100, 1000.0, time, (1, 100_000)), 200);
+    assert_eq!(adapted_limit(100, 0.0, time, (1, 100_000)), 50);
+    assert_eq!(adapted_limit(100, 15.0, time, (1, 100_000)), 150);
+    assert_eq!(adapted_limit(100, 1000.0, time, (1, 120)), 120);
+    assert_eq!(adapted_limit(100, 0.0, time, (80, 120)), 80);
+}
+
+#[test]
+fn limits_follow_the_committed_events() {
+    let config = AdaptiveLimitsConfig::new((10, 1000), (100, 10_000));
+    let mut limits = AdaptiveLimits::new(config);
+    assert_eq!(limits.limits(), (10, 100));
+
+    let start = Instant::now();
+    limits.connected(start, 0);
+    // 50 events per second
+    assert!(limits.disconnected(start + Duration::from_secs(60), 3000));
+    assert_eq!(limits.limits(), (20, 200));
+
+    limits.connected(start, 3000);
+    assert!(!limits.disconnected(start + Duration::from_secs(10), 3500));
+    assert_eq!(limits.limits(), (20, 200));
+}
@@ -1,22 +1,37 @@
-use std::sync::Arc;
+use std::collections::BTreeMap;
+use std::sync::{mpsc, Arc, Mutex};
 use std::env;
-use std::time::Duration;
-use std::io::Read;
-
-use auth::{AccessToken, ProvidesAccessToken, TokenError};
-use nakadi::model::{FlowId, StreamId, SubscriptionId};
+use std::time::{Duration, Instant};
+use std::fmt;
+use std::io::{BufRead, BufReader, Read};
+use std::panic::{self, AssertUnwindSafe};
+use std::thread;
+
+use auth::{send_with_fresh_token_on_401, AccessToken, ProvidesAccessToken, TokenError};
+use nakadi::model::{FlowId, Offset, PartitionId, StreamId, SubscriptionId};
+use nakadi::response_headers::{self, CapturedHeaders};
+use nakadi::wire_debug;
+use nakadi::failover::NakadiHosts;
+use nakadi::retry_scheduler::RetryScheduler;
 
 use serde::{self, Deserialize, Deserializer, Serialize, Serializer};
-use serde_json;
+use serde::de::DeserializeOwned;
+use serde_json::{self, Map, Value};
+use url::form_urlencoded;
 
 use reqwest::{Client as HttpClient, ClientBuilder as HttpClientBuilder, Response};
 use reqwest::StatusCode;
 use reqwest::header::{Authorization, Bearer, ContentType, Headers};
 use backoff::{Error as BackoffError, ExponentialBackoff, Operation};
+use chrono::DateTime;
+use chrono::offset::Utc;
 use failure::*;
+use futures::Future;
+use futures::sync::oneshot;
 
 header! { (XNakadiStreamId, "X-Nakadi-StreamId") => [String] }
 header! { (XFlowId, "X-Flow-Id") => [String] }
+header! { (XNakadiCursors, "X-Nakadi-Cursors") => [String] }
 
 /// A client to the Nakadi Event Broker
 pub trait ApiClient {
@@ -58,6 +73,43 @@ pub trait ApiClient {
     ) -> Result<CreateSubscriptionStatus, CreateSubscriptionError>;
 
     fn delete_subscription(&self, id: &SubscriptionId) -> Result<(), DeleteSubscriptionError>;
+
+    /// Delete a subscription unless it is still in use.
+    ///
+    /// Refuses to delete the subscription while partitions are assigned
+    /// to a stream or events have not been consumed yet and returns what
+    /// blocked it. Also refuses if the stats can not be fetched. With
+    /// `force` the subscription is deleted regardless.
+    fn delete_subscription_safe(
+        &self,
+        id: &SubscriptionId,
+        force: bool,
+    ) -> Result<(), SafeDeleteSubscriptionError> {
+        if !force {
+            let stats = match self.stats(id) {
+                Ok(stats) => stats,
+                Err(StatsError::NotFound(msg)) => {
+                    return Err(SafeDeleteSubscriptionError::Delete(
+                        DeleteSubscriptionError::NotFound(msg),
+                    ))
+                }
+                Err(err) => return Err(SafeDeleteSubscriptionError::Check(err.to_string())),
+            };
+            let blockers = deletion_blockers(&stats);
+            if !blockers.is_empty() {
+                return Err(SafeDeleteSubscriptionError::Blocked(DeletionBlockers(
+                    blockers,
+                )));
+            }
+        }
+        self.delete_subscription(id)
+            .map_err(SafeDeleteSubscriptionError::Delete)
+    }
+
+    /// Get the stats of a subscription which contain the
+    /// assignment of the partitions to streams.
+    fn stats(&self, subscription_id: &SubscriptionId)
+        -> Result<stats::SubscriptionStats, StatsError>;
 }
 
 /// Settings for establishing a connection to `Nakadi`.
@@ -159,6 +211,8 @@ pub struct NakadiApiClient {
     nakadi_host: String,
     http_client: HttpClient,
     token_provider: Arc<ProvidesAccessToken + Send + Sync + 'static>,
+    commit_retries: Option<RetryScheduler>,
+    failover: Option<NakadiHosts>,
 }
 
 impl NakadiApiClient {
@@ -178,11 +232,53 @@ impl NakadiApiClient {
             .build()
             .context("Could not create HTTP client")?;
 
-        Ok(NakadiApiClient {
-            nakadi_host: config.nakadi_host,
+        Ok(NakadiApiClient::with_http_client(
+            config.nakadi_host,
             http_client,
             token_provider,
-        })
+        ))
+    }
+
+    /// Create a new `NakadiApiClient` that uses an already configured
+    /// HTTP client.
+    pub fn with_http_client<U: Into<String>>(
+        nakadi_host: U,
+        http_client: HttpClient,
+        token_provider: Arc<ProvidesAccessToken + Send + Sync + 'static>,
+    ) -> NakadiApiClient {
+        NakadiApiClient {
+            nakadi_host: nakadi_host.into(),
+            http_client,
+            token_provider,
+            commit_retries: None,
+            failover: None,
+        }
+    }
+
+    /// Retry failed commits only with a permit of `scheduler`.
+    ///
+    /// Share the scheduler between all clients of the process so that
+    /// their retries are coordinated when `Nakadi` is struggling.
+    pub fn with_commit_retries(mut self, scheduler: RetryScheduler) -> NakadiApiClient {
+        self.commit_retries = Some(scheduler);
+        self
+    }
+
+    /// Send requests to the current host of `hosts` instead of
+    /// the configured `nakadi_host`.
+    ///
+    /// Share the hosts with the `NakadiStreamingClient` so that cursors
+    /// are committed to the host they came from.
+    pub fn with_failover(mut self, hosts: NakadiHosts) -> NakadiApiClient {
+        self.failover = Some(hosts);
+        self
+    }
+
+    fn nakadi_host(&self) -> String {
+        match self.failover {
+            Some(ref hosts) => hosts.current(),
+            None => self.nakadi_host.clone(),
+        }
     }
 
     pub fn attempt_commit<T: AsRef<[u8]>>(
@@ -192,26 +288,41 @@ impl NakadiApiClient {
         cursors: &[T],
         flow_id: FlowId,
     ) -> ::std::result::Result<CommitStatus, CommitError> {
-        let mut headers = Headers::new();
-        if let Some(AccessToken(token)) = self.token_provider.get_token()? {
-            headers.set(Authorization(Bearer { token }));
-        }
+        let body = make_cursors_body(cursors);
 
-        headers.set(XFlowId(flow_id.0.clone()));
-        headers.set(XNakadiStreamId(stream_id.0));
-        headers.set(ContentType::json());
+        let mut response =
+            send_with_fresh_token_on_401(&*self.token_provider, || -> Result<_, CommitError> {
+                let mut headers = Headers::new();
+                if let Some(AccessToken(token)) = self.token_provider.get_token()? {
+                    headers.set(Authorization(Bearer { token }));
+                }
 
-        let body = make_cursors_body(cursors);
+                headers.set(XFlowId(flow_id.0.clone()));
+                headers.set(XNakadiStreamId(stream_id.0.clone()));
+                headers.set(ContentType::json());
 
-        let mut response = self.http_client
-            .post(url)
-            .headers(headers)
-            .body(body)
-            .send()?;
+                let mut request_builder = self.http_client.post(url);
+                request_builder.headers(headers).body(body.clone());
+
+                Ok(wire_debug::send(&self.http_client, &mut request_builder, Some(&body))?)
+            })?;
 
         match response.status() {
             // All cursors committed but at least one did not increase an offset.
-            StatusCode::Ok => Ok(CommitStatus::NotAllOffsetsIncreased),
+            StatusCode::Ok => {
+                let body = read_response_body(&mut response);
+                let results = match parse_commit_results(&body) {
+                    Ok(results) => results,
+                    Err(err) => {
+                        warn!(
+                            "Could not parse commit results(FlowId: {}): {}",
+                            flow_id, err
+                        );
+                        Vec::new()
+                    }
+                };
+                Ok(commit_status(results))
+            }
             // All cursors committed and all increased the offset.
             StatusCode::NoContent => Ok(CommitStatus::AllOffsetsIncreased),
             StatusCode::NotFound => Err(CommitError::SubscriptionNotFound(
@@ -252,42 +363,475 @@ impl NakadiApiClient {
             )),
         }
     }
-}
 
-impl ApiClient for NakadiApiClient {
-    /*    fn stats(&self) -> ::std::result::Result<SubscriptionStats, StatsError> {
-        let mut headers = Headers::new();
-        if let Some(token) = self.token_provider.get_token()? {
-            headers.set(Authorization(Bearer { token: token.0 }));
+    /// List all subscriptions. Optionally filtered by the owning application
+    /// and the event types.
+    ///
+    /// All pages are fetched lazily while iterating.
+    pub fn list_subscriptions(
+        &self,
+        owning_application: Option<&str>,
+        event_types: &[&str],
+    ) -> Paginated<Subscription> {
+        let mut query = form_urlencoded::Serializer::new(String::new());
+        if let Some(owning_application) = owning_application {
+            query.append_pair("owning_application", owning_application);
+        }
+        for event_type in event_types {
+            query.append_pair("event_type", event_type);
+        }
+        let query = query.finish();
+
+        let url = if query.is_empty() {
+            format!("{}/subscriptions", self.nakadi_host())
+        } else {
+            format!("{}/subscriptions?{}", self.nakadi_host(), query)
+        };
+
+        self.paginated(url)
+    }
+
+    /// Get a single subscription.
+    pub fn get_subscription(
+        &self,
+        subscription_id: &SubscriptionId,
+    ) -> Result<Subscription, ListError> {
+        let url = format!("{}/subscriptions/{}", self.nakadi_host(), subscription_id.0);
+        fetch_json(&self.http_client, &url, &*self.token_provider)
+    }
+
+    /// List all event types.
+    pub fn list_event_types(&self) -> Paginated<EventTypeDefinition> {
+        let url = format!("{}/event-types", self.nakadi_host());
+        self.paginated(url)
+    }
+
+    /// Get the definition of a single event type.
+    pub fn get_event_type(&self, event_type_name: &str) -> Result<EventTypeDefinition, ListError> {
+        let url = format!("{}/event-types/{}", self.nakadi_host(), event_type_name);
+        fetch_json(&self.http_client, &url, &*self.token_provider)
+    }
+
+    /// List all versions of the schema of the given event type.
+    ///
+    /// The most recent version comes first.
+    pub fn list_schemas(&self, event_type_name: &str) -> Paginated<EventTypeSchema> {
+        let url = format!("{}/event-types/{}/schemas", self.nakadi_host(), event_type_name);
+        self.paginated(url)
+    }
+
+    /// List the partitions of the given event type with their currently
+    /// available offsets.
+    pub fn list_partitions(&self, event_type_name: &str) -> Paginated<EventTypePartition> {
+        let url = format!(
+            "{}/event-types/{}/partitions",
+            self.nakadi_host(), event_type_name
+        );
+        self.paginated(url)
+    }
+
+    /// Get the number of unconsumed events for each partition
+    /// of the subscription.
+    pub fn subscription_stats(
+        &self,
+        subscription_id: &SubscriptionId,
+    ) -> Result<stats::SubscriptionStats, StatsError> {
+        let url = format!(
+            "{}/subscriptions/{}/stats",
+            self.nakadi_host(), subscription_id.0
+        );
+
+        let mut response =
+            send_with_fresh_token_on_401(&*self.token_provider, || -> Result<_, StatsError> {
+                let mut request_builder = self.http_client.get(&url);
+                if let Some(AccessToken(token)) = self.token_provider.get_token()? {
+                    request_builder.header(Authorization(Bearer { token }));
+                }
+
+                Ok(wire_debug::send(&self.http_client, &mut request_builder, None)?)
+            })?;
+
+        match response.status() {
+            StatusCode::Ok => Ok(serde_json::from_reader(response)?),
+            StatusCode::Unauthorized => Err(StatsError::Unauthorized(format!(
+                "{}: {}",
+                StatusCode::Unauthorized,
+                read_response_body(&mut response)
+            ))),
+            StatusCode::Forbidden => Err(StatsError::Forbidden(format!(
+                "{}: {}",
+                StatusCode::Forbidden,
+                "<Nakadion: Nakadi said forbidden.>"
+            ))),
+            StatusCode::NotFound => Err(StatsError::NotFound(format!(
+                "{}: {}",
+                StatusCode::NotFound,
+                read_response_body(&mut response)
+            ))),
+            other_status if other_status.is_client_error() => Err(StatsError::Client(format!(
+                "{}: {}",
+                other_status,
+                read_response_body(&mut response)
+            ))),
+            other_status if other_status.is_server_error() => Err(StatsError::Server(format!(
+                "{}: {}",
+                other_status,
+                read_response_body(&mut response)
+            ))),
+            other_status => Err(StatsError::Other(format!(
+                "{}: {}",
+                other_status,
+                read_response_body(&mut response)
+            ))),
+        }
+    }
+
+    /// Replace the authorization section of a subscription.
+    ///
+    /// All other fields of the subscription are left untouched.
+    pub fn update_subscription_authorization(
+        &self,
+        subscription_id: &SubscriptionId,
+        authorization: &SubscriptionAuthorization,
+    ) -> Result<(), UpdateSubscriptionError> {
+        let authorization = serde_json::to_value(authorization)
+            .map_err(|err| UpdateSubscriptionError::Other(err.to_string()))?;
+        self.update_subscription(subscription_id, |subscription| {
+            subscription.insert("authorization".to_string(), authorization);
+            Ok(())
+        })
+    }
+
+    /// Set the consumer group of a subscription.
+    ///
+    /// Only supported by `Nakadi` installations that know about
+    /// consumer groups. Others will reject the update.
+    pub fn update_consumer_group(
+        &self,
+        subscription_id: &SubscriptionId,
+        consumer_group: &str,
+    ) -> Result<(), UpdateSubscriptionError> {
+        self.update_subscription(subscription_id, |subscription| {
+            subscription.insert("consumer_group".to_string(), json!(consumer_group));
+            Ok(())
+        })
+    }
+
+    /// Hand a subscription over to another application.
+    ///
+    /// `Nakadi` does not allow changing the owning application of a
+    /// subscription. Instead the new owner is made an admin and a reader
+    /// of the subscription. Before that it is checked that the new owner
+    /// may read all event types of the subscription. Existing admins and
+    /// readers are kept so that they can be removed once the new owner
+    /// has taken over.
+    ///
+    /// Returns the updated authorization.
+    pub fn transfer_ownership(
+        &self,
+        subscription_id: &SubscriptionId,
+        new_owner: &AuthorizationAttribute,
+    ) -> Result<SubscriptionAuthorization, UpdateSubscriptionError> {
+        let subscription_url = format!(
+            "{}/subscriptions/{}",
+            self.nakadi_host(), subscription_id.0
+        );
+        let subscription: Subscription = serde_json::from_value(get_json(
+            &self.http_client,
+            &subscription_url,
+            &*self.token_provider,
+        )?).map_err(|err| UpdateSubscriptionError::Other(err.to_string()))?;
+
+        for event_type in &subscription.event_types {
+            let event_type_url = format!("{}/event-types/{}", self.nakadi_host(), event_type);
+            let event_type_json =
+                get_json(&self.http_client, &event_type_url, &*self.token_provider)?;
+            // Event types without an authorization section can be read by everyone
+            if let Some(readers) = event_type_json
+                .get("authorization")
+                .and_then(|authorization| authorization.get("readers"))
+            {
+                let readers: Vec<AuthorizationAttribute> =
+                    serde_json::from_value(readers.clone())
+                        .map_err(|err| UpdateSubscriptionError::Other(err.to_string()))?;
+                if !readers.iter().any(|reader| reader.grants_access_to(new_owner)) {
+                    return Err(UpdateSubscriptionError::NoAccess(format!(
+                        "{} may not read event type {}",
+                        new_owner, event_type
+                    )));
+                }
+            }
+        }
+
+        let mut authorization = subscription.authorization.unwrap_or_default();
+        authorization.add_admin(new_owner.clone());
+        authorization.add_reader(new_owner.clone());
+        self.update_subscription_authorization(subscription_id, &authorization)?;
+
+        info!(
+            "Made {} admin and reader of subscription {}",
+            new_owner, subscription_id
+        );
+
+        Ok(authorization)
+    }
+
+    /// Read the subscription, let `f` modify it and write it back.
+    ///
+    /// Works on the raw JSON so that fields unknown to `Nakadion`
+    /// are kept.
+    fn update_subscription<F>(
+        &self,
+        subscription_id: &SubscriptionId,
+        f: F,
+    ) -> Result<(), UpdateSubscriptionError>
+    where
+        F: FnOnce(&mut serde_json::Map<String, serde_json::Value>)
+            -> Result<(), UpdateSubscriptionError>,
+    {
+        let url = format!(
+            "{}/subscriptions/{}",
+            self.nakadi_host(), subscription_id.0
+        );
+
+        let mut subscription = match get_json(&self.http_client, &url, &*self.token_provider)? {
+            serde_json::Value::Object(subscription) => subscription,
+            other => {
+                return Err(UpdateSubscriptionError::Other(format!(
+                    "Expected a subscription but got: {}",
+                    other
+                )))
+            }
         };
+        f(&mut subscription)?;
+
+        put_json(
+            &self.http_client,
+            &url,
+            &*self.token_provider,
+            &serde_json::Value::Object(subscription),
+        )
+    }
+
+    /// Get the cursors committed for a subscription.
+    pub fn subscription_cursors(
+        &self,
+        subscription_id: &SubscriptionId,
+    ) -> Result<Vec<InitialCursor>, CursorLookupError> {
+        let url = format!(
+            "{}/subscriptions/{}/cursors",
+            self.nakadi_host(), subscription_id.0
+        );
+
+        let mut response = send_with_fresh_token_on_401(
+            &*self.token_provider,
+            || -> Result<_, CursorLookupError> {
+                let mut request_builder = self.http_client.get(&url);
+                if let Some(AccessToken(token)) = self.token_provider.get_token()? {
+                    request_builder.header(Authorization(Bearer { token }));
+                }
+
+                Ok(wire_debug::send(&self.http_client, &mut request_builder, None)?)
+            },
+        )?;
 
-        let mut response = self.http_client
-            .get(&self.stats_url)
-            .headers(headers)
-            .send()?;
         match response.status() {
             StatusCode::Ok => {
-                let parsed = serde_json::from_reader(response)?;
-                Ok(parsed)
+                let cursors: CommittedCursors = serde_json::from_reader(response)?;
+                Ok(cursors.items)
             }
-            StatusCode::Forbidden => Err(StatsError::Client {
-                message: format!(
-                    "{}: {}",
-                    StatusCode::Forbidden,
-                    "<Nakadion: Nakadi said forbidden.>"
-                ),
-            }),
-            other_status if other_status.is_client_error() => Err(StatsError::Client {
-                message: format!("{}: {}", other_status, read_response_body(&mut response)),
-            }),
-            other_status if other_status.is_server_error() => Err(StatsError::Server {
-                message: format!("{}: {}", other_status, read_response_body(&mut response)),
-            }),
-            other_status => Err(StatsError::Other {
-                message: format!("{}: {}", other_status, read_response_body(&mut response)),
+            _ => Err(cursor_lookup_error_from_response(&mut response)),
+        }
+    }
+
+    /// Find the cursors for all partitions of the given event type so that
+    /// consumption starts with the first event received by `Nakadi` at or after
+    /// `timestamp`.
+    ///
+    /// The returned cursors can be used with
+    /// `CreateSubscriptionRequest::from_cursors` or to reset a subscription.
+    ///
+    /// For each partition a binary search over the available events is
+    /// performed where each step reads a single event via the low level API.
+    pub fn cursors_at_timestamp(
+        &self,
+        event_type_name: &str,
+        timestamp: DateTime<Utc>,
+    ) -> Result<Vec<InitialCursor>, CursorLookupError> {
+        let partitions = self.list_partitions(event_type_name).collect_all()?;
+        partitions
+            .iter()
+            .map(|partition| self.cursor_at_timestamp(event_type_name, partition, timestamp))
+            .collect()
+    }
+
+    fn cursor_at_timestamp(
+        &self,
+        event_type_name: &str,
+        partition: &EventTypePartition,
+        timestamp: DateTime<Utc>,
+    ) -> Result<InitialCursor, CursorLookupError> {
+        if partition.is_empty() {
+            return Ok(partition.cursor_at_begin(event_type_name));
+        }
+
+        let num_events = self.cursor_distance(
+            event_type_name,
+            &partition.partition,
+            &partition.oldest_available_offset,
+            &partition.newest_available_offset,
+        )? + 1;
+
+        // The cursor after which the event with the given index
+        // within the available events comes
+        let cursor_before = |idx: u64| -> Result<String, CursorLookupError> {
+            if idx == 0 {
+                Ok(Offset::BEGIN.to_string())
+            } else {
+                self.shift_cursor(
+                    event_type_name,
+                    &partition.partition,
+                    &partition.oldest_available_offset,
+                    idx - 1,
+                )
+            }
+        };
+
+        let first_idx = first_received_at_or_after(num_events, timestamp, |idx| {
+            self.received_at_after(event_type_name, &partition.partition, &cursor_before(idx)?)
+        })?;
+
+        if first_idx == num_events {
+            Ok(partition.cursor_at_end(event_type_name))
+        } else {
+            Ok(partition.cursor_at(event_type_name, cursor_before(first_idx)?))
+        }
+    }
+
+    fn cursor_distance(
+        &self,
+        event_type_name: &str,
+        partition: &PartitionId,
+        from_offset: &str,
+        to_offset: &str,
+    ) -> Result<u64, CursorLookupError> {
+        let url = format!(
+            "{}/event-types/{}/cursor-distances",
+            self.nakadi_host(), event_type_name
+        );
+        let query = vec![
+            json!({
+                "initial_cursor": { "partition": partition.0, "offset": from_offset },
+                "final_cursor": { "partition": partition.0, "offset": to_offset },
             }),
+        ];
+        let result: Vec<CursorDistanceResult> =
+            post_for_cursor_lookup(&self.http_client, &url, &*self.token_provider, &query)?;
+        match result.first() {
+            Some(result) if result.distance >= 0 => Ok(result.distance as u64),
+            Some(result) => Err(CursorLookupError::Other(format!(
+                "Negative distance {} on partition {}",
+                result.distance, partition
+            ))),
+            None => Err(CursorLookupError::Other(
+                "Nakadi did not return a cursor distance".into(),
+            )),
+        }
+    }
+
+    fn shift_cursor(
+        &self,
+        event_type_name: &str,
+        partition: &PartitionId,
+        offset: &str,
+        shift: u64,
+    ) -> Result<String, CursorLookupError> {
+        let url = format!(
+            "{}/event-types/{}/shifted-cursors",
+            self.nakadi_host(), event_type_name
+        );
+        let query = vec![
+            json!({ "partition": partition.0, "offset": offset, "shift": shift }),
+        ];
+        let mut result: Vec<ShiftedCursor> =
+            post_for_cursor_lookup(&self.http_client, &url, &*self.token_provider, &query)?;
+        if result.is_empty() {
+            Err(CursorLookupError::Other(
+                "Nakadi did not return a shifted cursor".into(),
+            ))
+        } else {
+            Ok(result.swap_remove(0).offset)
         }
-    }*/
+    }
+
+    /// Reads the first event after the given offset via the low level API
+    /// and returns when `Nakadi` received it.
+    ///
+    /// Fails if there is no event after the offset.
+    pub fn received_at_after(
+        &self,
+        event_type_name: &str,
+        partition: &PartitionId,
+        offset: &str,
+    ) -> Result<DateTime<Utc>, CursorLookupError> {
+        let url = format!(
+            "{}/event-types/{}/events?batch_limit=1&stream_limit=1&batch_flush_timeout=1",
+            self.nakadi_host(), event_type_name
+        );
+
+        let mut response = send_with_fresh_token_on_401(
+            &*self.token_provider,
+            || -> Result<_, CursorLookupError> {
+                let mut request_builder = self.http_client.get(&url);
+                if let Some(AccessToken(token)) = self.token_provider.get_token()? {
+                    request_builder.header(Authorization(Bearer { token }));
+                }
+                request_builder.header(XNakadiCursors(
+                    json!([{ "partition": partition.0, "offset": offset }]).to_string(),
+                ));
+
+                Ok(wire_debug::send(&self.http_client, &mut request_builder, None)?)
+            },
+        )?;
+        if !response.status().is_success() {
+            return Err(cursor_lookup_error_from_response(&mut response));
+        }
+
+        for line in BufReader::new(response).lines() {
+            let line = line.map_err(|err| CursorLookupError::Connection(err.to_string()))?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let batch: LowLevelBatch = serde_json::from_str(&line)?;
+            if let Some(event) = batch.events.and_then(|events| events.into_iter().next()) {
+                return Ok(event.metadata.received_at);
+            }
+        }
+
+        Err(CursorLookupError::Other(format!(
+            "No event after offset {} on partition {}",
+            offset, partition
+        )))
+    }
+
+    fn paginated<T: DeserializeOwned>(&self, first_page_url: String) -> Paginated<T> {
+        Paginated {
+            nakadi_host: self.nakadi_host(),
+            http_client: self.http_client.clone(),
+            token_provider: self.token_provider.clone(),
+            next_url: Some(first_page_url),
+            items: Vec::new().into_iter(),
+        }
+    }
+}
+
+impl ApiClient for NakadiApiClient {
+    fn stats(
+        &self,
+        subscription_id: &SubscriptionId,
+    ) -> Result<stats::SubscriptionStats, StatsError> {
+        self.subscription_stats(subscription_id)
+    }
 
     fn commit_cursors_budgeted<T: AsRef<[u8]>>(
         &self,
@@ -303,13 +847,36 @@ impl ApiClient for NakadiApiClient {
 
         let url = format!(
             "{}/subscriptions/{}/cursors",
-            self.nakadi_host, subscription_id.0
+            self.nakadi_host(), subscription_id.0
         );
 
+        let deadline = Instant::now() + budget;
+        let mut is_retry = false;
         let mut op = || {
+            let _permit = match self.commit_retries {
+                Some(ref scheduler) if is_retry => {
+                    let now = Instant::now();
+                    let timeout = if deadline > now {
+                        deadline - now
+                    } else {
+                        Duration::from_secs(0)
+                    };
+                    match scheduler.acquire(timeout) {
+                        Some(permit) => Some(permit),
+                        None => {
+                            return Err(BackoffError::Permanent(CommitError::Connection(
+                                "No permit to retry the commit within the budget".to_string(),
+                            )))
+                        }
+                    }
+                }
+                _ => None,
+            };
+            is_retry = true;
             self.attempt_commit(&url, stream_id.clone(), cursors, flow_id.clone())
                 .map_err(|err| match err {
                     err @ CommitError::Client { .. } => BackoffError::Permanent(err),
+                    err @ CommitError::AuthBroken(_) => BackoffError::Permanent(err),
                     err => BackoffError::Transient(err),
                 })
         };
@@ -336,7 +903,7 @@ impl ApiClient for NakadiApiClient {
     }
 
     fn delete_event_type(&self, event_type_name: &str) -> Result<(), DeleteEventTypeError> {
-        let url = format!("{}/event-types/{}", self.nakadi_host, event_type_name);
+        let url = format!("{}/event-types/{}", self.nakadi_host(), event_type_name);
 
         let mut op = || match delete_event_type(&self.http_client, &url, &*self.token_provider) {
             Ok(_) => Ok(()),
@@ -369,7 +936,7 @@ impl ApiClient for NakadiApiClient {
         &self,
         event_type: &EventTypeDefinition,
     ) -> Result<(), CreateEventTypeError> {
-        let url = format!("{}/event-types", self.nakadi_host);
+        let url = format!("{}/event-types", self.nakadi_host());
 
         let mut op = || match create_event_type(
             &self.http_client,
@@ -407,16 +974,257 @@ impl ApiClient for NakadiApiClient {
         &self,
         request: &CreateSubscriptionRequest,
     ) -> Result<CreateSubscriptionStatus, CreateSubscriptionError> {
-        let url = format!("{}/subscriptions", self.nakadi_host);
+        let url = format!("{}/subscriptions", self.nakadi_host());
         create_subscription(&self.http_client, &url, &*self.token_provider, request)
     }
 
     fn delete_subscription(&self, id: &SubscriptionId) -> Result<(), DeleteSubscriptionError> {
-        let url = format!("{}/subscriptions/{}", self.nakadi_host, id.0);
+        let url = format!("{}/subscriptions/{}", self.nakadi_host(), id.0);
         delete_subscription(&self.http_client, &url, &*self.token_provider)
     }
 }
 
+/// The outcome of a call of an `AsyncApiClient`
+pub type ApiFuture<T, E> = Box<Future<Item = T, Error = E> + Send>;
+
+/// The number of threads of an `AsyncApiClient` created with `new`
+const DEFAULT_ASYNC_API_THREADS: usize = 2;
+
+type ApiCall = Box<FnMut(&NakadiApiClient) + Send>;
+
+/// Manages subscriptions and event types without blocking
+/// the calling thread.
+///
+/// The calls are made by a `NakadiApiClient` on a fixed number of
+/// threads and return a future of the outcome. Meant for services
+/// running on an event loop. The calls are not meant to be made at a
+/// high rate. Calls wait until a thread becomes available.
+#[derive(Clone)]
+pub struct AsyncApiClient {
+    client: NakadiApiClient,
+    calls: mpsc::Sender<ApiCall>,
+}
+
+impl AsyncApiClient {
+    /// Make the calls on 2 threads.
+    pub fn new(client: NakadiApiClient) -> AsyncApiClient {
+        AsyncApiClient::with_threads(client, DEFAULT_ASYNC_API_THREADS)
+    }
+
+    /// Make at most `num_threads` calls at the same time.
+    ///
+    /// The threads stop once the `AsyncApiClient` and all
+    /// of its clones have been dropped.
+    pub fn with_threads(client: NakadiApiClient, num_threads: usize) -> AsyncApiClient {
+        let (calls, receiver) = mpsc::channel::<ApiCall>();
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        for n in 0..::std::cmp::max(num_threads, 1) {
+            let receiver = receiver.clone();
+            let client = client.clone();
+            let spawned = thread::Builder::new()
+                .name(format!("nakadion-api-call-{}", n))
+                .spawn(move || loop {
+                    let next = match receiver.lock() {
+                        Ok(receiver) => receiver.recv(),
+                        Err(poisoned) => poisoned.into_inner().recv(),
+                    };
+                    match next {
+                        Ok(mut call) => call(&client),
+                        Err(_) => break,
+                    }
+                });
+            if let Err(err) = spawned {
+                error!("Could not start API call thread {}: {}", n, err);
+            }
+        }
+
+        AsyncApiClient { client, calls }
+    }
+
+    /// The `NakadiApiClient` making the calls
+    pub fn blocking(&self) -> &NakadiApiClient {
+        &self.client
+    }
+
+    /// Make a call on one of the threads.
+    ///
+    /// A call that panics makes the future panic when polled.
+    fn spawn<T, E, F>(&self, call: F) -> ApiFuture<T, E>
+    where
+        T: Send + 'static,
+        E: ApiCallFailed + Send + 'static,
+        F: FnOnce(&NakadiApiClient) -> Result<T, E> + Send + 'static,
+    {
+        let (sender, outcome) = oneshot::channel();
+        let mut call = Some(call);
+        let mut sender = Some(sender);
+        let queued = self.calls.send(Box::new(move |client: &NakadiApiClient| {
+            if let (Some(call), Some(sender)) = (call.take(), sender.take()) {
+                let result = panic::catch_unwind(AssertUnwindSafe(|| call(client)));
+                let _ = sender.send(result);
+            }
+        }));
+        if queued.is_err() {
+            return Box::new(::futures::future::err(E::call_failed(
+                "There is no thread left to make the API call".to_string(),
+            )));
+        }
+        Box::new(outcome.then(|result| match result {
+            Ok(Ok(result)) => result,
+            Ok(Err(panicked)) => panic::resume_unwind(panicked),
+            Err(_) => Err(E::call_failed(
+                "The API call stopped without a result".to_string(),
+            )),
+        }))
+    }
+
+    pub fn get_subscription(
+        &self,
+        subscription_id: &SubscriptionId,
+    ) -> ApiFuture<Subscription, ListError> {
+        let subscription_id = subscription_id.clone();
+        self.spawn(move |client| client.get_subscription(&subscription_id))
+    }
+
+    /// List all subscriptions, see `NakadiApiClient::list_subscriptions`.
+    ///
+    /// Resolves once all pages have been fetched.
+    pub fn list_subscriptions(
+        &self,
+        owning_application: Option<&str>,
+        event_types: &[&str],
+    ) -> ApiFuture<Vec<Subscription>, ListError> {
+        let owning_application = owning_application.map(|s| s.to_string());
+        let event_types: Vec<String> = event_types.iter().map(|s| s.to_string()).collect();
+        self.spawn(move |client| {
+            let event_types: Vec<&str> = event_types.iter().map(|s| s.as_str()).collect();
+            client
+                .list_subscriptions(
+                    owning_application.as_ref().map(|s| s.as_str()),
+                    &event_types,
+                )
+                .collect_all()
+        })
+    }
+
+    pub fn create_subscription(
+        &self,
+        request: &CreateSubscriptionRequest,
+    ) -> ApiFuture<CreateSubscriptionStatus, CreateSubscriptionError> {
+        let request = request.clone();
+        self.spawn(move |client| client.create_subscription(&request))
+    }
+
+    pub fn delete_subscription(
+        &self,
+        subscription_id: &SubscriptionId,
+    ) -> ApiFuture<(), DeleteSubscriptionError> {
+        let subscription_id = subscription_id.clone();
+        self.spawn(move |client| client.delete_subscription(&subscription_id))
+    }
+
+    /// See `ApiClient::delete_subscription_safe`
+    pub fn delete_subscription_safe(
+        &self,
+        subscription_id: &SubscriptionId,
+        force: bool,
+    ) -> ApiFuture<(), SafeDeleteSubscriptionError> {
+        let subscription_id = subscription_id.clone();
+        self.spawn(move |client| client.delete_subscription_safe(&subscription_id, force))
+    }
+
+    pub fn stats(
+        &self,
+        subscription_id: &SubscriptionId,
+    ) -> ApiFuture<stats::SubscriptionStats, StatsError> {
+        let subscription_id = subscription_id.clone();
+        self.spawn(move |client| client.stats(&subscription_id))
+    }
+
+    pub fn get_event_type(
+        &self,
+        event_type_name: &str,
+    ) -> ApiFuture<EventTypeDefinition, ListError> {
+        let event_type_name = event_type_name.to_string();
+        self.spawn(move |client| client.get_event_type(&event_type_name))
+    }
+
+    /// List all event types. Resolves once all pages have been fetched.
+    pub fn list_event_types(&self) -> ApiFuture<Vec<EventTypeDefinition>, ListError> {
+        self.spawn(|client| client.list_event_types().collect_all())
+    }
+
+    /// List the partitions of the given event type.
+    pub fn list_partitions(
+        &self,
+        event_type_name: &str,
+    ) -> ApiFuture<Vec<EventTypePartition>, ListError> {
+        let event_type_name = event_type_name.to_string();
+        self.spawn(move |client| client.list_partitions(&event_type_name).collect_all())
+    }
+
+    pub fn create_event_type(
+        &self,
+        event_type: &EventTypeDefinition,
+    ) -> ApiFuture<(), CreateEventTypeError> {
+        let event_type = event_type.clone();
+        self.spawn(move |client| client.create_event_type(&event_type))
+    }
+
+    pub fn delete_event_type(&self, event_type_name: &str) -> ApiFuture<(), DeleteEventTypeError> {
+        let event_type_name = event_type_name.to_string();
+        self.spawn(move |client| client.delete_event_type(&event_type_name))
+    }
+}
+
+/// Errors an `AsyncApiClient` resolves to when a call could not be made
+pub(crate) trait ApiCallFailed {
+    fn call_failed(reason: String) -> Self;
+}
+
+impl ApiCallFailed for ListError {
+    fn call_failed(reason: String) -> ListError {
+        ListError::Other(reason)
+    }
+}
+
+impl ApiCallFailed for StatsError {
+    fn call_failed(reason: String) -> StatsError {
+        StatsError::Other(reason)
+    }
+}
+
+impl ApiCallFailed for CreateSubscriptionError {
+    fn call_failed(reason: String) -> CreateSubscriptionError {
+        CreateSubscriptionError::Other(reason)
+    }
+}
+
+impl ApiCallFailed for DeleteSubscriptionError {
+    fn call_failed(reason: String) -> DeleteSubscriptionError {
+        DeleteSubscriptionError::Other(reason)
+    }
+}
+
+impl ApiCallFailed for SafeDeleteSubscriptionError {
+    fn call_failed(reason: String) -> SafeDeleteSubscriptionError {
+        SafeDeleteSubscriptionError::Delete(DeleteSubscriptionError::Other(reason))
+    }
+}
+
+impl ApiCallFailed for CreateEventTypeError {
+    fn call_failed(reason: String) -> CreateEventTypeError {
+        CreateEventTypeError::Other(reason)
+    }
+}
+
+impl ApiCallFailed for DeleteEventTypeError {
+    fn call_failed(reason: String) -> DeleteEventTypeError {
+        DeleteEventTypeError::Other(reason)
+    }
+}
+
 fn make_cursors_body<T: AsRef<[u8]>>(cursors: &[T]) -> Vec<u8> {
     let bytes_required: usize = cursors.iter().map(|c| c.as_ref().len()).sum();
     let mut body = Vec::with_capacity(bytes_required + 20);
@@ -434,14 +1242,87 @@ fn make_cursors_body<T: AsRef<[u8]>>(cursors: &[T]) -> Vec<u8> {
 #[derive(Debug)]
 pub enum CommitStatus {
     AllOffsetsIncreased,
-    NotAllOffsetsIncreased,
+    /// Contains the result for each cursor as reported by Nakadi.
+    NotAllOffsetsIncreased(Vec<CommitResult>),
     NothingToCommit,
+    /// Nakadi reported every cursor as outdated because they had already
+    /// been committed. Contains the number of cursors.
+    AlreadyCommitted(usize),
+}
+
+impl CommitStatus {
+    /// The number of cursors that did not increase an offset
+    /// because a newer cursor had already been committed.
+    pub fn num_outdated(&self) -> usize {
+        match *self {
+            CommitStatus::NotAllOffsetsIncreased(ref results) => results
+                .iter()
+                .filter(|r| r.result == CommitResultKind::Outdated)
+                .count(),
+            CommitStatus::AlreadyCommitted(n) => n,
+            _ => 0,
+        }
+    }
+}
+
+/// The result of committing a single cursor.
+#[derive(Debug, Clone, Deserialize)]
+pub struct CommitResult {
+    pub cursor: CommittedCursor,
+    pub result: CommitResultKind,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CommittedCursor {
+    pub partition: PartitionId,
+    pub offset: String,
+    pub event_type: String,
+    #[serde(flatten)]
+    pub unknown_fields: Map<String, Value>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum CommitResultKind {
+    /// The cursor increased the offset
+    #[serde(rename = "committed")]
+    Committed,
+    /// A cursor with the same or a greater offset had already been committed
+    #[serde(rename = "outdated")]
+    Outdated,
+}
+
+#[derive(Deserialize)]
+struct CommitResults {
+    items: Vec<CommitResult>,
+}
+
+fn parse_commit_results(body: &str) -> Result<Vec<CommitResult>, serde_json::Error> {
+    serde_json::from_str::<CommitResults>(body).map(|results| results.items)
+}
+
+/// The status of a commit `Nakadi` answered with results for each cursor.
+///
+/// If all cursors are outdated they had already been committed which
+/// is routine after reconnects.
+fn commit_status(results: Vec<CommitResult>) -> CommitStatus {
+    if !results.is_empty()
+        && results
+            .iter()
+            .all(|r| r.result == CommitResultKind::Outdated)
+    {
+        CommitStatus::AlreadyCommitted(results.len())
+    } else {
+        CommitStatus::NotAllOffsetsIncreased(results)
+    }
 }
 
 #[derive(Fail, Debug)]
 pub enum CommitError {
     #[fail(display = "Token Error on commit: {}", _0)]
     TokenError(String),
+    /// The token provider failed permanently
+    #[fail(display = "Authentication broken on commit: {}", _0)]
+    AuthBroken(String),
     #[fail(display = "Connection Error: {}", _0)]
     Connection(String),
     #[fail(display = "Subscription not found(FlowId: {}): {}", _1, _0)]
@@ -460,6 +1341,13 @@ pub enum CommitError {
 pub enum StatsError {
     #[fail(display = "Token Error on stats: {}", _0)]
     TokenError(String),
+    #[fail(display = "Unauthorized: {}", _0)]
+    Unauthorized(String),
+    #[fail(display = "Forbidden: {}", _0)]
+    Forbidden(String),
+    /// The subscription does not exist
+    #[fail(display = "NotFound: {}", _0)]
+    NotFound(String),
     #[fail(display = "Connection Error: {}", _0)]
     Connection(String),
     #[fail(display = "Server Error: {}", _0)]
@@ -474,7 +1362,11 @@ pub enum StatsError {
 
 impl From<TokenError> for CommitError {
     fn from(e: TokenError) -> CommitError {
-        CommitError::TokenError(format!("{}", e))
+        if e.is_permanent() {
+            CommitError::AuthBroken(format!("{}", e))
+        } else {
+            CommitError::TokenError(format!("{}", e))
+        }
     }
 }
 
@@ -508,17 +1400,22 @@ fn create_event_type(
     token_provider: &ProvidesAccessToken,
     event_type: &EventTypeDefinition,
 ) -> Result<(), CreateEventTypeError> {
-    let mut request_builder = client.post(url);
+    let mut result = send_with_fresh_token_on_401(token_provider, || {
+        let mut request_builder = client.post(url);
 
-    match token_provider.get_token() {
-        Ok(Some(AccessToken(token))) => {
-            request_builder.header(Authorization(Bearer { token }));
-        }
-        Ok(None) => (),
-        Err(err) => return Err(CreateEventTypeError::Other(err.to_string())),
-    };
+        match token_provider.get_token() {
+            Ok(Some(AccessToken(token))) => {
+                request_builder.header(Authorization(Bearer { token }));
+            }
+            Ok(None) => (),
+            Err(err) => return Err(CreateEventTypeError::Other(err.to_string())),
+        };
+
+        wire_debug::send_json(client, &mut request_builder, event_type)
+            .map_err(|err| CreateEventTypeError::Other(format!("{}", err)))
+    });
 
-    match request_builder.json(event_type).send() {
+    match result {
         Ok(ref mut response) => match response.status() {
             StatusCode::Created => Ok(()),
             StatusCode::Unauthorized => {
@@ -538,7 +1435,7 @@ fn create_event_type(
                 Err(CreateEventTypeError::Other(msg))
             }
         },
-        Err(err) => Err(CreateEventTypeError::Other(format!("{}", err))),
+        Err(err) => Err(err),
     }
 }
 
@@ -547,17 +1444,22 @@ fn delete_event_type(
     url: &str,
     token_provider: &ProvidesAccessToken,
 ) -> Result<(), DeleteEventTypeError> {
-    let mut request_builder = client.delete(url);
+    let mut result = send_with_fresh_token_on_401(token_provider, || {
+        let mut request_builder = client.delete(url);
 
-    match token_provider.get_token() {
-        Ok(Some(AccessToken(token))) => {
-            request_builder.header(Authorization(Bearer { token }));
-        }
-        Ok(None) => (),
-        Err(err) => return Err(DeleteEventTypeError::Other(err.to_string())),
-    };
+        match token_provider.get_token() {
+            Ok(Some(AccessToken(token))) => {
+                request_builder.header(Authorization(Bearer { token }));
+            }
+            Ok(None) => (),
+            Err(err) => return Err(DeleteEventTypeError::Other(err.to_string())),
+        };
 
-    match request_builder.send() {
+        wire_debug::send(client, &mut request_builder, None)
+            .map_err(|err| DeleteEventTypeError::Other(format!("{}", err)))
+    });
+
+    match result {
         Ok(ref mut response) => match response.status() {
             StatusCode::Ok => Ok(()),
             StatusCode::Unauthorized => {
@@ -573,7 +1475,7 @@ fn delete_event_type(
                 Err(DeleteEventTypeError::Other(msg))
             }
         },
-        Err(err) => Err(DeleteEventTypeError::Other(format!("{}", err))),
+        Err(err) => Err(err),
     }
 }
 
@@ -582,17 +1484,22 @@ fn delete_subscription(
     url: &str,
     token_provider: &ProvidesAccessToken,
 ) -> Result<(), DeleteSubscriptionError> {
-    let mut request_builder = client.delete(url);
+    let mut result = send_with_fresh_token_on_401(token_provider, || {
+        let mut request_builder = client.delete(url);
 
-    match token_provider.get_token() {
-        Ok(Some(AccessToken(token))) => {
-            request_builder.header(Authorization(Bearer { token }));
-        }
-        Ok(None) => (),
-        Err(err) => return Err(DeleteSubscriptionError::Other(err.to_string())),
-    };
+        match token_provider.get_token() {
+            Ok(Some(AccessToken(token))) => {
+                request_builder.header(Authorization(Bearer { token }));
+            }
+            Ok(None) => (),
+            Err(err) => return Err(DeleteSubscriptionError::Other(err.to_string())),
+        };
 
-    match request_builder.send() {
+        wire_debug::send(client, &mut request_builder, None)
+            .map_err(|err| DeleteSubscriptionError::Other(format!("{}", err)))
+    });
+
+    match result {
         Ok(ref mut response) => match response.status() {
             StatusCode::NoContent => Ok(()),
             StatusCode::NotFound => {
@@ -612,71 +1519,529 @@ fn delete_subscription(
                 Err(DeleteSubscriptionError::Other(msg))
             }
         },
-        Err(err) => Err(DeleteSubscriptionError::Other(format!("{}", err))),
+        Err(err) => Err(err),
+    }
+}
+
+fn get_json(
+    client: &HttpClient,
+    url: &str,
+    token_provider: &ProvidesAccessToken,
+) -> Result<serde_json::Value, UpdateSubscriptionError> {
+    let mut result = send_with_fresh_token_on_401(token_provider, || {
+        let mut request_builder = client.get(url);
+
+        match token_provider.get_token() {
+            Ok(Some(AccessToken(token))) => {
+                request_builder.header(Authorization(Bearer { token }));
+            }
+            Ok(None) => (),
+            Err(err) => return Err(UpdateSubscriptionError::Other(err.to_string())),
+        };
+
+        wire_debug::send(client, &mut request_builder, None)
+            .map_err(|err| UpdateSubscriptionError::Other(format!("{}", err)))
+    });
+
+    match result {
+        Ok(ref mut response) => match response.status() {
+            StatusCode::Ok => serde_json::from_reader(response)
+                .map_err(|err| UpdateSubscriptionError::Other(err.to_string())),
+            _ => Err(update_subscription_error_from_response(response)),
+        },
+        Err(err) => Err(err),
+    }
+}
+
+fn put_json(
+    client: &HttpClient,
+    url: &str,
+    token_provider: &ProvidesAccessToken,
+    body: &serde_json::Value,
+) -> Result<(), UpdateSubscriptionError> {
+    let mut result = send_with_fresh_token_on_401(token_provider, || {
+        let mut request_builder = client.put(url);
+
+        match token_provider.get_token() {
+            Ok(Some(AccessToken(token))) => {
+                request_builder.header(Authorization(Bearer { token }));
+            }
+            Ok(None) => (),
+            Err(err) => return Err(UpdateSubscriptionError::Other(err.to_string())),
+        };
+
+        wire_debug::send_json(client, &mut request_builder, body)
+            .map_err(|err| UpdateSubscriptionError::Other(format!("{}", err)))
+    });
+
+    match result {
+        Ok(ref mut response) => match response.status() {
+            StatusCode::Ok | StatusCode::NoContent => Ok(()),
+            _ => Err(update_subscription_error_from_response(response)),
+        },
+        Err(err) => Err(err),
+    }
+}
+
+fn update_subscription_error_from_response(response: &mut Response) -> UpdateSubscriptionError {
+    let status = response.status();
+    let msg = read_response_body(response);
+    match status {
+        StatusCode::Unauthorized => UpdateSubscriptionError::Unauthorized(msg),
+        StatusCode::Forbidden => UpdateSubscriptionError::Forbidden(msg),
+        StatusCode::NotFound => UpdateSubscriptionError::NotFound(msg),
+        StatusCode::UnprocessableEntity => UpdateSubscriptionError::UnprocessableEntity(msg),
+        _ => UpdateSubscriptionError::Other(format!("{}: {}", status, msg)),
+    }
+}
+
+fn read_response_body(response: &mut Response) -> String {
+    let headers = CapturedHeaders::from_headers(response.headers());
+    let mut buf = String::new();
+    let body = response
+        .read_to_string(&mut buf)
+        .map(|_| buf)
+        .unwrap_or("<Could not read body.>".to_string());
+    wire_debug::log_response_body(&body);
+    response_headers::with_headers(body, &headers)
+}
+
+fn create_subscription(
+    client: &HttpClient,
+    url: &str,
+    token_provider: &ProvidesAccessToken,
+    request: &CreateSubscriptionRequest,
+) -> Result<CreateSubscriptionStatus, CreateSubscriptionError> {
+    let mut result = send_with_fresh_token_on_401(token_provider, || {
+        let mut request_builder = client.post(url);
+
+        match token_provider.get_token() {
+            Ok(Some(AccessToken(token))) => {
+                request_builder.header(Authorization(Bearer { token }));
+            }
+            Ok(None) => (),
+            Err(err) => return Err(CreateSubscriptionError::Other(err.to_string())),
+        };
+
+        wire_debug::send_json(client, &mut request_builder, request)
+            .map_err(|err| CreateSubscriptionError::Other(format!("{}", err)))
+    });
+
+    match result {
+        Ok(ref mut response) => match response.status() {
+            StatusCode::Ok => match serde_json::from_reader(response) {
+                Ok(sub) => Ok(CreateSubscriptionStatus::AlreadyExists(sub)),
+                Err(err) => Err(CreateSubscriptionError::Other(err.to_string())),
+            },
+            StatusCode::Created => match serde_json::from_reader(response) {
+                Ok(sub) => Ok(CreateSubscriptionStatus::Created(sub)),
+                Err(err) => Err(CreateSubscriptionError::Other(err.to_string())),
+            },
+            StatusCode::Unauthorized => {
+                let msg = read_response_body(response);
+                Err(CreateSubscriptionError::Unauthorized(msg))
+            }
+            StatusCode::UnprocessableEntity => {
+                let msg = read_response_body(response);
+                Err(CreateSubscriptionError::UnprocessableEntity(msg))
+            }
+            StatusCode::BadRequest => {
+                let msg = read_response_body(response);
+                Err(CreateSubscriptionError::BadRequest(msg))
+            }
+            _ => {
+                let msg = read_response_body(response);
+                Err(CreateSubscriptionError::Other(msg))
+            }
+        },
+        Err(err) => Err(err),
+    }
+}
+
+/// An iterator over all items of a list endpoint of `Nakadi`.
+///
+/// Follows the `_links.next` of each page until there are no more pages.
+/// After an error has been returned the iterator is exhausted.
+pub struct Paginated<T> {
+    nakadi_host: String,
+    http_client: HttpClient,
+    token_provider: Arc<ProvidesAccessToken + Send + Sync + 'static>,
+    next_url: Option<String>,
+    items: ::std::vec::IntoIter<T>,
+}
+
+impl<T: DeserializeOwned> Paginated<T> {
+    /// Fetch all remaining pages and collect their items.
+    pub fn collect_all(self) -> Result<Vec<T>, ListError> {
+        self.collect()
+    }
+}
+
+impl<T: DeserializeOwned> Iterator for Paginated<T> {
+    type Item = Result<T, ListError>;
+
+    fn next(&mut self) -> Option<Result<T, ListError>> {
+        loop {
+            if let Some(item) = self.items.next() {
+                return Some(Ok(item));
+            }
+
+            let url = match self.next_url.take() {
+                Some(url) => url,
+                None => return None,
+            };
+
+            match fetch_json::<Page<T>>(&self.http_client, &url, &*self.token_provider) {
+                Ok(page) => {
+                    let (items, next) = page.into_parts();
+                    if !items.is_empty() {
+                        self.next_url = next.map(|href| resolve_link(&self.nakadi_host, &href));
+                    }
+                    self.items = items.into_iter();
+                }
+                Err(err) => return Some(Err(err)),
+            }
+        }
+    }
+}
+
+/// A page as returned by a list endpoint.
+///
+/// Some endpoints simply return an array of items which
+/// is treated as a single page.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Page<T> {
+    Linked {
+        items: Vec<T>,
+        #[serde(rename = "_links", default)]
+        links: PageLinks,
+    },
+    Plain(Vec<T>),
+}
+
+impl<T> Page<T> {
+    fn into_parts(self) -> (Vec<T>, Option<String>) {
+        match self {
+            Page::Linked { items, links } => (items, links.next.map(|link| link.href)),
+            Page::Plain(items) => (items, None),
+        }
     }
 }
 
-fn read_response_body(response: &mut Response) -> String {
-    let mut buf = String::new();
-    response
-        .read_to_string(&mut buf)
-        .map(|_| buf)
-        .unwrap_or("<Could not read body.>".to_string())
+#[derive(Debug, Default, Deserialize)]
+struct PageLinks {
+    next: Option<PageLink>,
 }
 
-fn create_subscription(
+#[derive(Debug, Deserialize)]
+struct PageLink {
+    href: String,
+}
+
+fn resolve_link(nakadi_host: &str, href: &str) -> String {
+    if href.starts_with("http://") || href.starts_with("https://") {
+        href.to_string()
+    } else if href.starts_with('/') {
+        format!("{}{}", nakadi_host.trim_right_matches('/'), href)
+    } else {
+        format!("{}/{}", nakadi_host.trim_right_matches('/'), href)
+    }
+}
+
+fn fetch_json<T: DeserializeOwned>(
     client: &HttpClient,
     url: &str,
     token_provider: &ProvidesAccessToken,
-    request: &CreateSubscriptionRequest,
-) -> Result<CreateSubscriptionStatus, CreateSubscriptionError> {
-    let mut request_builder = client.post(url);
+) -> Result<T, ListError> {
+    let mut result = send_with_fresh_token_on_401(token_provider, || {
+        let mut request_builder = client.get(url);
 
-    match token_provider.get_token() {
-        Ok(Some(AccessToken(token))) => {
-            request_builder.header(Authorization(Bearer { token }));
-        }
-        Ok(None) => (),
-        Err(err) => return Err(CreateSubscriptionError::Other(err.to_string())),
-    };
+        match token_provider.get_token() {
+            Ok(Some(AccessToken(token))) => {
+                request_builder.header(Authorization(Bearer { token }));
+            }
+            Ok(None) => (),
+            Err(err) => return Err(ListError::Other(err.to_string())),
+        };
+
+        wire_debug::send(client, &mut request_builder, None)
+            .map_err(|err| ListError::Other(format!("{}", err)))
+    });
 
-    match request_builder.json(request).send() {
+    match result {
         Ok(ref mut response) => match response.status() {
             StatusCode::Ok => match serde_json::from_reader(response) {
-                Ok(sub) => Ok(CreateSubscriptionStatus::AlreadyExists(sub)),
-                Err(err) => Err(CreateSubscriptionError::Other(err.to_string())),
-            },
-            StatusCode::Created => match serde_json::from_reader(response) {
-                Ok(sub) => Ok(CreateSubscriptionStatus::Created(sub)),
-                Err(err) => Err(CreateSubscriptionError::Other(err.to_string())),
+                Ok(value) => Ok(value),
+                Err(err) => Err(ListError::Other(err.to_string())),
             },
             StatusCode::Unauthorized => {
                 let msg = read_response_body(response);
-                Err(CreateSubscriptionError::Unauthorized(msg))
+                Err(ListError::Unauthorized(msg))
             }
-            StatusCode::UnprocessableEntity => {
+            StatusCode::Forbidden => {
                 let msg = read_response_body(response);
-                Err(CreateSubscriptionError::UnprocessableEntity(msg))
+                Err(ListError::Forbidden(msg))
             }
-            StatusCode::BadRequest => {
+            StatusCode::NotFound => {
                 let msg = read_response_body(response);
-                Err(CreateSubscriptionError::BadRequest(msg))
+                Err(ListError::NotFound(msg))
             }
             _ => {
                 let msg = read_response_body(response);
-                Err(CreateSubscriptionError::Other(msg))
+                Err(ListError::Other(msg))
             }
         },
-        Err(err) => Err(CreateSubscriptionError::Other(format!("{}", err))),
+        Err(err) => Err(err),
+    }
+}
+
+#[derive(Deserialize)]
+struct CursorDistanceResult {
+    distance: i64,
+}
+
+#[derive(Deserialize)]
+struct ShiftedCursor {
+    offset: String,
+}
+
+#[derive(Deserialize)]
+struct CommittedCursors {
+    items: Vec<InitialCursor>,
+}
+
+#[derive(Deserialize)]
+struct LowLevelBatch {
+    events: Option<Vec<LowLevelEvent>>,
+}
+
+#[derive(Deserialize)]
+struct LowLevelEvent {
+    metadata: LowLevelMetadata,
+}
+
+#[derive(Deserialize)]
+struct LowLevelMetadata {
+    received_at: DateTime<Utc>,
+}
+
+/// Binary search for the index of the first of `num_events` events
+/// received at or after `timestamp` where `received_at` returns when
+/// the event with the given index was received.
+///
+/// Returns `num_events` if all events were received before `timestamp`.
+fn first_received_at_or_after<F>(
+    num_events: u64,
+    timestamp: DateTime<Utc>,
+    mut received_at: F,
+) -> Result<u64, CursorLookupError>
+where
+    F: FnMut(u64) -> Result<DateTime<Utc>, CursorLookupError>,
+{
+    let mut lower = 0;
+    let mut upper = num_events;
+    while lower < upper {
+        let mid = lower + (upper - lower) / 2;
+        if received_at(mid)? < timestamp {
+            lower = mid + 1;
+        } else {
+            upper = mid;
+        }
+    }
+    Ok(lower)
+}
+
+fn post_for_cursor_lookup<B: Serialize, T: DeserializeOwned>(
+    client: &HttpClient,
+    url: &str,
+    token_provider: &ProvidesAccessToken,
+    body: &B,
+) -> Result<T, CursorLookupError> {
+    let mut response =
+        send_with_fresh_token_on_401(token_provider, || -> Result<_, CursorLookupError> {
+            let mut request_builder = client.post(url);
+            if let Some(AccessToken(token)) = token_provider.get_token()? {
+                request_builder.header(Authorization(Bearer { token }));
+            }
+
+            Ok(wire_debug::send_json(client, &mut request_builder, body)?)
+        })?;
+    if response.status().is_success() {
+        Ok(serde_json::from_reader(response)?)
+    } else {
+        Err(cursor_lookup_error_from_response(&mut response))
+    }
+}
+
+fn cursor_lookup_error_from_response(response: &mut Response) -> CursorLookupError {
+    let status = response.status();
+    let msg = format!("{}: {}", status, read_response_body(response));
+    if status.is_client_error() {
+        CursorLookupError::Client(msg)
+    } else if status.is_server_error() {
+        CursorLookupError::Server(msg)
+    } else {
+        CursorLookupError::Other(msg)
+    }
+}
+
+#[derive(Fail, Debug)]
+pub enum CursorLookupError {
+    #[fail(display = "Token Error on cursor lookup: {}", _0)]
+    TokenError(String),
+    #[fail(display = "Connection Error: {}", _0)]
+    Connection(String),
+    #[fail(display = "Server Error: {}", _0)]
+    Server(String),
+    #[fail(display = "Client Error: {}", _0)]
+    Client(String),
+    #[fail(display = "Parse Error: {}", _0)]
+    Parse(String),
+    #[fail(display = "Other Error: {}", _0)]
+    Other(String),
+}
+
+impl From<TokenError> for CursorLookupError {
+    fn from(e: TokenError) -> CursorLookupError {
+        CursorLookupError::TokenError(format!("{}", e))
+    }
+}
+
+impl From<serde_json::error::Error> for CursorLookupError {
+    fn from(e: serde_json::error::Error) -> CursorLookupError {
+        CursorLookupError::Parse(format!("{}", e))
     }
 }
 
+impl From<::reqwest::Error> for CursorLookupError {
+    fn from(e: ::reqwest::Error) -> CursorLookupError {
+        CursorLookupError::Connection(format!("{}", e))
+    }
+}
+
+impl From<ListError> for CursorLookupError {
+    fn from(e: ListError) -> CursorLookupError {
+        CursorLookupError::Other(format!("Could not list partitions: {}", e))
+    }
+}
+
+#[derive(Fail, Debug)]
+pub enum ListError {
+    #[fail(display = "Unauthorized: {}", _0)]
+    Unauthorized(String),
+    #[fail(display = "Forbidden: {}", _0)]
+    Forbidden(String),
+    #[fail(display = "NotFound: {}", _0)]
+    NotFound(String),
+    #[fail(display = "An error occured: {}", _0)]
+    Other(String),
+}
+
 #[derive(Debug, Clone, Serialize)]
 pub struct CreateSubscriptionRequest {
     pub owning_application: String,
     pub event_types: Vec<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub read_from: Option<ReadFrom>,
+    /// The cursors to start reading from. Required if `read_from` is
+    /// `ReadFrom::Cursors`. There must be exactly one cursor for each
+    /// partition of each event type.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub initial_cursors: Option<Vec<InitialCursor>>,
+    #[serde(flatten)]
+    pub unknown_fields: Map<String, Value>,
+}
+
+impl CreateSubscriptionRequest {
+    /// Creates a request for a subscription that starts reading
+    /// after the given cursors.
+    pub fn from_cursors<T: Into<String>>(
+        owning_application: T,
+        event_types: Vec<String>,
+        initial_cursors: Vec<InitialCursor>,
+    ) -> CreateSubscriptionRequest {
+        CreateSubscriptionRequest {
+            owning_application: owning_application.into(),
+            event_types,
+            read_from: Some(ReadFrom::Cursors),
+            initial_cursors: Some(initial_cursors),
+            unknown_fields: Map::new(),
+        }
+    }
+}
+
+/// A cursor a new subscription starts reading from.
+///
+/// The event at `offset` itself will not be consumed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InitialCursor {
+    pub event_type: String,
+    pub partition: PartitionId,
+    pub offset: String,
+    #[serde(flatten)]
+    pub unknown_fields: Map<String, Value>,
+}
+
+/// A partition of an event type with its currently available offsets.
+#[derive(Debug, Clone, Deserialize)]
+pub struct EventTypePartition {
+    pub partition: PartitionId,
+    pub oldest_available_offset: String,
+    pub newest_available_offset: String,
+    #[serde(flatten)]
+    pub unknown_fields: Map<String, Value>,
+}
+
+impl EventTypePartition {
+    /// Returns true if there are no events available on this partition.
+    pub fn is_empty(&self) -> bool {
+        let oldest = Offset::new(self.oldest_available_offset.as_str());
+        let newest = Offset::new(self.newest_available_offset.as_str());
+        newest.is_before_first_event() || oldest.is_after(&newest)
+    }
+
+    /// A cursor to consume all events still available on this partition.
+    pub fn cursor_at_begin<T: Into<String>>(&self, event_type: T) -> InitialCursor {
+        self.cursor_at(event_type, Offset::BEGIN)
+    }
+
+    /// A cursor to consume only events published after the
+    /// currently newest event of this partition.
+    pub fn cursor_at_end<T: Into<String>>(&self, event_type: T) -> InitialCursor {
+        let offset = self.newest_available_offset.clone();
+        self.cursor_at(event_type, offset)
+    }
+
+    /// A cursor to consume all events after `offset`.
+    pub fn cursor_at<T: Into<String>, O: Into<String>>(
+        &self,
+        event_type: T,
+        offset: O,
+    ) -> InitialCursor {
+        InitialCursor {
+            event_type: event_type.into(),
+            partition: self.partition.clone(),
+            offset: offset.into(),
+            unknown_fields: Map::new(),
+        }
+    }
+}
+
+/// Creates an initial cursor for each of the given partitions of an event type
+/// where the offset is determined by `offset_for`.
+pub fn initial_cursors_for_partitions<F>(
+    event_type: &str,
+    partitions: &[EventTypePartition],
+    offset_for: F,
+) -> Vec<InitialCursor>
+where
+    F: Fn(&EventTypePartition) -> String,
+{
+    partitions
+        .iter()
+        .map(|p| p.cursor_at(event_type, offset_for(p)))
+        .collect()
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -684,12 +2049,77 @@ pub struct Subscription {
     pub id: SubscriptionId,
     pub owning_application: String,
     pub event_types: Vec<String>,
+    #[serde(default)]
+    pub consumer_group: Option<String>,
+    #[serde(default)]
+    pub authorization: Option<SubscriptionAuthorization>,
+    /// Fields not known to `Nakadion`. Kept so that they survive
+    /// reading and writing back the subscription.
+    #[serde(flatten)]
+    pub unknown_fields: Map<String, Value>,
+}
+
+/// A subject of an authorization section, e.g. an application
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuthorizationAttribute {
+    /// The kind of subject, e.g. "service". "*" matches all kinds.
+    pub data_type: String,
+    /// The subject. "*" matches all subjects of the `data_type`.
+    pub value: String,
+}
+
+impl AuthorizationAttribute {
+    pub fn new<T: Into<String>, V: Into<String>>(data_type: T, value: V) -> AuthorizationAttribute {
+        AuthorizationAttribute {
+            data_type: data_type.into(),
+            value: value.into(),
+        }
+    }
+
+    /// Returns true if `subject` is matched by this attribute.
+    pub fn grants_access_to(&self, subject: &AuthorizationAttribute) -> bool {
+        if self.data_type == "*" {
+            return true;
+        }
+        self.data_type == subject.data_type && (self.value == "*" || self.value == subject.value)
+    }
+}
+
+impl fmt::Display for AuthorizationAttribute {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.data_type, self.value)
+    }
+}
+
+/// Who may administer and who may read a subscription
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SubscriptionAuthorization {
+    pub admins: Vec<AuthorizationAttribute>,
+    pub readers: Vec<AuthorizationAttribute>,
+}
+
+impl SubscriptionAuthorization {
+    /// Add an admin unless it is already an admin.
+    pub fn add_admin(&mut self, admin: AuthorizationAttribute) {
+        if !self.admins.contains(&admin) {
+            self.admins.push(admin);
+        }
+    }
+
+    /// Add a reader unless it is already a reader.
+    pub fn add_reader(&mut self, reader: AuthorizationAttribute) {
+        if !self.readers.contains(&reader) {
+            self.readers.push(reader);
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub enum ReadFrom {
     Begin,
     End,
+    /// Start from the `initial_cursors`
+    Cursors,
 }
 impl Serialize for ReadFrom {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
@@ -699,6 +2129,7 @@ impl Serialize for ReadFrom {
         match *self {
             ReadFrom::Begin => serializer.serialize_str("begin"),
             ReadFrom::End => serializer.serialize_str("end"),
+            ReadFrom::Cursors => serializer.serialize_str("cursors"),
         }
     }
 }
@@ -708,10 +2139,11 @@ impl<'de> Deserialize<'de> for ReadFrom {
     where
         D: Deserializer<'de>,
     {
-        let tag: &str = Deserialize::deserialize(deserializer)?;
-        match tag {
+        let tag: String = Deserialize::deserialize(deserializer)?;
+        match tag.as_ref() {
             "begin" => Ok(ReadFrom::Begin),
             "end" => Ok(ReadFrom::End),
+            "cursors" => Ok(ReadFrom::Cursors),
             other => Err(serde::de::Error::custom(format!(
                 "not a read from: {}",
                 other
@@ -733,6 +2165,25 @@ pub enum CreateSubscriptionError {
     Other(String),
 }
 
+#[derive(Fail, Debug)]
+pub enum UpdateSubscriptionError {
+    #[fail(display = "Unauthorized: {}", _0)]
+    Unauthorized(String),
+    #[fail(display = "Forbidden: {}", _0)]
+    Forbidden(String),
+    #[fail(display = "NotFound: {}", _0)]
+    NotFound(String),
+    /// `Nakadi` rejected the update, e.g. because a field
+    /// may not be changed
+    #[fail(display = "Unprocessable Entity: {}", _0)]
+    UnprocessableEntity(String),
+    /// The new owner may not read all event types
+    #[fail(display = "No access: {}", _0)]
+    NoAccess(String),
+    #[fail(display = "An error occured: {}", _0)]
+    Other(String),
+}
+
 #[derive(Fail, Debug)]
 pub enum DeleteSubscriptionError {
     #[fail(display = "Unauthorized: {}", _0)]
@@ -745,6 +2196,93 @@ pub enum DeleteSubscriptionError {
     Other(String),
 }
 
+/// What keeps `delete_subscription_safe` from deleting a subscription
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeletionBlocker {
+    /// Partitions of the subscription are assigned to a stream
+    ActiveStream { stream_id: String, partitions: usize },
+    /// Events of a partition have not been consumed yet
+    UnconsumedEvents {
+        event_type: String,
+        partition: String,
+        unconsumed_events: usize,
+    },
+}
+
+impl fmt::Display for DeletionBlocker {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DeletionBlocker::ActiveStream {
+                ref stream_id,
+                partitions,
+            } => write!(
+                f,
+                "stream {} consumes {} partitions",
+                stream_id, partitions
+            ),
+            DeletionBlocker::UnconsumedEvents {
+                ref event_type,
+                ref partition,
+                unconsumed_events,
+            } => write!(
+                f,
+                "{} events of partition {} of event type {} are unconsumed",
+                unconsumed_events, partition, event_type
+            ),
+        }
+    }
+}
+
+/// All that keeps a subscription from being deleted
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeletionBlockers(pub Vec<DeletionBlocker>);
+
+impl fmt::Display for DeletionBlockers {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let blockers: Vec<String> = self.0.iter().map(|b| b.to_string()).collect();
+        write!(f, "{}", blockers.join(", "))
+    }
+}
+
+/// What is in use of a subscription according to its stats
+pub fn deletion_blockers(stats: &stats::SubscriptionStats) -> Vec<DeletionBlocker> {
+    let mut partitions_by_stream: BTreeMap<&str, usize> = BTreeMap::new();
+    let mut unconsumed = Vec::new();
+    for event_type in &stats.event_types {
+        for partition in &event_type.partitions {
+            if !partition.stream_id.is_empty() {
+                *partitions_by_stream.entry(partition.stream_id.as_str()).or_insert(0) += 1;
+            }
+            if partition.unconsumed_events > 0 {
+                unconsumed.push(DeletionBlocker::UnconsumedEvents {
+                    event_type: event_type.event_type.clone(),
+                    partition: partition.partition.clone(),
+                    unconsumed_events: partition.unconsumed_events,
+                });
+            }
+        }
+    }
+    partitions_by_stream
+        .into_iter()
+        .map(|(stream_id, partitions)| DeletionBlocker::ActiveStream {
+            stream_id: stream_id.to_string(),
+            partitions,
+        })
+        .chain(unconsumed)
+        .collect()
+}
+
+#[derive(Fail, Debug)]
+pub enum SafeDeleteSubscriptionError {
+    #[fail(display = "Refusing to delete the subscription: {}", _0)]
+    Blocked(DeletionBlockers),
+    /// Whether the subscription is in use could not be checked
+    #[fail(display = "Could not check the subscription: {}", _0)]
+    Check(String),
+    #[fail(display = "{}", _0)]
+    Delete(DeleteSubscriptionError),
+}
+
 #[derive(Debug, Clone)]
 pub enum CreateSubscriptionStatus {
     AlreadyExists(Subscription),
@@ -829,8 +2367,8 @@ impl<'de> Deserialize<'de> for EventCategory {
     where
         D: Deserializer<'de>,
     {
-        let tag: &str = Deserialize::deserialize(deserializer)?;
-        match tag {
+        let tag: String = Deserialize::deserialize(deserializer)?;
+        match tag.as_ref() {
             "undefined" => Ok(EventCategory::Undefined),
             "data" => Ok(EventCategory::Data),
             "business" => Ok(EventCategory::Business),
@@ -865,8 +2403,8 @@ impl<'de> Deserialize<'de> for EnrichmentStrategy {
     where
         D: Deserializer<'de>,
     {
-        let tag: &str = Deserialize::deserialize(deserializer)?;
-        match tag {
+        let tag: String = Deserialize::deserialize(deserializer)?;
+        match tag.as_ref() {
             "metadata_enrichment" => Ok(EnrichmentStrategy::MetadataEnrichment),
             other => Err(serde::de::Error::custom(format!(
                 "not an enrichment strategy: {}",
@@ -901,8 +2439,8 @@ impl<'de> Deserialize<'de> for PartitionStrategy {
     where
         D: Deserializer<'de>,
     {
-        let tag: &str = Deserialize::deserialize(deserializer)?;
-        match tag {
+        let tag: String = Deserialize::deserialize(deserializer)?;
+        match tag.as_ref() {
             "random" => Ok(PartitionStrategy::Random),
             "hash" => Ok(PartitionStrategy::Hash),
             "user_defined" => Ok(PartitionStrategy::UserDefined),
@@ -939,8 +2477,8 @@ impl<'de> Deserialize<'de> for CompatibilityMode {
     where
         D: Deserializer<'de>,
     {
-        let tag: &str = Deserialize::deserialize(deserializer)?;
-        match tag {
+        let tag: String = Deserialize::deserialize(deserializer)?;
+        match tag.as_ref() {
             "compatible" => Ok(CompatibilityMode::Compatible),
             "forward" => Ok(CompatibilityMode::Forward),
             "none" => Ok(CompatibilityMode::None),
@@ -967,6 +2505,39 @@ pub struct EventTypeDefinition {
     pub schema: EventTypeSchema,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub default_statistic: Option<EventTypeStatistics>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub options: Option<EventTypeOptions>,
+    /// Fields not known to `Nakadion`. Kept so that they survive
+    /// reading and writing back the event type.
+    #[serde(flatten)]
+    pub unknown_fields: Map<String, Value>,
+}
+
+impl EventTypeDefinition {
+    /// Set the expected load of the event type.
+    ///
+    /// `Nakadi` uses it to determine the number of partitions
+    /// when the event type is created.
+    pub fn with_default_statistic(mut self, statistic: EventTypeStatistics) -> Self {
+        self.default_statistic = Some(statistic);
+        self
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct EventTypeOptions {
+    /// The time in milliseconds for which events are kept
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub retention_time: Option<u64>,
+    #[serde(flatten)]
+    pub unknown_fields: Map<String, Value>,
+}
+
+impl EventTypeOptions {
+    /// The time for which events are kept
+    pub fn retention(&self) -> Option<Duration> {
+        self.retention_time.map(Duration::from_millis)
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -975,6 +2546,8 @@ pub struct EventTypeSchema {
     #[serde(rename = "type")]
     pub schema_type: SchemaType,
     pub schema: String,
+    #[serde(flatten)]
+    pub unknown_fields: Map<String, Value>,
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -998,8 +2571,8 @@ impl<'de> Deserialize<'de> for SchemaType {
     where
         D: Deserializer<'de>,
     {
-        let tag: &str = Deserialize::deserialize(deserializer)?;
-        match tag {
+        let tag: String = Deserialize::deserialize(deserializer)?;
+        match tag.as_ref() {
             "json_schema" => Ok(SchemaType::JsonSchema),
             other => Err(serde::de::Error::custom(format!(
                 "not a schema type: {}",
@@ -1011,19 +2584,49 @@ impl<'de> Deserialize<'de> for SchemaType {
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct EventTypeStatistics {
+    /// The expected number of events published per minute
     pub messages_per_minute: usize,
+    /// The expected average size of an event in bytes
     pub message_size: usize,
+    /// The number of parallel readers
     pub read_parallelism: u16,
+    /// The number of parallel writers
     pub write_parallelism: u16,
+    #[serde(flatten)]
+    pub unknown_fields: Map<String, Value>,
+}
+
+impl EventTypeStatistics {
+    pub fn new(
+        messages_per_minute: usize,
+        message_size: usize,
+        read_parallelism: u16,
+        write_parallelism: u16,
+    ) -> EventTypeStatistics {
+        EventTypeStatistics {
+            messages_per_minute,
+            message_size,
+            read_parallelism,
+            write_parallelism,
+            unknown_fields: Map::new(),
+        }
+    }
 }
 
 pub mod stats {
+    use serde_json::{Map, Value};
+
     /// Information on a partition
     #[derive(Debug, Deserialize)]
     pub struct PartitionInfo {
         pub partition: String,
+        /// Empty if the partition is not assigned to a stream
+        #[serde(default)]
         pub stream_id: String,
+        #[serde(default)]
         pub unconsumed_events: usize,
+        #[serde(flatten)]
+        pub unknown_fields: Map<String, Value>,
     }
 
     /// An `EventType` can be published on multiple partitions.
@@ -1031,6 +2634,8 @@ pub mod stats {
     pub struct EventTypeInfo {
         pub event_type: String,
         pub partitions: Vec<PartitionInfo>,
+        #[serde(flatten)]
+        pub unknown_fields: Map<String, Value>,
     }
 
     impl EventTypeInfo {
@@ -1047,9 +2652,21 @@ pub mod stats {
     pub struct SubscriptionStats {
         #[serde(rename = "items")]
         pub event_types: Vec<EventTypeInfo>,
+        #[serde(flatten)]
+        pub unknown_fields: Map<String, Value>,
     }
 
     impl SubscriptionStats {
+        /// Returns the number of events not yet consumed
+        /// over all partitions.
+        pub fn unconsumed_events(&self) -> usize {
+            self.event_types
+                .iter()
+                .flat_map(|et| et.partitions.iter())
+                .map(|p| p.unconsumed_events)
+                .sum()
+        }
+
         /// Returns the number of partitions of the `EventType`
         /// that has the most partitions.
         pub fn max_partitions(&self) -> usize {
@@ -1061,3 +2678,272 @@ pub mod stats {
         }
     }
 }
+
+#[test]
+fn parse_linked_page() {
+    let sample = r#"{"items":[1,2,3],"_links":{"next":{"href":"/subscriptions?offset=3"}}}"#;
+    let page: Page<u32> = serde_json::from_str(sample).unwrap();
+    let (items, next) = page.into_parts();
+    assert_eq!(items, vec![1, 2, 3]);
+    assert_eq!(next, Some("/subscriptions?offset=3".to_string()));
+}
+
+#[test]
+fn parse_last_linked_page() {
+    let sample = r#"{"items":[1],"_links":{"prev":{"href":"/subscriptions?offset=0"}}}"#;
+    let page: Page<u32> = serde_json::from_str(sample).unwrap();
+    let (items, next) = page.into_parts();
+    assert_eq!(items, vec![1]);
+    assert_eq!(next, None);
+}
+
+#[test]
+fn parse_plain_page() {
+    let page: Page<u32> = serde_json::from_str("[1,2]").unwrap();
+    let (items, next) = page.into_parts();
+    assert_eq!(items, vec![1, 2]);
+    assert_eq!(next, None);
+}
+
+#[test]
+fn resolve_relative_link() {
+    assert_eq!(
+        resolve_link("http://localhost:8080/", "/subscriptions?offset=20"),
+        "http://localhost:8080/subscriptions?offset=20"
+    );
+}
+
+#[test]
+fn parse_commit_results_with_outdated_cursor() {
+    let sample = r#"{"items":[
+        {"cursor":{"partition":"0","offset":"001-0001-000000000000000009",
+            "event_type":"order.ORDER_RECEIVED","cursor_token":"abc"},"result":"committed"},
+        {"cursor":{"partition":"1","offset":"001-0001-000000000000000004",
+            "event_type":"order.ORDER_RECEIVED","cursor_token":"def"},"result":"outdated"}
+    ]}"#;
+    let results = parse_commit_results(sample).unwrap();
+    assert_eq!(results.len(), 2);
+    assert_eq!(results[1].cursor.partition, PartitionId("1".into()));
+
+    let status = CommitStatus::NotAllOffsetsIncreased(results);
+    assert_eq!(status.num_outdated(), 1);
+}
+
+#[test]
+fn commits_of_only_outdated_cursors_were_already_committed() {
+    let sample = r#"{"items": [
+        {"cursor": {"partition": "0", "offset": "543", "event_type": "et",
+                    "cursor_token": "a"}, "result": "outdated"},
+        {"cursor": {"partition": "1", "offset": "923", "event_type": "et",
+                    "cursor_token": "b"}, "result": "outdated"}
+    ]}"#;
+    let status = commit_status(parse_commit_results(sample).unwrap());
+    match status {
+        CommitStatus::AlreadyCommitted(2) => (),
+        ref other => panic!("{:?}", other),
+    }
+    assert_eq!(status.num_outdated(), 2);
+
+    match commit_status(Vec::new()) {
+        CommitStatus::NotAllOffsetsIncreased(ref results) if results.is_empty() => (),
+        other => panic!("{:?}", other),
+    }
+}
+
+#[test]
+fn partitions_without_events_are_empty() {
+    let partition = |oldest: &str, newest: &str| -> EventTypePartition {
+        serde_json::from_value(json!({
+            "partition": "0",
+            "oldest_available_offset": oldest,
+            "newest_available_offset": newest,
+        })).unwrap()
+    };
+
+    assert!(partition("BEGIN", "BEGIN").is_empty());
+    assert!(partition("001-0001-000000000000000000", "001-0001--1").is_empty());
+    // All events were removed by the retention
+    assert!(partition("001-0001-000000000000000010", "001-0001-000000000000000009").is_empty());
+    assert!(!partition("001-0001-000000000000000000", "001-0001-000000000000000000").is_empty());
+    assert!(!partition("BEGIN", "001-0001-000000000000000009").is_empty());
+}
+
+#[cfg(test)]
+fn search_received_at(received_at_secs: &[i64], timestamp_secs: i64) -> u64 {
+    use chrono::TimeZone;
+
+    first_received_at_or_after(
+        received_at_secs.len() as u64,
+        Utc.timestamp(timestamp_secs, 0),
+        |idx| Ok(Utc.timestamp(received_at_secs[idx as usize], 0)),
+    ).unwrap()
+}
+
+#[test]
+fn no_events_are_searched_on_an_empty_partition() {
+    use chrono::TimeZone;
+
+    let idx = first_received_at_or_after(0, Utc.timestamp(10, 0), |_| {
+        panic!("there is no event to read")
+    }).unwrap();
+    assert_eq!(idx, 0);
+}
+
+#[test]
+fn a_single_event_is_found_at_or_after_the_timestamp() {
+    assert_eq!(search_received_at(&[10], 5), 0);
+    assert_eq!(search_received_at(&[10], 10), 0);
+    assert_eq!(search_received_at(&[10], 11), 1);
+}
+
+#[test]
+fn the_first_event_at_or_after_the_timestamp_is_found() {
+    let received_at = [10, 20, 20, 20, 30];
+
+    assert_eq!(search_received_at(&received_at, 0), 0);
+    assert_eq!(search_received_at(&received_at, 10), 0);
+    assert_eq!(search_received_at(&received_at, 11), 1);
+    assert_eq!(search_received_at(&received_at, 20), 1);
+    assert_eq!(search_received_at(&received_at, 21), 4);
+    assert_eq!(search_received_at(&received_at, 30), 4);
+    assert_eq!(search_received_at(&received_at, 31), 5);
+}
+
+#[test]
+fn wildcards_grant_access() {
+    let app = AuthorizationAttribute::new("service", "my-app");
+
+    assert!(AuthorizationAttribute::new("service", "my-app").grants_access_to(&app));
+    assert!(AuthorizationAttribute::new("service", "*").grants_access_to(&app));
+    assert!(AuthorizationAttribute::new("*", "*").grants_access_to(&app));
+    assert!(!AuthorizationAttribute::new("service", "other").grants_access_to(&app));
+    assert!(!AuthorizationAttribute::new("user", "*").grants_access_to(&app));
+}
+
+#[test]
+fn admins_and_readers_are_added_once() {
+    let app = AuthorizationAttribute::new("service", "my-app");
+    let mut authorization = SubscriptionAuthorization::default();
+
+    authorization.add_admin(app.clone());
+    authorization.add_admin(app.clone());
+    authorization.add_reader(app.clone());
+    authorization.add_reader(app.clone());
+
+    assert_eq!(authorization.admins, vec![app.clone()]);
+    assert_eq!(authorization.readers, vec![app]);
+}
+
+#[test]
+fn serialize_default_statistic() {
+    let statistic = EventTypeStatistics::new(1000, 512, 4, 2);
+    assert_eq!(
+        serde_json::to_value(&statistic).unwrap(),
+        json!({
+            "messages_per_minute": 1000,
+            "message_size": 512,
+            "read_parallelism": 4,
+            "write_parallelism": 2
+        })
+    );
+}
+
+#[test]
+fn unknown_fields_survive_a_round_trip() {
+    let sample = json!({
+        "name": "order.created",
+        "owning_application": "shop",
+        "category": "business",
+        "enrichment_strategies": [],
+        "schema": {"type": "json_schema", "schema": "{}", "version": "1.0.0", "created_at": "x"},
+        "ordering_key_fields": ["id"],
+        "options": {"retention_time": 1000}
+    });
+
+    let definition: EventTypeDefinition = serde_json::from_value(sample.clone()).unwrap();
+    assert_eq!(
+        definition.unknown_fields.get("ordering_key_fields"),
+        Some(&json!(["id"]))
+    );
+    assert_eq!(serde_json::to_value(&definition).unwrap(), sample);
+}
+
+#[test]
+fn subscriptions_in_use_block_deletion() {
+    let stats: stats::SubscriptionStats = serde_json::from_str(
+        r#"{"items":[{"event_type":"order.created","partitions":[
+            {"partition":"0","stream_id":"s1","unconsumed_events":0},
+            {"partition":"1","stream_id":"s1","unconsumed_events":3},
+            {"partition":"2","stream_id":"","unconsumed_events":0}]}]}"#,
+    ).unwrap();
+
+    assert_eq!(
+        deletion_blockers(&stats),
+        vec![
+            DeletionBlocker::ActiveStream {
+                stream_id: "s1".to_string(),
+                partitions: 2,
+            },
+            DeletionBlocker::UnconsumedEvents {
+                event_type: "order.created".to_string(),
+                partition: "1".to_string(),
+                unconsumed_events: 3,
+            },
+        ]
+    );
+    assert!(deletion_blockers(&Default::default()).is_empty());
+}
+
+#[test]
+fn async_calls_resolve_to_the_outcome_of_the_call() {
+    struct BrokenToken;
+
+    impl ProvidesAccessToken for BrokenToken {
+        fn get_token(&self) -> Result<Option<AccessToken>, TokenError> {
+            Err(TokenError::Other {
+                message: "no token".to_string(),
+            })
+        }
+    }
+
+    let client = NakadiApiClient::with_http_client(
+        "http://localhost:1",
+        HttpClient::new(),
+        Arc::new(BrokenToken),
+    );
+
+    match AsyncApiClient::new(client).get_event_type("et").wait() {
+        Err(ListError::Other(msg)) => assert!(msg.contains("no token")),
+        other => panic!("{:?}", other.map(|_| ())),
+    }
+}
+
+#[test]
+fn async_calls_wait_for_a_thread_of_the_client() {
+    struct BrokenToken;
+
+    impl ProvidesAccessToken for BrokenToken {
+        fn get_token(&self) -> Result<Option<AccessToken>, TokenError> {
+            Err(TokenError::Other {
+                message: "no token".to_string(),
+            })
+        }
+    }
+
+    let client = NakadiApiClient::with_http_client(
+        "http://localhost:1",
+        HttpClient::new(),
+        Arc::new(BrokenToken),
+    );
+    let async_client = AsyncApiClient::with_threads(client, 1);
+
+    let calls: Vec<_> = (0..3)
+        .map(|_| async_client.get_event_type("et"))
+        .collect();
+    for call in calls {
+        match call.wait() {
+            Err(ListError::Other(msg)) => assert!(msg.contains("no token")),
+            other => panic!("{:?}", other.map(|_| ())),
+        }
+    }
+}
@@ -0,0 +1,290 @@
+//! Which stream consumes which partitions
+//!
+//! `Nakadi` balances the partitions of a subscription over all streams
+//! connected to it. With many instances consuming the same subscription
+//! it is hard to tell which instance owns which partitions, especially
+//! while `Nakadi` is rebalancing. The monitor periodically takes an
+//! `AssignmentView` of the subscription, logs partitions that moved
+//! and passes the view to an `AssignmentListener`.
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::DateTime;
+use chrono::offset::Utc;
+
+use nakadi::api_client::{stats, NakadiApiClient};
+use nakadi::consumer::Consumer;
+use nakadi::model::SubscriptionId;
+
+/// A partition of an event type of the subscription
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub struct AssignedPartition {
+    pub event_type: String,
+    pub partition: String,
+    pub unconsumed_events: usize,
+}
+
+/// The assignment of the partitions of a subscription to streams
+#[derive(Debug, Clone, Serialize)]
+pub struct AssignmentView {
+    pub subscription_id: String,
+    pub owning_application: Option<String>,
+    pub consumer_group: Option<String>,
+    pub taken_at: DateTime<Utc>,
+    /// The stream of this instance if it is connected
+    pub own_stream_id: Option<String>,
+    /// The partitions by the id of the stream consuming them
+    pub streams: BTreeMap<String, Vec<AssignedPartition>>,
+    /// Partitions no stream consumes
+    pub unassigned: Vec<AssignedPartition>,
+}
+
+impl AssignmentView {
+    pub fn from_stats(
+        subscription_id: &SubscriptionId,
+        stats: stats::SubscriptionStats,
+        own_stream_id: Option<String>,
+    ) -> AssignmentView {
+        let mut streams: BTreeMap<String, Vec<AssignedPartition>> = BTreeMap::new();
+        let mut unassigned = Vec::new();
+        for event_type in stats.event_types {
+            for partition in event_type.partitions {
+                let assigned = AssignedPartition {
+                    event_type: event_type.event_type.clone(),
+                    partition: partition.partition,
+                    unconsumed_events: partition.unconsumed_events,
+                };
+                if partition.stream_id.is_empty() {
+                    unassigned.push(assigned);
+                } else {
+                    streams
+                        .entry(partition.stream_id)
+                        .or_insert_with(Vec::new)
+                        .push(assigned);
+                }
+            }
+        }
+
+        AssignmentView {
+            subscription_id: subscription_id.0.clone(),
+            owning_application: None,
+            consumer_group: None,
+            taken_at: Utc::now(),
+            own_stream_id,
+            streams,
+            unassigned,
+        }
+    }
+
+    /// The partitions consumed by this instance
+    pub fn own_partitions(&self) -> &[AssignedPartition] {
+        self.own_stream_id
+            .as_ref()
+            .and_then(|stream_id| self.streams.get(stream_id))
+            .map(|partitions| &partitions[..])
+            .unwrap_or(&[])
+    }
+
+    /// The stream consuming the partition
+    pub fn stream_of(&self, event_type: &str, partition: &str) -> Option<&str> {
+        self.streams
+            .iter()
+            .find(|&(_, partitions)| {
+                partitions
+                    .iter()
+                    .any(|p| p.event_type == event_type && p.partition == partition)
+            })
+            .map(|(stream_id, _)| stream_id.as_ref())
+    }
+
+    /// The partitions assigned to a different stream than in `previous`.
+    pub fn moved_since(&self, previous: &AssignmentView) -> Vec<MovedPartition> {
+        let mut moved = Vec::new();
+        let all = self.streams
+            .values()
+            .flat_map(|partitions| partitions.iter())
+            .chain(self.unassigned.iter());
+        for p in all {
+            let from = previous.stream_of(&p.event_type, &p.partition);
+            let to = self.stream_of(&p.event_type, &p.partition);
+            if from != to {
+                moved.push(MovedPartition {
+                    event_type: p.event_type.clone(),
+                    partition: p.partition.clone(),
+                    from: from.map(str::to_string),
+                    to: to.map(str::to_string),
+                });
+            }
+        }
+        moved
+    }
+}
+
+/// A partition that was assigned to another stream
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MovedPartition {
+    pub event_type: String,
+    pub partition: String,
+    /// `None` if it was not assigned
+    pub from: Option<String>,
+    /// `None` if it is not assigned anymore
+    pub to: Option<String>,
+}
+
+/// Gets notified whenever a new `AssignmentView` was taken.
+pub trait AssignmentListener {
+    fn on_assignment(&self, view: &AssignmentView);
+}
+
+/// An `AssignmentListener` that can be shared between threads.
+#[derive(Clone)]
+pub struct SharedAssignmentListener(pub Arc<AssignmentListener + Send + Sync>);
+
+impl fmt::Debug for SharedAssignmentListener {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SharedAssignmentListener")
+    }
+}
+
+/// Settings for periodically taking an `AssignmentView`
+#[derive(Debug, Clone)]
+pub struct AssignmentConfig {
+    /// How often to look at the assignment
+    pub interval: Duration,
+    pub listener: Option<SharedAssignmentListener>,
+}
+
+/// Periodically takes an `AssignmentView` while the consumer is running.
+pub fn start_monitor(
+    api_client: NakadiApiClient,
+    subscription_id: SubscriptionId,
+    consumer: Consumer,
+    config: AssignmentConfig,
+) {
+    let lifecycle = consumer.lifecycle().clone();
+    lifecycle.spawn_named("nakadion-assignment", move |lifecycle| {
+        let mut previous: Option<AssignmentView> = None;
+        while !lifecycle.wait_for_abort(config.interval) {
+            let view = match take_view(&api_client, &subscription_id, &consumer) {
+                Ok(view) => view,
+                Err(err) => {
+                    warn!(
+                        "[Assignment, subscription={}] Could not take the assignment: {}",
+                        subscription_id, err
+                    );
+                    continue;
+                }
+            };
+
+            if let Some(ref previous) = previous {
+                for moved in view.moved_since(previous) {
+                    info!(
+                        "[Assignment, subscription={}] Partition {} of {} moved from {} to {}",
+                        subscription_id,
+                        moved.partition,
+                        moved.event_type,
+                        moved.from.as_ref().map(|s| s.as_ref()).unwrap_or("<none>"),
+                        moved.to.as_ref().map(|s| s.as_ref()).unwrap_or("<none>")
+                    );
+                }
+            }
+            debug!(
+                "[Assignment, subscription={}] {} streams, {} own partitions, {} unassigned",
+                subscription_id,
+                view.streams.len(),
+                view.own_partitions().len(),
+                view.unassigned.len()
+            );
+
+            if let Some(ref listener) = config.listener {
+                listener.0.on_assignment(&view);
+            }
+            previous = Some(view);
+        }
+    });
+}
+
+fn take_view(
+    api_client: &NakadiApiClient,
+    subscription_id: &SubscriptionId,
+    consumer: &Consumer,
+) -> Result<AssignmentView, String> {
+    let subscription = api_client
+        .get_subscription(subscription_id)
+        .map_err(|err| format!("Could not get the subscription: {}", err))?;
+    let stats = api_client
+        .subscription_stats(subscription_id)
+        .map_err(|err| format!("Could not get the stats: {}", err))?;
+    let own_stream_id = consumer.introspect(None).connection.stream_id;
+
+    let mut view = AssignmentView::from_stats(subscription_id, stats, own_stream_id);
+    view.owning_application = Some(subscription.owning_application);
+    view.consumer_group = subscription.consumer_group;
+    Ok(view)
+}
+
+#[cfg(test)]
+fn test_view(assignment: &[(&str, &str)], own_stream_id: Option<&str>) -> AssignmentView {
+    let partitions = assignment
+        .iter()
+        .map(|&(partition, stream_id)| stats::PartitionInfo {
+            partition: partition.to_string(),
+            stream_id: stream_id.to_string(),
+            unconsumed_events: 0,
+            unknown_fields: Default::default(),
+        })
+        .collect();
+    let stats = stats::SubscriptionStats {
+        event_types: vec![stats::EventTypeInfo {
+            event_type: "et".to_string(),
+            partitions,
+            unknown_fields: Default::default(),
+        }],
+        unknown_fields: Default::default(),
+    };
+    AssignmentView::from_stats(
+        &SubscriptionId("s".to_string()),
+        stats,
+        own_stream_id.map(str::to_string),
+    )
+}
+
+#[test]
+fn partitions_are_grouped_by_stream() {
+    let view = test_view(&[("0", "a"), ("1", "b"), ("2", "a"), ("3", "")], Some("a"));
+
+    assert_eq!(view.streams.len(), 2);
+    let own: Vec<&str> = view.own_partitions()
+        .iter()
+        .map(|p| p.partition.as_ref())
+        .collect();
+    assert_eq!(own, vec!["0", "2"]);
+    assert_eq!(view.unassigned.len(), 1);
+    assert_eq!(view.stream_of("et", "1"), Some("b"));
+}
+
+#[test]
+fn moved_partitions_are_detected() {
+    let before = test_view(&[("0", "a"), ("1", "a"), ("2", "")], None);
+    let after = test_view(&[("0", "a"), ("1", "b"), ("2", "b")], None);
+
+    assert_eq!(
+        after.moved_since(&before),
+        vec![
+            MovedPartition {
+                event_type: "et".to_string(),
+                partition: "1".to_string(),
+                from: Some("a".to_string()),
+                to: Some("b".to_string()),
+            },
+            MovedPartition {
+                event_type: "et".to_string(),
+                partition: "2".to_string(),
+                from: None,
+                to: Some("b".to_string()),
+            },
+        ]
+    );
+}
@@ -0,0 +1,409 @@
+//! Replaying historical events while consuming live
+//!
+//! A `Backfill` consumes a subscription as usual and at the same time
+//! replays the events of an event type via the low level API. The replay
+//! starts at given cursors and ends at the cursors the subscription had
+//! committed when the `Backfill` was started. From there on only the
+//! subscription is consumed.
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::sync::Arc;
+use std::time::Duration;
+
+use failure::*;
+use serde_json;
+
+use nakadi::{Lifecycle, Nakadion, NakadionConfig, SubscriptionDiscovery};
+use nakadi::api_client::{InitialCursor, NakadiApiClient};
+use nakadi::handler::{BatchHandler, CreateHandlerError, HandlerFactory, ProcessingStatus};
+use nakadi::metrics::MetricsCollector;
+use nakadi::model::{EventType, FlowId, PartitionId};
+use nakadi::ordering::{distance, is_after};
+use nakadi::streaming_client::NakadiStreamingClient;
+
+/// The time to wait before reconnecting a broken replay stream
+const REPLAY_RECONNECT_DELAY_SECS: u64 = 1;
+
+/// Where events handed to a `SourcedBatchHandler` come from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum EventSource {
+    /// Replayed via the low level API. Not committed.
+    Replay,
+    /// Consumed from the subscription
+    Live,
+}
+
+/// A handler that is told where the events come from.
+///
+/// Replayed and live events of the same partition are handled
+/// concurrently by different handlers.
+pub trait SourcedBatchHandler {
+    /// Handle the events.
+    ///
+    /// Calling this method may never panic!
+    fn handle(
+        &mut self,
+        source: EventSource,
+        event_type: EventType,
+        events: &[u8],
+    ) -> ProcessingStatus;
+
+    /// Called once when the handler is not used anymore.
+    fn on_shutdown(&mut self, _source: EventSource) {}
+}
+
+pub trait SourcedHandlerFactory {
+    type Handler: SourcedBatchHandler + Send + 'static;
+    fn create_handler(
+        &self,
+        source: EventSource,
+        partition: &PartitionId,
+    ) -> Result<Self::Handler, CreateHandlerError>;
+}
+
+/// Consumes a subscription while replaying older events.
+pub struct Backfill {
+    live: Nakadion,
+    replay: Lifecycle,
+}
+
+impl Backfill {
+    /// Start consuming the subscription and replaying the events of
+    /// `event_type` after the `replay_from` cursors.
+    ///
+    /// The subscription must be given by id since its committed
+    /// cursors are needed before consumption starts.
+    pub fn start<HF, M>(
+        config: NakadionConfig,
+        api_client: NakadiApiClient,
+        streaming_client: NakadiStreamingClient<M>,
+        event_type: String,
+        replay_from: Vec<InitialCursor>,
+        handler_factory: HF,
+        metrics_collector: M,
+    ) -> Result<Backfill, Error>
+    where
+        HF: SourcedHandlerFactory + Send + Sync + 'static,
+        M: MetricsCollector + Clone + Send + Sync + 'static,
+    {
+        let subscription_id = match config.subscription_discovery {
+            SubscriptionDiscovery::Id(ref id) => id.clone(),
+            ref other => bail!("A backfill requires a subscription id but got {}", other),
+        };
+
+        let targets: HashMap<PartitionId, String> = api_client
+            .subscription_cursors(&subscription_id)?
+            .into_iter()
+            .filter(|cursor| cursor.event_type == event_type)
+            .map(|cursor| (cursor.partition, cursor.offset))
+            .collect();
+
+        let positions: HashMap<PartitionId, String> = replay_from
+            .into_iter()
+            .filter(|cursor| cursor.event_type == event_type)
+            .map(|cursor| (cursor.partition, cursor.offset))
+            .collect();
+
+        let handler_factory = Arc::new(handler_factory);
+
+        let live = Nakadion::start_with_clients(
+            config,
+            api_client,
+            streaming_client.clone(),
+            LiveHandlerFactory(handler_factory.clone()),
+            metrics_collector,
+            false,
+        )?;
+
+        let replay = Lifecycle::default().spawn_named("nakadion-backfill", move |lifecycle| {
+            replay_loop(
+                &streaming_client,
+                &event_type,
+                positions,
+                &targets,
+                &*handler_factory,
+                &lifecycle,
+            )
+        });
+
+        Ok(Backfill { live, replay })
+    }
+
+    /// Returns true while events are replayed.
+    pub fn replaying(&self) -> bool {
+        self.replay.running()
+    }
+
+    /// The consumer of the subscription
+    pub fn live(&self) -> &Nakadion {
+        &self.live
+    }
+
+    pub fn running(&self) -> bool {
+        self.replaying() || self.live.running()
+    }
+
+    /// Stop replaying and consuming and wait until all threads stopped.
+    pub fn stop(&self) {
+        self.replay.request_abort();
+        self.live.stop();
+        self.replay.wait();
+    }
+}
+
+struct LiveHandlerFactory<HF>(Arc<HF>);
+
+impl<HF: SourcedHandlerFactory> HandlerFactory for LiveHandlerFactory<HF> {
+    type Handler = LiveHandler<HF::Handler>;
+
+    fn create_handler(&self, partition: &PartitionId) -> Result<Self::Handler, CreateHandlerError> {
+        self.0
+            .create_handler(EventSource::Live, partition)
+            .map(LiveHandler)
+    }
+}
+
+struct LiveHandler<H>(H);
+
+impl<H: SourcedBatchHandler> BatchHandler for LiveHandler<H> {
+    fn handle(&mut self, event_type: EventType, events: &[u8]) -> ProcessingStatus {
+        self.0.handle(EventSource::Live, event_type, events)
+    }
+
+    fn on_shutdown(&mut self) {
+        self.0.on_shutdown(EventSource::Live)
+    }
+}
+
+#[derive(Deserialize)]
+struct ReplayBatch {
+    cursor: ReplayCursor,
+    events: Option<Vec<serde_json::Value>>,
+}
+
+#[derive(Deserialize)]
+struct ReplayCursor {
+    partition: PartitionId,
+    offset: String,
+}
+
+fn replay_loop<HF, M>(
+    streaming_client: &NakadiStreamingClient<M>,
+    event_type: &str,
+    mut positions: HashMap<PartitionId, String>,
+    targets: &HashMap<PartitionId, String>,
+    handler_factory: &HF,
+    lifecycle: &Lifecycle,
+) where
+    HF: SourcedHandlerFactory,
+    M: MetricsCollector,
+{
+    let mut handlers: HashMap<PartitionId, HF::Handler> = HashMap::new();
+
+    'connect: loop {
+        positions.retain(|partition, offset| {
+            targets
+                .get(partition)
+                .map(|target| is_after(offset, target))
+                .unwrap_or(false)
+        });
+
+        if positions.is_empty() {
+            info!(
+                "[Backfill, event_type={}] Replay caught up. Consuming live only.",
+                event_type
+            );
+            break;
+        }
+
+        if lifecycle.abort_requested() {
+            info!("[Backfill, event_type={}] Replay stopped.", event_type);
+            break;
+        }
+
+        let cursors: Vec<InitialCursor> = positions
+            .iter()
+            .map(|(partition, offset)| InitialCursor {
+                event_type: event_type.to_string(),
+                partition: partition.clone(),
+                offset: offset.clone(),
+                unknown_fields: Default::default(),
+            })
+            .collect();
+
+        let lines = match streaming_client.stream_event_type(event_type, &cursors, FlowId::default())
+        {
+            Ok(lines) => lines,
+            Err(err) => {
+                if err.is_permanent() {
+                    error!(
+                        "[Backfill, event_type={}] Could not replay. Stopping replay: {}",
+                        event_type, err
+                    );
+                    break;
+                }
+                warn!(
+                    "[Backfill, event_type={}] Could not connect for replay: {}",
+                    event_type, err
+                );
+                lifecycle.wait_for_abort(Duration::from_secs(REPLAY_RECONNECT_DELAY_SECS));
+                continue;
+            }
+        };
+
+        for line in lines {
+            if lifecycle.abort_requested() {
+                info!("[Backfill, event_type={}] Replay stopped.", event_type);
+                break 'connect;
+            }
+
+            let line = match line {
+                Ok(line) => line,
+                Err(err) => {
+                    warn!(
+                        "[Backfill, event_type={}] Replay connection broke: {}",
+                        event_type, err
+                    );
+                    continue 'connect;
+                }
+            };
+
+            let batch: ReplayBatch = match serde_json::from_slice(&line.bytes) {
+                Ok(batch) => batch,
+                Err(err) => {
+                    error!(
+                        "[Backfill, event_type={}] Invalid batch. Stopping replay: {}",
+                        event_type, err
+                    );
+                    break 'connect;
+                }
+            };
+
+            let partition = batch.cursor.partition;
+            let offset = batch.cursor.offset;
+            let target = match (targets.get(&partition), positions.contains_key(&partition)) {
+                (Some(target), true) => target,
+                _ => continue,
+            };
+
+            if let Some(events) = batch.events {
+                let n = num_events_to_replay(&offset, target, events.len());
+                if n > 0 {
+                    let handler = match handlers.entry(partition.clone()) {
+                        Entry::Occupied(entry) => entry.into_mut(),
+                        Entry::Vacant(entry) => {
+                            match handler_factory.create_handler(EventSource::Replay, &partition)
+                            {
+                                Ok(handler) => entry.insert(handler),
+                                Err(err) => {
+                                    error!(
+                                        "[Backfill, event_type={}, partition={}] Could not \
+                                         create handler. Stopping replay: {}",
+                                        event_type, partition, err
+                                    );
+                                    break 'connect;
+                                }
+                            }
+                        }
+                    };
+
+                    let events = match serde_json::to_vec(&events[..n]) {
+                        Ok(events) => events,
+                        Err(err) => {
+                            error!(
+                                "[Backfill, event_type={}, partition={}] Could not serialize \
+                                 events. Stopping replay: {}",
+                                event_type, partition, err
+                            );
+                            break 'connect;
+                        }
+                    };
+
+                    if let ProcessingStatus::Failed { reason } =
+                        handler.handle(EventSource::Replay, EventType::new(event_type), &events)
+                    {
+                        error!(
+                            "[Backfill, event_type={}, partition={}] Stopping replay for \
+                             reason '{}'",
+                            event_type, partition, reason
+                        );
+                        break 'connect;
+                    }
+                }
+            }
+
+            if is_after(&offset, target) {
+                positions.insert(partition, offset);
+            } else {
+                info!(
+                    "[Backfill, event_type={}, partition={}] Replay caught up.",
+                    event_type, partition
+                );
+                positions.remove(&partition);
+                if let Some(mut handler) = handlers.remove(&partition) {
+                    handler.on_shutdown(EventSource::Replay);
+                }
+                if positions.is_empty() {
+                    continue 'connect;
+                }
+            }
+        }
+    }
+
+    for (_, mut handler) in handlers {
+        handler.on_shutdown(EventSource::Replay);
+    }
+}
+
+/// The number of events of a batch ending at `offset` that are not
+/// after `target` and therefore not consumed from the subscription.
+fn num_events_to_replay(offset: &str, target: &str, num_events: usize) -> usize {
+    if offset == target || is_after(offset, target) {
+        num_events
+    } else {
+        match distance(target, offset) {
+            Some(beyond_target) => num_events.saturating_sub(beyond_target as usize),
+            None => 0,
+        }
+    }
+}
+
+#[test]
+fn batches_before_the_target_are_replayed_completely() {
+    assert_eq!(
+        num_events_to_replay(
+            "001-0001-000000000000000009",
+            "001-0001-000000000000000019",
+            10
+        ),
+        10
+    );
+    assert_eq!(
+        num_events_to_replay(
+            "001-0001-000000000000000019",
+            "001-0001-000000000000000019",
+            10
+        ),
+        10
+    );
+}
+
+#[test]
+fn batches_crossing_the_target_are_cut() {
+    assert_eq!(
+        num_events_to_replay(
+            "001-0001-000000000000000019",
+            "001-0001-000000000000000015",
+            10
+        ),
+        6
+    );
+    assert_eq!(
+        num_events_to_replay(
+            "001-0001-000000000000000039",
+            "001-0001-000000000000000015",
+            10
+        ),
+        0
+    );
+}
@@ -1,30 +1,47 @@
+use std::fmt;
 use std::time::Instant;
 
+use serde_json;
+
+use nakadi::buffer_pool::PooledBuffer;
+use nakadi::model::StreamId;
+use nakadi::stage_timing::StageTimings;
+
 pub struct Batch {
     pub batch_line: BatchLine,
     pub received_at: Instant,
+    /// The stream the batch was received on. Its cursor
+    /// can only be committed on this stream.
+    pub stream_id: StreamId,
+    /// The time spent in the stages the batch passed so far
+    pub timings: StageTimings,
 }
 
 #[derive(Debug, PartialEq, Eq)]
 pub struct BatchLine {
-    bytes: Vec<u8>,
+    /// Given back to its pool once the batch is dropped
+    /// after its cursor has been committed
+    bytes: PooledBuffer,
     items: LineItems,
 }
 
 impl BatchLine {
-    pub fn new(bytes: Vec<u8>) -> Result<BatchLine, String> {
-        let items = lineparsing::parse_line(&bytes)?;
+    pub fn new<B: Into<PooledBuffer>>(bytes: B) -> Result<BatchLine, LineParseError> {
+        let bytes = bytes.into();
+        let items = match lineparsing::parse_line(&bytes) {
+            Ok(items) => items,
+            Err((offset, reason)) => return Err(LineParseError::new(&bytes, offset, reason)),
+        };
 
         Ok(BatchLine { bytes, items })
     }
 
     #[allow(unused)]
-    pub fn from_slice(bytes: &[u8]) -> Result<BatchLine, String> {
+    pub fn from_slice(bytes: &[u8]) -> Result<BatchLine, LineParseError> {
         let bytes: Vec<_> = bytes.iter().cloned().collect();
         BatchLine::new((bytes))
     }
 
-    #[allow(unused)]
     pub fn bytes(&self) -> &[u8] {
         &self.bytes
     }
@@ -62,11 +79,95 @@ impl BatchLine {
         self.items.info.map(|e| &self.bytes[e.0..e.1 + 1])
     }
 
+    /// The `info` object parsed as JSON
+    pub fn info_json(&self) -> Option<Result<serde_json::Value, String>> {
+        self.info().map(|info| {
+            serde_json::from_slice(info).map_err(|err| format!("Info is not JSON: {}", err))
+        })
+    }
+
     pub fn is_keep_alive_line(&self) -> bool {
         self.items.events.is_none()
     }
 }
 
+/// Where and why a line received from `Nakadi` could not be parsed
+///
+/// Lines can be several megabytes long so the line itself is not
+/// part of the error. The snippet only keeps the JSON structure around
+/// the failure and masks the payload which might contain personal data.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LineParseError {
+    pub reason: String,
+    /// The byte where parsing failed
+    pub offset: usize,
+    pub line_length: usize,
+    /// The partition of the cursor if it could still be found
+    pub partition: Option<String>,
+    /// The bytes around `offset` with everything but
+    /// the JSON structure replaced by `*`
+    pub snippet: String,
+}
+
+/// Bytes before and after the offset to show in a snippet
+const SNIPPET_RADIUS: usize = 32;
+
+impl LineParseError {
+    pub fn new(line: &[u8], offset: usize, reason: String) -> LineParseError {
+        LineParseError {
+            reason,
+            offset,
+            line_length: line.len(),
+            partition: find_partition(line),
+            snippet: redacted_snippet(line, offset),
+        }
+    }
+}
+
+impl fmt::Display for LineParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} at byte {} of {} (partition: {}) near `{}`",
+            self.reason,
+            self.offset,
+            self.line_length,
+            self.partition.as_ref().map(|p| p.as_str()).unwrap_or("unknown"),
+            self.snippet
+        )
+    }
+}
+
+fn redacted_snippet(line: &[u8], offset: usize) -> String {
+    let offset = ::std::cmp::min(offset, line.len());
+    let start = offset.saturating_sub(SNIPPET_RADIUS);
+    let end = ::std::cmp::min(offset + SNIPPET_RADIUS, line.len());
+    line[start..end]
+        .iter()
+        .map(|&b| match b {
+            b'{' | b'}' | b'[' | b']' | b'"' | b':' | b',' => b as char,
+            b if (b as char).is_ascii_whitespace() => ' ',
+            _ => '*',
+        })
+        .collect()
+}
+
+/// Looks for the partition of the cursor in a line that could not be parsed.
+fn find_partition(line: &[u8]) -> Option<String> {
+    const PARTITION_FIELD: &'static [u8] = b"\"partition\":\"";
+
+    let cursor_at = line.windows(8).position(|w| w == b"\"cursor\"")?;
+    let rest = &line[cursor_at..];
+    let value_at = rest
+        .windows(PARTITION_FIELD.len())
+        .position(|w| w == PARTITION_FIELD)? + PARTITION_FIELD.len();
+    let value = &rest[value_at..];
+    let len = value.iter().position(|&b| b == b'"')?;
+    ::std::str::from_utf8(&value[..len])
+        .ok()
+        .map(|p| p.to_owned())
+}
+
 #[derive(Debug, PartialEq, Eq)]
 pub struct LineItems {
     pub cursor: Cursor,
@@ -191,6 +292,20 @@ fn parse_subscription_batch_line_keep_alive_with_info() {
     assert_eq!(line.is_keep_alive_line(), true);
 }
 
+#[test]
+fn parse_subscription_batch_line_info_as_json() {
+    let line_sample = r#"{"cursor":{"partition":"6","offset":"543","#.to_owned()
+        + r#""event_type":"order.ORDER_RECEIVED","cursor_token":"#
+        + r#""b75c3102-98a4-4385-a5fd-b96f1d7872f2"},"info":{"debug":"Stream started"}}"#;
+
+    let line = BatchLine::from_slice(line_sample.as_bytes()).unwrap();
+
+    assert_eq!(
+        line.info_json(),
+        Some(Ok(json!({"debug": "Stream started"})))
+    );
+}
+
 #[test]
 fn parse_subscription_batch_line_keep_alive_without_info() {
     let line_sample = r#"{"cursor":{"partition":"6","offset":"543","#.to_owned()
@@ -211,9 +326,26 @@ fn parse_subscription_batch_line_keep_alive_without_info() {
     assert_eq!(line.is_keep_alive_line(), true);
 }
 
+#[test]
+fn unparsable_lines_tell_where_they_broke() {
+    let line_sample = br#"{"cursor":{"partition":"6","offset":"543","event_type":"secret"},"events":[{"a":"b"}"#;
+
+    let err = BatchLine::from_slice(line_sample).unwrap_err();
+
+    assert_eq!(err.reason, "Not an array. Missing ending `]`.");
+    assert_eq!(err.offset, 74);
+    assert_eq!(err.line_length, line_sample.len());
+    assert_eq!(err.partition, Some("6".to_owned()));
+    assert_eq!(err.snippet, r#""**********":"******"},"******":[{"*":"*"}"#);
+    assert!(!err.to_string().contains("secret"));
+}
+
 mod lineparsing {
     use super::{Cursor, LineItems};
 
+    /// Where parsing failed and why
+    pub type Failure = (usize, String);
+
     const OBJ_OPEN: u8 = b'{';
     const OBJ_CLOSE: u8 = b'}';
     const ARRAY_OPEN: u8 = b'[';
@@ -228,7 +360,7 @@ mod lineparsing {
     const CURSOR_PARTITION_LABEL: &'static [u8] = b"partition";
     const CURSOR_EVENT_TYPE_LABEL: &'static [u8] = b"event_type";
 
-    pub fn parse_line(json_bytes: &[u8]) -> Result<LineItems, String> {
+    pub fn parse_line(json_bytes: &[u8]) -> Result<LineItems, Failure> {
         let mut line_items = LineItems::default();
 
         let mut next_byte = 0;
@@ -241,7 +373,7 @@ mod lineparsing {
         }
 
         if line_items.cursor.line_position.1 == 0 {
-            Err("No cursor".into())
+            Err((json_bytes.len(), "No cursor".into()))
         } else {
             Ok(line_items)
         }
@@ -251,10 +383,10 @@ mod lineparsing {
         json_bytes: &[u8],
         start: usize,
         line_items: &mut LineItems,
-    ) -> Result<Option<usize>, String> {
+    ) -> Result<Option<usize>, Failure> {
         if let Ok(Some((begin, end))) = next_string(json_bytes, start) {
             if end - begin < 3 {
-                return Err("String can not be a label if len<3".into());
+                return Err((begin, "String can not be a label if len<3".into()));
             }
 
             let label = &json_bytes[begin + 1..end];
@@ -283,7 +415,7 @@ mod lineparsing {
         }
     }
 
-    fn next_string(json_bytes: &[u8], start: usize) -> Result<Option<(usize, usize)>, String> {
+    fn next_string(json_bytes: &[u8], start: usize) -> Result<Option<(usize, usize)>, Failure> {
         if start == json_bytes.len() {
             return Ok(None);
         }
@@ -301,9 +433,9 @@ mod lineparsing {
         }
 
         if idx_begin >= json_bytes.len() - 1 {
-            return Err(format!(
-                "Not a string. Missing starting `\"` after pos {}",
-                start
+            return Err((
+                idx_begin,
+                format!("Not a string. Missing starting `\"` after pos {}", start),
             ));
         }
 
@@ -327,19 +459,21 @@ mod lineparsing {
         }
 
         if idx_end == json_bytes.len() {
-            let start_seq = ::std::str::from_utf8(&json_bytes[start..idx_end]).unwrap_or("???");
-            return Err(format!(
-                "Not a string. Missing ending `\"` after pos {} but before {} in {}",
-                start, idx_end, start_seq
+            return Err((
+                idx_begin,
+                format!(
+                    "Not a string. Missing ending `\"` after pos {} but before {}",
+                    start, idx_end
+                ),
             ));
         }
 
         Ok(Some((idx_begin, idx_end)))
     }
 
-    fn find_next_obj(json_bytes: &[u8], start: usize) -> Result<(usize, usize), String> {
+    fn find_next_obj(json_bytes: &[u8], start: usize) -> Result<(usize, usize), Failure> {
         if start == json_bytes.len() {
-            return Err("Reached end".into());
+            return Err((start, "Reached end".into()));
         }
 
         let mut idx_begin = start;
@@ -351,7 +485,7 @@ mod lineparsing {
         }
 
         if idx_begin >= json_bytes.len() - 1 {
-            return Err("Not an object. Missing starting `{`.".into());
+            return Err((start, "Not an object. Missing starting `{`.".into()));
         }
 
         let mut idx_end = idx_begin + 1;
@@ -380,15 +514,15 @@ mod lineparsing {
         }
 
         if idx_end == json_bytes.len() {
-            return Err("Not an object. Missing ending `}`.".into());
+            return Err((idx_begin, "Not an object. Missing ending `}`.".into()));
         }
 
         Ok((idx_begin, idx_end))
     }
 
-    fn find_next_array(json_bytes: &[u8], start: usize) -> Result<(usize, usize), String> {
+    fn find_next_array(json_bytes: &[u8], start: usize) -> Result<(usize, usize), Failure> {
         if start == json_bytes.len() {
-            return Err("Reached end".into());
+            return Err((start, "Reached end".into()));
         }
 
         let mut idx_begin = start;
@@ -400,7 +534,7 @@ mod lineparsing {
         }
 
         if idx_begin >= json_bytes.len() - 1 {
-            return Err("Not an array. Missing starting `[`.".into());
+            return Err((start, "Not an array. Missing starting `[`.".into()));
         }
 
         let mut idx_end = idx_begin + 1;
@@ -429,7 +563,7 @@ mod lineparsing {
         }
 
         if idx_end == json_bytes.len() {
-            return Err("Not an array. Missing ending `]`.".into());
+            return Err((idx_begin, "Not an array. Missing ending `]`.".into()));
         }
 
         Ok((idx_begin, idx_end))
@@ -440,7 +574,7 @@ mod lineparsing {
         cursor: &mut Cursor,
         start: usize,
         end: usize,
-    ) -> Result<(), String> {
+    ) -> Result<(), Failure> {
         let mut next_byte = start;
         while next_byte <= end {
             if let Some(end) = parse_next_cursor_item(json_bytes, next_byte, cursor)? {
@@ -450,7 +584,7 @@ mod lineparsing {
             }
         }
         if cursor.partition.0 == 0 {
-            Err(format!("Partition missing in cursor @ {}", next_byte))
+            Err((start, "Partition missing in cursor".into()))
         } else {
             Ok(())
         }
@@ -460,10 +594,10 @@ mod lineparsing {
         json_bytes: &[u8],
         start: usize,
         cursor: &mut Cursor,
-    ) -> Result<Option<usize>, String> {
+    ) -> Result<Option<usize>, Failure> {
         if let Ok(Some((begin, end))) = next_string(json_bytes, start) {
             if end - begin < 2 {
-                return Err("String can not be a label if len<2".into());
+                return Err((begin, "String can not be a label if len<2".into()));
             }
 
             let label = &json_bytes[begin + 1..end];
@@ -471,25 +605,25 @@ mod lineparsing {
                 CURSOR_PARTITION_LABEL => {
                     if let Some((a, b)) = next_string(json_bytes, end + 1)? {
                         if b - a < 2 {
-                            return Err("Empty String for partition".into());
+                            return Err((a, "Empty String for partition".into()));
                         } else {
                             cursor.partition = (a + 1, b - 1);
                             b
                         }
                     } else {
-                        return Err("No String for partition".into());
+                        return Err((end, "No String for partition".into()));
                     }
                 }
                 CURSOR_EVENT_TYPE_LABEL => {
                     if let Some((a, b)) = next_string(json_bytes, end + 1)? {
                         if b - a < 2 {
-                            return Err("Empty String for event_type".into());
+                            return Err((a, "Empty String for event_type".into()));
                         } else {
                             cursor.event_type = (a + 1, b - 1);
                             b
                         }
                     } else {
-                        return Err("No String for event_type".into());
+                        return Err((end, "No String for event_type".into()));
                     }
                 }
                 _ => end,
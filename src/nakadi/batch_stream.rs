@@ -0,0 +1,132 @@
+//! Consuming batches as a `futures::Stream`
+//!
+//! Instead of implementing a `BatchHandler` the batches can be taken from
+//! a `BatchStream` and processed by a futures pipeline. Each batch is
+//! answered with `processed` or `failed`. The worker of the partition
+//! waits for the answer before it hands over the next batch of the
+//! partition and the cursor is only committed once the batch was
+//! processed. A batch dropped without an answer fails.
+use futures::sync::{mpsc, oneshot};
+use futures::{Future, Poll, Sink, Stream};
+
+use nakadi::handler::{BatchHandler, CreateHandlerError, HandlerFactory, ProcessingStatus};
+use nakadi::model::{EventType, PartitionId};
+
+/// A batch taken from a `BatchStream`
+#[derive(Debug)]
+pub struct StreamedBatch {
+    pub partition: PartitionId,
+    pub event_type: String,
+    /// The JSON array of the events
+    pub events: Vec<u8>,
+    answer: oneshot::Sender<ProcessingStatus>,
+}
+
+impl StreamedBatch {
+    /// The batch was processed and its cursor can be committed.
+    pub fn processed(self, num_events_hint: Option<usize>) {
+        let _ = self.answer
+            .send(ProcessingStatus::Processed(num_events_hint));
+    }
+
+    /// The batch could not be processed.
+    pub fn failed<T: Into<String>>(self, reason: T) {
+        let _ = self.answer.send(ProcessingStatus::failed(reason));
+    }
+}
+
+/// The batches of all partitions
+pub struct BatchStream {
+    receiver: mpsc::Receiver<StreamedBatch>,
+}
+
+impl Stream for BatchStream {
+    type Item = StreamedBatch;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<StreamedBatch>, ()> {
+        self.receiver.poll()
+    }
+}
+
+/// Creates a `HandlerFactory` whose handlers pass the batches
+/// to the returned `BatchStream`.
+///
+/// Up to `buffer` batches plus one batch per partition are
+/// queued until the stream takes them.
+pub fn batch_stream(buffer: usize) -> (StreamHandlerFactory, BatchStream) {
+    let (sender, receiver) = mpsc::channel(buffer);
+    (StreamHandlerFactory { sender }, BatchStream { receiver })
+}
+
+/// Creates handlers that pass the batches to a `BatchStream`
+#[derive(Clone)]
+pub struct StreamHandlerFactory {
+    sender: mpsc::Sender<StreamedBatch>,
+}
+
+impl HandlerFactory for StreamHandlerFactory {
+    type Handler = StreamHandler;
+
+    fn create_handler(&self, partition: &PartitionId) -> Result<StreamHandler, CreateHandlerError> {
+        Ok(StreamHandler {
+            partition: partition.clone(),
+            sender: self.sender.clone(),
+        })
+    }
+}
+
+/// Passes the batches of a partition to a `BatchStream`
+pub struct StreamHandler {
+    partition: PartitionId,
+    sender: mpsc::Sender<StreamedBatch>,
+}
+
+impl BatchHandler for StreamHandler {
+    fn handle(&mut self, event_type: EventType, events: &[u8]) -> ProcessingStatus {
+        let (answer, answered) = oneshot::channel();
+        let batch = StreamedBatch {
+            partition: self.partition.clone(),
+            event_type: event_type.0.to_string(),
+            events: events.to_vec(),
+            answer,
+        };
+        if self.sender.clone().send(batch).wait().is_err() {
+            return ProcessingStatus::failed("The batch stream was dropped");
+        }
+        match answered.wait() {
+            Ok(status) => status,
+            Err(_) => ProcessingStatus::failed("The batch was dropped without an answer"),
+        }
+    }
+}
+
+#[test]
+fn batches_are_answered_through_the_stream() {
+    use std::thread;
+
+    let (factory, stream) = batch_stream(1);
+    let mut handler = factory
+        .create_handler(&PartitionId("0".to_string()))
+        .unwrap();
+
+    let consumer = thread::spawn(move || {
+        let mut batches = stream.wait();
+        let first = batches.next().unwrap().unwrap();
+        assert_eq!(first.partition, PartitionId("0".to_string()));
+        assert_eq!(first.events, b"[1]".to_vec());
+        first.processed(Some(1));
+        let second = batches.next().unwrap().unwrap();
+        drop(second);
+    });
+
+    match handler.handle(EventType::new("et"), b"[1]") {
+        ProcessingStatus::Processed(Some(1)) => (),
+        other => panic!("{:?}", other),
+    }
+    match handler.handle(EventType::new("et"), b"[2]") {
+        ProcessingStatus::Failed { .. } => (),
+        other => panic!("{:?}", other),
+    }
+    consumer.join().unwrap();
+}
@@ -0,0 +1,171 @@
+//! Reusing the buffers lines from `Nakadi` are read into
+//!
+//! Every line received from `Nakadi` is read into a buffer that lives
+//! until the cursor of its batch has been committed. Instead of freeing
+//! the buffer afterwards it is given back to the `BufferPool` it was taken
+//! from so that the next line can be read into it without allocating.
+use std::fmt;
+use std::mem;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex};
+
+/// The maximum number of buffers kept by default
+pub const DEFAULT_MAX_POOLED_BUFFERS: usize = 256;
+
+/// Buffers that grew larger than this are freed instead of being pooled
+pub const DEFAULT_MAX_POOLED_CAPACITY: usize = 4 * 1024 * 1024;
+
+/// The capacity of a newly allocated buffer
+const INITIAL_BUFFER_CAPACITY: usize = 16 * 1024;
+
+/// Buffers shared between the threads lines pass through
+#[derive(Clone)]
+pub struct BufferPool {
+    buffers: Arc<Mutex<Vec<Vec<u8>>>>,
+    max_buffers: usize,
+    max_capacity: usize,
+}
+
+impl BufferPool {
+    /// Create a pool keeping at most `max_buffers` buffers with
+    /// a capacity of at most `max_capacity` bytes each.
+    pub fn new(max_buffers: usize, max_capacity: usize) -> BufferPool {
+        BufferPool {
+            buffers: Arc::new(Mutex::new(Vec::new())),
+            max_buffers,
+            max_capacity,
+        }
+    }
+
+    /// Take an empty buffer from the pool. A new buffer is
+    /// allocated if the pool is empty.
+    pub fn take(&self) -> PooledBuffer {
+        let buffer = self.update(|buffers| buffers.pop())
+            .unwrap_or_else(|| Vec::with_capacity(INITIAL_BUFFER_CAPACITY));
+        PooledBuffer {
+            buffer,
+            pool: Some(self.clone()),
+        }
+    }
+
+    /// The number of buffers currently available
+    pub fn available(&self) -> usize {
+        self.update(|buffers| buffers.len())
+    }
+
+    fn give_back(&self, mut buffer: Vec<u8>) {
+        if buffer.capacity() > self.max_capacity {
+            return;
+        }
+        buffer.clear();
+        let max_buffers = self.max_buffers;
+        self.update(|buffers| {
+            if buffers.len() < max_buffers {
+                buffers.push(buffer);
+            }
+        })
+    }
+
+    fn update<F, T>(&self, f: F) -> T
+    where
+        F: FnOnce(&mut Vec<Vec<u8>>) -> T,
+    {
+        match self.buffers.lock() {
+            Ok(mut buffers) => f(&mut buffers),
+            Err(poisoned) => f(&mut poisoned.into_inner()),
+        }
+    }
+}
+
+impl Default for BufferPool {
+    fn default() -> BufferPool {
+        BufferPool::new(DEFAULT_MAX_POOLED_BUFFERS, DEFAULT_MAX_POOLED_CAPACITY)
+    }
+}
+
+/// A buffer that returns to its pool when dropped
+pub struct PooledBuffer {
+    buffer: Vec<u8>,
+    pool: Option<BufferPool>,
+}
+
+impl PooledBuffer {
+    /// Detach the buffer from its pool.
+    pub fn into_vec(mut self) -> Vec<u8> {
+        self.pool = None;
+        mem::replace(&mut self.buffer, Vec::new())
+    }
+}
+
+impl From<Vec<u8>> for PooledBuffer {
+    /// A buffer that does not belong to a pool
+    fn from(buffer: Vec<u8>) -> PooledBuffer {
+        PooledBuffer { buffer, pool: None }
+    }
+}
+
+impl Deref for PooledBuffer {
+    type Target = Vec<u8>;
+
+    fn deref(&self) -> &Vec<u8> {
+        &self.buffer
+    }
+}
+
+impl DerefMut for PooledBuffer {
+    fn deref_mut(&mut self) -> &mut Vec<u8> {
+        &mut self.buffer
+    }
+}
+
+impl Drop for PooledBuffer {
+    fn drop(&mut self) {
+        if let Some(pool) = self.pool.take() {
+            pool.give_back(mem::replace(&mut self.buffer, Vec::new()));
+        }
+    }
+}
+
+impl PartialEq for PooledBuffer {
+    fn eq(&self, other: &PooledBuffer) -> bool {
+        self.buffer == other.buffer
+    }
+}
+
+impl Eq for PooledBuffer {}
+
+impl fmt::Debug for PooledBuffer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.buffer.fmt(f)
+    }
+}
+
+#[test]
+fn dropped_buffers_are_reused() {
+    let pool = BufferPool::new(1, 64 * 1024);
+
+    let mut buffer = pool.take();
+    buffer.extend_from_slice(b"line");
+    let ptr = buffer.as_ptr();
+    drop(buffer);
+    assert_eq!(pool.available(), 1);
+
+    let buffer = pool.take();
+    assert!(buffer.is_empty());
+    assert_eq!(buffer.as_ptr(), ptr);
+    assert_eq!(pool.available(), 0);
+}
+
+#[test]
+fn oversized_and_detached_buffers_are_not_pooled() {
+    let pool = BufferPool::new(2, 64 * 1024);
+
+    let mut oversized = pool.take();
+    oversized.reserve(1024 * 1024);
+    drop(oversized);
+
+    let detached = pool.take().into_vec();
+    drop(detached);
+
+    assert_eq!(pool.available(), 0);
+}
@@ -0,0 +1,229 @@
+//! Access to multiple `Nakadi` clusters from one process
+//!
+//! Each cluster is registered once with its host, token provider and
+//! TLS settings and can then be referenced by name.
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use failure::*;
+use reqwest::{Certificate, Client as HttpClient, ClientBuilder as HttpClientBuilder};
+
+use auth::ProvidesAccessToken;
+use nakadi::api_client::NakadiApiClient;
+use nakadi::metrics::MetricsCollector;
+use nakadi::publisher::NakadiPublisher;
+use nakadi::streaming_client::{self, NakadiStreamingClient};
+
+/// TLS settings for connecting to a cluster
+#[derive(Debug, Clone, Default)]
+pub struct TlsConfig {
+    /// Additional DER encoded root certificates to trust
+    pub root_certificates_der: Vec<Vec<u8>>,
+    /// Do not verify that the certificate matches the host.
+    ///
+    /// Only use this for testing.
+    pub disable_hostname_verification: bool,
+}
+
+/// Settings for a `Nakadi` cluster
+#[derive(Debug, Clone)]
+pub struct ClusterConfig {
+    /// The URI prefix for the Nakadi Host, e.g. "https://my.nakadi.com"
+    pub nakadi_host: String,
+    /// The timeout for requests other than streaming
+    pub request_timeout: Duration,
+    pub tls: TlsConfig,
+}
+
+/// A configured `Nakadi` cluster that creates clients for it.
+#[derive(Clone)]
+pub struct ClusterHandle {
+    name: String,
+    nakadi_host: String,
+    token_provider: Arc<ProvidesAccessToken + Send + Sync + 'static>,
+    http_client: HttpClient,
+    streaming_http_client: HttpClient,
+}
+
+impl ClusterHandle {
+    pub fn new<N: Into<String>>(
+        name: N,
+        config: ClusterConfig,
+        token_provider: Arc<ProvidesAccessToken + Send + Sync + 'static>,
+    ) -> Result<ClusterHandle, Error> {
+        let http_client = build_http_client(&config.tls, Some(config.request_timeout))?;
+        let streaming_http_client = build_http_client(&config.tls, None)?;
+
+        Ok(ClusterHandle {
+            name: name.into(),
+            nakadi_host: config.nakadi_host,
+            token_provider,
+            http_client,
+            streaming_http_client,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn nakadi_host(&self) -> &str {
+        &self.nakadi_host
+    }
+
+    pub fn api_client(&self) -> NakadiApiClient {
+        NakadiApiClient::with_http_client(
+            self.nakadi_host.clone(),
+            self.http_client.clone(),
+            self.token_provider.clone(),
+        )
+    }
+
+    pub fn publisher(&self) -> NakadiPublisher {
+        NakadiPublisher::with_http_client(
+            self.nakadi_host.clone(),
+            self.http_client.clone(),
+            self.token_provider.clone(),
+        )
+    }
+
+    /// Create a streaming client. The host of `config` is replaced
+    /// by the host of the cluster.
+    pub fn streaming_client<M>(
+        &self,
+        mut config: streaming_client::Config,
+        metrics_collector: M,
+    ) -> NakadiStreamingClient<M>
+    where
+        M: MetricsCollector,
+    {
+        config.nakadi_host = self.nakadi_host.clone();
+        NakadiStreamingClient::with_http_client(
+            config,
+            self.streaming_http_client.clone(),
+            self.token_provider.clone(),
+            metrics_collector,
+        )
+    }
+}
+
+/// The clusters known to the application
+#[derive(Clone, Default)]
+pub struct ClusterRegistry {
+    clusters: HashMap<String, ClusterHandle>,
+}
+
+impl ClusterRegistry {
+    pub fn new() -> ClusterRegistry {
+        ClusterRegistry::default()
+    }
+
+    /// Register a cluster under the given name.
+    ///
+    /// Fails if a cluster with that name has already been registered.
+    pub fn register<N, P>(
+        &mut self,
+        name: N,
+        config: ClusterConfig,
+        token_provider: P,
+    ) -> Result<&ClusterHandle, Error>
+    where
+        N: Into<String>,
+        P: ProvidesAccessToken + Send + Sync + 'static,
+    {
+        self.register_with_shared_access_token_provider(name, config, Arc::new(token_provider))
+    }
+
+    pub fn register_with_shared_access_token_provider<N: Into<String>>(
+        &mut self,
+        name: N,
+        config: ClusterConfig,
+        token_provider: Arc<ProvidesAccessToken + Send + Sync + 'static>,
+    ) -> Result<&ClusterHandle, Error> {
+        let name = name.into();
+        if self.clusters.contains_key(&name) {
+            return Err(format_err!("Cluster '{}' is already registered", name));
+        }
+
+        let handle = ClusterHandle::new(name.clone(), config, token_provider)?;
+        Ok(self.clusters.entry(name).or_insert(handle))
+    }
+
+    /// Get the cluster registered under `name`.
+    pub fn cluster(&self, name: &str) -> Result<&ClusterHandle, Error> {
+        self.clusters
+            .get(name)
+            .ok_or_else(|| format_err!("No cluster registered as '{}'", name))
+    }
+
+    /// The names of all registered clusters
+    pub fn names(&self) -> Vec<&str> {
+        let mut names: Vec<_> = self.clusters.keys().map(|n| n.as_str()).collect();
+        names.sort();
+        names
+    }
+}
+
+fn build_http_client(tls: &TlsConfig, timeout: Option<Duration>) -> Result<HttpClient, Error> {
+    let mut builder = HttpClientBuilder::new();
+    builder.timeout(timeout);
+    for der in &tls.root_certificates_der {
+        let certificate = Certificate::from_der(der).context("Invalid root certificate")?;
+        builder.add_root_certificate(certificate);
+    }
+    if tls.disable_hostname_verification {
+        builder.danger_disable_hostname_verification();
+    }
+
+    Ok(builder.build().context("Could not create HTTP client")?)
+}
+
+#[cfg(test)]
+struct NoToken;
+
+#[cfg(test)]
+impl ProvidesAccessToken for NoToken {
+    fn get_token(&self) -> Result<Option<::auth::AccessToken>, ::auth::TokenError> {
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+fn test_cluster_config(nakadi_host: &str) -> ClusterConfig {
+    ClusterConfig {
+        nakadi_host: nakadi_host.to_string(),
+        request_timeout: Duration::from_secs(1),
+        tls: TlsConfig::default(),
+    }
+}
+
+#[test]
+fn clusters_are_referenced_by_name() {
+    let mut registry = ClusterRegistry::new();
+    registry
+        .register("a", test_cluster_config("http://a"), NoToken)
+        .unwrap();
+    registry
+        .register("b", test_cluster_config("http://b"), NoToken)
+        .unwrap();
+
+    assert_eq!(registry.cluster("b").unwrap().nakadi_host(), "http://b");
+    assert_eq!(registry.names(), vec!["a", "b"]);
+    assert!(registry.cluster("c").is_err());
+}
+
+#[test]
+fn clusters_can_not_be_registered_twice() {
+    let mut registry = ClusterRegistry::new();
+    registry
+        .register("a", test_cluster_config("http://a"), NoToken)
+        .unwrap();
+
+    assert!(
+        registry
+            .register("a", test_cluster_config("http://other"), NoToken)
+            .is_err()
+    );
+    assert_eq!(registry.cluster("a").unwrap().nakadi_host(), "http://a");
+}
@@ -0,0 +1,423 @@
+//! Deciding when cursors get committed
+//!
+//! The `Committer` talks to `Nakadi` but it does not decide which cursors
+//! to commit. A `CommitTracker` keeps the latest cursor of each partition
+//! until it is due and asks a `CommitPolicy` when that is. Both never
+//! look at the clock themselves. The current time is passed in, so they
+//! can be driven and tested without threads or sleeps.
+//!
+//! `CommitStrategy` is the `CommitPolicy` used by the consumer unless
+//! another one is configured with `NakadionBuilder::commit_policy`.
+//! Other policies, e.g. one that waits for a downstream system to
+//! acknowledge the events, only need to implement the decisions. The
+//! committer asks `is_due` every 100 ms, so an acknowledgement the policy
+//! records from another thread is picked up without a new batch. The
+//! tracker does the bookkeeping and makes sure no cursor is held back
+//! longer than `Nakadi` allows.
+//!
+//! ```rust,ignore
+//! let mut tracker = CommitTracker::new(CommitStrategy::AfterSeconds { seconds: 5 });
+//! tracker.cursor_received(key, cursor, received_at, Some(num_events), Instant::now());
+//!
+//! for pending in tracker.take_due(Instant::now()) {
+//!     // commit pending.cursor
+//! }
+//! ```
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use nakadi::CommitStrategy;
+
+/// `Nakadi` closes a stream if a cursor is not committed within
+/// this many seconds after the batch was sent.
+pub const NAKADI_COMMIT_TIMEOUT_SECS: u64 = 60;
+
+/// Cursors are committed 5 seconds before `Nakadi` closes the stream
+/// to be on the safe side.
+pub const CURSOR_COMMIT_OFFSET: u64 = 55;
+
+/// A commit with less time left until `Nakadi` closes
+/// the stream is a near miss.
+pub const NEAR_MISS_SECS: u64 = 5;
+
+/// The partition and the event type a cursor belongs to
+pub type PartitionKey = (Vec<u8>, Vec<u8>);
+
+/// The latest time a cursor received at `received_at` gets committed
+/// so that `Nakadi` does not close the stream for uncommitted cursors.
+pub fn latest_commit(received_at: Instant) -> Instant {
+    received_at + Duration::from_secs(CURSOR_COMMIT_OFFSET)
+}
+
+/// The time left at `now` until `Nakadi` closes the stream for not
+/// committing the cursor of a batch received at `received_at`.
+pub fn time_left_on_commit(received_at: Instant, now: Instant) -> Duration {
+    let deadline = received_at + Duration::from_secs(NAKADI_COMMIT_TIMEOUT_SECS);
+    if deadline > now {
+        deadline - now
+    } else {
+        Duration::from_secs(0)
+    }
+}
+
+/// Returns true if a commit with `time_left` came
+/// dangerously close to the deadline.
+pub fn is_near_miss(time_left: Duration) -> bool {
+    time_left < Duration::from_secs(NEAR_MISS_SECS)
+}
+
+/// Decides when cursors are due for a commit.
+///
+/// Implementations must not block. They are called by the committer
+/// for every cursor it receives.
+pub trait CommitPolicy {
+    /// The time the cursor of a partition that had no pending cursor
+    /// is due. Later cursors of the partition replace it but keep the
+    /// deadline.
+    ///
+    /// Deadlines after `latest_commit(received_at)` are moved to it.
+    fn deadline(&self, received_at: Instant, now: Instant) -> Instant;
+
+    /// Returns true if all pending cursors are due given the
+    /// numbers of batches and events they stand for.
+    fn commit_all(&self, num_batches: usize, num_events: usize) -> bool;
+
+    /// Returns true if the pending cursor of the partition `key`
+    /// with the given `deadline` is due.
+    ///
+    /// The default is due once the deadline has passed. Policies that
+    /// wait for something else can override it. A cursor is still due
+    /// at `latest_commit` of its first batch no matter what this returns.
+    fn is_due(&self, _key: &PartitionKey, deadline: Instant, now: Instant) -> bool {
+        deadline <= now
+    }
+}
+
+impl CommitPolicy for CommitStrategy {
+    fn deadline(&self, received_at: Instant, now: Instant) -> Instant {
+        let after_seconds = match *self {
+            CommitStrategy::AllBatches => return now,
+            CommitStrategy::Latest => None,
+            CommitStrategy::AfterSeconds { seconds } => Some(seconds),
+            CommitStrategy::Batches { after_seconds, .. }
+            | CommitStrategy::Events { after_seconds, .. } => after_seconds,
+        };
+        match after_seconds {
+            Some(seconds) => now + Duration::from_secs(u64::from(seconds)),
+            None => latest_commit(received_at),
+        }
+    }
+
+    fn commit_all(&self, num_batches: usize, num_events: usize) -> bool {
+        match *self {
+            CommitStrategy::AllBatches => true,
+            CommitStrategy::Batches { after_batches, .. } => num_batches >= after_batches as usize,
+            CommitStrategy::Events { after_events, .. } => num_events >= after_events as usize,
+            _ => false,
+        }
+    }
+}
+
+/// A `CommitPolicy` that can be shared between threads.
+#[derive(Clone)]
+pub struct SharedCommitPolicy(pub Arc<CommitPolicy + Send + Sync>);
+
+impl SharedCommitPolicy {
+    pub fn new<P>(policy: P) -> SharedCommitPolicy
+    where
+        P: CommitPolicy + Send + Sync + 'static,
+    {
+        SharedCommitPolicy(Arc::new(policy))
+    }
+}
+
+impl From<CommitStrategy> for SharedCommitPolicy {
+    fn from(strategy: CommitStrategy) -> SharedCommitPolicy {
+        SharedCommitPolicy::new(strategy)
+    }
+}
+
+impl fmt::Debug for SharedCommitPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SharedCommitPolicy")
+    }
+}
+
+impl CommitPolicy for SharedCommitPolicy {
+    fn deadline(&self, received_at: Instant, now: Instant) -> Instant {
+        self.0.deadline(received_at, now)
+    }
+
+    fn commit_all(&self, num_batches: usize, num_events: usize) -> bool {
+        self.0.commit_all(num_batches, num_events)
+    }
+
+    fn is_due(&self, key: &PartitionKey, deadline: Instant, now: Instant) -> bool {
+        self.0.is_due(key, deadline, now)
+    }
+}
+
+/// The latest cursor of a partition waiting to be committed
+#[derive(Debug, Clone)]
+pub struct PendingCursor<T> {
+    /// The latest cursor received
+    pub cursor: T,
+    /// The time the pending cursor is due by the policy
+    pub deadline: Instant,
+    /// The number of batches received since the last commit
+    pub num_batches: usize,
+    /// The number of events received since the last commit as far as known
+    pub num_events: usize,
+    /// When the first batch since the last commit was received
+    pub first_received_at: Instant,
+    /// When the latest batch was received
+    pub last_received_at: Instant,
+}
+
+/// Keeps the latest cursor of each partition until a `CommitPolicy`
+/// says it is due.
+///
+/// `T` is the cursor or whatever the caller needs to commit it.
+pub struct CommitTracker<P, T> {
+    policy: P,
+    pending: HashMap<PartitionKey, PendingCursor<T>>,
+}
+
+impl<P: CommitPolicy, T> CommitTracker<P, T> {
+    pub fn new(policy: P) -> CommitTracker<P, T> {
+        CommitTracker {
+            policy,
+            pending: HashMap::new(),
+        }
+    }
+
+    pub fn policy(&self) -> &P {
+        &self.policy
+    }
+
+    /// Replace the pending cursor of the partition `key`.
+    ///
+    /// `num_events` is added to the events pending for the partition
+    /// if it is known.
+    pub fn cursor_received(
+        &mut self,
+        key: PartitionKey,
+        cursor: T,
+        received_at: Instant,
+        num_events: Option<usize>,
+        now: Instant,
+    ) {
+        match self.pending.entry(key) {
+            Entry::Vacant(entry) => {
+                let deadline = ::std::cmp::min(
+                    self.policy.deadline(received_at, now),
+                    latest_commit(received_at),
+                );
+                entry.insert(PendingCursor {
+                    cursor,
+                    deadline,
+                    num_batches: 1,
+                    num_events: num_events.unwrap_or(0),
+                    first_received_at: received_at,
+                    last_received_at: received_at,
+                });
+            }
+            Entry::Occupied(mut entry) => {
+                let pending = entry.get_mut();
+                pending.cursor = cursor;
+                pending.num_batches += 1;
+                pending.num_events += num_events.unwrap_or(0);
+                pending.last_received_at = received_at;
+            }
+        }
+    }
+
+    /// No more cursors are coming for the partition `key` soon.
+    ///
+    /// Its pending cursor is due right away instead of waiting
+    /// for a later one to replace it.
+    pub fn partition_idle(&mut self, key: &PartitionKey, now: Instant) {
+        if let Some(pending) = self.pending.get_mut(key) {
+            pending.deadline = ::std::cmp::min(pending.deadline, now);
+        }
+    }
+
+    /// The partitions whose cursors are due at `now`
+    pub fn due(&self, now: Instant) -> Vec<PartitionKey> {
+        let num_batches: usize = self.pending.values().map(|p| p.num_batches).sum();
+        let num_events: usize = self.pending.values().map(|p| p.num_events).sum();
+        let commit_all = self.policy.commit_all(num_batches, num_events);
+
+        self.pending
+            .iter()
+            .filter(|&(key, pending)| {
+                commit_all || latest_commit(pending.first_received_at) <= now
+                    || self.policy.is_due(key, pending.deadline, now)
+            })
+            .map(|(key, _)| key.clone())
+            .collect()
+    }
+
+    /// Remove the cursors due at `now` to commit them.
+    pub fn take_due(&mut self, now: Instant) -> Vec<PendingCursor<T>> {
+        let due = self.due(now);
+        self.take(&due)
+    }
+
+    /// Remove the pending cursors of the partitions `keys`,
+    /// e.g. after they were committed.
+    pub fn take(&mut self, keys: &[PartitionKey]) -> Vec<PendingCursor<T>> {
+        keys.iter()
+            .filter_map(|key| self.pending.remove(key))
+            .collect()
+    }
+
+    /// Remove all pending cursors regardless of the policy.
+    pub fn take_all(&mut self) -> Vec<PendingCursor<T>> {
+        self.pending.drain().map(|(_, pending)| pending).collect()
+    }
+
+    pub fn get(&self, key: &PartitionKey) -> Option<&PendingCursor<T>> {
+        self.pending.get(key)
+    }
+
+    /// The number of partitions with a pending cursor
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+}
+
+#[cfg(test)]
+fn key(partition: &str) -> PartitionKey {
+    (partition.as_bytes().to_vec(), b"et".to_vec())
+}
+
+#[test]
+fn later_cursors_replace_the_pending_one_and_keep_its_deadline() {
+    let start = Instant::now();
+    let mut tracker = CommitTracker::new(CommitStrategy::AfterSeconds { seconds: 5 });
+    tracker.cursor_received(key("0"), 1, start, Some(3), start);
+    tracker.cursor_received(
+        key("0"),
+        2,
+        start + Duration::from_secs(2),
+        Some(4),
+        start + Duration::from_secs(2),
+    );
+
+    {
+        let pending = tracker.get(&key("0")).unwrap();
+        assert_eq!(pending.cursor, 2);
+        assert_eq!(pending.num_batches, 2);
+        assert_eq!(pending.num_events, 7);
+        assert_eq!(pending.deadline, start + Duration::from_secs(5));
+    }
+
+    assert!(tracker.take_due(start + Duration::from_secs(4)).is_empty());
+    let committed = tracker.take_due(start + Duration::from_secs(5));
+    assert_eq!(committed.len(), 1);
+    assert_eq!(committed[0].cursor, 2);
+    assert!(tracker.is_empty());
+}
+
+#[test]
+fn all_cursors_are_due_once_enough_batches_were_received() {
+    let now = Instant::now();
+    let mut tracker = CommitTracker::new(CommitStrategy::Batches {
+        after_batches: 3,
+        after_seconds: None,
+    });
+    tracker.cursor_received(key("0"), 1, now, None, now);
+    tracker.cursor_received(key("1"), 1, now, None, now);
+    assert!(tracker.due(now).is_empty());
+
+    tracker.cursor_received(key("1"), 2, now, None, now);
+    assert_eq!(tracker.due(now).len(), 2);
+}
+
+#[test]
+fn cursors_are_never_held_back_longer_than_nakadi_allows() {
+    let start = Instant::now();
+    let mut tracker = CommitTracker::new(CommitStrategy::AfterSeconds { seconds: 300 });
+    tracker.cursor_received(key("0"), 1, start, None, start);
+    assert_eq!(tracker.get(&key("0")).unwrap().deadline, latest_commit(start));
+
+    struct NeverDue;
+    impl CommitPolicy for NeverDue {
+        fn deadline(&self, received_at: Instant, _now: Instant) -> Instant {
+            latest_commit(received_at)
+        }
+        fn commit_all(&self, _num_batches: usize, _num_events: usize) -> bool {
+            false
+        }
+        fn is_due(&self, _key: &PartitionKey, _deadline: Instant, _now: Instant) -> bool {
+            false
+        }
+    }
+
+    let mut tracker = CommitTracker::new(NeverDue);
+    tracker.cursor_received(key("0"), 1, start, None, start);
+    assert!(tracker.due(start + Duration::from_secs(54)).is_empty());
+    assert_eq!(tracker.due(latest_commit(start)), vec![key("0")]);
+}
+
+#[test]
+fn a_shared_policy_sees_acknowledgements_made_elsewhere() {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    struct AfterAck(Arc<AtomicBool>);
+    impl CommitPolicy for AfterAck {
+        fn deadline(&self, received_at: Instant, _now: Instant) -> Instant {
+            latest_commit(received_at)
+        }
+        fn commit_all(&self, _num_batches: usize, _num_events: usize) -> bool {
+            false
+        }
+        fn is_due(&self, _key: &PartitionKey, _deadline: Instant, _now: Instant) -> bool {
+            self.0.load(Ordering::SeqCst)
+        }
+    }
+
+    let acknowledged = Arc::new(AtomicBool::new(false));
+    let now = Instant::now();
+    let mut tracker = CommitTracker::new(SharedCommitPolicy::new(AfterAck(acknowledged.clone())));
+    tracker.cursor_received(key("0"), 1, now, None, now);
+    assert!(tracker.due(now).is_empty());
+
+    acknowledged.store(true, Ordering::SeqCst);
+    assert_eq!(tracker.due(now), vec![key("0")]);
+}
+
+#[test]
+fn idle_partitions_are_due_right_away() {
+    let start = Instant::now();
+    let mut tracker = CommitTracker::new(CommitStrategy::Latest);
+    tracker.cursor_received(key("0"), 1, start, None, start);
+    tracker.cursor_received(key("1"), 1, start, None, start);
+
+    let later = start + Duration::from_secs(1);
+    tracker.partition_idle(&key("1"), later);
+    assert_eq!(tracker.due(later), vec![key("1")]);
+}
+
+#[test]
+fn commits_shortly_before_the_deadline_are_near_misses() {
+    let received_at = Instant::now();
+    let on_time = time_left_on_commit(received_at, latest_commit(received_at));
+    assert_eq!(on_time, Duration::from_secs(5));
+    assert!(!is_near_miss(on_time));
+
+    let late = time_left_on_commit(received_at, received_at + Duration::from_secs(57));
+    assert!(is_near_miss(late));
+    assert_eq!(
+        time_left_on_commit(received_at, received_at + Duration::from_secs(70)),
+        Duration::from_secs(0)
+    );
+}
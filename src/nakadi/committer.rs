@@ -1,17 +1,14 @@
 use std::sync::mpsc;
-use std::thread;
-use std::collections::HashMap;
-use std::collections::hash_map::Entry;
 use std::time::{Duration, Instant};
 
-use nakadi::CommitStrategy;
+use nakadi::commit_policy::{self, latest_commit, CommitTracker, PendingCursor, SharedCommitPolicy};
 use nakadi::api_client::{ApiClient, CommitError, CommitStatus};
 use nakadi::model::{FlowId, StreamId, SubscriptionId};
 use nakadi::batch::Batch;
-use nakadi::Lifecycle;
+use nakadi::{millis, Lifecycle};
 use nakadi::metrics::MetricsCollector;
-
-const CURSOR_COMMIT_OFFSET: u64 = 55;
+use nakadi::introspection::IntrospectionState;
+use nakadi::stage_timing::{self, Stage};
 
 #[derive(Clone)]
 pub struct Committer {
@@ -23,15 +20,21 @@ pub struct Committer {
 
 enum CommitterMessage {
     Commit(Batch, Option<usize>),
+    /// Commit all cursors received so far regardless of the strategy
+    Flush,
+    /// A keep alive line was received for the partition and event type
+    Idle(Vec<u8>, Vec<u8>),
 }
 
 impl Committer {
     pub fn start<C, M>(
         client: C,
-        strategy: CommitStrategy,
+        policy: SharedCommitPolicy,
         subscription_id: SubscriptionId,
         stream_id: StreamId,
         metrics_collector: M,
+        introspection_state: IntrospectionState,
+        parent: &Lifecycle,
     ) -> Self
     where
         C: ApiClient + Send + 'static,
@@ -39,16 +42,15 @@ impl Committer {
     {
         let (sender, receiver) = mpsc::channel();
 
-        let lifecycle = Lifecycle::default();
-
-        start_commit_loop(
+        let lifecycle = start_commit_loop(
             receiver,
-            strategy,
+            policy,
             subscription_id.clone(),
             stream_id.clone(),
             client,
-            lifecycle.clone(),
             metrics_collector,
+            introspection_state,
+            parent,
         );
 
         Committer {
@@ -70,6 +72,32 @@ impl Committer {
             })
     }
 
+    /// Commit the cursors of all batches processed so far
+    /// without waiting for the commit policy.
+    pub fn flush(&self) -> Result<(), String> {
+        self.sender.send(CommitterMessage::Flush).map_err(|err| {
+            format!(
+                "[Committer, stream={}] Could not accept flush request: {}",
+                self.stream_id, err
+            )
+        })
+    }
+
+    /// No events are pending for the partition of the event type.
+    ///
+    /// The cursor waiting for the commit policy is committed right away
+    /// since no later cursor will replace it soon.
+    pub fn partition_idle(&self, partition: &[u8], event_type: &[u8]) -> Result<(), String> {
+        self.sender
+            .send(CommitterMessage::Idle(partition.to_vec(), event_type.to_vec()))
+            .map_err(|err| {
+                format!(
+                    "[Committer, stream={}] Could not accept idle notification: {}",
+                    self.stream_id, err
+                )
+            })
+    }
+
     pub fn stream_id(&self) -> &StreamId {
         &self.stream_id
     }
@@ -81,143 +109,125 @@ impl Committer {
     pub fn stop(&self) {
         self.lifecycle.request_abort()
     }
+
+    /// Wait until the remaining cursors have been committed
+    /// and the committer stopped.
+    pub fn wait_until_stopped(&self) {
+        self.lifecycle.wait()
+    }
 }
 
 fn start_commit_loop<C, M>(
     receiver: mpsc::Receiver<CommitterMessage>,
-    strategy: CommitStrategy,
+    policy: SharedCommitPolicy,
     subscription_id: SubscriptionId,
     stream_id: StreamId,
     connector: C,
-    lifecycle: Lifecycle,
     metrics_collector: M,
-) where
+    introspection_state: IntrospectionState,
+    parent: &Lifecycle,
+) -> Lifecycle
+where
     C: ApiClient + Send + 'static,
     M: MetricsCollector + Send + 'static,
 {
-    thread::spawn(move || {
+    parent.spawn_named("nakadion-committer", move |lifecycle| {
         run_commit_loop(
             receiver,
-            strategy,
+            policy,
             subscription_id,
             stream_id,
             connector,
             lifecycle,
             metrics_collector,
+            introspection_state,
         );
-    });
+    })
 }
 
-struct CommitEntry {
-    commit_deadline: Instant,
-    num_batches: usize,
-    num_events: usize,
-    batch: Batch,
-    first_cursor_received_at: Instant,
-    current_cursor_received_at: Instant,
-}
-
-impl CommitEntry {
-    pub fn new(
-        batch: Batch,
-        strategy: CommitStrategy,
-        num_events_hint: Option<usize>,
-    ) -> CommitEntry {
-        let first_cursor_received_at = batch.received_at;
-        let commit_deadline = match strategy {
-            CommitStrategy::AllBatches => Instant::now(),
-            CommitStrategy::Batches {
-                after_seconds: Some(after_seconds),
-                ..
-            } => {
-                let by_strategy = Instant::now() + Duration::from_secs(after_seconds as u64);
-                ::std::cmp::min(
-                    by_strategy,
-                    batch.received_at + Duration::from_secs(CURSOR_COMMIT_OFFSET),
-                )
-            }
-            CommitStrategy::Events {
-                after_seconds: Some(after_seconds),
-                ..
-            } => {
-                let by_strategy = Instant::now() + Duration::from_secs(after_seconds as u64);
-                ::std::cmp::min(
-                    by_strategy,
-                    batch.received_at + Duration::from_secs(CURSOR_COMMIT_OFFSET),
-                )
-            }
-            CommitStrategy::AfterSeconds { seconds } => {
-                let by_strategy = Instant::now() + Duration::from_secs(seconds as u64);
-                ::std::cmp::min(
-                    by_strategy,
-                    batch.received_at + Duration::from_secs(CURSOR_COMMIT_OFFSET),
-                )
-            }
-            _ => batch.received_at + Duration::from_secs(CURSOR_COMMIT_OFFSET),
-        };
-        let received_at = batch.received_at;
-        CommitEntry {
-            commit_deadline,
-            num_batches: 1,
-            num_events: num_events_hint.unwrap_or(0),
-            batch,
-            first_cursor_received_at,
-            current_cursor_received_at: received_at,
-        }
-    }
-
-    pub fn update(&mut self, next_batch: Batch, num_events_hint: Option<usize>) {
-        let received_at = next_batch.received_at;
-        self.batch = next_batch;
-        self.num_events += num_events_hint.unwrap_or(0);
-        self.num_batches += 1;
-        self.current_cursor_received_at = received_at;
-    }
-
-    pub fn is_due_by_deadline(&self) -> bool {
-        self.commit_deadline <= Instant::now()
-    }
+/// The latest time the cursor of a batch gets committed so that
+/// `Nakadi` does not close the stream for uncommitted cursors.
+pub fn commit_deadline(batch: &Batch) -> Instant {
+    latest_commit(batch.received_at)
 }
 
 fn run_commit_loop<C, M>(
     receiver: mpsc::Receiver<CommitterMessage>,
-    strategy: CommitStrategy,
+    policy: SharedCommitPolicy,
     subscription_id: SubscriptionId,
     stream_id: StreamId,
     client: C,
     lifecycle: Lifecycle,
     metrics_collector: M,
+    introspection_state: IntrospectionState,
 ) where
     C: ApiClient,
     M: MetricsCollector,
 {
-    let mut cursors = HashMap::new();
+    let mut cursors = CommitTracker::new(policy);
     loop {
         if lifecycle.abort_requested() {
             info!(
                 "[Committer, subscription={}, stream={}] Abort requested. Flushing cursors",
                 subscription_id, stream_id
             );
-            flush_all_cursors::<_>(cursors, &subscription_id, &stream_id, &client);
+            flush_all_cursors::<_>(
+                cursors.take_all(),
+                &subscription_id,
+                &stream_id,
+                &client,
+                &introspection_state,
+            );
             break;
         }
 
         match receiver.recv_timeout(Duration::from_millis(100)) {
+            // Cursors of different streams must never be mixed in a commit
+            Ok(CommitterMessage::Commit(ref stale_batch, _))
+                if stale_batch.stream_id != stream_id =>
+            {
+                warn!(
+                    "[Committer, subscription={}, stream={}] Discarding stale cursor \
+                     from stream {}: {}",
+                    subscription_id,
+                    stream_id,
+                    stale_batch.stream_id,
+                    String::from_utf8_lossy(stale_batch.batch_line.cursor())
+                );
+                metrics_collector.committer_stale_cursors_discarded(1);
+                introspection_state.batch_processed(stale_batch);
+            }
             Ok(CommitterMessage::Commit(next_batch, num_events_hint)) => {
                 metrics_collector.committer_cursor_received(next_batch.received_at);
-                let mut key = (
+                introspection_state.batch_processed(&next_batch);
+                let key = (
                     next_batch.batch_line.partition().to_vec(),
                     next_batch.batch_line.event_type().to_vec(),
                 );
-
-                match cursors.entry(key) {
-                    Entry::Vacant(mut entry) => {
-                        entry.insert(CommitEntry::new(next_batch, strategy, num_events_hint));
-                    }
-                    Entry::Occupied(mut entry) => {
-                        entry.get_mut().update(next_batch, num_events_hint);
-                    }
-                }
+                let received_at = next_batch.received_at;
+                cursors.cursor_received(
+                    key,
+                    next_batch,
+                    received_at,
+                    num_events_hint,
+                    Instant::now(),
+                );
+            }
+            Ok(CommitterMessage::Flush) => {
+                info!(
+                    "[Committer, subscription={}, stream={}] Flush requested.",
+                    subscription_id, stream_id
+                );
+                flush_all_cursors::<_>(
+                    cursors.take_all(),
+                    &subscription_id,
+                    &stream_id,
+                    &client,
+                    &introspection_state,
+                );
+            }
+            Ok(CommitterMessage::Idle(partition, event_type)) => {
+                cursors.partition_idle(&(partition, event_type), Instant::now());
             }
             Err(mpsc::RecvTimeoutError::Timeout) => (),
             Err(mpsc::RecvTimeoutError::Disconnected) => {
@@ -226,7 +236,13 @@ fn run_commit_loop<C, M>(
                      Flushing cursors.",
                     subscription_id, stream_id
                 );
-                flush_all_cursors::<_>(cursors, &subscription_id, &stream_id, &client);
+                flush_all_cursors::<_>(
+                    cursors.take_all(),
+                    &subscription_id,
+                    &stream_id,
+                    &client,
+                    &introspection_state,
+                );
                 break;
             }
         }
@@ -236,8 +252,8 @@ fn run_commit_loop<C, M>(
             &subscription_id,
             &stream_id,
             &client,
-            strategy,
             &metrics_collector,
+            &introspection_state,
         ) {
             error!(
                 "[Committer, subscription={}, stream={}] Failed to commit cursors: {}",
@@ -255,14 +271,16 @@ fn run_commit_loop<C, M>(
 }
 
 fn flush_all_cursors<C>(
-    all_cursors: HashMap<(Vec<u8>, Vec<u8>), CommitEntry>,
+    all_cursors: Vec<PendingCursor<Batch>>,
     subscription_id: &SubscriptionId,
     stream_id: &StreamId,
     connector: &C,
+    introspection_state: &IntrospectionState,
 ) where
     C: ApiClient,
 {
-    // We are not interested in metrics here
+    // We are not interested in metrics here but the final commit
+    // still counts for introspection.
 
     if all_cursors.is_empty() {
         info!(
@@ -271,96 +289,126 @@ fn flush_all_cursors<C>(
         )
     } else {
         let cursors_to_commit: Vec<_> = all_cursors
-            .values()
-            .map(|v| v.batch.batch_line.cursor())
+            .iter()
+            .map(|v| v.cursor.batch_line.cursor())
             .collect();
 
+        let num_batches: usize = all_cursors.iter().map(|p| p.num_batches).sum();
+        let num_events: usize = all_cursors.iter().map(|p| p.num_events).sum();
+
         let flow_id = FlowId::default();
 
-        match connector.commit_cursors(
+        let start = Instant::now();
+        let result = connector.commit_cursors(
             subscription_id,
             stream_id,
             &cursors_to_commit,
             flow_id.clone(),
-        ) {
+        );
+        match result {
+            Ok(ref status) => {
+                introspection_state.committed(num_batches, num_events);
+                introspection_state.commit_took(start.elapsed());
+                introspection_state.outdated(status.num_outdated());
+            }
+            Err(_) => introspection_state.commit_failed(),
+        }
+
+        match result {
             Ok(CommitStatus::AllOffsetsIncreased) => info!(
                 "[Committer, subscription={}, stream={}, flow id={}] All remaining offsets\
                  increased.",
                 subscription_id, stream_id, flow_id
             ),
-            Ok(CommitStatus::NotAllOffsetsIncreased) => info!(
+            Ok(ref status @ CommitStatus::NotAllOffsetsIncreased(_)) => info!(
                 "[Committer, subscription={}, stream={}, flow id={}] Not all remaining\
-                 offstets increased.",
-                subscription_id, stream_id, flow_id
+                 offstets increased. {} cursors were outdated.",
+                subscription_id,
+                stream_id,
+                flow_id,
+                status.num_outdated()
+            ),
+            Ok(CommitStatus::AlreadyCommitted(n)) => info!(
+                "[Committer, subscription={}, stream={}, flow id={}] {} remaining cursors \
+                 had already been committed.",
+                subscription_id, stream_id, flow_id, n
             ),
             Ok(CommitStatus::NothingToCommit) => info!(
                 "[Committer, subscription={}, stream={}, flow id={}] There was nothing\
                  to be finally committed.",
                 subscription_id, stream_id, flow_id
             ),
-            Err(err) => error!(
-                "[Committer, subscription={}, stream={}, flow id={}] Failed to commit all\
-                 remaining cursors: {}",
-                subscription_id, stream_id, flow_id, err
-            ),
+            Err(err) => {
+                let uncommitted: Vec<_> = cursors_to_commit
+                    .iter()
+                    .map(|c| String::from_utf8_lossy(c))
+                    .collect();
+                error!(
+                    "[Committer, subscription={}, stream={}, flow id={}] Failed to commit all\
+                     remaining cursors: {}. Uncommitted cursors: {}",
+                    subscription_id,
+                    stream_id,
+                    flow_id,
+                    err,
+                    uncommitted.join(", ")
+                )
+            }
         }
     }
 }
 
 fn flush_due_cursors<C, M>(
-    all_cursors: &mut HashMap<(Vec<u8>, Vec<u8>), CommitEntry>,
+    all_cursors: &mut CommitTracker<SharedCommitPolicy, Batch>,
     subscription_id: &SubscriptionId,
     stream_id: &StreamId,
     client: &C,
-    strategy: CommitStrategy,
     metrics_collector: &M,
+    introspection_state: &IntrospectionState,
 ) -> Result<CommitStatus, CommitError>
 where
     C: ApiClient,
     M: MetricsCollector,
 {
-    let num_batches: usize = all_cursors.iter().map(|entry| entry.1.num_batches).sum();
-    let num_events: usize = all_cursors.iter().map(|entry| entry.1.num_events).sum();
-
-    let commit_all = match strategy {
-        CommitStrategy::AllBatches => true,
-        CommitStrategy::Batches { after_batches, .. } => num_batches >= after_batches as usize,
-        CommitStrategy::Events { after_events, .. } => num_events >= after_events as usize,
-        _ => false,
-    };
+    let now = Instant::now();
+    let keys_to_commit = all_cursors.due(now);
 
     let mut cursors_to_commit: Vec<Vec<u8>> = Vec::new();
-    let mut keys_to_commit: Vec<(Vec<u8>, Vec<u8>)> = Vec::new();
+    // The last batch of each partition to be committed
+    let mut batches_to_commit = Vec::new();
     let mut num_batches_to_commit = 0;
     let mut num_events_to_commit = 0;
-    if commit_all {
-        for (key, entry) in &*all_cursors {
-            num_batches_to_commit += entry.num_batches;
-            num_events_to_commit += entry.num_events;
-            metrics_collector.committer_cursor_age_on_commit(entry.current_cursor_received_at);
-            metrics_collector.committer_time_elapsed_until_commit(entry.first_cursor_received_at);
+    for key in &keys_to_commit {
+        if let Some(pending) = all_cursors.get(key) {
+            num_batches_to_commit += pending.num_batches;
+            num_events_to_commit += pending.num_events;
+            metrics_collector.committer_cursor_age_on_commit(pending.last_received_at);
+            metrics_collector.committer_time_elapsed_until_commit(pending.first_received_at);
             metrics_collector.committer_time_left_on_commit(
-                Instant::now(),
-                entry.first_cursor_received_at + Duration::from_secs(60),
+                now,
+                pending.first_received_at
+                    + Duration::from_secs(commit_policy::NAKADI_COMMIT_TIMEOUT_SECS),
             );
-            cursors_to_commit.push(entry.batch.batch_line.cursor().to_vec());
-            keys_to_commit.push(key.clone());
-        }
-    } else {
-        for (key, entry) in &*all_cursors {
-            if entry.is_due_by_deadline() {
-                num_batches_to_commit += entry.num_batches;
-                num_events_to_commit += entry.num_events;
-                metrics_collector.committer_cursor_age_on_commit(entry.current_cursor_received_at);
-                metrics_collector
-                    .committer_time_elapsed_until_commit(entry.first_cursor_received_at);
-                metrics_collector.committer_time_left_on_commit(
-                    Instant::now(),
-                    entry.first_cursor_received_at + Duration::from_secs(60),
+            let partition = String::from_utf8_lossy(&key.0);
+            let time_left = commit_policy::time_left_on_commit(pending.first_received_at, now);
+            metrics_collector.committer_partition_time_left_on_commit(&partition, time_left);
+            if commit_policy::is_near_miss(time_left) {
+                warn!(
+                    "[Committer, subscription={}, stream={}] The cursor of partition {} is \
+                     committed with only {} ms left until Nakadi closes the stream.",
+                    subscription_id,
+                    stream_id,
+                    partition,
+                    millis(time_left)
                 );
-                cursors_to_commit.push(entry.batch.batch_line.cursor().to_vec());
-                keys_to_commit.push(key.clone());
+                metrics_collector.committer_commit_near_miss(&partition, time_left);
+                introspection_state.near_miss();
             }
+            cursors_to_commit.push(pending.cursor.batch_line.cursor().to_vec());
+            batches_to_commit.push((
+                partition.into_owned(),
+                pending.cursor.received_at,
+                pending.cursor.timings,
+            ));
         }
     }
 
@@ -380,11 +428,58 @@ where
                 metrics_collector.committer_cursor_committed(start);
                 metrics_collector.committer_batches_committed(num_batches_to_commit);
                 metrics_collector.committer_events_committed(num_events_to_commit);
+                let num_outdated = s.num_outdated();
+                if num_outdated > 0 {
+                    debug!(
+                        "[Committer, subscription={}, stream={}, flow id={}] {} of {} \
+                         cursors were outdated.",
+                        subscription_id,
+                        stream_id,
+                        flow_id,
+                        num_outdated,
+                        cursors_to_commit.len()
+                    );
+                }
+                metrics_collector.committer_outdated_cursors(num_outdated);
+                if let CommitStatus::AlreadyCommitted(n) = s {
+                    warn!(
+                        "[Committer, subscription={}, stream={}, flow id={}] {} cursors \
+                         had already been committed.",
+                        subscription_id, stream_id, flow_id, n
+                    );
+                    metrics_collector.committer_cursors_already_committed(n);
+                }
+                introspection_state.committed(num_batches_to_commit, num_events_to_commit);
+                introspection_state.commit_took(start.elapsed());
+                let committed_at = Instant::now();
+                for (partition, received_at, mut timings) in batches_to_commit {
+                    timings.commit = timings.commit_until(received_at, committed_at);
+                    metrics_collector.batch_stage_took(Stage::Commit, timings.commit);
+                    debug!(
+                        target: stage_timing::LOG_TARGET,
+                        "[Committer, stream={}, partition={}] {}", stream_id, partition, timings
+                    );
+                }
+                introspection_state.outdated(num_outdated);
                 s
             }
             Err(err) => {
                 metrics_collector.committer_cursor_commit_attempt(start);
                 metrics_collector.committer_cursor_commit_failed(start);
+                introspection_state.commit_failed();
+                if let CommitError::UnprocessableEntity(..) = err {
+                    // Nakadi does not know the stream anymore. The cursors can
+                    // never be committed.
+                    warn!(
+                        "[Committer, subscription={}, stream={}] Stream is stale. \
+                         Discarding {} cursors.",
+                        subscription_id,
+                        stream_id,
+                        all_cursors.len()
+                    );
+                    metrics_collector.committer_stale_cursors_discarded(all_cursors.len());
+                    all_cursors.take_all();
+                }
                 return Err(err);
             }
         }
@@ -392,9 +487,7 @@ where
         CommitStatus::NothingToCommit
     };
 
-    for key in keys_to_commit {
-        all_cursors.remove(&key);
-    }
+    all_cursors.take(&keys_to_commit);
 
     Ok(status)
 }
@@ -0,0 +1,167 @@
+//! The names of the connector API from before the rewrite
+//!
+//! Before the rewrite a `HyperClientConnector` configured with
+//! `ConnectorSettings` did both, streaming and committing. Now a
+//! `NakadiStreamingClient` streams and a `NakadiApiClient` commits.
+//!
+//! The items of this module keep code written against the old names
+//! compiling with deprecation warnings so that it can be migrated step
+//! by step. They only map the old names to the new clients. Everything
+//! else is done by the new clients.
+#![allow(deprecated)]
+
+use std::sync::Arc;
+
+use failure::*;
+
+use auth::ProvidesAccessToken;
+use nakadi::api_client::{self, stats, ApiClient, CommitError, NakadiApiClient, StatsError};
+use nakadi::metrics::{DevNullMetricsCollector, MetricsCollector};
+use nakadi::model::{FlowId, StreamId, SubscriptionId};
+use nakadi::streaming_client::{self, ConnectError, NakadiLineIterator, NakadiStreamingClient,
+                               StreamingClient};
+
+#[deprecated(note = "use `streaming_client::Config`")]
+pub type ConnectorSettings = streaming_client::Config;
+
+#[deprecated(note = "use `streaming_client::ConfigBuilder`")]
+pub type ConnectorSettingsBuilder = streaming_client::ConfigBuilder;
+
+/// Streams and commits like the connector before the rewrite.
+///
+/// Implements `StreamingClient` by streaming with a
+/// `NakadiStreamingClient`. Use `api_client` for committing
+/// and `into_parts` to start a `Nakadion` with the clients.
+#[deprecated(note = "use `NakadiStreamingClient` and `NakadiApiClient`")]
+#[derive(Clone)]
+pub struct HyperClientConnector<M = DevNullMetricsCollector> {
+    settings: ConnectorSettings,
+    streaming_client: NakadiStreamingClient<M>,
+    api_client: NakadiApiClient,
+}
+
+impl HyperClientConnector<DevNullMetricsCollector> {
+    /// Create a connector without metrics.
+    pub fn new<T: ProvidesAccessToken + Send + Sync + 'static>(
+        settings: ConnectorSettings,
+        token_provider: T,
+    ) -> Result<HyperClientConnector<DevNullMetricsCollector>, Error> {
+        HyperClientConnector::with_metrics(settings, token_provider, DevNullMetricsCollector)
+    }
+
+    /// Create a connector without metrics configured
+    /// from the environment.
+    #[deprecated(note = "use `streaming_client::ConfigBuilder::from_env` \
+                         and `api_client::ConfigBuilder::from_env`")]
+    pub fn from_env<T: ProvidesAccessToken + Send + Sync + 'static>(
+        token_provider: T,
+    ) -> Result<HyperClientConnector<DevNullMetricsCollector>, Error> {
+        let settings = ConnectorSettingsBuilder::from_env()?.build()?;
+        HyperClientConnector::new(settings, token_provider)
+    }
+}
+
+impl<M: MetricsCollector> HyperClientConnector<M> {
+    pub fn with_metrics<T: ProvidesAccessToken + Send + Sync + 'static>(
+        settings: ConnectorSettings,
+        token_provider: T,
+        metrics_collector: M,
+    ) -> Result<HyperClientConnector<M>, Error> {
+        let token_provider: Arc<ProvidesAccessToken + Send + Sync + 'static> =
+            Arc::new(token_provider);
+        // The old settings had no timeout for requests other than
+        // streaming. Commits get the default of the `NakadiApiClient`.
+        let api_client = api_client::ConfigBuilder::default()
+            .nakadi_host(settings.nakadi_host.clone())
+            .build_client_with_shared_access_token_provider(token_provider.clone())?;
+        let streaming_client = NakadiStreamingClient::with_shared_access_token_provider(
+            settings.clone(),
+            token_provider,
+            metrics_collector,
+        )?;
+        Ok(HyperClientConnector {
+            settings,
+            streaming_client,
+            api_client,
+        })
+    }
+
+    #[deprecated(note = "use `streaming_client::Config`")]
+    pub fn settings(&self) -> &ConnectorSettings {
+        &self.settings
+    }
+
+    /// Connect to a stream of the subscription.
+    #[deprecated(note = "use `StreamingClient::connect`")]
+    pub fn read(
+        &self,
+        subscription_id: &SubscriptionId,
+    ) -> ::std::result::Result<(NakadiLineIterator, StreamId), ConnectError> {
+        self.streaming_client
+            .connect(subscription_id, FlowId::default())
+            .map(|(stream_id, lines)| (lines, stream_id))
+    }
+
+    /// Commit the cursors of a stream.
+    #[deprecated(note = "use `ApiClient::commit_cursors` of `api_client()`")]
+    pub fn checkpoint<T: AsRef<[u8]>>(
+        &self,
+        stream_id: &StreamId,
+        subscription_id: &SubscriptionId,
+        cursors: &[T],
+    ) -> ::std::result::Result<(), CommitError> {
+        self.api_client
+            .commit_cursors(subscription_id, stream_id, cursors, FlowId::default())
+            .map(|_| ())
+    }
+
+    /// The partitions of the subscription and the streams
+    /// they are assigned to.
+    #[deprecated(note = "use `ApiClient::stats` of `api_client()`")]
+    pub fn stream_info(
+        &self,
+        subscription_id: &SubscriptionId,
+    ) -> ::std::result::Result<stats::SubscriptionStats, StatsError> {
+        ApiClient::stats(&self.api_client, subscription_id)
+    }
+
+    pub fn streaming_client(&self) -> &NakadiStreamingClient<M> {
+        &self.streaming_client
+    }
+
+    pub fn api_client(&self) -> &NakadiApiClient {
+        &self.api_client
+    }
+
+    /// The clients to use instead of the connector
+    pub fn into_parts(self) -> (NakadiStreamingClient<M>, NakadiApiClient) {
+        (self.streaming_client, self.api_client)
+    }
+}
+
+impl<M: MetricsCollector> StreamingClient for HyperClientConnector<M> {
+    type LineIterator = NakadiLineIterator;
+
+    fn connect(
+        &self,
+        subscription_id: &SubscriptionId,
+        flow_id: FlowId,
+    ) -> ::std::result::Result<(StreamId, NakadiLineIterator), ConnectError> {
+        self.streaming_client.connect(subscription_id, flow_id)
+    }
+
+    fn connect_with_limits(
+        &self,
+        subscription_id: &SubscriptionId,
+        flow_id: FlowId,
+        batch_limit: usize,
+        max_uncommitted_events: usize,
+    ) -> ::std::result::Result<(StreamId, NakadiLineIterator), ConnectError> {
+        self.streaming_client.connect_with_limits(
+            subscription_id,
+            flow_id,
+            batch_limit,
+            max_uncommitted_events,
+        )
+    }
+}
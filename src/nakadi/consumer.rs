@@ -1,24 +1,192 @@
 use nakadi::api_client::ApiClient;
 use nakadi::streaming_client::{ConnectError, LineResult, RawLine};
 use nakadi::Lifecycle;
-use std::thread;
+use std::fmt;
 use std::time::{Duration, Instant};
 use std::sync::Arc;
 
-use nakadi::CommitStrategy;
+use chrono::offset::Utc;
+
+use nakadi::{millis, CommitStrategy, ShutdownConfig};
+use nakadi::commit_policy::SharedCommitPolicy;
 use nakadi::handler::HandlerFactory;
 use nakadi::streaming_client::StreamingClient;
 use nakadi::model::*;
 use nakadi::committer::Committer;
-use nakadi::dispatcher::Dispatcher;
+use nakadi::dispatcher::{Dispatcher, PausedPartitions};
+use nakadi::worker::ErrorLog;
+use nakadi::mailbox::MailboxConfig;
 use nakadi::batch::{Batch, BatchLine};
 use nakadi::metrics::MetricsCollector;
+use nakadi::introspection::{ConfigSummary, ConnectionAttempt, ConnectionState, Introspection,
+                            IntrospectionState};
+use nakadi::quota::{self, QuotaAction, QuotaConfig, QuotaTracker};
+use nakadi::ordering::{self, OrderingValidation, OrderingValidator};
+use nakadi::gaps::{GapAction, GapConfig, GapDetector};
+use nakadi::filtering::{self, EventFilter, Filtered};
+use nakadi::warm_up::{WarmUp, WarmUpConfig};
+use nakadi::adaptive_limits::{AdaptiveLimits, AdaptiveLimitsConfig};
+use nakadi::stage_timing::{Stage, StageTimings};
+use nakadi::rebalancing::{RebalanceConfig, Rebalancer, ReconnectRequest};
 
 const CONNECT_RETRY_BACKOFF_MS: &'static [u64] = &[
     10, 50, 100, 500, 1000, 1000, 1000, 3000, 3000, 3000, 5000, 5000, 5000, 10_000, 10_000, 10_000,
     15_000, 15_000, 15_000,
 ];
 
+/// The minimum time to wait after `Nakadi` responded with 503
+/// without saying how long to back off
+const OVERLOADED_MIN_BACKOFF_MS: u64 = 1_000;
+
+/// The maximum time a `Retry-After` sent by `Nakadi` is honored
+const MAX_RETRY_AFTER_SECS: u64 = 300;
+
+/// The `info` object `Nakadi` sent along with a line of the stream
+///
+/// `Nakadi` uses it for debugging information, e.g. when a stream started.
+#[derive(Debug, Clone)]
+pub struct StreamInfo {
+    pub stream_id: StreamId,
+    pub partition: String,
+    pub event_type: String,
+    /// False if it was sent with a keep alive line
+    pub with_events: bool,
+    pub info: ::serde_json::Value,
+}
+
+/// Receives the `info` objects of the stream.
+pub trait InfoListener {
+    fn on_info(&self, info: &StreamInfo);
+
+    /// Called whenever a stream was closed before the
+    /// consumer reconnects or stops.
+    fn on_stream_closed(&self, _closed: &StreamClosed) {}
+}
+
+/// An `InfoListener` that can be shared between threads.
+#[derive(Clone)]
+pub struct SharedInfoListener(pub Arc<InfoListener + Send + Sync>);
+
+impl fmt::Debug for SharedInfoListener {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SharedInfoListener")
+    }
+}
+
+/// Why a stream was closed
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub enum StreamClosedReason {
+    /// `Nakadi` ended the response, e.g. because the `stream_timeout`,
+    /// `stream_limit` or `stream_keep_alive_limit` was reached or the
+    /// partitions of the subscription were rebalanced.
+    EndedByNakadi,
+    /// Reading from the connection failed, e.g. because it was
+    /// reset without `Nakadi` ending the response.
+    ConnectionBroken(String),
+    /// Stopping the consumer was requested
+    StopRequested,
+    /// The committer stopped, e.g. because a commit failed
+    CommitterStopped,
+    /// A line of the stream could not be processed
+    LineFailed(String),
+    /// The warm-up phase is over and the consumer reconnects
+    /// with the configured limits
+    WarmUpOver,
+    /// The consumer reconnects to get a connection to another
+    /// backend, e.g. because the stream was old
+    Rebalancing(String),
+    /// Reconnecting was requested, e.g. because the
+    /// partitions of an event type changed
+    ReconnectRequested(String),
+}
+
+impl StreamClosedReason {
+    /// Returns true if `Nakadi` closed the stream
+    pub fn is_ended_by_nakadi(&self) -> bool {
+        *self == StreamClosedReason::EndedByNakadi
+    }
+}
+
+impl fmt::Display for StreamClosedReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StreamClosedReason::EndedByNakadi => write!(f, "ended by Nakadi"),
+            StreamClosedReason::ConnectionBroken(ref err) => {
+                write!(f, "the connection broke: {}", err)
+            }
+            StreamClosedReason::StopRequested => write!(f, "stop requested"),
+            StreamClosedReason::CommitterStopped => write!(f, "the committer stopped"),
+            StreamClosedReason::LineFailed(ref err) => write!(f, "a line failed: {}", err),
+            StreamClosedReason::WarmUpOver => write!(f, "the warm-up is over"),
+            StreamClosedReason::Rebalancing(ref why) => write!(f, "rebalancing: {}", why),
+            StreamClosedReason::ReconnectRequested(ref why) => {
+                write!(f, "reconnect requested: {}", why)
+            }
+        }
+    }
+}
+
+/// A stream that was closed and what is known about why
+#[derive(Debug, Clone)]
+pub struct StreamClosed {
+    pub stream_id: StreamId,
+    pub reason: StreamClosedReason,
+    /// For how long the stream was connected
+    pub connected_for: Duration,
+    /// The number of lines received on the stream
+    pub lines_received: usize,
+    /// The `info` object received last on the stream.
+    ///
+    /// If `Nakadi` tells why it closes a stream it does so in the
+    /// `debug` field of the `info` of the last line.
+    pub last_info: Option<::serde_json::Value>,
+}
+
+impl StreamClosed {
+    /// The `debug` message of the last `info` object
+    pub fn debug(&self) -> Option<&str> {
+        self.last_info
+            .as_ref()
+            .and_then(|info| info.get("debug"))
+            .and_then(|debug| debug.as_str())
+    }
+}
+
+impl fmt::Display for StreamClosed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Stream {} closed after {}s and {} lines: {}",
+            self.stream_id,
+            self.connected_for.as_secs(),
+            self.lines_received,
+            self.reason
+        )?;
+        if let Some(debug) = self.debug() {
+            write!(f, " (Nakadi: {})", debug)?;
+        }
+        Ok(())
+    }
+}
+
+/// Why a `Consumer` stopped
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub enum ConsumerOutcome {
+    /// Stopping was requested
+    Stopped,
+    /// `Nakadi` closed the stream and the consumer was
+    /// configured to stop then
+    StreamEnded,
+    /// Connecting to the stream failed with an error
+    /// that can not be resolved by retrying
+    ConnectFailed(String),
+    /// The token provider failed permanently.
+    ///
+    /// The batches received until then have been drained
+    /// according to the `ShutdownConfig`.
+    AuthBroken(String),
+}
+
 /// The consumer connects to the stream and sends batch lines to the processor.
 ///
 /// This is the top level component used by an application that wants to consume a
@@ -27,6 +195,60 @@ const CONNECT_RETRY_BACKOFF_MS: &'static [u64] = &[
 pub struct Consumer {
     lifecycle: Lifecycle,
     subscription_id: SubscriptionId,
+    introspection_state: IntrospectionState,
+    paused_partitions: PausedPartitions,
+    reconnect_request: ReconnectRequest,
+}
+
+/// The settings of a `Consumer`
+#[derive(Debug, Clone)]
+pub struct ConsumerConfig {
+    /// Decides when cursors are committed
+    pub commit_policy: SharedCommitPolicy,
+    pub min_idle_worker_lifetime: Option<Duration>,
+    /// The maximum time a handler may work on a batch
+    pub handler_timeout: Option<Duration>,
+    pub shutdown: ShutdownConfig,
+    pub quota: Option<QuotaConfig>,
+    pub validate_ordering: Option<OrderingValidation>,
+    /// The maximum number of bytes of batches waiting for the workers
+    pub max_queued_bytes: Option<usize>,
+    pub mailbox_config: MailboxConfig,
+    pub worker_threads: Option<usize>,
+    pub info_listener: Option<SharedInfoListener>,
+    pub gaps: Option<GapConfig>,
+    pub event_filter: Option<EventFilter>,
+    pub warm_up: Option<WarmUpConfig>,
+    pub adaptive_limits: Option<AdaptiveLimitsConfig>,
+    pub rebalance: Option<RebalanceConfig>,
+    /// Keep the last raw batches of each partition for introspection
+    pub capture_recent_batches: Option<usize>,
+    /// Stop once the stream ended instead of connecting again
+    pub stop_when_stream_ends: bool,
+}
+
+impl Default for ConsumerConfig {
+    fn default() -> ConsumerConfig {
+        ConsumerConfig {
+            commit_policy: CommitStrategy::AllBatches.into(),
+            min_idle_worker_lifetime: None,
+            handler_timeout: None,
+            shutdown: ShutdownConfig::default(),
+            quota: None,
+            validate_ordering: None,
+            max_queued_bytes: None,
+            mailbox_config: MailboxConfig::default(),
+            worker_threads: None,
+            info_listener: None,
+            gaps: None,
+            event_filter: None,
+            warm_up: None,
+            adaptive_limits: None,
+            rebalance: None,
+            capture_recent_batches: None,
+            stop_when_stream_ends: false,
+        }
+    }
 }
 
 impl Consumer {
@@ -45,113 +267,284 @@ impl Consumer {
         HF: HandlerFactory + Send + Sync + 'static,
         M: MetricsCollector + Clone + Send + 'static,
     {
-        let lifecycle = Lifecycle::default();
+        Consumer::start_with_config(
+            streaming_client,
+            api_client,
+            subscription_id,
+            handler_factory,
+            metrics_collector,
+            ConsumerConfig {
+                commit_policy: commit_strategy.into(),
+                min_idle_worker_lifetime,
+                ..ConsumerConfig::default()
+            },
+        )
+    }
 
-        let consumer = Consumer {
-            lifecycle: lifecycle.clone(),
-            subscription_id: subscription_id.clone(),
-        };
+    /// Start a `Consumer` with all of its settings.
+    pub fn start_with_config<C, A, HF, M>(
+        streaming_client: C,
+        api_client: A,
+        subscription_id: SubscriptionId,
+        handler_factory: HF,
+        metrics_collector: M,
+        config: ConsumerConfig,
+    ) -> Consumer
+    where
+        C: StreamingClient + Clone + Send + 'static,
+        A: ApiClient + Clone + Send + 'static,
+        HF: HandlerFactory + Send + Sync + 'static,
+        M: MetricsCollector + Clone + Send + 'static,
+    {
+        let introspection_state = IntrospectionState::default();
+        if let Some(n) = config.capture_recent_batches {
+            introspection_state.capture_recent_batches(n);
+        }
+        let paused_partitions = PausedPartitions::default();
+        let reconnect_request = ReconnectRequest::default();
 
-        start_consumer_loop(
+        let lifecycle = start_consumer_loop(
             streaming_client,
             api_client,
             handler_factory,
-            commit_strategy,
-            subscription_id,
-            lifecycle,
+            subscription_id.clone(),
             metrics_collector,
-            min_idle_worker_lifetime,
+            config,
+            introspection_state.clone(),
+            paused_partitions.clone(),
+            reconnect_request.clone(),
         );
 
-        consumer
+        Consumer {
+            lifecycle,
+            subscription_id,
+            introspection_state,
+            paused_partitions,
+            reconnect_request,
+        }
     }
 
+    /// Returns true until all threads of the consumer stopped.
     pub fn running(&self) -> bool {
         self.lifecycle.running()
     }
 
+    /// The `Lifecycle` of the consumer loop.
+    ///
+    /// Threads that must stop with the consumer are started
+    /// as its children.
+    pub fn lifecycle(&self) -> &Lifecycle {
+        &self.lifecycle
+    }
+
+    /// Take a snapshot of the current state of the consumer.
+    pub fn introspect(&self, config: Option<ConfigSummary>) -> Introspection {
+        self.introspection_state
+            .snapshot(&self.subscription_id, config)
+    }
+
+    /// The batches waiting for the workers and the average time
+    /// from receiving a batch until it has been processed.
+    pub fn load(&self) -> (u64, Duration) {
+        self.introspection_state.load()
+    }
+
+    /// Hold back the batches of the partition until it is resumed.
+    pub fn pause_partition(&self, partition: PartitionId) {
+        info!(
+            "[Consumer, subscription={}] Pausing partition {}",
+            self.subscription_id, partition
+        );
+        self.paused_partitions.pause(partition)
+    }
+
+    pub fn resume_partition(&self, partition: &PartitionId) {
+        info!(
+            "[Consumer, subscription={}] Resuming partition {}",
+            self.subscription_id, partition
+        );
+        self.paused_partitions.resume(partition)
+    }
+
+    pub fn paused_partitions(&self) -> Vec<PartitionId> {
+        self.paused_partitions.paused()
+    }
+
+    /// Whether the consumer is connected, connecting, backing off
+    /// because `Nakadi` is overloaded or can not reach `Nakadi`.
+    ///
+    /// Cheap enough to be used for health checks.
+    pub fn connection_state(&self) -> ConnectionState {
+        self.introspection_state.connection_state()
+    }
+
+    /// Why the consumer stopped. `None` while it is still running.
+    pub fn outcome(&self) -> Option<ConsumerOutcome> {
+        self.introspection_state.outcome()
+    }
+
+    /// Stop the consumer and wait until all of its threads stopped.
+    ///
+    /// Must not be called from a handler since it would wait for itself.
     pub fn stop(&self) {
+        self.request_stop();
+        self.wait_until_stopped();
+    }
+
+    /// Close the current stream and connect again once the next
+    /// line was received, e.g. to get the partitions of a
+    /// repartitioned event type assigned.
+    pub fn request_reconnect<T: Into<String>>(&self, why: T) {
+        self.reconnect_request.request(why)
+    }
+
+    /// Ask the consumer to stop without waiting for it.
+    pub fn request_stop(&self) {
         self.lifecycle.request_abort()
     }
+
+    /// Wait until all threads of the consumer stopped.
+    pub fn wait_until_stopped(&self) {
+        self.lifecycle.wait()
+    }
+
+    /// Wait at most `timeout` until all threads of the consumer stopped.
+    ///
+    /// Returns true if they stopped.
+    pub fn wait_until_stopped_timeout(&self, timeout: Duration) -> bool {
+        self.lifecycle.wait_timeout(timeout)
+    }
 }
 
 fn start_consumer_loop<C, A, HF, M>(
     streaming_client: C,
     api_client: A,
     handler_factory: HF,
-    commit_strategy: CommitStrategy,
     subscription_id: SubscriptionId,
-    lifecycle: Lifecycle,
     metrics_collector: M,
-    min_idle_worker_lifetime: Option<Duration>,
-) where
+    config: ConsumerConfig,
+    introspection_state: IntrospectionState,
+    paused_partitions: PausedPartitions,
+    reconnect_request: ReconnectRequest,
+) -> Lifecycle
+where
     C: StreamingClient + Clone + Send + 'static,
     A: ApiClient + Clone + Send + 'static,
     HF: HandlerFactory + Send + Sync + 'static,
     M: MetricsCollector + Clone + Send + 'static,
 {
-    thread::spawn(move || {
+    let name = format!("nakadion-consumer-{}", subscription_id);
+    Lifecycle::default().spawn_named(name, move |lifecycle| {
         consumer_loop(
             streaming_client,
             api_client,
             handler_factory,
-            commit_strategy,
             subscription_id,
             lifecycle,
             metrics_collector,
-            min_idle_worker_lifetime,
+            config,
+            introspection_state,
+            paused_partitions,
+            reconnect_request,
         )
-    });
+    })
 }
 
 fn consumer_loop<C, A, HF, M>(
     streaming_client: C,
     api_client: A,
     handler_factory: HF,
-    commit_strategy: CommitStrategy,
     subscription_id: SubscriptionId,
     lifecycle: Lifecycle,
     metrics_collector: M,
-    min_idle_worker_lifetime: Option<Duration>,
+    config: ConsumerConfig,
+    introspection_state: IntrospectionState,
+    paused_partitions: PausedPartitions,
+    reconnect_request: ReconnectRequest,
 ) where
     C: StreamingClient + Clone + Send + 'static,
     A: ApiClient + Clone + Send + 'static,
     HF: HandlerFactory + Send + Sync + 'static,
     M: MetricsCollector + Clone + Send + 'static,
 {
+    let ConsumerConfig {
+        commit_policy,
+        min_idle_worker_lifetime,
+        handler_timeout,
+        shutdown,
+        quota,
+        validate_ordering,
+        max_queued_bytes,
+        mailbox_config,
+        worker_threads,
+        info_listener,
+        gaps: gap_config,
+        event_filter,
+        warm_up,
+        adaptive_limits,
+        rebalance,
+        stop_when_stream_ends,
+        ..
+    } = config;
     let handler_factory = Arc::new(handler_factory);
+    let mut quota_tracker = quota.map(QuotaTracker::new);
+    let mut ordering_validator = validate_ordering.map(OrderingValidator::new);
+    let mut gap_detector = gap_config.map(GapDetector::new);
+    let mut warm_up = warm_up.map(WarmUp::new);
+    let mut adaptive_limits = adaptive_limits.map(AdaptiveLimits::new);
+    let mut rebalancer = rebalance.map(Rebalancer::new);
+    let error_log = ErrorLog::default();
 
-    loop {
+    let outcome = loop {
         if lifecycle.abort_requested() {
             info!(
                 "[Consumer, subscription={}] Abort requested",
                 subscription_id
             );
-            break;
+            break ConsumerOutcome::Stopped;
         }
 
         info!(
             "[Consumer, subscription={}] Connecting to stream",
             subscription_id
         );
+        introspection_state.connecting();
         let start = Instant::now();
+        let limits = match (&warm_up, &adaptive_limits) {
+            (&Some(ref warm_up), _) if warm_up.is_pending() => Some((
+                warm_up.config().batch_limit,
+                warm_up.config().max_uncommitted_events,
+            )),
+            (_, &Some(ref adaptive_limits)) => Some(adaptive_limits.limits()),
+            _ => None,
+        };
         let (stream_id, line_iterator) = match connect(
             &streaming_client,
             &subscription_id,
             Duration::from_secs(300),
             &lifecycle,
+            &introspection_state,
+            limits,
         ) {
             Ok(v) => {
                 metrics_collector.consumer_connected(start);
                 v
             }
+            Err(ConnectError::AuthBroken(msg)) => {
+                error!(
+                    "[Consumer, subscription={}] The token provider failed permanently. \
+                     Stopping: {}",
+                    subscription_id, msg
+                );
+                break ConsumerOutcome::AuthBroken(msg);
+            }
             Err(err) => {
                 if err.is_permanent() {
                     error!(
                         "[Consumer, subscription={}] Permanent connection error: {}",
                         subscription_id, err
                     );
-                    break;
+                    break ConsumerOutcome::ConnectFailed(err.to_string());
                 } else {
                     warn!(
                         "[Consumer, subscription={}] Temporary connection error: {}",
@@ -167,13 +560,43 @@ fn consumer_loop<C, A, HF, M>(
             subscription_id, stream_id
         );
         let connected_since = Instant::now();
+        introspection_state.connected(&stream_id);
+        if let Some(ref mut adaptive_limits) = adaptive_limits {
+            adaptive_limits.connected(connected_since, introspection_state.events_committed());
+        }
+        if let Some(ref mut rebalancer) = rebalancer {
+            rebalancer.connected(connected_since);
+        }
+        // A new stream already got the current partitions
+        let _ = reconnect_request.take();
+        if let Some(ref mut warm_up) = warm_up {
+            warm_up.connected(connected_since);
+            if warm_up.is_warming_up() {
+                info!(
+                    "[Consumer, subscription={}] Warming up stream {} with batch_limit={} \
+                     and max_uncommitted_events={}",
+                    subscription_id,
+                    stream_id,
+                    warm_up.config().batch_limit,
+                    warm_up.config().max_uncommitted_events
+                );
+            }
+        }
+        if let Some(ref mut validator) = ordering_validator {
+            validator.reset();
+        }
+        if let Some(ref mut detector) = gap_detector {
+            detector.reset();
+        }
 
         let committer = Committer::start(
             api_client.clone(),
-            commit_strategy,
+            commit_policy.clone(),
             subscription_id.clone(),
             stream_id.clone(),
             metrics_collector.clone(),
+            introspection_state.clone(),
+            &lifecycle,
         );
 
         let dispatcher = Dispatcher::start(
@@ -181,19 +604,71 @@ fn consumer_loop<C, A, HF, M>(
             committer.clone(),
             metrics_collector.clone(),
             min_idle_worker_lifetime,
+            handler_timeout,
+            shutdown,
+            introspection_state.clone(),
+            paused_partitions.clone(),
+            error_log.clone(),
+            assigned_partitions(&api_client, &subscription_id, &stream_id),
+            mailbox_config,
+            worker_threads,
+            &lifecycle,
         );
 
-        consume(
+        let closed = consume(
             line_iterator,
             dispatcher,
             committer,
             lifecycle.clone(),
             &metrics_collector,
+            &mut quota_tracker,
+            &mut ordering_validator,
+            &mut gap_detector,
+            &mut warm_up,
+            rebalancer.as_ref(),
+            &reconnect_request,
+            &stream_id,
+            &introspection_state,
+            max_queued_bytes,
+            connected_since,
+            info_listener.as_ref(),
+            event_filter.as_ref(),
         );
 
         metrics_collector.consumer_connection_lifetime(connected_since);
-    }
+        info!("[Consumer, subscription={}] {}", subscription_id, closed);
+        introspection_state.stream_closed(&closed);
+        if let Some(ref listener) = info_listener {
+            listener.0.on_stream_closed(&closed);
+        }
+        if let Some(ref mut warm_up) = warm_up {
+            warm_up.disconnected();
+        }
+        if let Some(ref mut rebalancer) = rebalancer {
+            rebalancer.disconnected();
+        }
+        if let Some(ref mut adaptive_limits) = adaptive_limits {
+            if adaptive_limits.disconnected(Instant::now(), introspection_state.events_committed())
+            {
+                let (batch_limit, max_uncommitted_events) = adaptive_limits.limits();
+                info!(
+                    "[Consumer, subscription={}] Adapted limits to the throughput of stream {}: \
+                     batch_limit={} and max_uncommitted_events={}",
+                    subscription_id, stream_id, batch_limit, max_uncommitted_events
+                );
+            }
+        }
 
+        if stop_when_stream_ends && closed.reason.is_ended_by_nakadi() {
+            info!(
+                "[Consumer, subscription={}] Stream {} was closed by Nakadi. Stopping.",
+                subscription_id, stream_id
+            );
+            break ConsumerOutcome::StreamEnded;
+        }
+    };
+
+    introspection_state.stopped(outcome);
     lifecycle.stopped();
 
     info!(
@@ -202,29 +677,139 @@ fn consumer_loop<C, A, HF, M>(
     );
 }
 
+/// The partitions `Nakadi` assigned to the stream so that their
+/// workers can be created before the first batch arrives.
+///
+/// Returns no partitions if the assignment could not be determined.
+/// Workers are then created with the first batch of their partition.
+fn assigned_partitions<A: ApiClient>(
+    api_client: &A,
+    subscription_id: &SubscriptionId,
+    stream_id: &StreamId,
+) -> Vec<PartitionId> {
+    match api_client.stats(subscription_id) {
+        Ok(stats) => stats
+            .event_types
+            .into_iter()
+            .flat_map(|event_type| event_type.partitions)
+            .filter(|partition| partition.stream_id == stream_id.0)
+            .map(|partition| PartitionId(partition.partition))
+            .collect(),
+        Err(err) => {
+            warn!(
+                "[Consumer, subscription={}] Could not get the partitions assigned to \
+                 stream {}: {}",
+                subscription_id, stream_id, err
+            );
+            Vec::new()
+        }
+    }
+}
+
 fn consume<I, M>(
     line_iterator: I,
     dispatcher: Dispatcher,
     committer: Committer,
     lifecycle: Lifecycle,
     metrics_collector: &M,
-) where
+    quota_tracker: &mut Option<QuotaTracker>,
+    ordering_validator: &mut Option<OrderingValidator>,
+    gap_detector: &mut Option<GapDetector>,
+    warm_up: &mut Option<WarmUp>,
+    rebalancer: Option<&Rebalancer>,
+    reconnect_request: &ReconnectRequest,
+    stream_id: &StreamId,
+    introspection_state: &IntrospectionState,
+    max_queued_bytes: Option<usize>,
+    connected_since: Instant,
+    info_listener: Option<&SharedInfoListener>,
+    event_filter: Option<&EventFilter>,
+) -> StreamClosed
+where
     I: Iterator<Item = LineResult>,
     M: MetricsCollector,
 {
+    // Stays `EndedByNakadi` if the line iterator runs out
+    let mut reason = StreamClosedReason::EndedByNakadi;
+    let mut lines_received = 0;
+    let mut last_info = None;
+    // Set until the first batch of the stream was received
+    let mut waiting_for_first_batch = Some(connected_since);
     for line_result in line_iterator {
         if lifecycle.abort_requested() {
+            reason = StreamClosedReason::StopRequested;
+            break;
+        }
+        if !committer.running() {
+            // Reconnecting will tell whether the committer
+            // failed because authentication is broken.
+            error!("The committer stopped unexpectedly. Draining.");
+            reason = StreamClosedReason::CommitterStopped;
             break;
         }
         match line_result {
             Ok(raw_line) => {
-                if let Err(err) = send_line(&dispatcher, raw_line, metrics_collector) {
+                lines_received += 1;
+                if let Err(err) = send_line(
+                    &dispatcher,
+                    &committer,
+                    raw_line,
+                    stream_id,
+                    metrics_collector,
+                    quota_tracker,
+                    ordering_validator,
+                    gap_detector,
+                    warm_up,
+                    introspection_state,
+                    &mut waiting_for_first_batch,
+                    &mut last_info,
+                    info_listener,
+                    event_filter,
+                ) {
                     error!("Could not process batch: {}", err);
+                    if let Some(ref validator) = *ordering_validator {
+                        if validator.validation() == OrderingValidation::Abort {
+                            lifecycle.request_abort();
+                        }
+                    }
+                    if let Some(ref detector) = *gap_detector {
+                        if detector.action() == GapAction::Abort {
+                            lifecycle.request_abort();
+                        }
+                    }
+                    reason = StreamClosedReason::LineFailed(err);
                     break;
                 }
+                if let Some(ref mut warm_up) = *warm_up {
+                    if warm_up.check(Instant::now()) {
+                        info!("Warm-up is over. Reconnecting with the configured limits.");
+                        reason = StreamClosedReason::WarmUpOver;
+                        break;
+                    }
+                }
+                if let Some(rebalancer) = rebalancer {
+                    let avg_commit_latency = introspection_state.avg_commit_latency();
+                    if let Some(why) = rebalancer.check(Instant::now(), avg_commit_latency) {
+                        info!("Reconnecting to rebalance because {}.", why);
+                        reason = StreamClosedReason::Rebalancing(why.to_string());
+                        break;
+                    }
+                }
+                if let Some(why) = reconnect_request.take() {
+                    info!("Reconnecting as requested because {}.", why);
+                    reason = StreamClosedReason::ReconnectRequested(why);
+                    break;
+                }
+                if let Some(ref mut tracker) = *quota_tracker {
+                    pause_while_quota_exceeded(tracker, &lifecycle);
+                }
+                if let Some(max_queued_bytes) = max_queued_bytes {
+                    pause_while_queues_full(introspection_state, max_queued_bytes, &lifecycle);
+                }
             }
             Err(err) => {
                 error!("The connection broke: {}", err);
+                reason = StreamClosedReason::ConnectionBroken(err.to_string());
                 break;
             }
         }
@@ -232,25 +817,38 @@ fn consume<I, M>(
 
     info!("Stopping dispatcher");
     dispatcher.stop();
-
-    while dispatcher.is_running() {
-        thread::sleep(Duration::from_millis(10));
-    }
+    dispatcher.wait_until_stopped();
 
     info!("Stopping commiter");
     committer.stop();
-
-    while committer.running() {
-        thread::sleep(Duration::from_millis(10));
-    }
+    committer.wait_until_stopped();
 
     info!("Committer stopped");
+
+    StreamClosed {
+        stream_id: stream_id.clone(),
+        reason,
+        connected_for: connected_since.elapsed(),
+        lines_received,
+        last_info,
+    }
 }
 
 fn send_line<M>(
     dispatcher: &Dispatcher,
+    committer: &Committer,
     raw_line: RawLine,
+    stream_id: &StreamId,
     metrics_collector: &M,
+    quota_tracker: &mut Option<QuotaTracker>,
+    ordering_validator: &mut Option<OrderingValidator>,
+    gap_detector: &mut Option<GapDetector>,
+    warm_up: &mut Option<WarmUp>,
+    introspection_state: &IntrospectionState,
+    waiting_for_first_batch: &mut Option<Instant>,
+    last_info: &mut Option<::serde_json::Value>,
+    info_listener: Option<&SharedInfoListener>,
+    event_filter: Option<&EventFilter>,
 ) -> Result<(), String>
 where
     M: MetricsCollector,
@@ -258,7 +856,7 @@ where
     let num_bytes = raw_line.bytes.len();
     metrics_collector.consumer_line_received(num_bytes);
 
-    let batch_line = BatchLine::new(raw_line.bytes)?;
+    let batch_line = BatchLine::new(raw_line.bytes).map_err(|err| err.to_string())?;
 
     if let Some(info) = batch_line.info() {
         match ::std::str::from_utf8(info) {
@@ -268,44 +866,260 @@ where
             }
             Err(err) => warn!("Received info line which is not UTF-8: {}", err),
         };
+
+        match batch_line.info_json() {
+            Some(Ok(info)) => {
+                *last_info = Some(info.clone());
+                if let Some(listener) = info_listener {
+                    listener.0.on_info(&StreamInfo {
+                        stream_id: stream_id.clone(),
+                        partition: batch_line.partition_str()?.to_string(),
+                        event_type: batch_line.event_type_str()?.to_string(),
+                        with_events: !batch_line.is_keep_alive_line(),
+                        info,
+                    })
+                }
+            }
+            Some(Err(err)) => warn!("Could not keep the info of the line: {}", err),
+            None => (),
+        }
     }
 
     if batch_line.is_keep_alive_line() {
         debug!("Keep alive!");
         metrics_collector.consumer_keep_alive_line_received(num_bytes);
-        Ok(())
+        introspection_state.keep_alive_received();
+        // Nothing more is coming for the partition. Commit what is pending
+        // instead of waiting for the commit strategy.
+        committer.partition_idle(batch_line.partition(), batch_line.event_type())
     } else {
         metrics_collector.consumer_batch_line_received(num_bytes);
-        dispatcher.process(Batch {
+        if let Some(connected_since) = waiting_for_first_batch.take() {
+            metrics_collector.consumer_first_batch_received(connected_since);
+            introspection_state.first_batch_received();
+        }
+        if let Some(ref mut warm_up) = *warm_up {
+            warm_up.batch_received();
+        }
+        if let Some(ref mut tracker) = *quota_tracker {
+            if let Some(events) = batch_line.events() {
+                tracker.record(quota::count_events(events) as u64, events.len() as u64);
+            }
+        }
+        if let Some(ref mut validator) = *ordering_validator {
+            validate_ordering(validator, &batch_line, metrics_collector)?;
+        }
+        if let Some(ref mut detector) = *gap_detector {
+            detect_gap(detector, &batch_line, metrics_collector)?;
+        }
+        let filtered = match (event_filter, batch_line.events()) {
+            (Some(filter), Some(events)) => Some(filter.filter_events(events)?),
+            _ => None,
+        };
+        let batch_line = match filtered {
+            Some(Filtered::Some(events, dropped)) => {
+                debug!("{} events of the batch were filtered", dropped);
+                filtering::replace_events(&batch_line, Some(&events))?
+            }
+            Some(Filtered::Nothing(dropped)) => {
+                // The worker commits the cursor without calling the handler
+                debug!("All {} events of the batch were filtered", dropped);
+                filtering::replace_events(&batch_line, None)?
+            }
+            _ => batch_line,
+        };
+        let timings = StageTimings::dispatched(raw_line.read_took, raw_line.received_at);
+        metrics_collector.batch_stage_took(Stage::Read, timings.read);
+        metrics_collector.batch_stage_took(Stage::Parse, timings.parse);
+        let batch = Batch {
             batch_line: batch_line,
             received_at: raw_line.received_at,
-        })
+            stream_id: stream_id.clone(),
+            timings,
+        };
+        introspection_state.batch_received(&batch);
+        metrics_collector.consumer_queued_bytes(introspection_state.queued_bytes());
+        dispatcher.process(batch)
     }
 }
 
+fn validate_ordering<M>(
+    validator: &mut OrderingValidator,
+    batch_line: &BatchLine,
+    metrics_collector: &M,
+) -> Result<(), String>
+where
+    M: MetricsCollector,
+{
+    let offset = ordering::offset_of(batch_line.cursor())?;
+    let num_events = batch_line.events().map(quota::count_events).unwrap_or(0);
+    let violation = validator.check(
+        batch_line.event_type_str()?,
+        batch_line.partition_str()?,
+        &offset,
+        num_events,
+    );
+
+    match violation {
+        Some(violation) => {
+            metrics_collector.consumer_ordering_violation();
+            match validator.validation() {
+                OrderingValidation::Warn => {
+                    warn!("Ordering violated: {}", violation);
+                    Ok(())
+                }
+                OrderingValidation::Abort => Err(format!("Ordering violated: {}", violation)),
+            }
+        }
+        None => Ok(()),
+    }
+}
+
+fn detect_gap<M>(
+    detector: &mut GapDetector,
+    batch_line: &BatchLine,
+    metrics_collector: &M,
+) -> Result<(), String>
+where
+    M: MetricsCollector,
+{
+    let offset = ordering::offset_of(batch_line.cursor())?;
+    let num_events = batch_line.events().map(quota::count_events).unwrap_or(0);
+    let gap = detector.check(
+        batch_line.event_type_str()?,
+        batch_line.partition_str()?,
+        &offset,
+        num_events,
+    );
+
+    match gap {
+        Some(gap) => {
+            metrics_collector.consumer_offset_gap(gap.missing);
+            if let Some(listener) = detector.listener() {
+                listener.0.on_gap(&gap);
+            }
+            match detector.action() {
+                GapAction::Report => {
+                    warn!("Gap detected: {}", gap);
+                    Ok(())
+                }
+                GapAction::Abort => Err(format!("Gap detected: {}", gap)),
+            }
+        }
+        None => Ok(()),
+    }
+}
+
+/// Stops reading from the stream as long as the quota
+/// is exceeded and the action is `QuotaAction::Pause`.
+fn pause_while_quota_exceeded(tracker: &mut QuotaTracker, lifecycle: &Lifecycle) {
+    if tracker.action() != QuotaAction::Pause || !tracker.is_exceeded() {
+        return;
+    }
+
+    warn!("Quota exceeded. Pausing consumption.");
+    let paused_since = Instant::now();
+    while tracker.is_exceeded() && !lifecycle.wait_for_abort(Duration::from_secs(1)) {}
+    info!(
+        "Resuming consumption after pausing for {:?}",
+        paused_since.elapsed()
+    );
+}
+
+/// Stop reading from the stream while the batches held in memory
+/// exceed `max_queued_bytes`.
+fn pause_while_queues_full(
+    introspection_state: &IntrospectionState,
+    max_queued_bytes: usize,
+    lifecycle: &Lifecycle,
+) {
+    if introspection_state.queued_bytes() <= max_queued_bytes {
+        return;
+    }
+
+    warn!(
+        "More than {} bytes queued. Pausing consumption.",
+        max_queued_bytes
+    );
+    let paused_since = Instant::now();
+    while introspection_state.queued_bytes() > max_queued_bytes
+        && !lifecycle.wait_for_abort(Duration::from_millis(10))
+    {}
+    info!(
+        "Resuming consumption after pausing for {:?}",
+        paused_since.elapsed()
+    );
+}
+
 fn connect<C: StreamingClient>(
     client: &C,
     subscription_id: &SubscriptionId,
     max_dur: Duration,
     lifecycle: &Lifecycle,
+    introspection_state: &IntrospectionState,
+    limits: Option<(usize, usize)>,
 ) -> Result<(StreamId, C::LineIterator), ConnectError> {
     let deadline = Instant::now() + max_dur;
     let mut attempt = 0;
     loop {
         attempt += 1;
         let flow_id = FlowId::default();
-        match client.connect(subscription_id, flow_id.clone()) {
+        let started_at = Utc::now();
+        let result = match limits {
+            Some((batch_limit, max_uncommitted_events)) => client.connect_with_limits(
+                subscription_id,
+                flow_id.clone(),
+                batch_limit,
+                max_uncommitted_events,
+            ),
+            None => client.connect(subscription_id, flow_id.clone()),
+        };
+        introspection_state.connect_attempted(ConnectionAttempt::finished(
+            started_at,
+            match result {
+                Ok((ref stream_id, _)) => Ok(stream_id),
+                Err(ref err) => Err(err.to_string()),
+            },
+        ));
+        match result {
             Ok(it) => {
                 return Ok(it);
             }
+            // Retrying does not help e.g. when the subscription does
+            // not exist or the token provider is broken.
+            Err(err) if err.is_permanent() => return Err(err),
             Err(err) => {
-                let sleep_dur_ms = *CONNECT_RETRY_BACKOFF_MS.get(attempt).unwrap_or(&30_000);
+                let backoff_ms = *CONNECT_RETRY_BACKOFF_MS.get(attempt).unwrap_or(&30_000);
+                let sleep_dur_ms = match err {
+                    ConnectError::Overloaded(_, retry_after, _) => {
+                        introspection_state.overloaded(retry_after);
+                        // Nakadi told us when to come back so there is no
+                        // need to escalate the backoff.
+                        match retry_after {
+                            Some(retry_after) => {
+                                millis(retry_after.min(Duration::from_secs(MAX_RETRY_AFTER_SECS)))
+                            }
+                            None => backoff_ms.max(OVERLOADED_MIN_BACKOFF_MS),
+                        }
+                    }
+                    ConnectError::Connection(_) => {
+                        introspection_state.unreachable();
+                        backoff_ms
+                    }
+                    _ => backoff_ms,
+                };
                 if Instant::now() >= deadline {
                     return Err(ConnectError::Other(
                         format!("Failed to connect to Nakadi after {} attempts.", attempt),
                         flow_id,
                     ));
-                } else if lifecycle.abort_requested() {
+                }
+
+                warn!(
+                    "Failed to connect(attempt {}) to Nakadi(retry in {}ms): {}",
+                    attempt, sleep_dur_ms, err
+                );
+                if lifecycle.wait_for_abort(Duration::from_millis(sleep_dur_ms)) {
                     return Err(ConnectError::Other(
                         format!(
                             "Failed to connect to Nakadi after {} attempts. Abort requested",
@@ -313,12 +1127,6 @@ fn connect<C: StreamingClient>(
                         ),
                         flow_id,
                     ));
-                } else {
-                    warn!(
-                        "Failed to connect(attempt {}) to Nakadi(retry in {}ms): {}",
-                        attempt, sleep_dur_ms, err
-                    );
-                    thread::sleep(Duration::from_millis(sleep_dur_ms));
                 }
             }
         }
@@ -1,22 +1,104 @@
 //! The processor orchestrates the workers
 
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::time::{Duration, Instant};
-use std::thread;
 use std::sync::mpsc;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
-use nakadi::Lifecycle;
-use nakadi::worker::Worker;
+use nakadi::{Lifecycle, ShutdownConfig};
+use nakadi::worker::{ErrorLog, Worker};
+use nakadi::worker_pool::WorkerPool;
+use nakadi::mailbox::{Delivery, DispatchOrder, MailboxConfig};
 use nakadi::model::{PartitionId, StreamId};
 use nakadi::committer::Committer;
 use nakadi::handler::HandlerFactory;
 use nakadi::batch::Batch;
 use nakadi::metrics::MetricsCollector;
+use nakadi::introspection::IntrospectionState;
+
+/// The workers by the partition they process and the
+/// instant they were last used
+type Workers = HashMap<PartitionId, (Worker, Instant)>;
+
+/// The interval at which idle workers are looked for
+const IDLE_WORKERS_CHECK_INTERVAL_SECS: u64 = 5;
+
+/// The interval at which the workers and the handler
+/// factory are checked
+const HOUSEKEEPING_INTERVAL_MS: u64 = 1_000;
+
+/// The interval at which paused partitions are checked
+/// for being resumed while batches are held back
+const HELD_BACK_CHECK_INTERVAL_MS: u64 = 50;
+
+/// Messages sent over the data channel
+enum DispatcherMessage {
+    Batch(Batch),
+    /// Wakes up the dispatcher to look at the control channel
+    Wakeup,
+}
+
+/// Messages sent over the control channel
+enum Control {
+    Stop,
+}
+
+/// The partitions whose batches should not be processed for now.
+///
+/// Batches of a paused partition are held back by the `Dispatcher`
+/// until the partition is resumed. Since their cursors are not committed
+/// `Nakadi` will stop sending batches for the partition once
+/// `max_uncommitted_events` is reached and might close the stream
+/// once the commit timeout has elapsed. The held back batches
+/// are then discarded and sent again on the next stream.
+#[derive(Clone, Default)]
+pub struct PausedPartitions {
+    inner: Arc<Mutex<HashSet<PartitionId>>>,
+}
+
+impl PausedPartitions {
+    pub fn pause(&self, partition: PartitionId) {
+        self.update(|paused| {
+            paused.insert(partition);
+        })
+    }
+
+    pub fn resume(&self, partition: &PartitionId) {
+        self.update(|paused| {
+            paused.remove(partition);
+        })
+    }
+
+    pub fn is_paused(&self, partition: &PartitionId) -> bool {
+        let mut is_paused = false;
+        self.update(|paused| is_paused = paused.contains(partition));
+        is_paused
+    }
+
+    pub fn paused(&self) -> Vec<PartitionId> {
+        let mut partitions = Vec::new();
+        self.update(|paused| partitions = paused.iter().cloned().collect());
+        partitions.sort_by(|a, b| a.0.cmp(&b.0));
+        partitions
+    }
+
+    fn update<F: FnOnce(&mut HashSet<PartitionId>)>(&self, f: F) {
+        match self.inner.lock() {
+            Ok(mut paused) => f(&mut paused),
+            Err(poisoned) => f(&mut poisoned.into_inner()),
+        }
+    }
+}
 
 /// The dispatcher takes batch lines and sends them to the workers.
+///
+/// Control messages are sent over a separate channel so that they
+/// are not queued behind the batches. The dispatcher blocks on the
+/// batches and is woken up to look at the control messages.
 pub struct Dispatcher {
     /// Send batches with this sender
-    sender: mpsc::Sender<Batch>,
+    sender: mpsc::Sender<DispatcherMessage>,
+    control: mpsc::Sender<Control>,
     lifecycle: Lifecycle,
 }
 
@@ -26,42 +108,65 @@ impl Dispatcher {
         committer: Committer,
         metrics_collector: M,
         min_idle_worker_lifetime: Option<Duration>,
+        handler_timeout: Option<Duration>,
+        shutdown: ShutdownConfig,
+        introspection_state: IntrospectionState,
+        paused_partitions: PausedPartitions,
+        error_log: ErrorLog,
+        assigned_partitions: Vec<PartitionId>,
+        mailbox_config: MailboxConfig,
+        worker_threads: Option<usize>,
+        parent: &Lifecycle,
     ) -> Dispatcher
     where
         HF: HandlerFactory + Send + Sync + 'static,
         M: MetricsCollector + Clone + Send + 'static,
     {
         let (sender, receiver) = mpsc::channel();
+        let (control, control_receiver) = mpsc::channel();
 
-        let lifecycle = Lifecycle::default();
-
-        let handle = Dispatcher {
-            lifecycle: lifecycle.clone(),
-            sender,
-        };
-
-        start_dispatcher_loop(
+        let lifecycle = start_dispatcher_loop(
             receiver,
-            lifecycle,
+            control_receiver,
             handler_factory,
             committer,
             metrics_collector,
             min_idle_worker_lifetime,
+            handler_timeout,
+            shutdown,
+            introspection_state,
+            paused_partitions,
+            error_log,
+            assigned_partitions,
+            mailbox_config,
+            worker_threads,
+            parent,
         );
 
-        handle
+        Dispatcher {
+            lifecycle,
+            sender,
+            control,
+        }
     }
 
     pub fn is_running(&self) -> bool {
         self.lifecycle.running()
     }
 
+    /// Wait until the dispatcher and all of its workers stopped.
+    pub fn wait_until_stopped(&self) {
+        self.lifecycle.wait()
+    }
+
     pub fn stop(&self) {
-        self.lifecycle.request_abort()
+        if self.control.send(Control::Stop).is_ok() {
+            let _ = self.sender.send(DispatcherMessage::Wakeup);
+        }
     }
 
     pub fn process(&self, batch: Batch) -> Result<(), String> {
-        if let Err(err) = self.sender.send(batch) {
+        if let Err(err) = self.sender.send(DispatcherMessage::Batch(batch)) {
             Err(format!(
                 "Could not send batch. Worker possibly closed: {}",
                 err
@@ -73,35 +178,63 @@ impl Dispatcher {
 }
 
 fn start_dispatcher_loop<HF, M>(
-    receiver: mpsc::Receiver<Batch>,
-    lifecycle: Lifecycle,
+    receiver: mpsc::Receiver<DispatcherMessage>,
+    control: mpsc::Receiver<Control>,
     handler_factory: Arc<HF>,
     committer: Committer,
     metrics_collector: M,
     min_idle_worker_lifetime: Option<Duration>,
-) where
+    handler_timeout: Option<Duration>,
+    shutdown: ShutdownConfig,
+    introspection_state: IntrospectionState,
+    paused_partitions: PausedPartitions,
+    error_log: ErrorLog,
+    assigned_partitions: Vec<PartitionId>,
+    mailbox_config: MailboxConfig,
+    worker_threads: Option<usize>,
+    parent: &Lifecycle,
+) -> Lifecycle
+where
     HF: HandlerFactory + Send + Sync + 'static,
     M: MetricsCollector + Clone + Send + 'static,
 {
-    thread::spawn(move || {
+    parent.spawn_named("nakadion-dispatcher", move |lifecycle| {
         dispatcher_loop(
             receiver,
+            control,
             lifecycle,
             handler_factory,
             committer,
             metrics_collector,
             min_idle_worker_lifetime,
+            handler_timeout,
+            shutdown,
+            introspection_state,
+            paused_partitions,
+            error_log,
+            assigned_partitions,
+            mailbox_config,
+            worker_threads,
         )
-    });
+    })
 }
 
 fn dispatcher_loop<HF, M>(
-    receiver: mpsc::Receiver<Batch>,
+    receiver: mpsc::Receiver<DispatcherMessage>,
+    control: mpsc::Receiver<Control>,
     lifecycle: Lifecycle,
     handler_factory: Arc<HF>,
     committer: Committer,
     metrics_collector: M,
     min_idle_worker_lifetime: Option<Duration>,
+    handler_timeout: Option<Duration>,
+    shutdown: ShutdownConfig,
+    introspection_state: IntrospectionState,
+    paused_partitions: PausedPartitions,
+    error_log: ErrorLog,
+    assigned_partitions: Vec<PartitionId>,
+    mailbox_config: MailboxConfig,
+    worker_threads: Option<usize>,
 ) where
     HF: HandlerFactory,
     M: MetricsCollector + Clone + Send + 'static,
@@ -109,43 +242,179 @@ fn dispatcher_loop<HF, M>(
     metrics_collector.dispatcher_current_workers(0);
 
     let stream_id = committer.stream_id().clone();
-    let mut workers: Vec<(Worker, Instant)> = Vec::with_capacity(32);
+    let mut workers: Workers = HashMap::with_capacity(assigned_partitions.len().max(32));
     let mut idle_workers_last_checked = Instant::now();
+    let mut handlers_last_checked = Instant::now();
+    let mut draining = false;
+    let mut held_back: HashMap<PartitionId, VecDeque<Batch>> = HashMap::new();
+    let mut pending: RoundRobinQueue<Batch> = RoundRobinQueue::default();
+    let mut handler_generation = handler_factory.generation();
+    let worker_pool = worker_threads.map(|threads| WorkerPool::new(threads, &lifecycle));
 
     info!("[Dispatcher, stream={}] Started.", committer.stream_id(),);
-    loop {
-        if lifecycle.abort_requested() {
-            info!(
-                "[Dispatcher, stream={}] Stop requested externally.",
-                stream_id
-            );
 
+    if !assigned_partitions.is_empty() {
+        info!(
+            "[Dispatcher, stream={}] Creating workers for {} assigned partitions.",
+            stream_id,
+            assigned_partitions.len()
+        );
+    }
+    let mut startup_failed = false;
+    for partition in assigned_partitions {
+        if let Err(err) = worker_for_partition(
+            partition,
+            &mut workers,
+            &*handler_factory,
+            &committer,
+            &metrics_collector,
+            &error_log,
+            mailbox_config,
+            worker_pool.as_ref(),
+            &lifecycle,
+        ) {
+            error!("[Dispatcher, stream={}] {}. Stopping.", stream_id, err);
+            startup_failed = true;
             break;
         }
+    }
+
+    while !startup_failed {
+        if !draining && stop_requested(&control) {
+            if shutdown.drain_queues {
+                info!(
+                    "[Dispatcher, stream={}] Stop requested externally. Draining queue.",
+                    stream_id
+                );
+                draining = true;
+            } else {
+                info!(
+                    "[Dispatcher, stream={}] Stop requested externally. \
+                     Committing processed batches first.",
+                    stream_id
+                );
+                if let Err(err) = committer.flush() {
+                    warn!("[Dispatcher, stream={}] {}", stream_id, err);
+                }
+
+                break;
+            }
+        }
 
-        if idle_workers_last_checked.elapsed() >= Duration::from_secs(5) {
+        if idle_workers_last_checked.elapsed()
+            >= Duration::from_secs(IDLE_WORKERS_CHECK_INTERVAL_SECS)
+        {
             if let Some(min_idle_worker_lifetime) = min_idle_worker_lifetime {
-                workers = kill_idle_workers(
-                    workers,
+                kill_idle_workers(
+                    &mut workers,
                     &metrics_collector,
                     min_idle_worker_lifetime,
                     &stream_id,
+                    &introspection_state,
                 );
                 idle_workers_last_checked = Instant::now()
             }
         }
 
-        let batch = match receiver.recv_timeout(Duration::from_millis(5)) {
-            Ok(batch) => batch,
-            Err(mpsc::RecvTimeoutError::Timeout) => continue,
-            Err(mpsc::RecvTimeoutError::Disconnected) => {
+        if handlers_last_checked.elapsed() >= Duration::from_millis(HOUSEKEEPING_INTERVAL_MS) {
+            metrics_collector.dispatcher_handler_stalled_for(longest_stall(&workers));
+            if let Some(handler_timeout) = handler_timeout {
+                if let Some(stuck) = find_stuck_worker(&workers, handler_timeout, &stream_id) {
+                    error!(
+                        "[Dispatcher, stream={}] Handler for partition {} made no progress \
+                         for more than {:?}. Stopping.",
+                        stream_id, stuck, handler_timeout
+                    );
+
+                    break;
+                }
+            }
+            let current_generation = handler_factory.generation();
+            if current_generation != handler_generation {
                 info!(
-                    "[Dispatcher, stream={}] Channel disconnected. Stopping.",
+                    "[Dispatcher, stream={}] Handler factory changed. Restarting workers.",
                     stream_id
                 );
+                stop_workers(&workers, true, shutdown.timeout, &stream_id);
+                workers.clear();
+                metrics_collector.dispatcher_current_workers(0);
+                introspection_state.all_workers_stopped();
+                handler_generation = current_generation;
+            }
 
-                break;
+            handlers_last_checked = Instant::now();
+        }
+
+        if let Err(err) = release_resumed_partitions(
+            &mut held_back,
+            &paused_partitions,
+            &mut workers,
+            &*handler_factory,
+            &committer,
+            &metrics_collector,
+            &introspection_state,
+            &error_log,
+            mailbox_config,
+            worker_pool.as_ref(),
+            &lifecycle,
+        ) {
+            error!("[Dispatcher, stream={}] {}. Stopping.", stream_id, err);
+            break;
+        }
+
+        if let Err(err) = dispatch_pending(
+            &mut pending,
+            draining,
+            &mut workers,
+            &*handler_factory,
+            &committer,
+            &metrics_collector,
+            &introspection_state,
+            &error_log,
+            mailbox_config,
+            worker_pool.as_ref(),
+            &lifecycle,
+        ) {
+            error!("[Dispatcher, stream={}] {}. Stopping.", stream_id, err);
+            break;
+        }
+
+        let message = if draining {
+            match receiver.try_recv() {
+                Ok(message) => message,
+                Err(_) if !pending.is_empty() => continue,
+                Err(_) => {
+                    info!("[Dispatcher, stream={}] Queue drained.", stream_id);
+
+                    break;
+                }
+            }
+        } else {
+            let mut wait_for = next_housekeeping_in(
+                idle_workers_last_checked,
+                handlers_last_checked,
+                min_idle_worker_lifetime.is_some(),
+            );
+            if !held_back.is_empty() || !pending.is_empty() {
+                wait_for = wait_for.min(Duration::from_millis(HELD_BACK_CHECK_INTERVAL_MS));
             }
+            match receiver.recv_timeout(wait_for) {
+                Ok(message) => message,
+                Err(mpsc::RecvTimeoutError::Timeout) => continue,
+                Err(mpsc::RecvTimeoutError::Disconnected) => {
+                    info!(
+                        "[Dispatcher, stream={}] Channel disconnected. Stopping.",
+                        stream_id
+                    );
+
+                    break;
+                }
+            }
+        };
+
+        let batch = match message {
+            DispatcherMessage::Batch(batch) => batch,
+            DispatcherMessage::Wakeup => continue,
         };
 
         if batch.batch_line.events().is_none() {
@@ -169,94 +438,512 @@ fn dispatcher_loop<HF, M>(
             }
         };
 
-        let worker_idx = workers.iter().position(|w| w.0.partition() == &partition);
+        if paused_partitions.is_paused(&partition) {
+            held_back
+                .entry(partition)
+                .or_insert_with(VecDeque::new)
+                .push_back(batch);
+            continue;
+        }
 
-        let worker = if let Some(idx) = worker_idx {
-            let &mut (ref worker, ref mut last_used) = &mut workers[idx];
-            *last_used = Instant::now();
-            worker
-        } else {
-            info!(
-                "[Dispatcher, stream={}] Creating new worker for partition {}",
-                stream_id, partition
-            );
-            let handler = match handler_factory.create_handler(&partition) {
-                Ok(handler) => handler,
-                Err(err) => {
-                    error!("Could not create handler: {}", err);
-                    break;
-                }
+        if mailbox_config.dispatch == DispatchOrder::RoundRobin {
+            pending.push(partition, batch);
+            continue;
+        }
+
+        if let Err(err) = dispatch_batch(
+            batch,
+            partition,
+            &mut workers,
+            &*handler_factory,
+            &committer,
+            &metrics_collector,
+            &introspection_state,
+            &error_log,
+            mailbox_config,
+            worker_pool.as_ref(),
+            &lifecycle,
+        ) {
+            error!("[Dispatcher, stream={}] {}. Stopping.", stream_id, err);
+            break;
+        }
+    }
+
+    let num_held_back: usize = held_back.values().map(|batches| batches.len()).sum();
+    if num_held_back > 0 {
+        warn!(
+            "[Dispatcher, stream={}] Discarding {} batches of paused partitions.",
+            stream_id, num_held_back
+        );
+    }
+    if !pending.is_empty() {
+        warn!(
+            "[Dispatcher, stream={}] Discarding {} batches waiting for their turn.",
+            stream_id,
+            pending.len()
+        );
+    }
+
+    stop_workers(&workers, shutdown.drain_queues, shutdown.timeout, &stream_id);
+
+    metrics_collector.dispatcher_current_workers(0);
+    introspection_state.all_workers_stopped();
+
+    info!("[Dispatcher, stream={}] All wokers stopped.", stream_id);
+
+    lifecycle.stopped();
+    info!("[Dispatcher, stream={}] Stopped.", stream_id);
+}
+
+/// Returns true if a stop was requested or the `Dispatcher` was dropped.
+fn stop_requested(control: &mpsc::Receiver<Control>) -> bool {
+    match control.try_recv() {
+        Ok(Control::Stop) => true,
+        Err(mpsc::TryRecvError::Empty) => false,
+        Err(mpsc::TryRecvError::Disconnected) => true,
+    }
+}
+
+/// The time until the next periodic check is due.
+fn next_housekeeping_in(
+    idle_workers_last_checked: Instant,
+    handlers_last_checked: Instant,
+    check_idle_workers: bool,
+) -> Duration {
+    let mut next_in = Duration::from_millis(HOUSEKEEPING_INTERVAL_MS)
+        .checked_sub(handlers_last_checked.elapsed())
+        .unwrap_or_else(|| Duration::from_millis(0));
+    if check_idle_workers {
+        let idle_check_in = Duration::from_secs(IDLE_WORKERS_CHECK_INTERVAL_SECS)
+            .checked_sub(idle_workers_last_checked.elapsed())
+            .unwrap_or_else(|| Duration::from_millis(0));
+        next_in = next_in.min(idle_check_in);
+    }
+    next_in
+}
+
+/// Stops the workers and waits for them to finish. Workers not stopped
+/// within `timeout` are abandoned.
+fn stop_workers(workers: &Workers, drain: bool, timeout: Duration, stream: &StreamId) {
+    if drain {
+        workers.values().for_each(|w| w.0.drain());
+    } else {
+        workers.values().for_each(|w| w.0.stop());
+    }
+
+    info!("[Dispatcher, stream={}] Waiting for workers to stop", stream);
+
+    let deadline = Instant::now() + timeout;
+    let abandoned: Vec<&Worker> = workers
+        .values()
+        .map(|w| &w.0)
+        .filter(|w| {
+            let now = Instant::now();
+            let left = if deadline > now {
+                deadline - now
+            } else {
+                Duration::from_millis(0)
             };
+            !w.wait_until_stopped_timeout(left)
+        })
+        .collect();
+
+    if !abandoned.is_empty() {
+        let partitions: Vec<_> = abandoned
+            .iter()
+            .map(|w| w.partition().to_string())
+            .collect();
+        warn!(
+            "[Dispatcher, stream={}] Workers did not stop within {:?}. Abandoning \
+             workers for partitions {}.",
+            stream,
+            timeout,
+            partitions.join(", ")
+        );
+        abandoned.iter().for_each(|w| w.abandon());
+    }
+}
 
-            let worker = Worker::start(
-                handler,
-                committer.clone(),
-                partition.clone(),
-                metrics_collector.clone(),
+/// Sends the batch to the worker for its partition. A worker is created
+/// if there is none for the partition yet.
+fn dispatch_batch<HF, M>(
+    batch: Batch,
+    partition: PartitionId,
+    workers: &mut Workers,
+    handler_factory: &HF,
+    committer: &Committer,
+    metrics_collector: &M,
+    introspection_state: &IntrospectionState,
+    error_log: &ErrorLog,
+    mailbox_config: MailboxConfig,
+    worker_pool: Option<&WorkerPool>,
+    lifecycle: &Lifecycle,
+) -> Result<(), String>
+where
+    HF: HandlerFactory,
+    M: MetricsCollector + Clone + Send + 'static,
+{
+    let &mut (ref worker, ref mut last_used) = worker_for_partition(
+        partition.clone(),
+        workers,
+        handler_factory,
+        committer,
+        metrics_collector,
+        error_log,
+        mailbox_config,
+        worker_pool,
+        lifecycle,
+    )?;
+    *last_used = Instant::now();
+
+    introspection_state.worker_used(&partition, worker.progress());
+    introspection_state.batch_dispatched();
+
+    match worker.process(batch) {
+        Ok(Delivery::Queued) => {
+            metrics_collector.dispatcher_worker_mailbox_size(worker.queued_batches());
+            Ok(())
+        }
+        Ok(Delivery::Discarded(n)) => {
+            warn!(
+                "[Dispatcher, stream={}] Mailbox of partition {} overflowed. Discarded {} \
+                 batches. They will be sent again on the next stream.",
+                committer.stream_id(),
+                partition,
+                n
             );
-            workers.push((worker, Instant::now()));
-            metrics_collector.dispatcher_current_workers(workers.len());
-            &workers[workers.len() - 1].0
-        };
+            metrics_collector.dispatcher_batches_discarded(n);
+            Ok(())
+        }
+        Err(err) => Err(format!("Worker did not accept batch: {}", err)),
+    }
+}
 
-        if let Err(err) = worker.process(batch) {
-            error!(
-                "[Dispatcher, stream={}] Worker did not accept batch. Stopping. - {}",
-                stream_id, err
+/// Returns the worker for the partition and creates it if there
+/// is none for the partition yet.
+fn worker_for_partition<'a, HF, M>(
+    partition: PartitionId,
+    workers: &'a mut Workers,
+    handler_factory: &HF,
+    committer: &Committer,
+    metrics_collector: &M,
+    error_log: &ErrorLog,
+    mailbox_config: MailboxConfig,
+    worker_pool: Option<&WorkerPool>,
+    lifecycle: &Lifecycle,
+) -> Result<&'a mut (Worker, Instant), String>
+where
+    HF: HandlerFactory,
+    M: MetricsCollector + Clone + Send + 'static,
+{
+    if !workers.contains_key(&partition) {
+        info!(
+            "[Dispatcher, stream={}] Creating new worker for partition {}",
+            committer.stream_id(),
+            partition
+        );
+        let handler = handler_factory
+            .create_handler(&partition)
+            .map_err(|err| format!("Could not create handler: {}", err))?;
+
+        let worker = Worker::start(
+            handler,
+            committer.clone(),
+            partition.clone(),
+            metrics_collector.clone(),
+            error_log.clone(),
+            mailbox_config,
+            worker_pool,
+            lifecycle,
+        );
+        workers.insert(partition.clone(), (worker, Instant::now()));
+        metrics_collector.dispatcher_current_workers(workers.len());
+    }
+
+    Ok(workers
+        .get_mut(&partition)
+        .expect("the worker for the partition exists"))
+}
+
+/// Dispatches the held back batches of partitions that are not paused anymore.
+fn release_resumed_partitions<HF, M>(
+    held_back: &mut HashMap<PartitionId, VecDeque<Batch>>,
+    paused_partitions: &PausedPartitions,
+    workers: &mut Workers,
+    handler_factory: &HF,
+    committer: &Committer,
+    metrics_collector: &M,
+    introspection_state: &IntrospectionState,
+    error_log: &ErrorLog,
+    mailbox_config: MailboxConfig,
+    worker_pool: Option<&WorkerPool>,
+    lifecycle: &Lifecycle,
+) -> Result<(), String>
+where
+    HF: HandlerFactory,
+    M: MetricsCollector + Clone + Send + 'static,
+{
+    if held_back.is_empty() {
+        return Ok(());
+    }
+
+    let resumed: Vec<PartitionId> = held_back
+        .keys()
+        .filter(|partition| !paused_partitions.is_paused(partition))
+        .cloned()
+        .collect();
+
+    for partition in resumed {
+        if let Some(batches) = held_back.remove(&partition) {
+            info!(
+                "[Dispatcher, stream={}] Partition {} resumed. Releasing {} batches.",
+                committer.stream_id(),
+                partition,
+                batches.len()
             );
-            break;
+            for batch in batches {
+                dispatch_batch(
+                    batch,
+                    partition.clone(),
+                    workers,
+                    handler_factory,
+                    committer,
+                    metrics_collector,
+                    introspection_state,
+                    error_log,
+                    mailbox_config,
+                    worker_pool,
+                    lifecycle,
+                )?;
+            }
         }
     }
 
-    workers.iter().for_each(|w| w.0.stop());
+    Ok(())
+}
 
-    info!(
-        "[Dispatcher, stream={}] Waiting for workers to stop",
-        stream_id
-    );
+/// Dispatches the batches waiting for their turn to the workers that
+/// have room in their mailbox. All batches are dispatched if `all` is true.
+fn dispatch_pending<HF, M>(
+    pending: &mut RoundRobinQueue<Batch>,
+    all: bool,
+    workers: &mut Workers,
+    handler_factory: &HF,
+    committer: &Committer,
+    metrics_collector: &M,
+    introspection_state: &IntrospectionState,
+    error_log: &ErrorLog,
+    mailbox_config: MailboxConfig,
+    worker_pool: Option<&WorkerPool>,
+    lifecycle: &Lifecycle,
+) -> Result<(), String>
+where
+    HF: HandlerFactory,
+    M: MetricsCollector + Clone + Send + 'static,
+{
+    loop {
+        let next = {
+            let has_room = |partition: &PartitionId| match mailbox_config.capacity {
+                Some(capacity) if !all => workers
+                    .get(partition)
+                    .map(|&(ref worker, _)| worker.queued_batches() < capacity)
+                    .unwrap_or(true),
+                _ => true,
+            };
+            pending.pop_where(has_room)
+        };
 
-    while workers.iter().any(|w| w.0.running()) {
-        thread::sleep(Duration::from_millis(10));
+        match next {
+            Some((partition, batch)) => dispatch_batch(
+                batch,
+                partition,
+                workers,
+                handler_factory,
+                committer,
+                metrics_collector,
+                introspection_state,
+                error_log,
+                mailbox_config,
+                worker_pool,
+                lifecycle,
+            )?,
+            None => return Ok(()),
+        }
     }
+}
 
-    metrics_collector.dispatcher_current_workers(0);
+/// Items queued by partition and taken from the partitions in turns
+struct RoundRobinQueue<T> {
+    queues: HashMap<PartitionId, VecDeque<T>>,
+    /// The partitions with queued items in the order of their turns
+    turns: VecDeque<PartitionId>,
+}
 
-    info!("[Dispatcher, stream={}] All wokers stopped.", stream_id);
+impl<T> Default for RoundRobinQueue<T> {
+    fn default() -> RoundRobinQueue<T> {
+        RoundRobinQueue {
+            queues: HashMap::new(),
+            turns: VecDeque::new(),
+        }
+    }
+}
 
-    lifecycle.stopped();
-    info!("[Dispatcher, stream={}] Stopped.", stream_id);
+impl<T> RoundRobinQueue<T> {
+    fn push(&mut self, partition: PartitionId, item: T) {
+        let queue = self.queues
+            .entry(partition.clone())
+            .or_insert_with(VecDeque::new);
+        if queue.is_empty() {
+            self.turns.push_back(partition);
+        }
+        queue.push_back(item);
+    }
+
+    /// Takes the next item of the next partition that is `ready`.
+    ///
+    /// Partitions that are not ready keep their items and
+    /// have to wait for their next turn.
+    fn pop_where<F>(&mut self, mut ready: F) -> Option<(PartitionId, T)>
+    where
+        F: FnMut(&PartitionId) -> bool,
+    {
+        for _ in 0..self.turns.len() {
+            let partition = match self.turns.pop_front() {
+                Some(partition) => partition,
+                None => return None,
+            };
+            if !ready(&partition) {
+                self.turns.push_back(partition);
+                continue;
+            }
+
+            let (item, more) = match self.queues.get_mut(&partition) {
+                Some(queue) => (queue.pop_front(), !queue.is_empty()),
+                None => (None, false),
+            };
+            if more {
+                self.turns.push_back(partition.clone());
+            } else {
+                self.queues.remove(&partition);
+            }
+            if let Some(item) = item {
+                return Some((partition, item));
+            }
+        }
+        None
+    }
+
+    fn len(&self) -> usize {
+        self.queues.values().map(VecDeque::len).sum()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.turns.is_empty()
+    }
+}
+
+/// Returns the partition of a worker whose handler made no progress
+/// within `handler_timeout`.
+fn find_stuck_worker(
+    workers: &Workers,
+    handler_timeout: Duration,
+    stream: &StreamId,
+) -> Option<PartitionId> {
+    for &(ref worker, _) in workers.values() {
+        let progress = worker.progress();
+        match (progress.busy_for(), progress.stalled_for()) {
+            (_, Some(stalled_for)) if stalled_for >= handler_timeout => {
+                return Some(worker.partition().clone())
+            }
+            (Some(busy_for), _) if busy_for >= handler_timeout => debug!(
+                "[Dispatcher, stream={}] Handler for partition {} still working on a batch \
+                 for {:?}.",
+                stream,
+                worker.partition(),
+                busy_for
+            ),
+            _ => (),
+        }
+    }
+    None
+}
+
+/// The longest time a handler working on a batch made no progress
+fn longest_stall(workers: &Workers) -> Duration {
+    workers
+        .values()
+        .filter_map(|&(ref worker, _)| worker.progress().stalled_for())
+        .max()
+        .unwrap_or_else(|| Duration::from_secs(0))
 }
 
 fn kill_idle_workers(
-    workers: Vec<(Worker, Instant)>,
+    workers: &mut Workers,
     metrics_collector: &MetricsCollector,
     min_idle_worker_lifetime: Duration,
     stream: &StreamId,
-) -> Vec<(Worker, Instant)> {
-    let mut survivors = Vec::new();
-    let mut stopped = Vec::new();
+    introspection_state: &IntrospectionState,
+) {
+    let idle: Vec<PartitionId> = workers
+        .iter()
+        .filter(|&(_, &(_, last_used))| last_used.elapsed() >= min_idle_worker_lifetime)
+        .map(|(partition, _)| partition.clone())
+        .collect();
 
-    for (worker, last_used) in workers {
-        if last_used.elapsed() >= min_idle_worker_lifetime {
+    let mut stopped = Vec::new();
+    for partition in idle {
+        if let Some((worker, _)) = workers.remove(&partition) {
             info!(
                 "[Dispatcher, stream={}] Stopping idle worker for partition '{}'",
                 stream,
                 worker.partition()
             );
             worker.stop();
+            introspection_state.worker_stopped(worker.partition());
             stopped.push(worker)
-        } else {
-            survivors.push((worker, last_used));
         }
     }
 
-    while stopped.iter().any(|w| w.running()) {
-        thread::sleep(Duration::from_millis(5));
-    }
+    stopped.iter().for_each(|w| w.wait_until_stopped());
 
     if stopped.len() > 0 {
-        metrics_collector.dispatcher_current_workers(survivors.len());
+        metrics_collector.dispatcher_current_workers(workers.len());
     }
+}
+
+#[test]
+fn housekeeping_is_due_after_the_interval() {
+    let now = Instant::now();
+    let long_ago = now - Duration::from_secs(10);
+
+    let interval = Duration::from_millis(HOUSEKEEPING_INTERVAL_MS);
+
+    assert!(next_housekeeping_in(now, now, true) <= interval);
+    assert_eq!(
+        next_housekeeping_in(long_ago, now, true),
+        Duration::from_millis(0)
+    );
+    assert!(next_housekeeping_in(long_ago, now, false) > Duration::from_millis(0));
+}
 
-    survivors
+#[test]
+fn pending_batches_are_taken_in_turns() {
+    let hot = PartitionId("hot".to_string());
+    let cold = PartitionId("cold".to_string());
+    let mut queue = RoundRobinQueue::default();
+    queue.push(hot.clone(), 1);
+    queue.push(hot.clone(), 2);
+    queue.push(hot.clone(), 3);
+    queue.push(cold.clone(), 10);
+
+    assert_eq!(queue.pop_where(|_| true), Some((hot.clone(), 1)));
+    assert_eq!(queue.pop_where(|_| true), Some((cold.clone(), 10)));
+    assert_eq!(queue.pop_where(|_| true), Some((hot.clone(), 2)));
+
+    queue.push(cold.clone(), 11);
+    assert_eq!(queue.pop_where(|p| *p != hot), Some((cold.clone(), 11)));
+    assert_eq!(queue.pop_where(|p| *p != hot), None);
+    assert_eq!(queue.len(), 1);
+    assert_eq!(queue.pop_where(|_| true), Some((hot, 3)));
+    assert!(queue.is_empty());
 }
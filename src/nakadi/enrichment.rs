@@ -0,0 +1,140 @@
+//! Enriching events before they are published
+//!
+//! A `NakadiPublisher` passes every outgoing event through its
+//! `EventEnricher`s in the order they were added. This is the place to
+//! inject metadata every event of a service carries, like the `span_ctx`,
+//! a version tag or the id of a tenant.
+use failure::Error;
+use serde_json::{self, Map, Value};
+
+/// Modifies events right before they are published.
+pub trait EventEnricher {
+    /// Enrich a single event of the given event type.
+    ///
+    /// Returning an error aborts publishing the whole batch.
+    fn enrich(&self, event_type: &str, event: &mut Value) -> Result<(), Error>;
+}
+
+impl<F> EventEnricher for F
+where
+    F: Fn(&str, &mut Value) -> Result<(), Error>,
+{
+    fn enrich(&self, event_type: &str, event: &mut Value) -> Result<(), Error> {
+        self(event_type, event)
+    }
+}
+
+/// Applies `EventEnricher`s in the order they were added.
+#[derive(Default)]
+pub struct EnrichmentPipeline {
+    enrichers: Vec<Box<EventEnricher + Send + Sync>>,
+}
+
+impl EnrichmentPipeline {
+    pub fn new() -> EnrichmentPipeline {
+        EnrichmentPipeline::default()
+    }
+
+    /// Add an enricher that runs after all enrichers added before.
+    pub fn add<E: EventEnricher + Send + Sync + 'static>(&mut self, enricher: E) {
+        self.enrichers.push(Box::new(enricher));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.enrichers.is_empty()
+    }
+
+    /// Enrich a single event with all enrichers.
+    pub fn enrich(&self, event_type: &str, event: &mut Value) -> Result<(), Error> {
+        for enricher in &self.enrichers {
+            enricher.enrich(event_type, event)?;
+        }
+        Ok(())
+    }
+
+    /// Enrich events encoded as a JSON array.
+    ///
+    /// The bytes are returned unchanged if there are no enrichers.
+    pub fn enrich_raw(&self, event_type: &str, bytes: Vec<u8>) -> Result<Vec<u8>, Error> {
+        if self.is_empty() {
+            return Ok(bytes);
+        }
+
+        let mut events: Vec<Value> = serde_json::from_slice(&bytes)?;
+        for event in &mut events {
+            self.enrich(event_type, event)?;
+        }
+        Ok(serde_json::to_vec(&events)?)
+    }
+}
+
+/// Sets fields of the `metadata` of every event.
+///
+/// Fields already present on an event are not overwritten. Events without
+/// a `metadata` object (e.g. of the `undefined` category) are not touched.
+#[derive(Debug, Clone, Default)]
+pub struct MetadataFields {
+    fields: Map<String, Value>,
+}
+
+impl MetadataFields {
+    pub fn new() -> MetadataFields {
+        MetadataFields::default()
+    }
+
+    /// Set the field `name` to `value`.
+    pub fn field<N: Into<String>, V: Into<Value>>(mut self, name: N, value: V) -> MetadataFields {
+        self.fields.insert(name.into(), value.into());
+        self
+    }
+}
+
+impl EventEnricher for MetadataFields {
+    fn enrich(&self, _event_type: &str, event: &mut Value) -> Result<(), Error> {
+        if let Some(metadata) = event
+            .get_mut("metadata")
+            .and_then(|metadata| metadata.as_object_mut())
+        {
+            for (name, value) in &self.fields {
+                if !metadata.contains_key(name) {
+                    metadata.insert(name.clone(), value.clone());
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn enrichers_run_in_order() {
+    let mut pipeline = EnrichmentPipeline::new();
+    pipeline.add(MetadataFields::new().field("version", "1.0"));
+    pipeline.add(|_: &str, event: &mut Value| -> Result<(), Error> {
+        let version = event["metadata"]["version"].clone();
+        event["metadata"]["version"] = json!(format!("v{}", version.as_str().unwrap_or("")));
+        Ok(())
+    });
+
+    let mut event = json!({"metadata": {"eid": "1"}});
+    pipeline.enrich("et", &mut event).unwrap();
+
+    assert_eq!(event, json!({"metadata": {"eid": "1", "version": "v1.0"}}));
+}
+
+#[test]
+fn metadata_fields_do_not_overwrite() {
+    let enricher = MetadataFields::new()
+        .field("tenant_id", "a")
+        .field("span_ctx", json!({"trace": "x"}));
+
+    let mut event = json!({"metadata": {"tenant_id": "b"}});
+    enricher.enrich("et", &mut event).unwrap();
+    assert_eq!(
+        event,
+        json!({"metadata": {"tenant_id": "b", "span_ctx": {"trace": "x"}}})
+    );
+
+    let mut undefined = json!({"data": 1});
+    enricher.enrich("et", &mut undefined).unwrap();
+    assert_eq!(undefined, json!({"data": 1}));
+}
@@ -1,8 +1,29 @@
+//! Events and their metadata
+//!
+//! A type implementing `NakadiEvent` knows the event type it belongs to.
+//! With the `derive` feature `#[derive(NakadiEvent)]` implements it:
+//!
+//! ```rust,ignore
+//! #[derive(Serialize, Deserialize, NakadiEvent)]
+//! #[nakadi(event_type = "order.created")]
+//! struct OrderCreated {
+//!     order_number: String,
+//! }
+//! ```
+//!
+//! `OutgoingEvent` wraps the event with metadata for publishing and
+//! `IncomingEvent` unwraps a consumed event from its metadata. An
+//! `IncomingEvent<E>` can be used as the `Event` of a `TypedBatchHandler`.
+use std::time::Duration;
+
 use chrono::DateTime;
 use chrono::offset::Utc;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
 use uuid::Uuid;
 
 use nakadi::model::{FlowId, PartitionId};
+use nakadi::publisher::{NakadiPublisher, PublishError, PublishStatus};
 
 #[derive(Debug, Clone, Serialize)]
 pub struct OutgoingMetadata {
@@ -15,6 +36,25 @@ pub struct OutgoingMetadata {
     pub partition: Option<PartitionId>,
 }
 
+impl OutgoingMetadata {
+    /// Metadata with a random `eid` for an event that occurred now.
+    pub fn new() -> OutgoingMetadata {
+        OutgoingMetadata {
+            eid: Uuid::new_v4(),
+            event_type: None,
+            occurred_at: Utc::now(),
+            parent_eids: Vec::new(),
+            partition: None,
+        }
+    }
+}
+
+impl Default for OutgoingMetadata {
+    fn default() -> OutgoingMetadata {
+        OutgoingMetadata::new()
+    }
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct IncomingMetadata {
     pub eid: Uuid,
@@ -26,3 +66,109 @@ pub struct IncomingMetadata {
     pub partition: PartitionId,
     pub flow_id: FlowId,
 }
+
+/// An event that belongs to a known event type
+///
+/// Can be derived with `#[derive(NakadiEvent)]` if the
+/// `derive` feature is enabled.
+pub trait NakadiEvent: Serialize + DeserializeOwned {
+    /// The name of the event type
+    fn event_type() -> &'static str;
+
+    /// Wrap the event with default metadata.
+    fn into_outgoing(self) -> OutgoingEvent<Self> {
+        OutgoingEvent::new(self)
+    }
+}
+
+/// An event wrapped with the metadata `Nakadi` requires for
+/// business and data change events
+#[derive(Debug, Clone, Serialize)]
+pub struct OutgoingEvent<E> {
+    pub metadata: OutgoingMetadata,
+    #[serde(flatten)]
+    pub data: E,
+}
+
+impl<E> OutgoingEvent<E> {
+    pub fn new(data: E) -> OutgoingEvent<E> {
+        OutgoingEvent {
+            metadata: OutgoingMetadata::new(),
+            data,
+        }
+    }
+
+    /// Mark the event as caused by the events with the given `eid`s.
+    pub fn parent_eids(mut self, parent_eids: Vec<Uuid>) -> OutgoingEvent<E> {
+        self.metadata.parent_eids = parent_eids;
+        self
+    }
+}
+
+/// A consumed event together with its metadata
+#[derive(Debug, Clone, Deserialize)]
+pub struct IncomingEvent<E> {
+    pub metadata: IncomingMetadata,
+    #[serde(flatten)]
+    pub data: E,
+}
+
+impl NakadiPublisher {
+    /// Wrap the events with default metadata and publish
+    /// them to their event type.
+    pub fn publish_nakadi_events<E: NakadiEvent>(
+        &self,
+        events: Vec<E>,
+        flow_id: Option<FlowId>,
+        budget: Duration,
+    ) -> Result<PublishStatus, PublishError> {
+        let events: Vec<OutgoingEvent<E>> = events.into_iter().map(OutgoingEvent::new).collect();
+        self.publish_events(E::event_type(), &events, flow_id, budget)
+    }
+}
+
+#[cfg(test)]
+#[derive(Debug, Serialize, Deserialize)]
+struct TestEvent {
+    order_number: String,
+}
+
+#[cfg(test)]
+impl NakadiEvent for TestEvent {
+    fn event_type() -> &'static str {
+        "order.created"
+    }
+}
+
+#[test]
+fn events_are_wrapped_with_metadata() {
+    let event = TestEvent {
+        order_number: "42".to_string(),
+    }.into_outgoing();
+
+    let json = ::serde_json::to_value(&event).unwrap();
+    assert_eq!(json["order_number"], "42");
+    assert_eq!(json["metadata"]["eid"], event.metadata.eid.to_string());
+    assert!(json["metadata"].get("event_type").is_none());
+}
+
+#[test]
+fn incoming_events_are_unwrapped() {
+    let json = r#"{
+        "order_number": "42",
+        "metadata": {
+            "eid": "d765de34-09c0-4bbb-8b1e-7160a33a0791",
+            "event_type": "order.created",
+            "occurred_at": "2018-03-01T10:00:00Z",
+            "received_at": "2018-03-01T10:00:01Z",
+            "version": "1.0.0",
+            "parent_eids": [],
+            "partition": "0",
+            "flow_id": "flow"
+        }
+    }"#;
+
+    let event: IncomingEvent<TestEvent> = ::serde_json::from_str(json).unwrap();
+    assert_eq!(event.data.order_number, "42");
+    assert_eq!(event.metadata.event_type, TestEvent::event_type());
+}
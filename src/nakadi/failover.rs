@@ -0,0 +1,198 @@
+//! Failing over between the hosts of active/passive `Nakadi` deployments
+//!
+//! The streaming client connects to the primary host, the configured
+//! `nakadi_host`. After `failures_before_failover` connects in a row
+//! failed because the host could not be reached or was overloaded it
+//! connects to the next of the failover hosts and so on, starting over
+//! with the primary after the last one.
+//!
+//! Failing over is sticky: The consumer stays on a host as long as
+//! connecting to it works. If `return_to_primary_after` is set the
+//! primary is tried again on the next connect once that time has passed
+//! since failing over.
+//!
+//! A `NakadiApiClient` sharing the `NakadiHosts` sends its requests to
+//! the host the stream is connected to so that cursors are committed
+//! where they came from.
+use std::cmp;
+use std::fmt;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// Settings for failing over to other hosts
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FailoverConfig {
+    /// The URI prefixes of the hosts to fail over to in order
+    pub hosts: Vec<String>,
+    /// The number of failed connects in a row to fail over after
+    pub failures_before_failover: usize,
+    /// Try the primary again after this time. Stay on the
+    /// host failed over to as long as it works if `None`.
+    pub return_to_primary_after: Option<Duration>,
+}
+
+impl FailoverConfig {
+    /// Fail over after 3 failed connects and stay there.
+    pub fn new<T: Into<String>, I: IntoIterator<Item = T>>(hosts: I) -> FailoverConfig {
+        FailoverConfig {
+            hosts: hosts.into_iter().map(Into::into).collect(),
+            failures_before_failover: 3,
+            return_to_primary_after: None,
+        }
+    }
+
+    pub fn failures_before_failover(mut self, failures: usize) -> FailoverConfig {
+        self.failures_before_failover = cmp::max(failures, 1);
+        self
+    }
+
+    pub fn return_to_primary_after(mut self, after: Duration) -> FailoverConfig {
+        self.return_to_primary_after = Some(after);
+        self
+    }
+}
+
+/// The host currently used. Clones share it.
+#[derive(Clone)]
+pub struct NakadiHosts {
+    /// The primary first
+    hosts: Arc<Vec<String>>,
+    failures_before_failover: usize,
+    return_to_primary_after: Option<Duration>,
+    state: Arc<Mutex<State>>,
+}
+
+struct State {
+    current: usize,
+    failures: usize,
+    failed_over_at: Option<Instant>,
+}
+
+impl NakadiHosts {
+    pub fn new<T: Into<String>>(primary: T, config: FailoverConfig) -> NakadiHosts {
+        let mut hosts = vec![primary.into()];
+        hosts.extend(config.hosts);
+        NakadiHosts {
+            hosts: Arc::new(hosts),
+            failures_before_failover: cmp::max(config.failures_before_failover, 1),
+            return_to_primary_after: config.return_to_primary_after,
+            state: Arc::new(Mutex::new(State {
+                current: 0,
+                failures: 0,
+                failed_over_at: None,
+            })),
+        }
+    }
+
+    /// The host requests are sent to
+    pub fn current(&self) -> String {
+        self.hosts[self.lock().current].clone()
+    }
+
+    pub fn is_primary(&self) -> bool {
+        self.lock().current == 0
+    }
+
+    /// The host to connect to at `now`.
+    ///
+    /// Returns to the primary if it is time to try it again.
+    pub fn host_for_connect(&self, now: Instant) -> String {
+        let mut state = self.lock();
+        if let (Some(after), Some(at)) = (self.return_to_primary_after, state.failed_over_at) {
+            if state.current != 0 && now >= at + after {
+                info!("Returning to the primary Nakadi host {}", self.hosts[0]);
+                state.current = 0;
+                state.failures = 0;
+                state.failed_over_at = None;
+            }
+        }
+        self.hosts[state.current].clone()
+    }
+
+    /// Connecting to the current host worked.
+    pub fn connect_succeeded(&self) {
+        self.lock().failures = 0;
+    }
+
+    /// Connecting to the current host failed at `now`.
+    ///
+    /// Returns the next host if it failed over.
+    pub fn connect_failed(&self, now: Instant) -> Option<String> {
+        let mut state = self.lock();
+        state.failures += 1;
+        if state.failures < self.failures_before_failover || self.hosts.len() < 2 {
+            return None;
+        }
+
+        state.current = (state.current + 1) % self.hosts.len();
+        state.failures = 0;
+        state.failed_over_at = if state.current == 0 {
+            None
+        } else {
+            Some(now)
+        };
+        Some(self.hosts[state.current].clone())
+    }
+
+    fn lock(&self) -> MutexGuard<State> {
+        match self.state.lock() {
+            Ok(state) => state,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+}
+
+impl fmt::Debug for NakadiHosts {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "NakadiHosts(current={})", self.current())
+    }
+}
+
+#[cfg(test)]
+fn test_hosts() -> FailoverConfig {
+    FailoverConfig::new(vec!["http://secondary"]).failures_before_failover(2)
+}
+
+#[test]
+fn repeated_failures_fail_over_to_the_next_host() {
+    let hosts = NakadiHosts::new("http://primary", test_hosts());
+    let now = Instant::now();
+
+    assert_eq!(hosts.connect_failed(now), None);
+    hosts.connect_succeeded();
+    assert_eq!(hosts.connect_failed(now), None);
+    assert_eq!(
+        hosts.connect_failed(now),
+        Some("http://secondary".to_string())
+    );
+    assert!(!hosts.is_primary());
+
+    // Sticky as long as the host works
+    hosts.connect_succeeded();
+    assert_eq!(
+        hosts.host_for_connect(now + Duration::from_secs(3600)),
+        "http://secondary"
+    );
+
+    hosts.connect_failed(now);
+    assert_eq!(hosts.connect_failed(now), Some("http://primary".to_string()));
+}
+
+#[test]
+fn the_primary_is_tried_again_after_a_while() {
+    let config = test_hosts().return_to_primary_after(Duration::from_secs(60));
+    let hosts = NakadiHosts::new("http://primary", config);
+    let now = Instant::now();
+    hosts.connect_failed(now);
+    hosts.connect_failed(now);
+
+    assert_eq!(
+        hosts.host_for_connect(now + Duration::from_secs(59)),
+        "http://secondary"
+    );
+    assert_eq!(
+        hosts.host_for_connect(now + Duration::from_secs(60)),
+        "http://primary"
+    );
+    assert!(hosts.is_primary());
+}
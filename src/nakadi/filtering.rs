@@ -0,0 +1,175 @@
+//! Filtering events by their metadata before they reach the handlers
+//!
+//! Until `Nakadi` can filter the events of a subscription the consumer
+//! drops the events that do not match an `EventFilter`. Handlers only see
+//! the events that matched. Batches without a matching event still go
+//! through the worker of their partition so that their cursors are
+//! committed in order, but without bothering the handler.
+use serde_json::{self, Value};
+
+use nakadi::batch::BatchLine;
+
+/// Keeps the events whose fields have one of the allowed values.
+///
+/// Fields are paths separated by dots starting at the root of the
+/// event, e.g. `metadata.event_type` or `metadata.tenant_id`. An event
+/// must match all fields. Events missing a field do not match.
+#[derive(Debug, Clone, Default)]
+pub struct EventFilter {
+    rules: Vec<(String, Vec<Value>)>,
+}
+
+/// What remained of the events of a batch
+#[derive(Debug, PartialEq)]
+pub enum Filtered {
+    /// All events matched
+    All,
+    /// No event matched. Contains the number of events dropped.
+    Nothing(usize),
+    /// Some events matched. Contains them encoded as a JSON
+    /// array and the number of events dropped.
+    Some(Vec<u8>, usize),
+}
+
+impl EventFilter {
+    pub fn new() -> EventFilter {
+        EventFilter::default()
+    }
+
+    /// Keep events where `field` equals `value`.
+    pub fn field_equals<F: Into<String>, V: Into<Value>>(self, field: F, value: V) -> EventFilter {
+        self.field_in(field, vec![value.into()])
+    }
+
+    /// Keep events where `field` equals one of `values`.
+    pub fn field_in<F, V, I>(mut self, field: F, values: I) -> EventFilter
+    where
+        F: Into<String>,
+        V: Into<Value>,
+        I: IntoIterator<Item = V>,
+    {
+        self.rules
+            .push((field.into(), values.into_iter().map(Into::into).collect()));
+        self
+    }
+
+    /// Keep events of the given `metadata.event_type`.
+    pub fn event_types<V, I>(self, event_types: I) -> EventFilter
+    where
+        V: Into<Value>,
+        I: IntoIterator<Item = V>,
+    {
+        self.field_in("metadata.event_type", event_types)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Returns true if the event matches all fields.
+    pub fn matches(&self, event: &Value) -> bool {
+        self.rules.iter().all(|&(ref field, ref values)| {
+            match field
+                .split('.')
+                .fold(Some(event), |value, segment| value.and_then(|v| v.get(segment)))
+            {
+                Some(value) => values.contains(value),
+                None => false,
+            }
+        })
+    }
+
+    /// Filter events encoded as a JSON array.
+    pub fn filter_events(&self, events: &[u8]) -> Result<Filtered, String> {
+        if self.is_empty() {
+            return Ok(Filtered::All);
+        }
+
+        let events: Vec<Value> = serde_json::from_slice(events)
+            .map_err(|err| format!("Could not parse events for filtering: {}", err))?;
+        let num_events = events.len();
+        let kept: Vec<Value> = events
+            .into_iter()
+            .filter(|event| self.matches(event))
+            .collect();
+
+        let dropped = num_events - kept.len();
+        if dropped == 0 {
+            Ok(Filtered::All)
+        } else if kept.is_empty() {
+            Ok(Filtered::Nothing(dropped))
+        } else {
+            let bytes = serde_json::to_vec(&kept)
+                .map_err(|err| format!("Could not encode filtered events: {}", err))?;
+            Ok(Filtered::Some(bytes, dropped))
+        }
+    }
+}
+
+/// Build a line with the cursor and info of `batch_line`
+/// but with other events or without events.
+pub fn replace_events(batch_line: &BatchLine, events: Option<&[u8]>) -> Result<BatchLine, String> {
+    let mut line = Vec::with_capacity(batch_line.bytes().len());
+    line.extend_from_slice(b"{\"cursor\":");
+    line.extend_from_slice(batch_line.cursor());
+    if let Some(events) = events {
+        line.extend_from_slice(b",\"events\":");
+        line.extend_from_slice(events);
+    }
+    if let Some(info) = batch_line.info() {
+        line.extend_from_slice(b",\"info\":");
+        line.extend_from_slice(info);
+    }
+    line.push(b'}');
+    BatchLine::new(line).map_err(|err| err.to_string())
+}
+
+#[test]
+fn events_must_match_all_fields() {
+    let filter = EventFilter::new()
+        .event_types(vec!["order.created", "order.cancelled"])
+        .field_equals("metadata.tenant_id", "a");
+
+    assert!(filter.matches(&json!({
+        "metadata": {"event_type": "order.created", "tenant_id": "a"}
+    })));
+    assert!(!filter.matches(&json!({
+        "metadata": {"event_type": "order.created", "tenant_id": "b"}
+    })));
+    assert!(!filter.matches(&json!({"metadata": {"event_type": "order.cancelled"}})));
+}
+
+#[test]
+fn filtering_keeps_the_matching_events() {
+    let filter = EventFilter::new().field_equals("metadata.version", "2");
+    let events = br#"[{"metadata":{"version":"1"}},{"metadata":{"version":"2"}}]"#;
+
+    assert_eq!(
+        filter.filter_events(events).unwrap(),
+        Filtered::Some(br#"[{"metadata":{"version":"2"}}]"#.to_vec(), 1)
+    );
+    assert_eq!(
+        filter.filter_events(br#"[{"metadata":{"version":"1"}}]"#).unwrap(),
+        Filtered::Nothing(1)
+    );
+    assert_eq!(
+        filter.filter_events(br#"[{"metadata":{"version":"2"}}]"#).unwrap(),
+        Filtered::All
+    );
+}
+
+#[test]
+fn replacing_events_keeps_the_cursor() {
+    let line = BatchLine::from_slice(
+        br#"{"cursor":{"partition":"5","offset":"543","event_type":"et","cursor_token":"t"},"events":[{"a":1},{"a":2}],"info":{"debug":"x"}}"#,
+    ).unwrap();
+
+    let replaced = replace_events(&line, Some(&br#"[{"a":2}]"#[..])).unwrap();
+    assert_eq!(replaced.cursor(), line.cursor());
+    assert_eq!(replaced.events(), Some(&br#"[{"a":2}]"#[..]));
+    assert_eq!(replaced.info(), line.info());
+
+    let emptied = replace_events(&line, None).unwrap();
+    assert_eq!(emptied.cursor(), line.cursor());
+    assert_eq!(emptied.events(), None);
+}
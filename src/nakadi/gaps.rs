@@ -0,0 +1,201 @@
+//! Detecting events skipped between consecutive batches
+//!
+//! The cursor of a batch points to its last event. If the offset of a
+//! partition advanced by more than the number of events in the batch some
+//! events were not delivered. This happens when events are filtered,
+//! when a compacted event type dropped older versions of a key or when
+//! events were lost.
+//!
+//! Pipelines that must see every event can treat gaps as fatal.
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use failure::Error;
+
+use nakadi::ordering::distance;
+
+/// What to do when events were skipped
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum GapAction {
+    /// Log and report the gap and keep consuming
+    Report,
+    /// Report the gap and stop the consumer
+    Abort,
+}
+
+impl fmt::Display for GapAction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            GapAction::Report => write!(f, "report"),
+            GapAction::Abort => write!(f, "abort"),
+        }
+    }
+}
+
+impl FromStr for GapAction {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_ref() {
+            "report" => Ok(GapAction::Report),
+            "abort" => Ok(GapAction::Abort),
+            _ => Err(format_err!("'{}' is not a gap action", s)),
+        }
+    }
+}
+
+/// Events skipped between two consecutive batches of a partition
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OffsetGap {
+    pub event_type: String,
+    pub partition: String,
+    /// The offset of the previous batch
+    pub previous: String,
+    /// The offset of the batch after the gap
+    pub current: String,
+    /// The number of events in the batch after the gap
+    pub num_events: usize,
+    /// The number of events that were skipped
+    pub missing: u64,
+}
+
+impl fmt::Display for OffsetGap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} events skipped in partition {} of event type {}: {} -> {} with {} events",
+            self.missing, self.partition, self.event_type, self.previous, self.current,
+            self.num_events
+        )
+    }
+}
+
+/// Gets notified about every gap.
+pub trait GapListener {
+    fn on_gap(&self, gap: &OffsetGap);
+}
+
+/// A `GapListener` that can be shared between threads.
+#[derive(Clone)]
+pub struct SharedGapListener(pub Arc<GapListener + Send + Sync>);
+
+impl fmt::Debug for SharedGapListener {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SharedGapListener")
+    }
+}
+
+/// Settings for detecting gaps
+#[derive(Debug, Clone)]
+pub struct GapConfig {
+    pub action: GapAction,
+    pub listener: Option<SharedGapListener>,
+}
+
+/// Remembers the offset of the previous batch of each partition
+pub struct GapDetector {
+    config: GapConfig,
+    last_offsets: HashMap<(String, String), String>,
+    gaps: u64,
+}
+
+impl GapDetector {
+    pub fn new(config: GapConfig) -> GapDetector {
+        GapDetector {
+            config,
+            last_offsets: HashMap::new(),
+            gaps: 0,
+        }
+    }
+
+    pub fn action(&self) -> GapAction {
+        self.config.action
+    }
+
+    pub fn listener(&self) -> Option<&SharedGapListener> {
+        self.config.listener.as_ref()
+    }
+
+    /// Forget all offsets. Must be called when a new stream is consumed
+    /// since the first batch of a stream starts at the committed cursor.
+    pub fn reset(&mut self) {
+        self.last_offsets.clear();
+    }
+
+    /// The number of gaps detected
+    pub fn gaps(&self) -> u64 {
+        self.gaps
+    }
+
+    /// Check the offset of a batch with `num_events` events.
+    ///
+    /// Offsets that did not increase or moved to another timeline
+    /// are not gaps.
+    pub fn check(
+        &mut self,
+        event_type: &str,
+        partition: &str,
+        offset: &str,
+        num_events: usize,
+    ) -> Option<OffsetGap> {
+        let key = (event_type.to_string(), partition.to_string());
+        let previous = match self.last_offsets.insert(key, offset.to_string()) {
+            Some(previous) => previous,
+            None => return None,
+        };
+
+        match distance(&previous, offset) {
+            Some(distance) if distance > num_events as u64 => {
+                self.gaps += 1;
+                Some(OffsetGap {
+                    event_type: event_type.to_string(),
+                    partition: partition.to_string(),
+                    previous,
+                    current: offset.to_string(),
+                    num_events,
+                    missing: distance - num_events as u64,
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+fn test_detector() -> GapDetector {
+    GapDetector::new(GapConfig {
+        action: GapAction::Report,
+        listener: None,
+    })
+}
+
+#[test]
+fn adjacent_batches_have_no_gap() {
+    let mut detector = test_detector();
+    assert_eq!(detector.check("et", "0", "001-0001-000000000000000009", 10), None);
+    assert_eq!(detector.check("et", "0", "001-0001-000000000000000019", 10), None);
+    assert_eq!(detector.check("et", "1", "001-0001-000000000000000002", 3), None);
+    assert_eq!(detector.gaps(), 0);
+}
+
+#[test]
+fn skipped_events_are_a_gap() {
+    let mut detector = test_detector();
+    detector.check("et", "0", "001-0001-000000000000000009", 10);
+    let gap = detector
+        .check("et", "0", "001-0001-000000000000000029", 5)
+        .unwrap();
+    assert_eq!(gap.missing, 15);
+    assert_eq!(gap.previous, "001-0001-000000000000000009");
+    assert_eq!(detector.gaps(), 1);
+}
+
+#[test]
+fn a_new_stream_has_no_gap() {
+    let mut detector = test_detector();
+    detector.check("et", "0", "001-0001-000000000000000009", 10);
+    detector.reset();
+    assert_eq!(detector.check("et", "0", "001-0001-000000000000000099", 1), None);
+}
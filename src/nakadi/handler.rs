@@ -1,8 +1,18 @@
 //! Handler for handling events.
+use std::fmt;
+use std::sync::{Arc, Condvar, Mutex, RwLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use futures::Future;
+use futures::sync::oneshot;
 use serde::de::DeserializeOwned;
 use serde_json;
+use serde_json::value::RawValue;
+use tokio::runtime::TaskExecutor;
 
-use nakadi::model::{EventType, PartitionId};
+use nakadi::metrics::MetricsCollector;
+use nakadi::model::{EventType, PartitionId, RawCursor};
 
 #[derive(Debug)]
 pub enum ProcessingStatus {
@@ -29,8 +39,180 @@ impl ProcessingStatus {
 pub trait BatchHandler {
     /// Handle the events.
     ///
+    /// The cursor of the batch is available via
+    /// `ProgressReporter::current_batch`.
+    ///
+    /// `events` is the JSON array of the events. Use `RawEvents::parse`
+    /// to deserialize each event on its own.
+    ///
     /// Calling this method may never panic!
     fn handle(&mut self, event_type: EventType, events: &[u8]) -> ProcessingStatus;
+
+    /// Called once before the first batch is handled.
+    ///
+    /// Keep the `ProgressReporter` if handling a batch might take long.
+    fn attach_progress_reporter(&mut self, _reporter: ProgressReporter) {}
+
+    /// Called once when the worker owning this handler shuts down
+    /// after the last batch has been handled.
+    ///
+    /// The cursors of the batches handled might not have been committed yet.
+    fn on_shutdown(&mut self) {}
+}
+
+/// The batch a handler is currently working on
+#[derive(Debug, Clone)]
+pub struct BatchInfo {
+    pub partition: PartitionId,
+    /// The cursor of the batch exactly as `Nakadi` sent it
+    pub cursor: RawCursor,
+    /// When the batch was received from `Nakadi`
+    pub received_at: Instant,
+    /// When the cursor of the batch has to be committed at the latest.
+    ///
+    /// A handler still working on the batch after this point risks
+    /// that `Nakadi` closes the stream. It is better to fail the batch
+    /// so that it is sent again on the next stream.
+    pub deadline: Instant,
+}
+
+impl BatchInfo {
+    /// The time left until the deadline. Zero if the deadline has passed.
+    pub fn remaining(&self) -> Duration {
+        let now = Instant::now();
+        if self.deadline > now {
+            self.deadline - now
+        } else {
+            Duration::from_secs(0)
+        }
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.deadline <= Instant::now()
+    }
+}
+
+/// Lets a `BatchHandler` signal that it is still making progress
+/// on a batch that takes long to process.
+///
+/// Each report extends the time a handler may spend on a batch
+/// before it is considered stuck(see `NakadionBuilder::handler_timeout`).
+///
+/// It also tells the handler about the batch it is working
+/// on(see `current_batch`) and lets it annotate the outcome of
+/// the batch with business metrics(see `annotate`).
+#[derive(Clone)]
+pub struct ProgressReporter {
+    state: Arc<Mutex<ProgressState>>,
+}
+
+struct ProgressState {
+    busy_since: Option<Instant>,
+    last_progress: Instant,
+    batch: Option<BatchInfo>,
+    annotations: Vec<(String, u64)>,
+}
+
+impl ProgressReporter {
+    /// Signal that the handler is still working on the current batch.
+    pub fn report_progress(&self) {
+        self.update(|state| state.last_progress = Instant::now())
+    }
+
+    /// A batch has been handed to the handler.
+    pub(crate) fn batch_started(&self, batch: BatchInfo) {
+        self.update(|state| {
+            let now = Instant::now();
+            state.busy_since = Some(now);
+            state.last_progress = now;
+            state.batch = Some(batch);
+            state.annotations.clear();
+        })
+    }
+
+    /// The handler returned from handling a batch.
+    pub(crate) fn batch_finished(&self) {
+        self.update(|state| {
+            state.busy_since = None;
+            state.batch = None;
+        })
+    }
+
+    /// The batch the handler is working on.
+    ///
+    /// `None` if the handler is not working on a batch.
+    pub fn current_batch(&self) -> Option<BatchInfo> {
+        self.read(|state| state.batch.clone())
+    }
+
+    /// For how long the handler has been working on the current batch.
+    ///
+    /// `None` if the handler is not working on a batch.
+    pub fn busy_for(&self) -> Option<Duration> {
+        self.read(|state| state.busy_since.map(|at| at.elapsed()))
+    }
+
+    /// The time since the handler last showed progress on the current
+    /// batch.
+    ///
+    /// `None` if the handler is not working on a batch.
+    pub fn stalled_for(&self) -> Option<Duration> {
+        self.read(|state| state.busy_since.map(|_| state.last_progress.elapsed()))
+    }
+
+    /// Annotate the outcome of the current batch, e.g. with
+    /// `("orders_created", 12)`.
+    ///
+    /// The annotations are passed to `MetricsCollector::handler_annotation`
+    /// once the batch has been processed. They are discarded if
+    /// the batch failed.
+    pub fn annotate<K: Into<String>>(&self, key: K, value: u64) {
+        let key = key.into();
+        self.update(|state| state.annotations.push((key, value)))
+    }
+
+    /// Remove the annotations of the current batch.
+    pub fn take_annotations(&self) -> Vec<(String, u64)> {
+        let mut annotations = Vec::new();
+        self.update(|state| ::std::mem::swap(&mut annotations, &mut state.annotations));
+        annotations
+    }
+
+    fn update<F: FnOnce(&mut ProgressState)>(&self, f: F) {
+        match self.state.lock() {
+            Ok(mut state) => f(&mut state),
+            Err(poisoned) => f(&mut poisoned.into_inner()),
+        }
+    }
+
+    fn read<T, F: FnOnce(&ProgressState) -> T>(&self, f: F) -> T {
+        match self.state.lock() {
+            Ok(state) => f(&state),
+            Err(poisoned) => f(&poisoned.into_inner()),
+        }
+    }
+}
+
+impl fmt::Debug for ProgressReporter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ProgressReporter")
+            .field("busy_for", &self.busy_for())
+            .field("stalled_for", &self.stalled_for())
+            .finish()
+    }
+}
+
+impl Default for ProgressReporter {
+    fn default() -> ProgressReporter {
+        ProgressReporter {
+            state: Arc::new(Mutex::new(ProgressState {
+                busy_since: None,
+                last_progress: Instant::now(),
+                batch: None,
+                annotations: Vec::new(),
+            })),
+        }
+    }
 }
 
 #[derive(Debug, Fail)]
@@ -42,6 +224,452 @@ pub struct CreateHandlerError {
 pub trait HandlerFactory {
     type Handler: BatchHandler + Send + 'static;
     fn create_handler(&self, partition: &PartitionId) -> Result<Self::Handler, CreateHandlerError>;
+
+    /// Changes whenever all existing handlers should be replaced
+    /// by new ones created by this factory.
+    ///
+    /// Once a change is noticed all workers finish their queued batches and
+    /// are restarted with new handlers.
+    fn generation(&self) -> usize {
+        0
+    }
+}
+
+/// A `HandlerFactory` whose underlying factory can be replaced
+/// while consuming.
+///
+/// Keep a clone to swap the factory later on. A swapped in factory
+/// is used for all workers created afterwards. Existing workers keep
+/// their handlers unless `swap_and_restart_workers` is used.
+pub struct SwappableHandlerFactory<HF> {
+    current: Arc<RwLock<Arc<HF>>>,
+    generation: Arc<AtomicUsize>,
+}
+
+impl<HF> SwappableHandlerFactory<HF> {
+    pub fn new(factory: HF) -> SwappableHandlerFactory<HF> {
+        SwappableHandlerFactory {
+            current: Arc::new(RwLock::new(Arc::new(factory))),
+            generation: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Use `factory` for all workers created from now on.
+    pub fn swap(&self, factory: HF) {
+        let factory = Arc::new(factory);
+        match self.current.write() {
+            Ok(mut current) => *current = factory,
+            Err(poisoned) => *poisoned.into_inner() = factory,
+        }
+    }
+
+    /// Use `factory` for all workers and restart the existing workers.
+    pub fn swap_and_restart_workers(&self, factory: HF) {
+        self.swap(factory);
+        self.generation.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn current(&self) -> Arc<HF> {
+        match self.current.read() {
+            Ok(current) => current.clone(),
+            Err(poisoned) => poisoned.into_inner().clone(),
+        }
+    }
+}
+
+impl<HF> Clone for SwappableHandlerFactory<HF> {
+    fn clone(&self) -> SwappableHandlerFactory<HF> {
+        SwappableHandlerFactory {
+            current: self.current.clone(),
+            generation: self.generation.clone(),
+        }
+    }
+}
+
+impl<HF: HandlerFactory> HandlerFactory for SwappableHandlerFactory<HF> {
+    type Handler = HF::Handler;
+
+    fn create_handler(&self, partition: &PartitionId) -> Result<Self::Handler, CreateHandlerError> {
+        self.current().create_handler(partition)
+    }
+
+    fn generation(&self) -> usize {
+        self.generation.load(Ordering::SeqCst)
+    }
+}
+
+/// The partition a `GlobalBatchHandler` handles a batch of
+#[derive(Debug)]
+pub struct GlobalBatchContext<'a> {
+    pub partition: &'a PartitionId,
+    /// Reports the progress of the worker of the partition
+    pub progress: Option<&'a ProgressReporter>,
+}
+
+/// A handler shared by all partitions.
+///
+/// Meant for stateless sinks where a handler for each partition
+/// would only duplicate resources like connection pools.
+pub trait GlobalBatchHandler {
+    /// Handle the events of a batch of `context.partition`.
+    ///
+    /// Calling this method may never panic!
+    fn handle(
+        &self,
+        context: GlobalBatchContext,
+        event_type: EventType,
+        events: &[u8],
+    ) -> ProcessingStatus;
+}
+
+/// A `HandlerFactory` passing the batches of all partitions
+/// to the same `GlobalBatchHandler`.
+///
+/// The batches are still dispatched by partition but at most
+/// `concurrency` of them are handled at the same time.
+pub struct GlobalHandlerFactory<H> {
+    handler: Arc<H>,
+    permits: Arc<Permits>,
+}
+
+impl<H> GlobalHandlerFactory<H> {
+    /// Handle one batch after the other.
+    pub fn new(handler: H) -> GlobalHandlerFactory<H> {
+        GlobalHandlerFactory::with_concurrency(handler, 1)
+    }
+
+    /// Handle up to `concurrency` batches of different partitions
+    /// at the same time. A `concurrency` of 0 is treated as 1.
+    pub fn with_concurrency(handler: H, concurrency: usize) -> GlobalHandlerFactory<H> {
+        GlobalHandlerFactory {
+            handler: Arc::new(handler),
+            permits: Arc::new(Permits::new(::std::cmp::max(concurrency, 1))),
+        }
+    }
+}
+
+impl<H> HandlerFactory for GlobalHandlerFactory<H>
+where
+    H: GlobalBatchHandler + Send + Sync + 'static,
+{
+    type Handler = PartitionOfGlobalHandler<H>;
+
+    fn create_handler(&self, partition: &PartitionId) -> Result<Self::Handler, CreateHandlerError> {
+        Ok(PartitionOfGlobalHandler {
+            partition: partition.clone(),
+            handler: self.handler.clone(),
+            permits: self.permits.clone(),
+            progress: None,
+        })
+    }
+}
+
+/// Hands the batches of a partition to a `GlobalBatchHandler`
+pub struct PartitionOfGlobalHandler<H> {
+    partition: PartitionId,
+    handler: Arc<H>,
+    permits: Arc<Permits>,
+    progress: Option<ProgressReporter>,
+}
+
+impl<H: GlobalBatchHandler> BatchHandler for PartitionOfGlobalHandler<H> {
+    fn handle(&mut self, event_type: EventType, events: &[u8]) -> ProcessingStatus {
+        let _permit = self.permits.acquire();
+        let context = GlobalBatchContext {
+            partition: &self.partition,
+            progress: self.progress.as_ref(),
+        };
+        self.handler.handle(context, event_type, events)
+    }
+
+    fn attach_progress_reporter(&mut self, reporter: ProgressReporter) {
+        self.progress = Some(reporter);
+    }
+}
+
+/// Limits how many batches are handled at the same time
+struct Permits {
+    available: Mutex<usize>,
+    released: Condvar,
+}
+
+impl Permits {
+    fn new(permits: usize) -> Permits {
+        Permits {
+            available: Mutex::new(permits),
+            released: Condvar::new(),
+        }
+    }
+
+    fn acquire(&self) -> Permit {
+        let mut available = match self.available.lock() {
+            Ok(available) => available,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        while *available == 0 {
+            available = match self.released.wait(available) {
+                Ok(available) => available,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+        }
+        *available -= 1;
+        Permit(self)
+    }
+}
+
+struct Permit<'a>(&'a Permits);
+
+impl<'a> Drop for Permit<'a> {
+    fn drop(&mut self) {
+        let mut available = match (self.0).available.lock() {
+            Ok(available) => available,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        *available += 1;
+        (self.0).released.notify_one();
+    }
+}
+
+/// A `HandlerFactory` whose handlers replace invalid UTF-8 in the
+/// events with `U+FFFD` before passing them on.
+///
+/// Batches that are valid UTF-8 are passed as they are. Every
+/// repaired batch is reported to the `MetricsCollector`.
+pub struct LossyUtf8HandlerFactory<HF, M> {
+    factory: HF,
+    enabled: bool,
+    metrics_collector: M,
+}
+
+impl<HF, M> LossyUtf8HandlerFactory<HF, M> {
+    /// If not `enabled` the batches are always passed as they are.
+    pub fn new(factory: HF, enabled: bool, metrics_collector: M) -> LossyUtf8HandlerFactory<HF, M> {
+        LossyUtf8HandlerFactory {
+            factory,
+            enabled,
+            metrics_collector,
+        }
+    }
+}
+
+impl<HF, M> HandlerFactory for LossyUtf8HandlerFactory<HF, M>
+where
+    HF: HandlerFactory,
+    M: MetricsCollector + Clone + Send + 'static,
+{
+    type Handler = LossyUtf8Handler<HF::Handler, M>;
+
+    fn create_handler(&self, partition: &PartitionId) -> Result<Self::Handler, CreateHandlerError> {
+        Ok(LossyUtf8Handler {
+            handler: self.factory.create_handler(partition)?,
+            enabled: self.enabled,
+            metrics_collector: self.metrics_collector.clone(),
+        })
+    }
+
+    fn generation(&self) -> usize {
+        self.factory.generation()
+    }
+}
+
+/// Replaces invalid UTF-8 before passing a batch to its handler
+pub struct LossyUtf8Handler<H, M> {
+    handler: H,
+    enabled: bool,
+    metrics_collector: M,
+}
+
+impl<H, M> BatchHandler for LossyUtf8Handler<H, M>
+where
+    H: BatchHandler,
+    M: MetricsCollector + Send,
+{
+    fn handle(&mut self, event_type: EventType, events: &[u8]) -> ProcessingStatus {
+        if !self.enabled || ::std::str::from_utf8(events).is_ok() {
+            return self.handler.handle(event_type, events);
+        }
+        warn!(
+            "Replaced invalid UTF-8 in a batch of event type {}",
+            event_type.0
+        );
+        self.metrics_collector.consumer_invalid_utf8_repaired();
+        let repaired = String::from_utf8_lossy(events).into_owned();
+        self.handler.handle(event_type, repaired.as_bytes())
+    }
+
+    fn attach_progress_reporter(&mut self, reporter: ProgressReporter) {
+        self.handler.attach_progress_reporter(reporter)
+    }
+
+    fn on_shutdown(&mut self) {
+        self.handler.on_shutdown()
+    }
+}
+
+/// A `HandlerFactory` whose handlers pass each batch to the
+/// handlers of several independent factories, e.g. one indexing
+/// and one archiving the events of the same subscription.
+///
+/// The handlers are called in the order their factories were added
+/// and each of them sees every batch. The batch only succeeds if all
+/// of them succeeded. Otherwise it fails with the reasons of the
+/// handlers that failed and is delivered again to all handlers, so
+/// each handler must cope with seeing a batch twice.
+#[derive(Default)]
+pub struct FanOutHandlerFactory {
+    factories: Vec<(String, Box<DynHandlerFactory + Send + Sync>)>,
+}
+
+impl FanOutHandlerFactory {
+    pub fn new() -> FanOutHandlerFactory {
+        FanOutHandlerFactory::default()
+    }
+
+    /// Also pass the batches to the handlers of `factory`.
+    ///
+    /// `name` identifies the handlers in failures.
+    pub fn with<N, HF>(mut self, name: N, factory: HF) -> FanOutHandlerFactory
+    where
+        N: Into<String>,
+        HF: HandlerFactory + Send + Sync + 'static,
+    {
+        self.factories.push((name.into(), Box::new(factory)));
+        self
+    }
+}
+
+impl HandlerFactory for FanOutHandlerFactory {
+    type Handler = FanOutHandler;
+
+    fn create_handler(&self, partition: &PartitionId) -> Result<Self::Handler, CreateHandlerError> {
+        let mut handlers = Vec::with_capacity(self.factories.len());
+        for &(ref name, ref factory) in &self.factories {
+            let handler = factory.create(partition).map_err(|err| CreateHandlerError {
+                message: format!("{}: {}", name, err.message),
+            })?;
+            handlers.push((name.clone(), handler));
+        }
+        Ok(FanOutHandler { handlers })
+    }
+
+    /// Changes whenever the generation of any of the factories changes
+    fn generation(&self) -> usize {
+        self.factories
+            .iter()
+            .fold(0, |sum, &(_, ref factory)| sum.wrapping_add(factory.generation()))
+    }
+}
+
+/// Passes each batch to the handlers of a `FanOutHandlerFactory`
+pub struct FanOutHandler {
+    handlers: Vec<(String, Box<BatchHandler + Send>)>,
+}
+
+impl BatchHandler for FanOutHandler {
+    fn handle(&mut self, event_type: EventType, events: &[u8]) -> ProcessingStatus {
+        let mut num_events_hint = None;
+        let mut failures = Vec::new();
+        for &mut (ref name, ref mut handler) in &mut self.handlers {
+            match handler.handle(event_type.clone(), events) {
+                ProcessingStatus::Processed(hint) => {
+                    num_events_hint = num_events_hint.or(hint);
+                }
+                ProcessingStatus::Failed { reason } => {
+                    failures.push(format!("{}: {}", name, reason));
+                }
+            }
+        }
+
+        if failures.is_empty() {
+            ProcessingStatus::Processed(num_events_hint)
+        } else {
+            ProcessingStatus::failed(failures.join("; "))
+        }
+    }
+
+    fn attach_progress_reporter(&mut self, reporter: ProgressReporter) {
+        for &mut (_, ref mut handler) in &mut self.handlers {
+            handler.attach_progress_reporter(reporter.clone());
+        }
+    }
+
+    fn on_shutdown(&mut self) {
+        for &mut (_, ref mut handler) in &mut self.handlers {
+            handler.on_shutdown();
+        }
+    }
+}
+
+/// A `HandlerFactory` whose handlers are boxed so that
+/// factories of different handlers can be kept together
+trait DynHandlerFactory {
+    fn create(&self, partition: &PartitionId) -> Result<Box<BatchHandler + Send>, CreateHandlerError>;
+    fn generation(&self) -> usize;
+}
+
+impl<HF: HandlerFactory> DynHandlerFactory for HF {
+    fn create(&self, partition: &PartitionId) -> Result<Box<BatchHandler + Send>, CreateHandlerError> {
+        let handler = self.create_handler(partition)?;
+        Ok(Box::new(handler))
+    }
+
+    fn generation(&self) -> usize {
+        HandlerFactory::generation(self)
+    }
+}
+
+/// The events of a batch as slices of the JSON they were received as.
+///
+/// Each event can be deserialized on its own so that an event that
+/// does not deserialize does not fail the others:
+///
+/// ```rust,ignore
+/// fn handle(&mut self, event_type: EventType, events: &[u8]) -> ProcessingStatus {
+///     let events = match RawEvents::parse(events) {
+///         Ok(events) => events,
+///         Err(err) => return ProcessingStatus::failed(err.to_string()),
+///     };
+///     for result in events.deserialize_each::<Order>() {
+///         ...
+///     }
+///     ProcessingStatus::processed(events.len())
+/// }
+/// ```
+#[derive(Debug)]
+pub struct RawEvents<'a> {
+    events: Vec<&'a RawValue>,
+}
+
+impl<'a> RawEvents<'a> {
+    /// Split the JSON array `events` into its events
+    /// without copying them.
+    pub fn parse(events: &'a [u8]) -> Result<RawEvents<'a>, serde_json::Error> {
+        Ok(RawEvents {
+            events: serde_json::from_slice(events)?,
+        })
+    }
+
+    pub fn events_raw(&self) -> &[&'a RawValue] {
+        &self.events
+    }
+
+    /// Deserialize each event on its own.
+    ///
+    /// The results are in the order of the events.
+    pub fn deserialize_each<T: DeserializeOwned>(&self) -> Vec<Result<T, serde_json::Error>> {
+        self.events
+            .iter()
+            .map(|event| serde_json::from_str(event.get()))
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.events.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
 }
 
 pub enum TypedProcessingStatus {
@@ -52,6 +680,15 @@ pub enum TypedProcessingStatus {
 pub trait TypedBatchHandler {
     type Event: DeserializeOwned;
     fn handle(&mut self, events: Vec<Self::Event>) -> TypedProcessingStatus;
+
+    /// Called once before the first batch is handled.
+    ///
+    /// Keep the `ProgressReporter` if handling a batch might take long.
+    fn attach_progress_reporter(&mut self, _reporter: ProgressReporter) {}
+
+    /// Called once when the worker owning this handler shuts down
+    /// after the last batch has been handled.
+    fn on_shutdown(&mut self) {}
 }
 
 impl<T, E> BatchHandler for T
@@ -79,4 +716,323 @@ where
             TypedProcessingStatus::Failed { reason } => ProcessingStatus::Failed { reason },
         }
     }
+
+    fn attach_progress_reporter(&mut self, reporter: ProgressReporter) {
+        TypedBatchHandler::attach_progress_reporter(self, reporter)
+    }
+
+    fn on_shutdown(&mut self) {
+        TypedBatchHandler::on_shutdown(self)
+    }
+}
+
+/// The outcome of handling a batch asynchronously. Resolving
+/// to an error fails the batch.
+pub type HandlerFuture = Box<Future<Item = ProcessingStatus, Error = String> + Send>;
+
+/// A handler that handles batches with futures, e.g. to call
+/// other services with an asynchronous HTTP client.
+///
+/// Wrap it in an `AsyncHandlerAdapter` to have it created by a
+/// `HandlerFactory`. The futures run on the runtime the adapter was
+/// given so they can use its reactor and timers. Batches of a partition
+/// are still handled one after the other.
+pub trait AsyncBatchHandler {
+    /// Handle the events.
+    ///
+    /// The future can not borrow `events`. Copy or
+    /// deserialize what it needs.
+    fn handle(&mut self, event_type: EventType, events: &[u8]) -> HandlerFuture;
+
+    /// Called once before the first batch is handled.
+    fn attach_progress_reporter(&mut self, _reporter: ProgressReporter) {}
+
+    /// Called once when the worker owning this handler shuts down
+    /// after the last batch has been handled.
+    fn on_shutdown(&mut self) {}
+}
+
+/// Makes an `AsyncBatchHandler` a `BatchHandler`.
+///
+/// The future of each batch is spawned on the runtime of the executor.
+/// The worker of the partition waits until it resolved.
+pub struct AsyncHandlerAdapter<H> {
+    handler: H,
+    executor: TaskExecutor,
+}
+
+impl<H: AsyncBatchHandler> AsyncHandlerAdapter<H> {
+    /// Run the futures of `handler` on the runtime of `executor`,
+    /// e.g. `Runtime::executor()` of the runtime the HTTP client uses.
+    pub fn new(handler: H, executor: TaskExecutor) -> AsyncHandlerAdapter<H> {
+        AsyncHandlerAdapter { handler, executor }
+    }
+}
+
+impl<H: AsyncBatchHandler> BatchHandler for AsyncHandlerAdapter<H> {
+    fn handle(&mut self, event_type: EventType, events: &[u8]) -> ProcessingStatus {
+        let (outcome, received) = oneshot::channel();
+        let handled = self.handler.handle(event_type, events);
+        self.executor.spawn(handled.then(move |result| {
+            let _ = outcome.send(result);
+            Ok::<(), ()>(())
+        }));
+
+        match received.wait() {
+            Ok(Ok(status)) => status,
+            Ok(Err(reason)) => ProcessingStatus::failed(reason),
+            Err(_) => ProcessingStatus::failed(
+                "The runtime shut down before the batch was handled".to_string(),
+            ),
+        }
+    }
+
+    fn attach_progress_reporter(&mut self, reporter: ProgressReporter) {
+        self.handler.attach_progress_reporter(reporter)
+    }
+
+    fn on_shutdown(&mut self) {
+        self.handler.on_shutdown()
+    }
+}
+
+#[test]
+fn the_current_batch_is_known_while_handling() {
+    let reporter = ProgressReporter::default();
+    assert!(reporter.current_batch().is_none());
+
+    let received_at = Instant::now();
+    reporter.batch_started(BatchInfo {
+        partition: PartitionId("0".to_string()),
+        cursor: RawCursor(br#"{"partition":"0","offset":"1"}"#.to_vec()),
+        received_at,
+        deadline: received_at + Duration::from_secs(55),
+    });
+    let batch = reporter.current_batch().unwrap();
+    assert!(!batch.is_expired());
+    assert_eq!(
+        batch.cursor.as_str(),
+        Ok(r#"{"partition":"0","offset":"1"}"#)
+    );
+    assert!(batch.remaining() <= Duration::from_secs(55));
+
+    reporter.batch_finished();
+    assert!(reporter.current_batch().is_none());
+}
+
+#[test]
+fn annotations_belong_to_the_current_batch() {
+    let reporter = ProgressReporter::default();
+    let batch = BatchInfo {
+        partition: PartitionId("0".to_string()),
+        cursor: RawCursor(br#"{"partition":"0","offset":"1"}"#.to_vec()),
+        received_at: Instant::now(),
+        deadline: Instant::now() + Duration::from_secs(55),
+    };
+
+    reporter.batch_started(batch.clone());
+    reporter.annotate("orders_created", 12);
+    reporter.batch_finished();
+    assert_eq!(
+        reporter.take_annotations(),
+        vec![("orders_created".to_string(), 12)]
+    );
+    assert!(reporter.take_annotations().is_empty());
+
+    reporter.annotate("stale", 1);
+    reporter.batch_started(batch);
+    assert!(reporter.take_annotations().is_empty());
+}
+
+#[test]
+fn raw_events_are_deserialized_independently() {
+    let events = RawEvents::parse(br#"[{"a":1}, {"a":"x"}, {"a":3}]"#).unwrap();
+    assert_eq!(events.len(), 3);
+    assert_eq!(events.events_raw()[1].get(), r#"{"a":"x"}"#);
+
+    #[derive(Deserialize)]
+    struct Event {
+        a: u32,
+    }
+
+    let results = events.deserialize_each::<Event>();
+    assert_eq!(results[0].as_ref().map(|e| e.a).ok(), Some(1));
+    assert!(results[1].is_err());
+    assert_eq!(results[2].as_ref().map(|e| e.a).ok(), Some(3));
+}
+
+#[test]
+fn global_handlers_see_the_partition_of_each_batch() {
+    struct Recorder(Mutex<Vec<String>>);
+
+    impl GlobalBatchHandler for Recorder {
+        fn handle(
+            &self,
+            context: GlobalBatchContext,
+            _event_type: EventType,
+            _events: &[u8],
+        ) -> ProcessingStatus {
+            match self.0.lock() {
+                Ok(mut seen) => seen.push(context.partition.0.clone()),
+                Err(poisoned) => poisoned.into_inner().push(context.partition.0.clone()),
+            }
+            ProcessingStatus::processed_no_hint()
+        }
+    }
+
+    let factory = GlobalHandlerFactory::new(Recorder(Mutex::new(Vec::new())));
+    let mut handler_0 = factory.create_handler(&PartitionId("0".to_string())).unwrap();
+    let mut handler_1 = factory.create_handler(&PartitionId("1".to_string())).unwrap();
+
+    handler_1.handle(EventType::new("et"), b"[]");
+    handler_0.handle(EventType::new("et"), b"[]");
+
+    let seen = factory.handler.0.lock().unwrap().clone();
+    assert_eq!(seen, vec!["1".to_string(), "0".to_string()]);
+}
+
+#[test]
+fn failed_futures_fail_the_batch() {
+    use futures::future;
+    use tokio::runtime::Runtime;
+
+    struct FailingHandler;
+
+    impl AsyncBatchHandler for FailingHandler {
+        fn handle(&mut self, _event_type: EventType, events: &[u8]) -> HandlerFuture {
+            if events.is_empty() {
+                Box::new(future::ok(ProcessingStatus::processed_no_hint()))
+            } else {
+                Box::new(future::err("downstream unavailable".to_string()))
+            }
+        }
+    }
+
+    let runtime = Runtime::new().unwrap();
+    let mut handler = AsyncHandlerAdapter::new(FailingHandler, runtime.executor());
+    match handler.handle(EventType::new("et"), b"") {
+        ProcessingStatus::Processed(None) => (),
+        other => panic!("{:?}", other),
+    }
+    match handler.handle(EventType::new("et"), b"[]") {
+        ProcessingStatus::Failed { reason } => assert_eq!(reason, "downstream unavailable"),
+        other => panic!("{:?}", other),
+    }
+}
+
+#[test]
+fn futures_run_on_the_runtime_of_the_adapter() {
+    use tokio::runtime::Runtime;
+    use tokio::timer::Delay;
+
+    // A timer only works on a runtime. Waiting for it on
+    // the worker thread would fail.
+    struct DelayingHandler;
+
+    impl AsyncBatchHandler for DelayingHandler {
+        fn handle(&mut self, _event_type: EventType, _events: &[u8]) -> HandlerFuture {
+            Box::new(
+                Delay::new(Instant::now() + Duration::from_millis(10))
+                    .map(|()| ProcessingStatus::processed(1))
+                    .map_err(|err| err.to_string()),
+            )
+        }
+    }
+
+    let runtime = Runtime::new().unwrap();
+    let mut handler = AsyncHandlerAdapter::new(DelayingHandler, runtime.executor());
+    match handler.handle(EventType::new("et"), b"[{}]") {
+        ProcessingStatus::Processed(Some(1)) => (),
+        other => panic!("{:?}", other),
+    }
+}
+
+#[test]
+fn fanned_out_batches_fail_if_one_handler_failed() {
+    struct Factory(Arc<AtomicUsize>, bool);
+
+    struct Handler(Arc<AtomicUsize>, bool);
+
+    impl BatchHandler for Handler {
+        fn handle(&mut self, _event_type: EventType, _events: &[u8]) -> ProcessingStatus {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            if self.1 {
+                ProcessingStatus::processed(2)
+            } else {
+                ProcessingStatus::failed("disk full")
+            }
+        }
+    }
+
+    impl HandlerFactory for Factory {
+        type Handler = Handler;
+
+        fn create_handler(&self, _partition: &PartitionId) -> Result<Handler, CreateHandlerError> {
+            Ok(Handler(self.0.clone(), self.1))
+        }
+    }
+
+    let calls = Arc::new(AtomicUsize::new(0));
+    let partition = PartitionId("0".to_string());
+
+    let factory = FanOutHandlerFactory::new()
+        .with("index", Factory(calls.clone(), true))
+        .with("archive", Factory(calls.clone(), true));
+    let mut handler = factory.create_handler(&partition).unwrap();
+    match handler.handle(EventType::new("et"), b"[]") {
+        ProcessingStatus::Processed(Some(2)) => (),
+        other => panic!("{:?}", other),
+    }
+
+    let factory = FanOutHandlerFactory::new()
+        .with("index", Factory(calls.clone(), true))
+        .with("archive", Factory(calls.clone(), false));
+    let mut handler = factory.create_handler(&partition).unwrap();
+    match handler.handle(EventType::new("et"), b"[]") {
+        ProcessingStatus::Failed { reason } => assert_eq!(reason, "archive: disk full"),
+        other => panic!("{:?}", other),
+    }
+    assert_eq!(calls.load(Ordering::SeqCst), 4);
+}
+
+#[test]
+fn invalid_utf8_is_replaced() {
+    use nakadi::metrics::CountingMetricsCollector;
+
+    struct Utf8Handler;
+
+    impl BatchHandler for Utf8Handler {
+        fn handle(&mut self, _event_type: EventType, events: &[u8]) -> ProcessingStatus {
+            match ::std::str::from_utf8(events) {
+                Ok(events) if events == "[\"a\u{FFFD}\"]" => ProcessingStatus::processed_no_hint(),
+                other => ProcessingStatus::failed(format!("{:?}", other)),
+            }
+        }
+    }
+
+    struct Factory;
+
+    impl HandlerFactory for Factory {
+        type Handler = Utf8Handler;
+
+        fn create_handler(&self, _partition: &PartitionId) -> Result<Utf8Handler, CreateHandlerError> {
+            Ok(Utf8Handler)
+        }
+    }
+
+    let metrics = CountingMetricsCollector::default();
+    let factory = LossyUtf8HandlerFactory::new(
+        Factory,
+        true,
+        metrics.clone(),
+    );
+    let mut handler = factory
+        .create_handler(&PartitionId("0".to_string()))
+        .unwrap();
+
+    match handler.handle(EventType::new("et"), b"[\"a\xFF\"]") {
+        ProcessingStatus::Processed(_) => (),
+        other => panic!("{:?}", other),
+    }
+    assert_eq!(metrics.count("consumer_invalid_utf8_repaired"), 1);
 }
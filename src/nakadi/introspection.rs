@@ -0,0 +1,825 @@
+//! Data on the current state of `Nakadion`
+//!
+//! Meant to be served by an admin endpoint of an application
+//! to see what `Nakadion` is doing right now.
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use chrono::DateTime;
+use chrono::offset::Utc;
+use serde_json;
+use url::Url;
+
+use nakadi::{millis, CommitStrategy, ConfigSource, NakadionConfig};
+use nakadi::model::{PartitionId, StreamId, SubscriptionId};
+use nakadi::quota::QuotaAction;
+use nakadi::scaling::ScalingTargets;
+use nakadi::ordering::OrderingValidation;
+use nakadi::gaps::GapAction;
+use nakadi::mailbox::{DispatchOrder, OverflowStrategy};
+use nakadi::consumer::{ConsumerOutcome, StreamClosed};
+use nakadi::batch::Batch;
+use nakadi::handler::ProgressReporter;
+use nakadi::metrics::{ThroughputMeter, ThroughputRates};
+
+/// A snapshot of the state of `Nakadion`.
+#[derive(Debug, Clone, Serialize)]
+pub struct Introspection {
+    /// The version of `Nakadion`
+    pub version: &'static str,
+    /// The subscription being consumed
+    pub subscription_id: String,
+    /// The configuration `Nakadion` was started with. Secrets are redacted.
+    ///
+    /// Not available if `Nakadion` was not started from a
+    /// `NakadionConfig`.
+    pub config: Option<ConfigSummary>,
+    pub connection: ConnectionInfo,
+    /// The workers currently processing partitions
+    pub workers: Vec<WorkerInfo>,
+    pub commits: CommitStats,
+    pub load: LoadInfo,
+    pub throughput: ThroughputInfo,
+    /// Why the consumer stopped. `None` while it is running.
+    pub outcome: Option<ConsumerOutcome>,
+    /// The last batches received by partition, the oldest first.
+    ///
+    /// Empty unless capturing recent batches was enabled.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub recent_batches: BTreeMap<String, Vec<CapturedBatch>>,
+}
+
+/// The configuration of `Nakadion` with secrets redacted.
+#[derive(Debug, Clone, Serialize)]
+pub struct ConfigSummary {
+    pub nakadi_host: String,
+    pub subscription_discovery: String,
+    pub stream_keep_alive_limit: usize,
+    pub stream_limit: usize,
+    pub stream_timeout_secs: u64,
+    pub batch_flush_timeout_secs: u64,
+    pub batch_limit: usize,
+    pub max_uncommitted_events: usize,
+    pub request_timeout_ms: u64,
+    pub commit_strategy: CommitStrategy,
+    pub min_idle_worker_lifetime_secs: Option<u64>,
+    pub handler_timeout_secs: Option<u64>,
+    pub drain_on_shutdown: bool,
+    pub shutdown_timeout_secs: u64,
+    pub max_events_per_hour: Option<u64>,
+    pub max_bytes_per_hour: Option<u64>,
+    pub quota_action: Option<QuotaAction>,
+    pub scaling_targets: Option<ScalingTargets>,
+    pub retention_warning_secs: Option<u64>,
+    pub assignment_interval_secs: Option<u64>,
+    pub repartition_check_interval_secs: Option<u64>,
+    pub reconnect_on_repartitioning: bool,
+    pub validate_ordering: Option<OrderingValidation>,
+    pub detect_gaps: Option<GapAction>,
+    pub warm_up_batch_limit: Option<usize>,
+    pub warm_up_max_uncommitted_events: Option<usize>,
+    pub adaptive_limits: bool,
+    pub failover_hosts: Vec<String>,
+    pub rebalance_after_secs: Option<u64>,
+    pub rebalance_on_commit_latency_ms: Option<u64>,
+    pub capture_recent_batches: Option<usize>,
+    pub wire_debug: bool,
+    pub max_queued_bytes: Option<usize>,
+    pub worker_mailbox_capacity: Option<usize>,
+    pub mailbox_overflow_strategy: OverflowStrategy,
+    pub dispatch_order: DispatchOrder,
+    pub worker_threads: Option<usize>,
+    pub strict_startup: bool,
+    pub lossy_utf8: bool,
+    /// Where the values came from
+    pub sources: BTreeMap<String, ConfigSource>,
+}
+
+impl ConfigSummary {
+    /// Log every parameter with its value and where the value came from.
+    pub fn log(&self) {
+        let values = match serde_json::to_value(self) {
+            Ok(serde_json::Value::Object(values)) => values,
+            Ok(_) => return,
+            Err(err) => {
+                warn!("Could not log the effective configuration: {}", err);
+                return;
+            }
+        };
+
+        info!("Effective configuration:");
+        for (parameter, value) in values {
+            if parameter == "sources" {
+                continue;
+            }
+            let source = self.sources
+                .get(&parameter)
+                .cloned()
+                .unwrap_or(ConfigSource::Explicit);
+            info!("    {} = {} ({})", parameter, value, source);
+        }
+    }
+}
+
+impl<'a> From<&'a NakadionConfig> for ConfigSummary {
+    fn from(config: &'a NakadionConfig) -> ConfigSummary {
+        ConfigSummary {
+            nakadi_host: redact_url(&config.nakadi_host),
+            subscription_discovery: config.subscription_discovery.to_string(),
+            stream_keep_alive_limit: config.stream_keep_alive_limit,
+            stream_limit: config.stream_limit,
+            stream_timeout_secs: config.stream_timeout.as_secs(),
+            batch_flush_timeout_secs: config.batch_flush_timeout.as_secs(),
+            batch_limit: config.batch_limit,
+            max_uncommitted_events: config.max_uncommitted_events,
+            request_timeout_ms: millis(config.request_timeout),
+            commit_strategy: config.commit_strategy,
+            min_idle_worker_lifetime_secs: config.min_idle_worker_lifetime.map(|d| d.as_secs()),
+            handler_timeout_secs: config.handler_timeout.map(|d| d.as_secs()),
+            drain_on_shutdown: config.shutdown.drain_queues,
+            shutdown_timeout_secs: config.shutdown.timeout.as_secs(),
+            max_events_per_hour: config.quota.as_ref().and_then(|q| q.max_events_per_hour),
+            max_bytes_per_hour: config.quota.as_ref().and_then(|q| q.max_bytes_per_hour),
+            quota_action: config.quota.as_ref().map(|q| q.action),
+            scaling_targets: config.scaling.as_ref().map(|s| s.targets),
+            retention_warning_secs: config.retention.as_ref().map(|r| r.warn_within.as_secs()),
+            assignment_interval_secs: config.assignment.as_ref().map(|a| a.interval.as_secs()),
+            repartition_check_interval_secs: config
+                .repartitioning
+                .as_ref()
+                .map(|r| r.interval.as_secs()),
+            reconnect_on_repartitioning: config
+                .repartitioning
+                .as_ref()
+                .map(|r| r.reconnect)
+                .unwrap_or(false),
+            validate_ordering: config.validate_ordering,
+            detect_gaps: config.gaps.as_ref().map(|g| g.action),
+            warm_up_batch_limit: config.warm_up.map(|w| w.batch_limit),
+            warm_up_max_uncommitted_events: config.warm_up.map(|w| w.max_uncommitted_events),
+            adaptive_limits: config.adaptive_limits.is_some(),
+            failover_hosts: config
+                .failover
+                .as_ref()
+                .map(|failover| failover.hosts.iter().map(|host| redact_url(host)).collect())
+                .unwrap_or_default(),
+            rebalance_after_secs: config
+                .rebalance
+                .and_then(|r| r.max_connection_age)
+                .map(|age| age.as_secs()),
+            rebalance_on_commit_latency_ms: config
+                .rebalance
+                .and_then(|r| r.max_commit_latency)
+                .map(millis),
+            capture_recent_batches: config.capture_recent_batches,
+            wire_debug: config.wire_debug,
+            max_queued_bytes: config.max_queued_bytes,
+            worker_mailbox_capacity: config.worker_mailbox.capacity,
+            mailbox_overflow_strategy: config.worker_mailbox.overflow,
+            dispatch_order: config.worker_mailbox.dispatch,
+            worker_threads: config.worker_threads,
+            strict_startup: config.strict_startup,
+            lossy_utf8: config.lossy_utf8,
+            sources: config.sources.clone(),
+        }
+    }
+}
+
+/// Removes credentials that might be part of a URL.
+pub fn redact_url(url: &str) -> String {
+    match Url::parse(url) {
+        Ok(mut parsed) => {
+            if parsed.username().is_empty() && parsed.password().is_none() {
+                url.to_string()
+            } else {
+                let _ = parsed.set_username("<redacted>");
+                let _ = parsed.set_password(None);
+                parsed.into_string()
+            }
+        }
+        Err(_) => url.to_string(),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum ConnectionState {
+    /// Trying to establish a connection to the stream
+    Connecting,
+    /// Connected and consuming
+    Connected,
+    /// `Nakadi` responded with 503 and the consumer is backing
+    /// off before connecting again
+    Overloaded,
+    /// `Nakadi` could not be reached because of a network error
+    Unreachable,
+    /// The consumer has stopped
+    Stopped,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ConnectionInfo {
+    pub state: ConnectionState,
+    /// The id of the stream currently connected to
+    pub stream_id: Option<String>,
+    /// For how long the current connection exists
+    pub connected_for_secs: Option<u64>,
+    /// The number of connections established since start
+    pub connections_established: u64,
+    /// The time from establishing the current connection
+    /// until its first batch was received
+    pub time_to_first_batch_ms: Option<u64>,
+    /// The latest attempts to connect. The latest attempt comes first.
+    pub last_attempts: Vec<ConnectionAttempt>,
+    /// When the last keep alive line was received on the current connection
+    pub last_keep_alive_secs_ago: Option<u64>,
+    /// How long `Nakadi` asked to back off while it is overloaded
+    pub retry_after_secs: Option<u64>,
+    /// Why the previous stream was closed
+    pub last_stream_closed: Option<String>,
+    /// The average time commits of the current connection took
+    pub avg_commit_latency_ms: Option<u64>,
+}
+
+/// An attempt to connect to a stream
+#[derive(Debug, Clone, Serialize)]
+pub struct ConnectionAttempt {
+    pub started_at: DateTime<Utc>,
+    pub duration_ms: u64,
+    /// The stream connected to if the attempt succeeded
+    pub stream_id: Option<String>,
+    /// Why the attempt failed
+    pub error: Option<String>,
+}
+
+impl ConnectionAttempt {
+    /// An attempt that started at `started_at` and ended now
+    pub fn finished(
+        started_at: DateTime<Utc>,
+        result: Result<&StreamId, String>,
+    ) -> ConnectionAttempt {
+        let (stream_id, error) = match result {
+            Ok(stream_id) => (Some(stream_id.0.clone()), None),
+            Err(err) => (None, Some(err)),
+        };
+        ConnectionAttempt {
+            started_at,
+            duration_ms: (Utc::now() - started_at).num_milliseconds().max(0) as u64,
+            stream_id,
+            error,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct WorkerInfo {
+    pub partition: String,
+    /// The time since the worker last received a batch
+    pub idle_for_ms: u64,
+    /// For how long the handler has been working on the current batch.
+    /// `None` if it is not working on a batch.
+    pub busy_for_ms: Option<u64>,
+    /// The time since the handler last showed progress on the current
+    /// batch. A handler is stuck once this exceeds the `handler_timeout`.
+    pub stalled_for_ms: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct CommitStats {
+    /// Successful commit requests
+    pub commits: u64,
+    /// Failed commit requests
+    pub commits_failed: u64,
+    pub batches_committed: u64,
+    pub events_committed: u64,
+    /// Committed cursors that were already behind a committed cursor.
+    /// Indicates events that have been processed more than once.
+    pub cursors_outdated: u64,
+    /// Commits with less than `commit_policy::NEAR_MISS_SECS`
+    /// seconds left until `Nakadi` would have closed the stream
+    pub near_misses: u64,
+    /// The time since the last successful commit
+    pub last_commit_secs_ago: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct LoadInfo {
+    /// Batches dispatched to the workers but not yet processed
+    pub queued_batches: u64,
+    /// The average time from receiving a batch until it
+    /// has been processed
+    pub avg_batch_latency_ms: u64,
+    /// The bytes of all batches received but not yet processed
+    pub queued_bytes: u64,
+    /// The bytes of the batches received but not yet processed
+    /// by partition
+    pub queued_bytes_per_partition: BTreeMap<String, u64>,
+}
+
+/// Moving averages of the throughput over 1, 5 and 15 minutes
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ThroughputInfo {
+    /// Committed events per second
+    pub events_per_sec: ThroughputRates,
+    /// Processed bytes of batches per second
+    pub bytes_per_sec: ThroughputRates,
+}
+
+/// Weight of the latest value in the average batch latency
+const LATENCY_SMOOTHING: f64 = 0.1;
+
+/// The number of connection attempts kept
+const MAX_CONNECTION_ATTEMPTS: usize = 20;
+
+/// A raw batch kept for introspection
+#[derive(Debug, Clone, Serialize)]
+pub struct CapturedBatch {
+    pub stream_id: String,
+    pub received_at: DateTime<Utc>,
+    /// The line as received from `Nakadi`
+    pub line: String,
+}
+
+/// Collects the state of the components of a `Consumer`.
+///
+/// Components report to it while running and
+/// a snapshot can be taken anytime.
+#[derive(Clone)]
+pub struct IntrospectionState {
+    inner: Arc<Mutex<StateData>>,
+}
+
+struct StateData {
+    connection_state: ConnectionState,
+    stream_id: Option<StreamId>,
+    connected_since: Option<Instant>,
+    connections_established: u64,
+    time_to_first_batch: Option<Duration>,
+    connection_attempts: VecDeque<ConnectionAttempt>,
+    last_keep_alive_at: Option<Instant>,
+    retry_after: Option<Duration>,
+    last_stream_closed: Option<String>,
+    workers: HashMap<String, (Instant, ProgressReporter)>,
+    commits: CommitStats,
+    last_commit_at: Option<Instant>,
+    queued_batches: u64,
+    avg_batch_latency_ms: f64,
+    /// Of the current connection. 0 if nothing was committed yet.
+    avg_commit_latency_ms: f64,
+    queued_bytes: HashMap<Vec<u8>, usize>,
+    events_throughput: ThroughputMeter,
+    bytes_throughput: ThroughputMeter,
+    outcome: Option<ConsumerOutcome>,
+    /// How many batches to keep for each partition
+    capture_recent_batches: usize,
+    recent_batches: BTreeMap<String, VecDeque<CapturedBatch>>,
+}
+
+impl Default for IntrospectionState {
+    fn default() -> IntrospectionState {
+        IntrospectionState {
+            inner: Arc::new(Mutex::new(StateData {
+                connection_state: ConnectionState::Connecting,
+                stream_id: None,
+                connected_since: None,
+                connections_established: 0,
+                time_to_first_batch: None,
+                connection_attempts: VecDeque::with_capacity(MAX_CONNECTION_ATTEMPTS),
+                last_keep_alive_at: None,
+                retry_after: None,
+                last_stream_closed: None,
+                workers: HashMap::new(),
+                commits: Default::default(),
+                last_commit_at: None,
+                queued_batches: 0,
+                avg_batch_latency_ms: 0.0,
+                avg_commit_latency_ms: 0.0,
+                queued_bytes: HashMap::new(),
+                events_throughput: ThroughputMeter::new(),
+                bytes_throughput: ThroughputMeter::new(),
+                outcome: None,
+                capture_recent_batches: 0,
+                recent_batches: BTreeMap::new(),
+            })),
+        }
+    }
+}
+
+impl IntrospectionState {
+    pub fn connecting(&self) {
+        self.update(|data| {
+            data.connection_state = ConnectionState::Connecting;
+            data.stream_id = None;
+            data.connected_since = None;
+            data.time_to_first_batch = None;
+            data.last_keep_alive_at = None;
+        })
+    }
+
+    /// A keep alive line was received which shows
+    /// that the connection is still alive.
+    pub fn keep_alive_received(&self) {
+        self.update(|data| data.last_keep_alive_at = Some(Instant::now()))
+    }
+
+    /// Keeps the attempt and forgets the oldest one
+    /// if there are too many.
+    pub fn connect_attempted(&self, attempt: ConnectionAttempt) {
+        self.update(|data| {
+            if data.connection_attempts.len() >= MAX_CONNECTION_ATTEMPTS {
+                data.connection_attempts.pop_back();
+            }
+            data.connection_attempts.push_front(attempt);
+        })
+    }
+
+    /// `Nakadi` responded with 503 when connecting.
+    pub fn overloaded(&self, retry_after: Option<Duration>) {
+        self.update(|data| {
+            data.connection_state = ConnectionState::Overloaded;
+            data.retry_after = retry_after;
+        })
+    }
+
+    /// `Nakadi` could not be reached when connecting.
+    pub fn unreachable(&self) {
+        self.update(|data| {
+            data.connection_state = ConnectionState::Unreachable;
+            data.retry_after = None;
+        })
+    }
+
+    pub fn connection_state(&self) -> ConnectionState {
+        let mut state = ConnectionState::Connecting;
+        self.update(|data| state = data.connection_state);
+        state
+    }
+
+    /// The first batch of the current connection was received.
+    pub fn first_batch_received(&self) {
+        self.update(|data| {
+            data.time_to_first_batch = data.connected_since.map(|at| at.elapsed());
+        })
+    }
+
+    pub fn connected(&self, stream_id: &StreamId) {
+        self.update(|data| {
+            data.connection_state = ConnectionState::Connected;
+            data.retry_after = None;
+            data.stream_id = Some(stream_id.clone());
+            data.connected_since = Some(Instant::now());
+            data.connections_established += 1;
+            data.avg_commit_latency_ms = 0.0;
+        })
+    }
+
+    pub fn stream_closed(&self, closed: &StreamClosed) {
+        self.update(|data| data.last_stream_closed = Some(closed.to_string()))
+    }
+
+    pub fn stopped(&self, outcome: ConsumerOutcome) {
+        self.update(|data| {
+            data.connection_state = ConnectionState::Stopped;
+            data.stream_id = None;
+            data.connected_since = None;
+            data.outcome = Some(outcome);
+        })
+    }
+
+    pub fn outcome(&self) -> Option<ConsumerOutcome> {
+        let mut outcome = None;
+        self.update(|data| outcome = data.outcome.clone());
+        outcome
+    }
+
+    pub fn worker_used(&self, partition: &PartitionId, progress: &ProgressReporter) {
+        self.update(|data| {
+            data.workers
+                .insert(partition.0.clone(), (Instant::now(), progress.clone()));
+        })
+    }
+
+    pub fn worker_stopped(&self, partition: &PartitionId) {
+        self.update(|data| {
+            data.workers.remove(&partition.0);
+        })
+    }
+
+    pub fn all_workers_stopped(&self) {
+        self.update(|data| {
+            data.workers.clear();
+            data.queued_batches = 0;
+            data.queued_bytes.clear();
+        })
+    }
+
+    /// The consumer received a batch which is held in memory
+    /// until it has been processed.
+    /// Keep the last `n` batches of each partition.
+    pub fn capture_recent_batches(&self, n: usize) {
+        self.update(|data| data.capture_recent_batches = n)
+    }
+
+    pub fn batch_received(&self, batch: &Batch) {
+        let bytes = batch.batch_line.bytes().len();
+        self.update(|data| {
+            *data.queued_bytes
+                .entry(batch.batch_line.partition().to_vec())
+                .or_insert(0) += bytes;
+            if data.capture_recent_batches > 0 {
+                let max = data.capture_recent_batches;
+                let recent = data.recent_batches
+                    .entry(String::from_utf8_lossy(batch.batch_line.partition()).into_owned())
+                    .or_insert_with(VecDeque::new);
+                if recent.len() >= max {
+                    recent.pop_front();
+                }
+                recent.push_back(CapturedBatch {
+                    stream_id: batch.stream_id.0.clone(),
+                    received_at: Utc::now(),
+                    line: String::from_utf8_lossy(batch.batch_line.bytes()).into_owned(),
+                });
+            }
+        })
+    }
+
+    pub fn batch_dispatched(&self) {
+        self.update(|data| data.queued_batches += 1)
+    }
+
+    /// A batch has been processed and its memory is released.
+    pub fn batch_processed(&self, batch: &Batch) {
+        let latency = batch.received_at.elapsed();
+        let latency_ms = millis(latency) as f64;
+        let bytes = batch.batch_line.bytes().len();
+        self.update(|data| {
+            data.queued_batches = data.queued_batches.saturating_sub(1);
+            data.bytes_throughput.mark(bytes as u64);
+            let now_empty = match data.queued_bytes.get_mut(batch.batch_line.partition()) {
+                Some(queued) => {
+                    *queued = queued.saturating_sub(bytes);
+                    *queued == 0
+                }
+                None => false,
+            };
+            if now_empty {
+                data.queued_bytes.remove(batch.batch_line.partition());
+            }
+            data.avg_batch_latency_ms = if data.avg_batch_latency_ms == 0.0 {
+                latency_ms
+            } else {
+                LATENCY_SMOOTHING * latency_ms
+                    + (1.0 - LATENCY_SMOOTHING) * data.avg_batch_latency_ms
+            };
+        })
+    }
+
+    /// The bytes of all batches received but not yet processed
+    pub fn queued_bytes(&self) -> usize {
+        let mut queued_bytes = 0;
+        self.update(|data| queued_bytes = data.queued_bytes.values().sum());
+        queued_bytes
+    }
+
+    /// The current load of the consumer
+    pub fn load(&self) -> (u64, Duration) {
+        let mut load = (0, Duration::from_millis(0));
+        self.update(|data| {
+            load = (
+                data.queued_batches,
+                Duration::from_millis(data.avg_batch_latency_ms as u64),
+            )
+        });
+        load
+    }
+
+    /// The number of events committed since start
+    pub fn events_committed(&self) -> u64 {
+        let mut events_committed = 0;
+        self.update(|data| events_committed = data.commits.events_committed);
+        events_committed
+    }
+
+    pub fn committed(&self, num_batches: usize, num_events: usize) {
+        self.update(|data| {
+            data.commits.commits += 1;
+            data.commits.batches_committed += num_batches as u64;
+            data.commits.events_committed += num_events as u64;
+            data.events_throughput.mark(num_events as u64);
+            data.last_commit_at = Some(Instant::now());
+        })
+    }
+
+    /// A commit of the current connection took `latency`.
+    pub fn commit_took(&self, latency: Duration) {
+        let latency_ms = millis(latency) as f64;
+        self.update(|data| {
+            data.avg_commit_latency_ms = if data.avg_commit_latency_ms == 0.0 {
+                latency_ms
+            } else {
+                LATENCY_SMOOTHING * latency_ms
+                    + (1.0 - LATENCY_SMOOTHING) * data.avg_commit_latency_ms
+            }
+        })
+    }
+
+    /// The average time commits of the current connection took.
+    /// `None` if nothing was committed yet.
+    pub fn avg_commit_latency(&self) -> Option<Duration> {
+        let mut latency_ms = 0.0;
+        self.update(|data| latency_ms = data.avg_commit_latency_ms);
+        if latency_ms > 0.0 {
+            Some(Duration::from_millis(latency_ms as u64))
+        } else {
+            None
+        }
+    }
+
+    pub fn outdated(&self, num_cursors: usize) {
+        self.update(|data| data.commits.cursors_outdated += num_cursors as u64)
+    }
+
+    /// A cursor was committed shortly before `Nakadi`
+    /// would have closed the stream.
+    pub fn near_miss(&self) {
+        self.update(|data| data.commits.near_misses += 1)
+    }
+
+    pub fn commit_failed(&self) {
+        self.update(|data| data.commits.commits_failed += 1)
+    }
+
+    /// Take a snapshot of the current state.
+    pub fn snapshot(
+        &self,
+        subscription_id: &SubscriptionId,
+        config: Option<ConfigSummary>,
+    ) -> Introspection {
+        let mut data = match self.inner.lock() {
+            Ok(data) => data,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+
+        let mut workers: Vec<_> = data.workers
+            .iter()
+            .map(|(partition, &(last_used, ref progress))| {
+                WorkerInfo {
+                    partition: partition.clone(),
+                    idle_for_ms: millis(last_used.elapsed()),
+                    busy_for_ms: progress.busy_for().map(millis),
+                    stalled_for_ms: progress.stalled_for().map(millis),
+                }
+            })
+            .collect();
+        workers.sort_by(|a, b| a.partition.cmp(&b.partition));
+
+        let throughput = ThroughputInfo {
+            events_per_sec: data.events_throughput.rates(),
+            bytes_per_sec: data.bytes_throughput.rates(),
+        };
+
+        let mut commits = data.commits.clone();
+        commits.last_commit_secs_ago = data.last_commit_at.map(|at| at.elapsed().as_secs());
+
+        Introspection {
+            version: env!("CARGO_PKG_VERSION"),
+            subscription_id: subscription_id.0.clone(),
+            config,
+            connection: ConnectionInfo {
+                state: data.connection_state,
+                stream_id: data.stream_id.as_ref().map(|id| id.0.clone()),
+                connected_for_secs: data.connected_since.map(|at| at.elapsed().as_secs()),
+                connections_established: data.connections_established,
+                time_to_first_batch_ms: data.time_to_first_batch.map(millis),
+                last_attempts: data.connection_attempts.iter().cloned().collect(),
+                last_keep_alive_secs_ago: data.last_keep_alive_at.map(|at| at.elapsed().as_secs()),
+                retry_after_secs: data.retry_after.map(|d| d.as_secs()),
+                last_stream_closed: data.last_stream_closed.clone(),
+                avg_commit_latency_ms: if data.avg_commit_latency_ms > 0.0 {
+                    Some(data.avg_commit_latency_ms as u64)
+                } else {
+                    None
+                },
+            },
+            workers,
+            commits,
+            load: LoadInfo {
+                queued_batches: data.queued_batches,
+                avg_batch_latency_ms: data.avg_batch_latency_ms as u64,
+                queued_bytes: data.queued_bytes.values().map(|b| *b as u64).sum(),
+                queued_bytes_per_partition: data.queued_bytes
+                    .iter()
+                    .map(|(partition, bytes)| {
+                        (String::from_utf8_lossy(partition).into_owned(), *bytes as u64)
+                    })
+                    .collect(),
+            },
+            throughput,
+            outcome: data.outcome.clone(),
+            recent_batches: data.recent_batches
+                .iter()
+                .map(|(partition, batches)| (partition.clone(), batches.iter().cloned().collect()))
+                .collect(),
+        }
+    }
+
+    fn update<F: FnOnce(&mut StateData)>(&self, f: F) {
+        match self.inner.lock() {
+            Ok(mut data) => f(&mut data),
+            Err(poisoned) => f(&mut poisoned.into_inner()),
+        }
+    }
+}
+
+#[test]
+fn only_the_latest_connection_attempts_are_kept() {
+    let state = IntrospectionState::default();
+    let stream_id = StreamId::new("stream");
+
+    for _ in 0..MAX_CONNECTION_ATTEMPTS {
+        state.connect_attempted(ConnectionAttempt::finished(
+            Utc::now(),
+            Err("boom".to_string()),
+        ));
+    }
+    state.connect_attempted(ConnectionAttempt::finished(Utc::now(), Ok(&stream_id)));
+
+    let snapshot = state.snapshot(&SubscriptionId("subscription".to_string()), None);
+    let attempts = snapshot.connection.last_attempts;
+    assert_eq!(attempts.len(), MAX_CONNECTION_ATTEMPTS);
+    assert_eq!(attempts[0].stream_id, Some("stream".to_string()));
+    assert_eq!(attempts[0].error, None);
+    assert_eq!(attempts[1].error, Some("boom".to_string()));
+}
+
+#[test]
+fn overloaded_is_told_apart_from_unreachable() {
+    let state = IntrospectionState::default();
+
+    state.overloaded(Some(Duration::from_secs(30)));
+    let snapshot = state.snapshot(&SubscriptionId("subscription".to_string()), None);
+    assert_eq!(snapshot.connection.state, ConnectionState::Overloaded);
+    assert_eq!(snapshot.connection.retry_after_secs, Some(30));
+
+    state.unreachable();
+    assert_eq!(state.connection_state(), ConnectionState::Unreachable);
+
+    state.connected(&StreamId::new("stream"));
+    let snapshot = state.snapshot(&SubscriptionId("subscription".to_string()), None);
+    assert_eq!(snapshot.connection.state, ConnectionState::Connected);
+    assert_eq!(snapshot.connection.retry_after_secs, None);
+}
+
+#[test]
+fn workers_show_whether_their_handler_is_busy() {
+    use nakadi::handler::BatchInfo;
+    use nakadi::model::RawCursor;
+
+    let state = IntrospectionState::default();
+    let partition = PartitionId("0".to_string());
+    let progress = ProgressReporter::default();
+    state.worker_used(&partition, &progress);
+
+    let subscription_id = SubscriptionId("s".to_string());
+    let idle = state.snapshot(&subscription_id, None).workers;
+    assert_eq!(idle[0].busy_for_ms, None);
+    assert_eq!(idle[0].stalled_for_ms, None);
+
+    let received_at = Instant::now();
+    progress.batch_started(BatchInfo {
+        partition: partition.clone(),
+        cursor: RawCursor(br#"{"partition":"0","offset":"1"}"#.to_vec()),
+        received_at,
+        deadline: received_at + Duration::from_secs(55),
+    });
+    let busy = state.snapshot(&subscription_id, None).workers;
+    assert!(busy[0].busy_for_ms.is_some());
+    assert!(busy[0].stalled_for_ms.is_some());
+}
+
+#[test]
+fn only_the_latest_batches_of_a_partition_are_captured() {
+    use nakadi::batch::BatchLine;
+
+    let state = IntrospectionState::default();
+    state.capture_recent_batches(2);
+
+    for offset in 1..4 {
+        let line = format!(
+            r#"{{"cursor":{{"partition":"0","offset":"{}","event_type":"et"}},"events":[]}}"#,
+            offset
+        );
+        state.batch_received(&Batch {
+            batch_line: BatchLine::from_slice(line.as_bytes()).unwrap(),
+            received_at: Instant::now(),
+            stream_id: StreamId::new("stream"),
+            timings: Default::default(),
+        });
+    }
+
+    let snapshot = state.snapshot(&SubscriptionId("subscription".to_string()), None);
+    let captured = &snapshot.recent_batches["0"];
+    assert_eq!(captured.len(), 2);
+    assert!(captured[0].line.contains(r#""offset":"2""#));
+    assert!(captured[1].line.contains(r#""offset":"3""#));
+}
@@ -0,0 +1,296 @@
+//! Making sure only one instance consumes with a given identity
+//!
+//! Some consumers must run as singletons, e.g. because they keep state
+//! in memory. During a deployment the old and the new instance would
+//! otherwise consume at the same time for a while.
+//!
+//! A `Lease` is held by one holder per identity for a time to live and
+//! is renewed while the consumer is running. Where the lease is stored
+//! is up to a `LeaseBackend`. `FileLeaseBackend` stores leases as files
+//! which works for instances sharing a file system. Other backends, e.g.
+//! for a database, implement `LeaseBackend`.
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use nakadi::millis;
+use nakadi::consumer::Consumer;
+
+#[derive(Debug, Fail)]
+#[fail(display = "{}", message)]
+pub struct LeaseError {
+    pub message: String,
+}
+
+/// Stores leases.
+pub trait LeaseBackend {
+    /// Acquire the lease of `identity` for `holder` or renew it if
+    /// `holder` already has it. The lease expires after `ttl`.
+    ///
+    /// Returns false if another holder has a lease that did not expire.
+    fn try_acquire(&self, identity: &str, holder: &str, ttl: Duration) -> Result<bool, LeaseError>;
+
+    /// Give up the lease if `holder` has it.
+    fn release(&self, identity: &str, holder: &str) -> Result<(), LeaseError>;
+}
+
+/// Settings for a `Lease`
+#[derive(Debug, Clone)]
+pub struct LeaseConfig {
+    /// The identity of the consumer. Only one holder consumes with it.
+    pub identity: String,
+    /// Identifies this instance
+    pub holder: String,
+    /// How long a lease lasts without being renewed. It is
+    /// renewed after a third of this time.
+    pub ttl: Duration,
+    /// How long to wait before trying again to acquire a lease
+    /// held by another instance
+    pub retry_interval: Duration,
+}
+
+impl LeaseConfig {
+    /// A lease for `identity` held by this process with a time to live
+    /// of 30 seconds.
+    ///
+    /// The holder is made of the `HOSTNAME` and the id of the process.
+    pub fn new<T: Into<String>>(identity: T) -> LeaseConfig {
+        let host = ::std::env::var("HOSTNAME").unwrap_or_else(|_| "localhost".to_string());
+        LeaseConfig {
+            identity: identity.into(),
+            holder: format!("{}-{}", host, ::std::process::id()),
+            ttl: Duration::from_secs(30),
+            retry_interval: Duration::from_secs(5),
+        }
+    }
+}
+
+/// The lease of a consumer identity
+#[derive(Clone)]
+pub struct Lease {
+    backend: Arc<LeaseBackend + Send + Sync>,
+    config: LeaseConfig,
+}
+
+impl Lease {
+    pub fn new<B>(backend: B, config: LeaseConfig) -> Lease
+    where
+        B: LeaseBackend + Send + Sync + 'static,
+    {
+        Lease {
+            backend: Arc::new(backend),
+            config,
+        }
+    }
+
+    pub fn config(&self) -> &LeaseConfig {
+        &self.config
+    }
+
+    /// Acquire or renew the lease without waiting.
+    pub fn try_acquire(&self) -> Result<bool, LeaseError> {
+        self.backend
+            .try_acquire(&self.config.identity, &self.config.holder, self.config.ttl)
+    }
+
+    /// Wait until the lease is acquired or `timeout` elapsed.
+    ///
+    /// Errors of the backend are logged and retried. Returns
+    /// false if the lease could not be acquired in time.
+    pub fn acquire(&self, timeout: Option<Duration>) -> bool {
+        let started = Instant::now();
+        loop {
+            match self.try_acquire() {
+                Ok(true) => {
+                    info!(
+                        "[Lease, identity={}] Acquired by {}",
+                        self.config.identity, self.config.holder
+                    );
+                    return true;
+                }
+                Ok(false) => debug!(
+                    "[Lease, identity={}] Held by another instance",
+                    self.config.identity
+                ),
+                Err(err) => warn!(
+                    "[Lease, identity={}] Could not acquire: {}",
+                    self.config.identity, err
+                ),
+            }
+            if let Some(timeout) = timeout {
+                if started.elapsed() + self.config.retry_interval > timeout {
+                    return false;
+                }
+            }
+            thread::sleep(self.config.retry_interval);
+        }
+    }
+
+    pub fn release(&self) -> Result<(), LeaseError> {
+        self.backend
+            .release(&self.config.identity, &self.config.holder)
+    }
+
+    /// Renew the lease while the consumer is running and release
+    /// it once the consumer stopped.
+    ///
+    /// The consumer is stopped if the lease was lost, i.e. another
+    /// instance acquired it or it could not be renewed in time.
+    pub fn hold_while_running(self, consumer: &Consumer) {
+        let consumer = consumer.clone();
+        let lifecycle = consumer.lifecycle().clone();
+        let renew_interval = self.config.ttl / 3;
+        lifecycle.spawn_named("nakadion-lease", move |lifecycle| {
+            let mut renewed_at = Instant::now();
+            while !lifecycle.wait_for_abort(renew_interval) {
+                match self.try_acquire() {
+                    Ok(true) => {
+                        renewed_at = Instant::now();
+                        continue;
+                    }
+                    Ok(false) => {
+                        error!(
+                            "[Lease, identity={}] Another instance took the lease. Stopping.",
+                            self.config.identity
+                        );
+                        consumer.request_stop();
+                        break;
+                    }
+                    Err(err) => warn!(
+                        "[Lease, identity={}] Could not renew: {}",
+                        self.config.identity, err
+                    ),
+                }
+                if renewed_at.elapsed() >= self.config.ttl {
+                    error!(
+                        "[Lease, identity={}] The lease expired. Stopping.",
+                        self.config.identity
+                    );
+                    consumer.request_stop();
+                    break;
+                }
+            }
+
+            if let Err(err) = self.release() {
+                warn!(
+                    "[Lease, identity={}] Could not release: {}",
+                    self.config.identity, err
+                );
+            }
+        });
+    }
+}
+
+/// Stores each lease in a file named after its identity.
+///
+/// The file contains the holder and when the lease expires in
+/// milliseconds since the epoch. The identity must be usable
+/// as a file name.
+#[derive(Debug, Clone)]
+pub struct FileLeaseBackend {
+    dir: PathBuf,
+}
+
+impl FileLeaseBackend {
+    pub fn new<P: Into<PathBuf>>(dir: P) -> FileLeaseBackend {
+        FileLeaseBackend { dir: dir.into() }
+    }
+
+    fn path(&self, identity: &str) -> PathBuf {
+        self.dir.join(format!("{}.lease", identity))
+    }
+
+    /// The holder and when the lease expires
+    fn read(&self, identity: &str) -> Result<Option<(String, u64)>, LeaseError> {
+        let mut contents = String::new();
+        match fs::File::open(self.path(identity)) {
+            Ok(mut file) => file.read_to_string(&mut contents)
+                .map_err(|err| io_error("read", identity, err))?,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(io_error("open", identity, err)),
+        };
+
+        let mut lines = contents.lines();
+        match (lines.next(), lines.next().and_then(|l| l.parse().ok())) {
+            (Some(holder), Some(expires_at)) => Ok(Some((holder.to_string(), expires_at))),
+            // Treat a file being written as expired
+            _ => Ok(None),
+        }
+    }
+}
+
+impl LeaseBackend for FileLeaseBackend {
+    fn try_acquire(&self, identity: &str, holder: &str, ttl: Duration) -> Result<bool, LeaseError> {
+        let now = millis_since_epoch(Duration::from_secs(0));
+        if let Some((current, expires_at)) = self.read(identity)? {
+            if current != holder && expires_at > now {
+                return Ok(false);
+            }
+        }
+
+        // Renaming replaces the lease atomically
+        let tmp_path = self.dir.join(format!("{}.lease.{}.tmp", identity, holder));
+        {
+            let mut file =
+                fs::File::create(&tmp_path).map_err(|err| io_error("create", identity, err))?;
+            write!(file, "{}\n{}\n", holder, millis_since_epoch(ttl))
+                .map_err(|err| io_error("write", identity, err))?;
+        }
+        fs::rename(&tmp_path, self.path(identity))
+            .map_err(|err| io_error("replace", identity, err))?;
+
+        // Another instance might have replaced it at the same time
+        Ok(self.read(identity)?
+            .map(|(current, _)| current == holder)
+            .unwrap_or(false))
+    }
+
+    fn release(&self, identity: &str, holder: &str) -> Result<(), LeaseError> {
+        match self.read(identity)? {
+            Some((ref current, _)) if current == holder => fs::remove_file(self.path(identity))
+                .map_err(|err| io_error("remove", identity, err)),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn millis_since_epoch(after: Duration) -> u64 {
+    let since_epoch = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_else(|_| Duration::from_secs(0)) + after;
+    millis(since_epoch)
+}
+
+fn io_error(action: &str, identity: &str, err: io::Error) -> LeaseError {
+    LeaseError {
+        message: format!("Could not {} the lease file of {}: {}", action, identity, err),
+    }
+}
+
+#[test]
+fn only_one_holder_has_a_file_lease() {
+    let dir = ::std::env::temp_dir().join(format!("nakadion-lease-{}", ::std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let backend = FileLeaseBackend::new(dir.clone());
+    let ttl = Duration::from_secs(60);
+
+    assert!(backend.try_acquire("consumer", "a", ttl).unwrap());
+    assert!(!backend.try_acquire("consumer", "b", ttl).unwrap());
+    assert!(backend.try_acquire("consumer", "a", ttl).unwrap());
+
+    backend.release("consumer", "b").unwrap();
+    assert!(!backend.try_acquire("consumer", "b", ttl).unwrap());
+    backend.release("consumer", "a").unwrap();
+    assert!(backend.try_acquire("consumer", "b", ttl).unwrap());
+
+    // An expired lease can be taken over
+    assert!(backend
+        .try_acquire("consumer", "b", Duration::from_secs(0))
+        .unwrap());
+    assert!(backend.try_acquire("consumer", "a", ttl).unwrap());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
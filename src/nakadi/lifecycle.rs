@@ -0,0 +1,342 @@
+//! Stopping the threads of the consumer as a tree
+//!
+//! Every thread of the consumer has a `Lifecycle` which is a child of the
+//! `Lifecycle` of the component that started it. The consumer loop is the
+//! root, its children are the committer, the dispatcher and the monitors
+//! and the workers are the children of the dispatcher.
+//!
+//! A component stops its children in the order it needs, e.g. the
+//! dispatcher drains its workers before the committer is stopped. Once
+//! its thread stopped all children still running are cancelled. A
+//! `Lifecycle` only counts as stopped once its own thread and all of its
+//! descendants have stopped so that waiting for the root waits for every
+//! thread of the consumer.
+//!
+//! The threads of the consumer are named after their component, e.g.
+//! `nakadion-committer` or `nakadion-worker-3` for the worker of partition
+//! 3, so that a stalled component can be found with a debugger, a profiler
+//! or `top -H`.
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Tracks whether a thread and its children are still running
+#[derive(Clone)]
+pub struct Lifecycle {
+    node: Arc<Node>,
+}
+
+struct Node {
+    state: Mutex<NodeState>,
+    changed: Condvar,
+    parent: Option<Arc<Node>>,
+}
+
+#[derive(Default)]
+struct NodeState {
+    abort_requested: bool,
+    /// The thread of this node stopped
+    stopped: bool,
+    /// The thread of this node and all of its children stopped
+    finished: bool,
+    /// The parent does not wait for this node anymore
+    detached: bool,
+    /// The children that did not finish yet
+    children: Vec<Arc<Node>>,
+}
+
+impl Lifecycle {
+    /// Create a `Lifecycle` that is cancelled when its parent stopped
+    /// and that the parent waits for.
+    pub fn child(&self) -> Lifecycle {
+        let mut state = self.node.lock();
+        let node = Arc::new(Node {
+            state: Mutex::new(NodeState {
+                abort_requested: state.abort_requested || state.stopped,
+                ..NodeState::default()
+            }),
+            changed: Condvar::new(),
+            parent: Some(self.node.clone()),
+        });
+        state.children.push(node.clone());
+        Lifecycle { node }
+    }
+
+    /// Run `f` on a new thread with a child of this `Lifecycle`.
+    ///
+    /// The child is stopped once `f` returned or panicked.
+    /// Returns the child.
+    pub fn spawn<F>(&self, f: F) -> Lifecycle
+    where
+        F: FnOnce(Lifecycle) + Send + 'static,
+    {
+        self.spawn_thread(thread::Builder::new(), f)
+    }
+
+    /// Like `spawn` but the thread is named `name`.
+    pub fn spawn_named<N, F>(&self, name: N, f: F) -> Lifecycle
+    where
+        N: Into<String>,
+        F: FnOnce(Lifecycle) + Send + 'static,
+    {
+        self.spawn_thread(thread::Builder::new().name(name.into()), f)
+    }
+
+    fn spawn_thread<F>(&self, builder: thread::Builder, f: F) -> Lifecycle
+    where
+        F: FnOnce(Lifecycle) + Send + 'static,
+    {
+        let child = self.child();
+        let guard = child.stop_guard();
+        builder
+            .spawn(move || {
+                let lifecycle = guard.0.clone();
+                f(lifecycle);
+                drop(guard);
+            })
+            .expect("failed to spawn thread");
+        child
+    }
+
+    /// Returns a guard that stops the `Lifecycle` when it is dropped
+    /// even if the thread holding it panicked.
+    pub fn stop_guard(&self) -> StopGuard {
+        StopGuard(self.clone())
+    }
+
+    pub fn abort_requested(&self) -> bool {
+        self.node.lock().abort_requested
+    }
+
+    /// Ask the thread to stop.
+    ///
+    /// The thread is responsible for stopping its children.
+    pub fn request_abort(&self) {
+        self.node.request_abort(false)
+    }
+
+    /// Called by the thread when it stopped.
+    ///
+    /// Descendants still running are asked to stop.
+    pub fn stopped(&self) {
+        let (children, finished) = {
+            let mut state = self.node.lock();
+            if state.stopped {
+                return;
+            }
+            state.stopped = true;
+            (state.children.clone(), self.node.finish_if_done(&mut state))
+        };
+        children.iter().for_each(|child| child.request_abort(true));
+        if finished {
+            report_finished(&self.node);
+        }
+    }
+
+    /// Returns true until the thread and all of its descendants stopped.
+    pub fn running(&self) -> bool {
+        !self.node.lock().finished
+    }
+
+    /// Wait until the thread and all of its descendants stopped.
+    pub fn wait(&self) {
+        let mut state = self.node.lock();
+        while !state.finished {
+            state = self.node.wait(state, None);
+        }
+    }
+
+    /// Wait at most `timeout` until the thread and all of
+    /// its descendants stopped.
+    ///
+    /// Returns true if they stopped.
+    pub fn wait_timeout(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.node.lock();
+        while !state.finished {
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            state = self.node.wait(state, Some(deadline - now));
+        }
+        true
+    }
+
+    /// Sleep for `timeout` unless an abort is requested in the meantime.
+    ///
+    /// Returns true if an abort was requested.
+    pub fn wait_for_abort(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.node.lock();
+        while !state.abort_requested {
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            state = self.node.wait(state, Some(deadline - now));
+        }
+        true
+    }
+
+    /// Stop waiting for a thread that did not stop in time.
+    ///
+    /// The parent will not wait for this `Lifecycle` anymore. Used
+    /// for threads blocked in user code which can not be stopped.
+    pub fn detach(&self) {
+        {
+            let mut state = self.node.lock();
+            if state.finished || state.detached {
+                return;
+            }
+            state.detached = true;
+        }
+        if let Some(ref parent) = self.node.parent {
+            child_finished(parent, &self.node);
+        }
+    }
+}
+
+impl Default for Lifecycle {
+    /// A root `Lifecycle` without a parent
+    fn default() -> Lifecycle {
+        Lifecycle {
+            node: Arc::new(Node {
+                state: Mutex::new(NodeState::default()),
+                changed: Condvar::new(),
+                parent: None,
+            }),
+        }
+    }
+}
+
+impl Node {
+    fn request_abort(&self, with_descendants: bool) {
+        let children = {
+            let mut state = self.lock();
+            state.abort_requested = true;
+            self.changed.notify_all();
+            if !with_descendants {
+                return;
+            }
+            state.children.clone()
+        };
+        children.iter().for_each(|child| child.request_abort(true));
+    }
+
+    /// Marks the node as finished if it and all of its children stopped.
+    ///
+    /// Returns true if the node just finished.
+    fn finish_if_done(&self, state: &mut NodeState) -> bool {
+        if state.finished || !state.stopped || !state.children.is_empty() {
+            return false;
+        }
+        state.finished = true;
+        self.changed.notify_all();
+        true
+    }
+
+    fn wait<'a>(
+        &self,
+        state: MutexGuard<'a, NodeState>,
+        timeout: Option<Duration>,
+    ) -> MutexGuard<'a, NodeState> {
+        match timeout {
+            Some(timeout) => match self.changed.wait_timeout(state, timeout) {
+                Ok((state, _)) => state,
+                Err(poisoned) => poisoned.into_inner().0,
+            },
+            None => match self.changed.wait(state) {
+                Ok(state) => state,
+                Err(poisoned) => poisoned.into_inner(),
+            },
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<NodeState> {
+        match self.state.lock() {
+            Ok(state) => state,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+}
+
+fn child_finished(parent: &Arc<Node>, child: &Arc<Node>) {
+    let finished = {
+        let mut state = parent.lock();
+        state.children.retain(|c| !Arc::ptr_eq(c, child));
+        parent.finish_if_done(&mut state)
+    };
+    if finished {
+        report_finished(parent);
+    }
+}
+
+/// Tell the parent that the node finished unless it was detached.
+fn report_finished(node: &Arc<Node>) {
+    if node.lock().detached {
+        return;
+    }
+    if let Some(ref parent) = node.parent {
+        child_finished(parent, node);
+    }
+}
+
+/// Stops a `Lifecycle` when dropped
+pub struct StopGuard(Lifecycle);
+
+impl Drop for StopGuard {
+    fn drop(&mut self) {
+        self.0.stopped()
+    }
+}
+
+#[test]
+fn a_stopped_parent_cancels_its_descendants() {
+    let root = Lifecycle::default();
+    let child = root.child();
+    let grand_child = child.child();
+
+    root.request_abort();
+    assert!(!child.abort_requested());
+
+    root.stopped();
+    assert!(child.abort_requested());
+    assert!(grand_child.abort_requested());
+    assert!(root.child().abort_requested());
+}
+
+#[test]
+fn the_parent_runs_until_all_children_stopped() {
+    let root = Lifecycle::default();
+    let child = root.child();
+    let grand_child = child.child();
+
+    root.stopped();
+    assert!(root.running());
+    assert!(grand_child.abort_requested());
+
+    child.stopped();
+    assert!(child.running());
+    grand_child.stopped();
+    assert!(!child.running());
+    assert!(root.wait_timeout(Duration::from_secs(1)));
+}
+
+#[test]
+fn detached_children_are_not_waited_for() {
+    let root = Lifecycle::default();
+    let child = root.spawn(|lifecycle| {
+        while !lifecycle.wait_for_abort(Duration::from_secs(10)) {}
+    });
+    let stuck = root.child();
+
+    root.request_abort();
+    root.stopped();
+    assert!(child.wait_timeout(Duration::from_secs(10)));
+    assert!(!root.wait_timeout(Duration::from_millis(10)));
+
+    stuck.detach();
+    assert!(!root.running());
+    assert!(stuck.running());
+}
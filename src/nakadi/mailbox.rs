@@ -0,0 +1,376 @@
+//! The queues of batches waiting for a worker
+//!
+//! Each worker has a mailbox the dispatcher delivers the batches of its
+//! partition to. A mailbox can be bounded so that a slow partition
+//! can not take up all the memory while the other partitions starve.
+use std::collections::VecDeque;
+use std::str::FromStr;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::Duration;
+
+use failure::Error;
+
+use nakadi::batch::Batch;
+
+/// What to do with a batch for a worker whose mailbox is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OverflowStrategy {
+    /// Block the dispatcher until the worker took a batch.
+    ///
+    /// All partitions wait for the slowest one.
+    Block,
+    /// Discard the oldest queued batch and all batches of the partition
+    /// that follow on the current stream.
+    ///
+    /// Committing a later batch would also commit the discarded events.
+    /// Therefore nothing is committed for the partition anymore so that
+    /// `Nakadi` closes the stream once the commit timeout has elapsed
+    /// and sends the discarded events again on the next stream.
+    DropOldest,
+    /// Abort the stream and reconnect.
+    Abort,
+}
+
+impl FromStr for OverflowStrategy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_ref() {
+            "block" => Ok(OverflowStrategy::Block),
+            "drop_oldest" => Ok(OverflowStrategy::DropOldest),
+            "abort" => Ok(OverflowStrategy::Abort),
+            _ => Err(format_err!("'{}' is not an overflow strategy", s)),
+        }
+    }
+}
+
+/// The order in which the dispatcher delivers batches to the mailboxes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DispatchOrder {
+    /// Deliver the batches in the order they were received.
+    ///
+    /// A partition whose mailbox is full delays the batches
+    /// of all partitions received after it.
+    Arrival,
+    /// Deliver the batches of the partitions in turns.
+    ///
+    /// Batches of a partition whose mailbox is full wait in the
+    /// dispatcher while the other partitions get their batches.
+    /// A hot partition can not starve the others. Mailboxes never
+    /// overflow since batches are only delivered to mailboxes with room.
+    RoundRobin,
+}
+
+impl FromStr for DispatchOrder {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_ref() {
+            "arrival" => Ok(DispatchOrder::Arrival),
+            "round_robin" => Ok(DispatchOrder::RoundRobin),
+            _ => Err(format_err!("'{}' is not a dispatch order", s)),
+        }
+    }
+}
+
+/// Configures the mailboxes of the workers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MailboxConfig {
+    /// The maximum number of batches queued for a worker.
+    /// Unbounded if `None`.
+    pub capacity: Option<usize>,
+    /// What to do once the capacity is reached
+    pub overflow: OverflowStrategy,
+    /// How batches of different partitions are delivered
+    pub dispatch: DispatchOrder,
+}
+
+impl Default for MailboxConfig {
+    fn default() -> MailboxConfig {
+        MailboxConfig {
+            capacity: None,
+            overflow: OverflowStrategy::Block,
+            dispatch: DispatchOrder::Arrival,
+        }
+    }
+}
+
+/// What happened to a delivered batch
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Delivery {
+    Queued,
+    /// The given number of batches were discarded
+    /// including the delivered one if it was not queued.
+    Discarded(usize),
+}
+
+/// Why no batch was received
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReceiveError {
+    Timeout,
+    /// The mailbox was closed and is empty
+    Closed,
+}
+
+/// Wakes up a thread waiting for any of many mailboxes
+///
+/// A mailbox created with `Mailbox::with_readiness` notifies its
+/// `Readiness` whenever a batch was queued or the mailbox was closed.
+#[derive(Clone, Default)]
+pub struct Readiness {
+    inner: Arc<(Mutex<bool>, Condvar)>,
+}
+
+impl Readiness {
+    pub fn notify(&self) {
+        let &(ref ready, ref condvar) = &*self.inner;
+        *lock(ready) = true;
+        condvar.notify_one();
+    }
+
+    /// Wait at most `timeout` for a notification.
+    ///
+    /// Returns immediately if there was a notification since the last call.
+    pub fn wait_timeout(&self, timeout: Duration) {
+        let &(ref ready, ref condvar) = &*self.inner;
+        let mut ready = lock(ready);
+        if !*ready {
+            ready = match condvar.wait_timeout(ready, timeout) {
+                Ok((ready, _)) => ready,
+                Err(poisoned) => poisoned.into_inner().0,
+            };
+        }
+        *ready = false;
+    }
+}
+
+/// A queue of batches shared by the dispatcher and a worker
+#[derive(Clone)]
+pub struct Mailbox {
+    inner: Arc<MailboxInner>,
+    config: MailboxConfig,
+    readiness: Option<Readiness>,
+}
+
+struct MailboxInner {
+    state: Mutex<MailboxState>,
+    not_empty: Condvar,
+    not_full: Condvar,
+}
+
+struct MailboxState {
+    batches: VecDeque<Batch>,
+    closed: bool,
+    /// Set once a batch was dropped
+    discarding: bool,
+}
+
+impl Mailbox {
+    pub fn new(config: MailboxConfig) -> Mailbox {
+        Mailbox {
+            inner: Arc::new(MailboxInner {
+                state: Mutex::new(MailboxState {
+                    batches: VecDeque::new(),
+                    closed: false,
+                    discarding: false,
+                }),
+                not_empty: Condvar::new(),
+                not_full: Condvar::new(),
+            }),
+            config,
+            readiness: None,
+        }
+    }
+
+    /// Create a mailbox that notifies `readiness` about new batches.
+    pub fn with_readiness(config: MailboxConfig, readiness: Readiness) -> Mailbox {
+        Mailbox {
+            readiness: Some(readiness),
+            ..Mailbox::new(config)
+        }
+    }
+
+    /// Deliver a batch according to the `OverflowStrategy`.
+    ///
+    /// Fails if the mailbox is closed or if it is full and
+    /// the stream should be aborted.
+    pub fn deliver(&self, batch: Batch) -> Result<Delivery, String> {
+        let mut state = self.lock();
+
+        if state.closed {
+            return Err("The mailbox is closed".to_string());
+        }
+
+        if state.discarding {
+            return Ok(Delivery::Discarded(1));
+        }
+
+        let capacity = match self.config.capacity {
+            Some(capacity) => capacity,
+            None => {
+                self.push(&mut state, batch);
+                return Ok(Delivery::Queued);
+            }
+        };
+
+        if state.batches.len() < capacity {
+            self.push(&mut state, batch);
+            return Ok(Delivery::Queued);
+        }
+
+        match self.config.overflow {
+            OverflowStrategy::Block => {
+                while state.batches.len() >= capacity && !state.closed {
+                    state = match self.inner.not_full.wait(state) {
+                        Ok(state) => state,
+                        Err(poisoned) => poisoned.into_inner(),
+                    };
+                }
+                if state.closed {
+                    return Err("The mailbox was closed while waiting".to_string());
+                }
+                self.push(&mut state, batch);
+                Ok(Delivery::Queued)
+            }
+            OverflowStrategy::DropOldest => {
+                state.discarding = true;
+                let discarded = state.batches.len() + 1;
+                state.batches.clear();
+                Ok(Delivery::Discarded(discarded))
+            }
+            OverflowStrategy::Abort => Err(format!(
+                "The mailbox is full with {} batches",
+                state.batches.len()
+            )),
+        }
+    }
+
+    /// Wait at most `timeout` for a batch.
+    pub fn receive_timeout(&self, timeout: Duration) -> Result<Batch, ReceiveError> {
+        let mut state = self.lock();
+        if state.batches.is_empty() && !state.closed {
+            state = match self.inner.not_empty.wait_timeout(state, timeout) {
+                Ok((state, _)) => state,
+                Err(poisoned) => poisoned.into_inner().0,
+            };
+        }
+        self.pop(&mut state)
+    }
+
+    /// Take a batch if there is one.
+    pub fn try_receive(&self) -> Option<Batch> {
+        self.pop(&mut self.lock()).ok()
+    }
+
+    /// Stop accepting batches and remove all queued batches.
+    pub fn close(&self) -> Vec<Batch> {
+        let mut state = self.lock();
+        state.closed = true;
+        self.inner.not_full.notify_all();
+        self.inner.not_empty.notify_all();
+        if let Some(ref readiness) = self.readiness {
+            readiness.notify();
+        }
+        state.batches.drain(..).collect()
+    }
+
+    /// The number of batches queued
+    pub fn len(&self) -> usize {
+        self.lock().batches.len()
+    }
+
+    fn push(&self, state: &mut MailboxState, batch: Batch) {
+        state.batches.push_back(batch);
+        self.inner.not_empty.notify_one();
+        if let Some(ref readiness) = self.readiness {
+            readiness.notify();
+        }
+    }
+
+    fn pop(&self, state: &mut MailboxState) -> Result<Batch, ReceiveError> {
+        match state.batches.pop_front() {
+            Some(batch) => {
+                self.inner.not_full.notify_one();
+                Ok(batch)
+            }
+            None if state.closed => Err(ReceiveError::Closed),
+            None => Err(ReceiveError::Timeout),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<MailboxState> {
+        lock(&self.inner.state)
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<T> {
+    match mutex.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+#[cfg(test)]
+fn test_batch() -> Batch {
+    use std::time::Instant;
+    use nakadi::batch::BatchLine;
+    use nakadi::model::StreamId;
+
+    let line = r#"{"cursor":{"partition":"0","offset":"1","event_type":"et","#.to_owned()
+        + r#""cursor_token":"t"},"events":[]}"#;
+
+    Batch {
+        batch_line: BatchLine::from_slice(line.as_bytes()).unwrap(),
+        received_at: Instant::now(),
+        stream_id: StreamId::new("stream"),
+        timings: Default::default(),
+    }
+}
+
+#[test]
+fn drop_oldest_discards_the_partition_for_the_stream() {
+    let mailbox = Mailbox::new(MailboxConfig {
+        capacity: Some(2),
+        overflow: OverflowStrategy::DropOldest,
+        dispatch: DispatchOrder::Arrival,
+    });
+
+    assert_eq!(mailbox.deliver(test_batch()), Ok(Delivery::Queued));
+    assert_eq!(mailbox.deliver(test_batch()), Ok(Delivery::Queued));
+    assert_eq!(mailbox.deliver(test_batch()), Ok(Delivery::Discarded(3)));
+    assert_eq!(mailbox.len(), 0);
+
+    assert!(mailbox.try_receive().is_none());
+    assert_eq!(mailbox.deliver(test_batch()), Ok(Delivery::Discarded(1)));
+}
+
+#[test]
+fn abort_fails_when_full_and_closed_mailboxes_reject() {
+    let mailbox = Mailbox::new(MailboxConfig {
+        capacity: Some(1),
+        overflow: OverflowStrategy::Abort,
+        dispatch: DispatchOrder::Arrival,
+    });
+
+    assert_eq!(mailbox.deliver(test_batch()), Ok(Delivery::Queued));
+    assert!(mailbox.deliver(test_batch()).is_err());
+
+    assert_eq!(mailbox.close().len(), 1);
+    assert!(mailbox.deliver(test_batch()).is_err());
+    assert_eq!(
+        mailbox.receive_timeout(Duration::from_millis(1)).err(),
+        Some(ReceiveError::Closed)
+    );
+}
+
+#[test]
+fn readiness_is_notified_on_delivery() {
+    let readiness = Readiness::default();
+    let mailbox = Mailbox::with_readiness(MailboxConfig::default(), readiness.clone());
+
+    assert_eq!(mailbox.deliver(test_batch()), Ok(Delivery::Queued));
+    let start = ::std::time::Instant::now();
+    readiness.wait_timeout(Duration::from_secs(10));
+    assert!(start.elapsed() < Duration::from_secs(10));
+    assert!(mailbox.try_receive().is_some());
+}
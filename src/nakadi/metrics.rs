@@ -1,5 +1,10 @@
 //! Metrics collected by `Nakadion`
-use std::time::Instant;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use nakadi::millis;
+use nakadi::stage_timing::Stage;
 
 #[cfg(feature = "metrix")]
 pub use self::metrix::MetrixCollector;
@@ -12,6 +17,9 @@ pub trait MetricsCollector {
 
     /// A connect attempt for streaming failed.
     fn streaming_connect_attempt_failed(&self);
+    /// `Nakadi` sent the number of requests remaining
+    /// within the current rate limit window.
+    fn streaming_rate_limit_remaining(&self, remaining: u64);
 
     /// A connect attempt the consumer requested succeeded.
     ///
@@ -25,6 +33,9 @@ pub trait MetricsCollector {
     /// down was initiated. Used to determine for how long Nakadion
     /// was connected.
     fn consumer_connection_lifetime(&self, connected_since: Instant);
+    /// The first batch of a connection was received. `connected_since` is
+    /// when the connection was established.
+    fn consumer_first_batch_received(&self, connected_since: Instant);
     /// A line with the given number of bytes was reveived.
     fn consumer_line_received(&self, bytes: usize);
     /// A line with an info field was received. The info
@@ -34,9 +45,31 @@ pub trait MetricsCollector {
     fn consumer_keep_alive_line_received(&self, bytes: usize);
     /// A line of events with the given number of bytes was reveived.
     fn consumer_batch_line_received(&self, bytes: usize);
+    /// The scaling pressure has been computed.
+    fn consumer_scaling_pressure(&self, pressure: f64);
+    /// The offsets of a partition were not strictly increasing.
+    fn consumer_ordering_violation(&self);
+    /// `missing` events were skipped between two batches of a partition.
+    fn consumer_offset_gap(&self, missing: u64);
+    /// Invalid UTF-8 in a batch was replaced before handling it.
+    fn consumer_invalid_utf8_repaired(&self);
+    /// The bytes of all batches received but not yet processed.
+    fn consumer_queued_bytes(&self, bytes: usize);
+    /// The number of partitions whose oldest uncommitted event
+    /// will soon be deleted due to the retention time.
+    fn consumer_partitions_at_retention_risk(&self, n: usize);
 
     /// The number of workers currently processing partitions.
     fn dispatcher_current_workers(&self, num_workers: usize);
+    /// The number of batches queued for a worker right after
+    /// a batch was delivered to it.
+    fn dispatcher_worker_mailbox_size(&self, num_batches: usize);
+    /// `n` batches were discarded because the mailbox
+    /// of a worker was full.
+    fn dispatcher_batches_discarded(&self, n: usize);
+    /// The longest time a handler working on a batch has not shown
+    /// progress. Reported periodically and 0 if all handlers are idle.
+    fn dispatcher_handler_stalled_for(&self, stalled_for: Duration);
 
     /// Events with a comined legth of `bytes` bytes have been
     /// received.
@@ -45,6 +78,9 @@ pub trait MetricsCollector {
     fn worker_batch_processed(&self, started: Instant);
     /// The worker processed `n` events of the same batch.
     fn worker_events_in_same_batch_processed(&self, n: usize);
+    /// A handler annotated a batch it processed with a business
+    /// metric, e.g. `orders_created` with a value of 12.
+    fn handler_annotation(&self, key: &str, value: u64);
 
     /// Time elapsed from receiving the cursor from `Nakadi` until
     /// it was send for being committed. This is most probably right
@@ -71,6 +107,55 @@ pub trait MetricsCollector {
     /// The time left when committing the event until the stream would have become
     /// invalid.
     fn committer_time_left_on_commit(&self, committed_at: Instant, deadline: Instant);
+    /// `n` cursors have been discarded because they belong to a stream
+    /// that is no longer the one being committed to.
+    fn committer_stale_cursors_discarded(&self, n: usize);
+    /// `n` committed cursors were outdated because Nakadi already
+    /// had a newer cursor for their partitions.
+    fn committer_outdated_cursors(&self, n: usize);
+    /// Nakadi rejected a commit of `n` cursors because they
+    /// had already been committed.
+    fn committer_cursors_already_committed(&self, n: usize);
+    /// A cursor of `partition` was committed with `time_left` until
+    /// `Nakadi` would have closed the stream.
+    fn committer_partition_time_left_on_commit(&self, partition: &str, time_left: Duration);
+    /// A cursor of `partition` was committed with less than
+    /// `commit_policy::NEAR_MISS_SECS` seconds left.
+    fn committer_commit_near_miss(&self, partition: &str, time_left: Duration);
+
+    /// A batch spent `took` in `stage`.
+    ///
+    /// The commit stage is only reported for the last
+    /// batch of each partition of a commit.
+    fn batch_stage_took(&self, stage: Stage, took: Duration);
+}
+
+/// An interface for a `NakadiPublisher` to notify on published events.
+///
+/// Every value is reported for the event type it belongs to so that
+/// the health of each event type can be observed.
+pub trait PublisherMetricsCollector {
+    /// A request with `num_events` events with a combined length
+    /// of `bytes` bytes is about to be sent.
+    fn publisher_request_sent(&self, event_type: &str, bytes: usize, num_events: usize);
+    /// `Nakadi` accepted a request that was started at `request_started`.
+    ///
+    /// This includes requests where not all events were published.
+    fn publisher_published(&self, event_type: &str, request_started: Instant);
+    /// A request started at `request_started` failed with the
+    /// given status. The status is `None` if there was no response.
+    fn publisher_publish_failed(
+        &self,
+        event_type: &str,
+        status: Option<u16>,
+        request_started: Instant,
+    );
+    /// `Nakadi` reported `n` events of a request to have the given
+    /// `publishing_status` which is one of `submitted`, `failed`
+    /// or `aborted`.
+    ///
+    /// Only reported if not all events were published.
+    fn publisher_publishing_status(&self, event_type: &str, publishing_status: &str, n: usize);
 }
 
 /// Using this disables metrics collection.
@@ -80,19 +165,31 @@ pub struct DevNullMetricsCollector;
 impl MetricsCollector for DevNullMetricsCollector {
     fn streaming_connect_attempt(&self) {}
     fn streaming_connect_attempt_failed(&self) {}
+    fn streaming_rate_limit_remaining(&self, _remaining: u64) {}
 
     fn consumer_connected(&self, _attempt_started: Instant) {}
     fn consumer_connection_lifetime(&self, _connected_since: Instant) {}
+    fn consumer_first_batch_received(&self, _connected_since: Instant) {}
     fn consumer_line_received(&self, _bytes: usize) {}
     fn consumer_info_line_received(&self, _bytes: usize) {}
     fn consumer_keep_alive_line_received(&self, _bytes: usize) {}
     fn consumer_batch_line_received(&self, _bytes: usize) {}
+    fn consumer_scaling_pressure(&self, _pressure: f64) {}
+    fn consumer_queued_bytes(&self, _bytes: usize) {}
+    fn consumer_ordering_violation(&self) {}
+    fn consumer_offset_gap(&self, _missing: u64) {}
+    fn consumer_invalid_utf8_repaired(&self) {}
+    fn consumer_partitions_at_retention_risk(&self, _n: usize) {}
 
     fn dispatcher_current_workers(&self, _num_workers: usize) {}
+    fn dispatcher_worker_mailbox_size(&self, _num_batches: usize) {}
+    fn dispatcher_batches_discarded(&self, _n: usize) {}
+    fn dispatcher_handler_stalled_for(&self, _stalled_for: Duration) {}
 
     fn worker_batch_size_bytes(&self, _bytes: usize) {}
     fn worker_batch_processed(&self, _started: Instant) {}
     fn worker_events_in_same_batch_processed(&self, _n: usize) {}
+    fn handler_annotation(&self, _key: &str, _value: u64) {}
 
     fn committer_cursor_received(&self, _cursor_received_at_timestamp: Instant) {}
     fn committer_cursor_committed(&self, _commit_attempt_started: Instant) {}
@@ -103,10 +200,530 @@ impl MetricsCollector for DevNullMetricsCollector {
     fn committer_cursor_age_on_commit(&self, _received_at_timestamp: Instant) {}
     fn committer_time_elapsed_until_commit(&self, _first_cursor_age: Instant) {}
     fn committer_time_left_on_commit(&self, _committed_at: Instant, _deadline: Instant) {}
+    fn committer_stale_cursors_discarded(&self, _n: usize) {}
+    fn committer_outdated_cursors(&self, _n: usize) {}
+    fn committer_cursors_already_committed(&self, _n: usize) {}
+    fn committer_partition_time_left_on_commit(&self, _partition: &str, _time_left: Duration) {}
+    fn committer_commit_near_miss(&self, _partition: &str, _time_left: Duration) {}
+
+    fn batch_stage_took(&self, _stage: Stage, _took: Duration) {}
+}
+
+impl PublisherMetricsCollector for DevNullMetricsCollector {
+    fn publisher_request_sent(&self, _event_type: &str, _bytes: usize, _num_events: usize) {}
+    fn publisher_published(&self, _event_type: &str, _request_started: Instant) {}
+    fn publisher_publish_failed(
+        &self,
+        _event_type: &str,
+        _status: Option<u16>,
+        _request_started: Instant,
+    ) {
+    }
+    fn publisher_publishing_status(&self, _event_type: &str, _publishing_status: &str, _n: usize) {
+    }
+}
+
+/// Forwards all metrics to several `MetricsCollector`s,
+/// e.g. to `metrix` and to a collector that logs.
+#[derive(Clone, Default)]
+pub struct MultiMetricsCollector {
+    collectors: Vec<Arc<MetricsCollector + Send + Sync>>,
+}
+
+impl MultiMetricsCollector {
+    pub fn new() -> MultiMetricsCollector {
+        MultiMetricsCollector::default()
+    }
+
+    /// Also forward the metrics to `collector`.
+    pub fn with<M>(mut self, collector: M) -> MultiMetricsCollector
+    where
+        M: MetricsCollector + Send + Sync + 'static,
+    {
+        self.add(collector);
+        self
+    }
+
+    /// Also forward the metrics to `collector`.
+    pub fn add<M>(&mut self, collector: M)
+    where
+        M: MetricsCollector + Send + Sync + 'static,
+    {
+        self.collectors.push(Arc::new(collector));
+    }
+
+    /// The number of collectors the metrics are forwarded to
+    pub fn len(&self) -> usize {
+        self.collectors.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.collectors.is_empty()
+    }
+
+    fn each<F>(&self, f: F)
+    where
+        F: Fn(&MetricsCollector),
+    {
+        for collector in &self.collectors {
+            f(&**collector)
+        }
+    }
+}
+
+impl MetricsCollector for MultiMetricsCollector {
+    fn streaming_connect_attempt(&self) {
+        self.each(|c| c.streaming_connect_attempt());
+    }
+    fn streaming_connect_attempt_failed(&self) {
+        self.each(|c| c.streaming_connect_attempt_failed());
+    }
+    fn streaming_rate_limit_remaining(&self, remaining: u64) {
+        self.each(|c| c.streaming_rate_limit_remaining(remaining));
+    }
+    fn consumer_connected(&self, attempt_started: Instant) {
+        self.each(|c| c.consumer_connected(attempt_started));
+    }
+    fn consumer_connection_lifetime(&self, connected_since: Instant) {
+        self.each(|c| c.consumer_connection_lifetime(connected_since));
+    }
+    fn consumer_first_batch_received(&self, connected_since: Instant) {
+        self.each(|c| c.consumer_first_batch_received(connected_since));
+    }
+    fn consumer_line_received(&self, bytes: usize) {
+        self.each(|c| c.consumer_line_received(bytes));
+    }
+    fn consumer_info_line_received(&self, bytes: usize) {
+        self.each(|c| c.consumer_info_line_received(bytes));
+    }
+    fn consumer_keep_alive_line_received(&self, bytes: usize) {
+        self.each(|c| c.consumer_keep_alive_line_received(bytes));
+    }
+    fn consumer_batch_line_received(&self, bytes: usize) {
+        self.each(|c| c.consumer_batch_line_received(bytes));
+    }
+    fn consumer_scaling_pressure(&self, pressure: f64) {
+        self.each(|c| c.consumer_scaling_pressure(pressure));
+    }
+    fn consumer_ordering_violation(&self) {
+        self.each(|c| c.consumer_ordering_violation());
+    }
+    fn consumer_offset_gap(&self, missing: u64) {
+        self.each(|c| c.consumer_offset_gap(missing));
+    }
+    fn consumer_invalid_utf8_repaired(&self) {
+        self.each(|c| c.consumer_invalid_utf8_repaired());
+    }
+    fn consumer_queued_bytes(&self, bytes: usize) {
+        self.each(|c| c.consumer_queued_bytes(bytes));
+    }
+    fn consumer_partitions_at_retention_risk(&self, n: usize) {
+        self.each(|c| c.consumer_partitions_at_retention_risk(n));
+    }
+    fn dispatcher_current_workers(&self, num_workers: usize) {
+        self.each(|c| c.dispatcher_current_workers(num_workers));
+    }
+    fn dispatcher_worker_mailbox_size(&self, num_batches: usize) {
+        self.each(|c| c.dispatcher_worker_mailbox_size(num_batches));
+    }
+    fn dispatcher_batches_discarded(&self, n: usize) {
+        self.each(|c| c.dispatcher_batches_discarded(n));
+    }
+    fn dispatcher_handler_stalled_for(&self, stalled_for: Duration) {
+        self.each(|c| c.dispatcher_handler_stalled_for(stalled_for));
+    }
+    fn worker_batch_size_bytes(&self, bytes: usize) {
+        self.each(|c| c.worker_batch_size_bytes(bytes));
+    }
+    fn worker_batch_processed(&self, started: Instant) {
+        self.each(|c| c.worker_batch_processed(started));
+    }
+    fn worker_events_in_same_batch_processed(&self, n: usize) {
+        self.each(|c| c.worker_events_in_same_batch_processed(n));
+    }
+    fn handler_annotation(&self, key: &str, value: u64) {
+        self.each(|c| c.handler_annotation(key, value));
+    }
+    fn committer_cursor_received(&self, cursor_received_at_timestamp: Instant) {
+        self.each(|c| c.committer_cursor_received(cursor_received_at_timestamp));
+    }
+    fn committer_cursor_commit_attempt(&self, commit_attempt_started: Instant) {
+        self.each(|c| c.committer_cursor_commit_attempt(commit_attempt_started));
+    }
+    fn committer_cursor_committed(&self, commit_attempt_started: Instant) {
+        self.each(|c| c.committer_cursor_committed(commit_attempt_started));
+    }
+    fn committer_cursor_commit_failed(&self, commit_attempt_started: Instant) {
+        self.each(|c| c.committer_cursor_commit_failed(commit_attempt_started));
+    }
+    fn committer_batches_committed(&self, n: usize) {
+        self.each(|c| c.committer_batches_committed(n));
+    }
+    fn committer_events_committed(&self, n: usize) {
+        self.each(|c| c.committer_events_committed(n));
+    }
+    fn committer_cursor_age_on_commit(&self, received_at_timestamp: Instant) {
+        self.each(|c| c.committer_cursor_age_on_commit(received_at_timestamp));
+    }
+    fn committer_time_elapsed_until_commit(&self, first_cursor_age: Instant) {
+        self.each(|c| c.committer_time_elapsed_until_commit(first_cursor_age));
+    }
+    fn committer_time_left_on_commit(&self, committed_at: Instant, deadline: Instant) {
+        self.each(|c| c.committer_time_left_on_commit(committed_at, deadline));
+    }
+    fn committer_stale_cursors_discarded(&self, n: usize) {
+        self.each(|c| c.committer_stale_cursors_discarded(n));
+    }
+    fn committer_outdated_cursors(&self, n: usize) {
+        self.each(|c| c.committer_outdated_cursors(n));
+    }
+    fn committer_cursors_already_committed(&self, n: usize) {
+        self.each(|c| c.committer_cursors_already_committed(n));
+    }
+    fn committer_partition_time_left_on_commit(&self, partition: &str, time_left: Duration) {
+        self.each(|c| c.committer_partition_time_left_on_commit(partition, time_left));
+    }
+    fn committer_commit_near_miss(&self, partition: &str, time_left: Duration) {
+        self.each(|c| c.committer_commit_near_miss(partition, time_left));
+    }
+
+    fn batch_stage_took(&self, stage: Stage, took: Duration) {
+        self.each(|c| c.batch_stage_took(stage, took));
+    }
+}
+
+/// Counts how often each metric was reported and sums up the
+/// reported amounts. Meant for tests and benchmarks.
+///
+/// The metrics are looked up by the name of the method of
+/// `MetricsCollector` or `PublisherMetricsCollector` that reported
+/// them. Clones share their counters.
+#[derive(Clone, Default)]
+pub struct CountingMetricsCollector {
+    counters: Arc<Mutex<HashMap<String, Counted>>>,
+}
+
+#[derive(Clone, Copy, Default)]
+struct Counted {
+    count: u64,
+    total: u64,
+}
+
+impl CountingMetricsCollector {
+    pub fn new() -> CountingMetricsCollector {
+        CountingMetricsCollector::default()
+    }
+
+    /// How often the metric was reported
+    pub fn count(&self, name: &str) -> u64 {
+        self.lock().get(name).map(|c| c.count).unwrap_or(0)
+    }
+
+    /// The sum of the amounts reported, e.g. bytes or events.
+    ///
+    /// Metrics without an amount like durations always sum up to 0.
+    /// The values of handler annotations are summed up under
+    /// `handler_annotation.<key>`.
+    pub fn total(&self, name: &str) -> u64 {
+        self.lock().get(name).map(|c| c.total).unwrap_or(0)
+    }
+
+    /// The names of all metrics reported so far
+    pub fn names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.lock().keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    /// Forget everything counted so far.
+    pub fn reset(&self) {
+        self.lock().clear()
+    }
+
+    fn record(&self, name: &str, amount: u64) {
+        let mut counters = self.lock();
+        let counted = counters.entry(name.to_string()).or_insert_with(Counted::default);
+        counted.count += 1;
+        counted.total += amount;
+    }
+
+    fn lock(&self) -> MutexGuard<HashMap<String, Counted>> {
+        match self.counters.lock() {
+            Ok(counters) => counters,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+}
+
+impl MetricsCollector for CountingMetricsCollector {
+    fn streaming_connect_attempt(&self) {
+        self.record("streaming_connect_attempt", 0);
+    }
+    fn streaming_connect_attempt_failed(&self) {
+        self.record("streaming_connect_attempt_failed", 0);
+    }
+    fn streaming_rate_limit_remaining(&self, remaining: u64) {
+        self.record("streaming_rate_limit_remaining", remaining);
+    }
+    fn consumer_connected(&self, _attempt_started: Instant) {
+        self.record("consumer_connected", 0);
+    }
+    fn consumer_connection_lifetime(&self, _connected_since: Instant) {
+        self.record("consumer_connection_lifetime", 0);
+    }
+    fn consumer_first_batch_received(&self, _connected_since: Instant) {
+        self.record("consumer_first_batch_received", 0);
+    }
+    fn consumer_line_received(&self, bytes: usize) {
+        self.record("consumer_line_received", bytes as u64);
+    }
+    fn consumer_info_line_received(&self, bytes: usize) {
+        self.record("consumer_info_line_received", bytes as u64);
+    }
+    fn consumer_keep_alive_line_received(&self, bytes: usize) {
+        self.record("consumer_keep_alive_line_received", bytes as u64);
+    }
+    fn consumer_batch_line_received(&self, bytes: usize) {
+        self.record("consumer_batch_line_received", bytes as u64);
+    }
+    fn consumer_scaling_pressure(&self, _pressure: f64) {
+        self.record("consumer_scaling_pressure", 0);
+    }
+    fn consumer_ordering_violation(&self) {
+        self.record("consumer_ordering_violation", 0);
+    }
+    fn consumer_offset_gap(&self, missing: u64) {
+        self.record("consumer_offset_gap", missing);
+    }
+    fn consumer_invalid_utf8_repaired(&self) {
+        self.record("consumer_invalid_utf8_repaired", 0);
+    }
+    fn consumer_queued_bytes(&self, bytes: usize) {
+        self.record("consumer_queued_bytes", bytes as u64);
+    }
+    fn consumer_partitions_at_retention_risk(&self, n: usize) {
+        self.record("consumer_partitions_at_retention_risk", n as u64);
+    }
+    fn dispatcher_current_workers(&self, num_workers: usize) {
+        self.record("dispatcher_current_workers", num_workers as u64);
+    }
+    fn dispatcher_worker_mailbox_size(&self, num_batches: usize) {
+        self.record("dispatcher_worker_mailbox_size", num_batches as u64);
+    }
+    fn dispatcher_batches_discarded(&self, n: usize) {
+        self.record("dispatcher_batches_discarded", n as u64);
+    }
+    fn dispatcher_handler_stalled_for(&self, stalled_for: Duration) {
+        self.record("dispatcher_handler_stalled_for", millis(stalled_for));
+    }
+    fn worker_batch_size_bytes(&self, bytes: usize) {
+        self.record("worker_batch_size_bytes", bytes as u64);
+    }
+    fn worker_batch_processed(&self, _started: Instant) {
+        self.record("worker_batch_processed", 0);
+    }
+    fn worker_events_in_same_batch_processed(&self, n: usize) {
+        self.record("worker_events_in_same_batch_processed", n as u64);
+    }
+    fn handler_annotation(&self, key: &str, value: u64) {
+        self.record("handler_annotation", value);
+        self.record(&format!("handler_annotation.{}", key), value);
+    }
+    fn committer_cursor_received(&self, _cursor_received_at_timestamp: Instant) {
+        self.record("committer_cursor_received", 0);
+    }
+    fn committer_cursor_commit_attempt(&self, _commit_attempt_started: Instant) {
+        self.record("committer_cursor_commit_attempt", 0);
+    }
+    fn committer_cursor_committed(&self, _commit_attempt_started: Instant) {
+        self.record("committer_cursor_committed", 0);
+    }
+    fn committer_cursor_commit_failed(&self, _commit_attempt_started: Instant) {
+        self.record("committer_cursor_commit_failed", 0);
+    }
+    fn committer_batches_committed(&self, n: usize) {
+        self.record("committer_batches_committed", n as u64);
+    }
+    fn committer_events_committed(&self, n: usize) {
+        self.record("committer_events_committed", n as u64);
+    }
+    fn committer_cursor_age_on_commit(&self, _received_at_timestamp: Instant) {
+        self.record("committer_cursor_age_on_commit", 0);
+    }
+    fn committer_time_elapsed_until_commit(&self, _first_cursor_age: Instant) {
+        self.record("committer_time_elapsed_until_commit", 0);
+    }
+    fn committer_time_left_on_commit(&self, _committed_at: Instant, _deadline: Instant) {
+        self.record("committer_time_left_on_commit", 0);
+    }
+    fn committer_stale_cursors_discarded(&self, n: usize) {
+        self.record("committer_stale_cursors_discarded", n as u64);
+    }
+    fn committer_outdated_cursors(&self, n: usize) {
+        self.record("committer_outdated_cursors", n as u64);
+    }
+    fn committer_cursors_already_committed(&self, n: usize) {
+        self.record("committer_cursors_already_committed", n as u64);
+    }
+    fn committer_partition_time_left_on_commit(&self, partition: &str, _time_left: Duration) {
+        self.record("committer_partition_time_left_on_commit", 0);
+        self.record(
+            &format!("committer_partition_time_left_on_commit.{}", partition),
+            0,
+        );
+    }
+    fn committer_commit_near_miss(&self, partition: &str, _time_left: Duration) {
+        self.record("committer_commit_near_miss", 0);
+        self.record(&format!("committer_commit_near_miss.{}", partition), 0);
+    }
+
+    fn batch_stage_took(&self, stage: Stage, _took: Duration) {
+        self.record(&format!("batch_stage_took.{}", stage), 0);
+    }
+}
+
+impl PublisherMetricsCollector for CountingMetricsCollector {
+    fn publisher_request_sent(&self, _event_type: &str, bytes: usize, num_events: usize) {
+        self.record("publisher_request_sent", bytes as u64);
+        self.record("publisher_events_sent", num_events as u64);
+    }
+    fn publisher_published(&self, _event_type: &str, _request_started: Instant) {
+        self.record("publisher_published", 0);
+    }
+    fn publisher_publish_failed(
+        &self,
+        _event_type: &str,
+        _status: Option<u16>,
+        _request_started: Instant,
+    ) {
+        self.record("publisher_publish_failed", 0);
+    }
+    fn publisher_publishing_status(&self, _event_type: &str, publishing_status: &str, n: usize) {
+        self.record("publisher_publishing_status", n as u64);
+        self.record(
+            &format!("publisher_publishing_status.{}", publishing_status),
+            n as u64,
+        );
+    }
+}
+
+/// The interval at which the averages of a `ThroughputMeter` are updated
+const THROUGHPUT_TICK_SECS: u64 = 5;
+
+/// Rates per second averaged over the last 1, 5 and 15 minutes
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct ThroughputRates {
+    pub one_minute: f64,
+    pub five_minutes: f64,
+    pub fifteen_minutes: f64,
+}
+
+/// Measures a rate per second with exponentially weighted
+/// moving averages over 1, 5 and 15 minutes.
+///
+/// Works like the load averages of Unix so that the averages
+/// can be read without an external metrics pipeline.
+#[derive(Debug, Clone)]
+pub struct ThroughputMeter {
+    one_minute: Ewma,
+    five_minutes: Ewma,
+    fifteen_minutes: Ewma,
+    uncounted: u64,
+    last_tick: Instant,
+}
+
+impl ThroughputMeter {
+    pub fn new() -> ThroughputMeter {
+        ThroughputMeter::started_at(Instant::now())
+    }
+
+    fn started_at(now: Instant) -> ThroughputMeter {
+        ThroughputMeter {
+            one_minute: Ewma::new(Duration::from_secs(60)),
+            five_minutes: Ewma::new(Duration::from_secs(5 * 60)),
+            fifteen_minutes: Ewma::new(Duration::from_secs(15 * 60)),
+            uncounted: 0,
+            last_tick: now,
+        }
+    }
+
+    /// Record that `n` items have been processed.
+    pub fn mark(&mut self, n: u64) {
+        self.mark_at(n, Instant::now())
+    }
+
+    /// The current rates per second
+    pub fn rates(&mut self) -> ThroughputRates {
+        self.rates_at(Instant::now())
+    }
+
+    fn mark_at(&mut self, n: u64, now: Instant) {
+        self.tick_until(now);
+        self.uncounted += n;
+    }
+
+    fn rates_at(&mut self, now: Instant) -> ThroughputRates {
+        self.tick_until(now);
+        ThroughputRates {
+            one_minute: self.one_minute.rate,
+            five_minutes: self.five_minutes.rate,
+            fifteen_minutes: self.fifteen_minutes.rate,
+        }
+    }
+
+    /// Apply all ticks that elapsed since the last tick. Only the first
+    /// tick gets the uncounted items, the others decay the averages.
+    fn tick_until(&mut self, now: Instant) {
+        let tick = Duration::from_secs(THROUGHPUT_TICK_SECS);
+        while now >= self.last_tick + tick {
+            let count = self.uncounted;
+            self.uncounted = 0;
+            self.one_minute.tick(count);
+            self.five_minutes.tick(count);
+            self.fifteen_minutes.tick(count);
+            self.last_tick += tick;
+        }
+    }
+}
+
+impl Default for ThroughputMeter {
+    fn default() -> ThroughputMeter {
+        ThroughputMeter::new()
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Ewma {
+    alpha: f64,
+    rate: f64,
+    initialized: bool,
+}
+
+impl Ewma {
+    fn new(window: Duration) -> Ewma {
+        let window_secs = window.as_secs() as f64;
+        Ewma {
+            alpha: 1.0 - (-(THROUGHPUT_TICK_SECS as f64) / window_secs).exp(),
+            rate: 0.0,
+            initialized: false,
+        }
+    }
+
+    fn tick(&mut self, count: u64) {
+        let instant_rate = count as f64 / THROUGHPUT_TICK_SECS as f64;
+        if self.initialized {
+            self.rate += self.alpha * (instant_rate - self.rate);
+        } else {
+            self.rate = instant_rate;
+            self.initialized = true;
+        }
+    }
 }
 
 #[cfg(feature = "metrix")]
 mod metrix {
+    use std::collections::HashSet;
+    use std::sync::{Arc, Mutex};
     use std::time::{Duration, Instant};
 
     use metrix::TelemetryTransmitterSync;
@@ -117,25 +734,39 @@ mod metrix {
     use metrix::instruments::switches::*;
     use metrix::TransmitsTelemetryData;
 
+    use nakadi::millis;
+    use nakadi::stage_timing::Stage;
+
     #[derive(Clone, PartialEq, Eq)]
     enum ConnectorMetrics {
         ConnectAttempt,
         ConnectAttemptFailed,
+        RateLimitRemaining,
     }
 
     #[derive(Clone, PartialEq, Eq)]
     enum ConsumerMetrics {
         Connected,
         ConnectionLifetime,
+        TimeToFirstBatch,
         LineReceived,
         KeepAliveLineReceived,
         InfoLineReceived,
         BatchLineReceived,
+        ScalingPressure,
+        OrderingViolation,
+        OffsetGap,
+        InvalidUtf8Repaired,
+        QueuedBytes,
+        PartitionsAtRetentionRisk,
     }
 
     #[derive(Clone, PartialEq, Eq)]
     enum DispatcherMetrics {
         NumWorkers,
+        MailboxSize,
+        BatchesDiscarded,
+        HandlerStalledFor,
     }
 
     #[derive(Clone, PartialEq, Eq)]
@@ -156,8 +787,36 @@ mod metrix {
         CursorAgeOnCommit,
         TimeElapsedUntilCommit,
         TimeLeftOnCommit,
+        StaleCursorsDiscarded,
+        OutdatedCursors,
+        AlreadyCommitted,
+        NearMisses,
     }
 
+    #[derive(Clone, Copy, PartialEq, Eq)]
+    enum PartitionMetrics {
+        TimeLeftOnCommit,
+        NearMisses,
+    }
+
+    #[derive(Clone, Copy, PartialEq, Eq)]
+    enum PublisherMetrics {
+        RequestSent,
+        RequestBytes,
+        EventsPerRequest,
+        Published,
+        FailedClientError,
+        FailedServerError,
+        FailedNoResponse,
+        EventsSubmitted,
+        EventsFailed,
+        EventsAborted,
+    }
+
+    /// The publisher metrics are labeled with the event type they belong to.
+    type PublisherLabel = (String, PublisherMetrics);
+    type PartitionLabel = (String, PartitionMetrics);
+
     /// A `MetricsCollector` that works with the [`metrix`](https://crates.io/crates/metrix)
     ///  library
     #[derive(Clone)]
@@ -167,6 +826,14 @@ mod metrix {
         dispatcher: TelemetryTransmitterSync<DispatcherMetrics>,
         worker: TelemetryTransmitterSync<WorkerMetrics>,
         cursor: TelemetryTransmitterSync<CursorMetrics>,
+        publisher: TelemetryTransmitterSync<PublisherLabel>,
+        publisher_event_types: Arc<Mutex<HashSet<String>>>,
+        partition: TelemetryTransmitterSync<PartitionLabel>,
+        partitions: Arc<Mutex<HashSet<String>>>,
+        stage: TelemetryTransmitterSync<Stage>,
+        /// Labeled with the key of the annotation
+        annotations: TelemetryTransmitterSync<String>,
+        annotation_keys: Arc<Mutex<HashSet<String>>>,
     }
 
     impl MetrixCollector {
@@ -181,12 +848,21 @@ mod metrix {
             let (dispatcher_tx, dispatcher_rx) = create_dispatcher_metrics();
             let (worker_tx, worker_rx) = create_worker_metrics();
             let (cursor_tx, cursor_rx) = create_cursor_metrics();
+            let (stage_tx, stage_rx) = create_stage_metrics();
+            let (publisher_tx, publisher_rx) = TelemetryProcessor::new_pair("publisher");
+            let (partition_tx, partition_rx) = TelemetryProcessor::new_pair("partitions");
+            let (annotations_tx, annotations_rx) =
+                TelemetryProcessor::new_pair("handler_annotations");
 
             add_metrics_to.add_processor(connector_rx);
             add_metrics_to.add_processor(consumer_rx);
             add_metrics_to.add_processor(dispatcher_rx);
             add_metrics_to.add_processor(worker_rx);
             add_metrics_to.add_processor(cursor_rx);
+            add_metrics_to.add_processor(stage_rx);
+            add_metrics_to.add_processor(publisher_rx);
+            add_metrics_to.add_processor(partition_rx);
+            add_metrics_to.add_processor(annotations_rx);
 
             MetrixCollector {
                 connector: connector_tx,
@@ -194,6 +870,119 @@ mod metrix {
                 dispatcher: dispatcher_tx,
                 worker: worker_tx,
                 cursor: cursor_tx,
+                publisher: publisher_tx.synced(),
+                publisher_event_types: Arc::new(Mutex::new(HashSet::new())),
+                partition: partition_tx.synced(),
+                partitions: Arc::new(Mutex::new(HashSet::new())),
+                stage: stage_tx,
+                annotations: annotations_tx.synced(),
+                annotation_keys: Arc::new(Mutex::new(HashSet::new())),
+            }
+        }
+
+        /// Observe a publisher metric for the given event type.
+        ///
+        /// The cockpit of an event type is created when
+        /// the event type is seen for the first time.
+        fn publisher_label(&self, event_type: &str, metric: PublisherMetrics) -> PublisherLabel {
+            let is_new = match self.publisher_event_types.lock() {
+                Ok(mut event_types) => event_types.insert(event_type.to_string()),
+                Err(poisoned) => poisoned.into_inner().insert(event_type.to_string()),
+            };
+            if is_new {
+                self.publisher
+                    .add_cockpit(create_publisher_cockpit(event_type));
+            }
+            (event_type.to_string(), metric)
+        }
+    }
+
+    impl MetrixCollector {
+        /// The cockpit of a partition is created when
+        /// the partition is seen for the first time.
+        fn partition_label(&self, partition: &str, metric: PartitionMetrics) -> PartitionLabel {
+            let is_new = match self.partitions.lock() {
+                Ok(mut partitions) => partitions.insert(partition.to_string()),
+                Err(poisoned) => poisoned.into_inner().insert(partition.to_string()),
+            };
+            if is_new {
+                let label = |metric| (partition.to_string(), metric);
+                let mut cockpit: Cockpit<PartitionLabel> =
+                    Cockpit::new(partition.to_string(), None);
+                let time_left_panel =
+                    Panel::with_name(label(PartitionMetrics::TimeLeftOnCommit), "time_left");
+                add_us_histogram_instruments_to_cockpit(time_left_panel, &mut cockpit);
+                let near_misses_panel =
+                    Panel::with_name(label(PartitionMetrics::NearMisses), "near_misses");
+                add_counting_instruments_to_cockpit(near_misses_panel, &mut cockpit);
+                self.partition.add_cockpit(cockpit);
+            }
+            (partition.to_string(), metric)
+        }
+
+        /// The cockpit of an annotation is created when
+        /// its key is seen for the first time.
+        fn annotation_label(&self, key: &str) -> String {
+            let is_new = match self.annotation_keys.lock() {
+                Ok(mut keys) => keys.insert(key.to_string()),
+                Err(poisoned) => poisoned.into_inner().insert(key.to_string()),
+            };
+            if is_new {
+                let mut cockpit: Cockpit<String> = Cockpit::new(key.to_string(), None);
+                let mut panel = Panel::with_name(key.to_string(), "value");
+                panel.set_counter(Counter::new_with_defaults("count"));
+                panel.add_instrument(ValueMeter::new_with_defaults("per_second"));
+                panel.set_histogram(Histogram::new_with_defaults("distribution"));
+                cockpit.add_panel(panel);
+                self.annotations.add_cockpit(cockpit);
+            }
+            key.to_string()
+        }
+    }
+
+    impl super::PublisherMetricsCollector for MetrixCollector {
+        fn publisher_request_sent(&self, event_type: &str, bytes: usize, num_events: usize) {
+            self.publisher
+                .observed_one_now(self.publisher_label(event_type, PublisherMetrics::RequestSent));
+            self.publisher.observed_one_value_now(
+                self.publisher_label(event_type, PublisherMetrics::RequestBytes),
+                bytes as u64,
+            );
+            self.publisher.observed_one_value_now(
+                self.publisher_label(event_type, PublisherMetrics::EventsPerRequest),
+                num_events as u64,
+            );
+        }
+        fn publisher_published(&self, event_type: &str, request_started: Instant) {
+            self.publisher.measure_time(
+                self.publisher_label(event_type, PublisherMetrics::Published),
+                request_started,
+            );
+        }
+        fn publisher_publish_failed(
+            &self,
+            event_type: &str,
+            status: Option<u16>,
+            request_started: Instant,
+        ) {
+            let metric = match status {
+                Some(status) if status < 500 => PublisherMetrics::FailedClientError,
+                Some(_) => PublisherMetrics::FailedServerError,
+                None => PublisherMetrics::FailedNoResponse,
+            };
+            self.publisher
+                .measure_time(self.publisher_label(event_type, metric), request_started);
+        }
+        fn publisher_publishing_status(&self, event_type: &str, publishing_status: &str, n: usize) {
+            let metric = match publishing_status {
+                "submitted" => PublisherMetrics::EventsSubmitted,
+                "failed" => PublisherMetrics::EventsFailed,
+                "aborted" => PublisherMetrics::EventsAborted,
+                _ => return,
+            };
+            if n > 0 {
+                self.publisher
+                    .observed_now(self.publisher_label(event_type, metric), n as u64);
             }
         }
     }
@@ -207,6 +996,10 @@ mod metrix {
             self.connector
                 .observed_one_now(ConnectorMetrics::ConnectAttemptFailed);
         }
+        fn streaming_rate_limit_remaining(&self, remaining: u64) {
+            self.connector
+                .observed_one_value_now(ConnectorMetrics::RateLimitRemaining, remaining);
+        }
 
         fn consumer_connected(&self, attempt_started: Instant) {
             self.consumer
@@ -216,6 +1009,10 @@ mod metrix {
             self.consumer
                 .measure_time(ConsumerMetrics::ConnectionLifetime, connected_since);
         }
+        fn consumer_first_batch_received(&self, connected_since: Instant) {
+            self.consumer
+                .measure_time(ConsumerMetrics::TimeToFirstBatch, connected_since);
+        }
         fn consumer_line_received(&self, bytes: usize) {
             self.consumer
                 .observed_one_value_now(ConsumerMetrics::LineReceived, bytes as u64);
@@ -232,11 +1029,52 @@ mod metrix {
             self.consumer
                 .observed_one_value_now(ConsumerMetrics::BatchLineReceived, bytes as u64);
         }
+        fn consumer_scaling_pressure(&self, pressure: f64) {
+            // Gauges only take integers
+            self.consumer.observed_one_value_now(
+                ConsumerMetrics::ScalingPressure,
+                (pressure * 100.0) as u64,
+            );
+        }
+        fn consumer_partitions_at_retention_risk(&self, n: usize) {
+            self.consumer
+                .observed_one_value_now(ConsumerMetrics::PartitionsAtRetentionRisk, n as u64);
+        }
+        fn consumer_ordering_violation(&self) {
+            self.consumer
+                .observed_one_now(ConsumerMetrics::OrderingViolation);
+        }
+        fn consumer_offset_gap(&self, missing: u64) {
+            self.consumer
+                .observed_one_value_now(ConsumerMetrics::OffsetGap, missing);
+        }
+        fn consumer_invalid_utf8_repaired(&self) {
+            self.consumer
+                .observed_one_now(ConsumerMetrics::InvalidUtf8Repaired);
+        }
+        fn consumer_queued_bytes(&self, bytes: usize) {
+            self.consumer
+                .observed_one_value_now(ConsumerMetrics::QueuedBytes, bytes as u64);
+        }
 
         fn dispatcher_current_workers(&self, num_workers: usize) {
             self.dispatcher
                 .observed_one_value_now(DispatcherMetrics::NumWorkers, num_workers as u64);
         }
+        fn dispatcher_worker_mailbox_size(&self, num_batches: usize) {
+            self.dispatcher
+                .observed_one_value_now(DispatcherMetrics::MailboxSize, num_batches as u64);
+        }
+        fn dispatcher_batches_discarded(&self, n: usize) {
+            if n > 0 {
+                self.dispatcher
+                    .observed_now(DispatcherMetrics::BatchesDiscarded, n as u64);
+            }
+        }
+        fn dispatcher_handler_stalled_for(&self, stalled_for: Duration) {
+            self.dispatcher
+                .observed_one_value_now(DispatcherMetrics::HandlerStalledFor, millis(stalled_for));
+        }
 
         fn worker_batch_size_bytes(&self, bytes: usize) {
             self.worker
@@ -250,6 +1088,10 @@ mod metrix {
             self.worker
                 .observed_one_value_now(WorkerMetrics::EventsProcessed, n as u64);
         }
+        fn handler_annotation(&self, key: &str, value: u64) {
+            self.annotations
+                .observed_one_value_now(self.annotation_label(key), value);
+        }
 
         fn committer_cursor_received(&self, cursor_received_at_timestamp: Instant) {
             self.cursor
@@ -296,6 +1138,39 @@ mod metrix {
                     .observed_one_duration_now(CursorMetrics::TimeLeftOnCommit, time_left);
             }
         }
+        fn committer_stale_cursors_discarded(&self, n: usize) {
+            if n > 0 {
+                self.cursor
+                    .observed_now(CursorMetrics::StaleCursorsDiscarded, n as u64);
+            }
+        }
+        fn committer_outdated_cursors(&self, n: usize) {
+            if n > 0 {
+                self.cursor
+                    .observed_now(CursorMetrics::OutdatedCursors, n as u64);
+            }
+        }
+        fn committer_cursors_already_committed(&self, n: usize) {
+            if n > 0 {
+                self.cursor
+                    .observed_now(CursorMetrics::AlreadyCommitted, n as u64);
+            }
+        }
+        fn committer_partition_time_left_on_commit(&self, partition: &str, time_left: Duration) {
+            self.partition.observed_one_duration_now(
+                self.partition_label(partition, PartitionMetrics::TimeLeftOnCommit),
+                time_left,
+            );
+        }
+        fn committer_commit_near_miss(&self, partition: &str, _time_left: Duration) {
+            self.cursor.observed_one_now(CursorMetrics::NearMisses);
+            self.partition
+                .observed_one_now(self.partition_label(partition, PartitionMetrics::NearMisses));
+        }
+
+        fn batch_stage_took(&self, stage: Stage, took: Duration) {
+            self.stage.observed_one_duration_now(stage, took);
+        }
     }
 
     fn create_connector_metrics() -> (
@@ -313,6 +1188,10 @@ mod metrix {
         );
         add_counting_instruments_to_cockpit(connect_attempts_failed_panel, &mut cockpit);
 
+        let mut rate_limit_remaining_panel = Panel::new(ConnectorMetrics::RateLimitRemaining);
+        rate_limit_remaining_panel.set_gauge(Gauge::new_with_defaults("rate_limit_remaining"));
+        cockpit.add_panel(rate_limit_remaining_panel);
+
         let (tx, rx) = TelemetryProcessor::new_pair("connector");
 
         tx.add_cockpit(cockpit);
@@ -333,6 +1212,10 @@ mod metrix {
             Panel::with_name(ConsumerMetrics::ConnectionLifetime, "connection_lifetimes");
         add_ms_histogram_instruments_to_cockpit(connection_lifetimes_panel, &mut cockpit);
 
+        let time_to_first_batch_panel =
+            Panel::with_name(ConsumerMetrics::TimeToFirstBatch, "time_to_first_batch");
+        add_ms_histogram_instruments_to_cockpit(time_to_first_batch_panel, &mut cockpit);
+
         let line_received_panel = Panel::with_name(ConsumerMetrics::LineReceived, "all_lines");
         add_line_instruments_to_cockpit(line_received_panel, &mut cockpit);
 
@@ -380,6 +1263,34 @@ mod metrix {
 
         cockpit.add_panel(alerts_panel);
 
+        let mut scaling_pressure_panel = Panel::new(ConsumerMetrics::ScalingPressure);
+        scaling_pressure_panel.set_gauge(Gauge::new_with_defaults("scaling_pressure_percent"));
+        cockpit.add_panel(scaling_pressure_panel);
+
+        let mut queued_bytes_panel = Panel::new(ConsumerMetrics::QueuedBytes);
+        queued_bytes_panel.set_gauge(Gauge::new_with_defaults("queued_bytes"));
+        cockpit.add_panel(queued_bytes_panel);
+
+        let mut retention_risk_panel = Panel::new(ConsumerMetrics::PartitionsAtRetentionRisk);
+        retention_risk_panel.set_gauge(Gauge::new_with_defaults(
+            "partitions_at_retention_risk",
+        ));
+        cockpit.add_panel(retention_risk_panel);
+
+        let ordering_violations_panel =
+            Panel::with_name(ConsumerMetrics::OrderingViolation, "ordering_violations");
+        add_counting_instruments_to_cockpit(ordering_violations_panel, &mut cockpit);
+
+        let mut offset_gaps_panel = Panel::with_name(ConsumerMetrics::OffsetGap, "offset_gaps");
+        offset_gaps_panel.set_histogram(Histogram::new_with_defaults("missing_events"));
+        add_counting_instruments_to_cockpit(offset_gaps_panel, &mut cockpit);
+
+        let invalid_utf8_panel = Panel::with_name(
+            ConsumerMetrics::InvalidUtf8Repaired,
+            "invalid_utf8_repaired",
+        );
+        add_counting_instruments_to_cockpit(invalid_utf8_panel, &mut cockpit);
+
         let (tx, rx) = TelemetryProcessor::new_pair("consumer");
 
         tx.add_cockpit(cockpit);
@@ -397,6 +1308,20 @@ mod metrix {
         num_workers_panel.set_gauge(Gauge::new_with_defaults("num_workers"));
         cockpit.add_panel(num_workers_panel);
 
+        let mut mailbox_size_panel =
+            Panel::with_name(DispatcherMetrics::MailboxSize, "worker_mailbox_size");
+        mailbox_size_panel.set_histogram(Histogram::new_with_defaults("batches"));
+        cockpit.add_panel(mailbox_size_panel);
+
+        let batches_discarded_panel =
+            Panel::with_name(DispatcherMetrics::BatchesDiscarded, "batches_discarded");
+        add_counting_instruments_to_cockpit(batches_discarded_panel, &mut cockpit);
+
+        let mut handler_stalled_for_panel =
+            Panel::with_name(DispatcherMetrics::HandlerStalledFor, "handler_stalled_for");
+        handler_stalled_for_panel.set_gauge(Gauge::new_with_defaults("ms"));
+        cockpit.add_panel(handler_stalled_for_panel);
+
         let (tx, rx) = TelemetryProcessor::new_pair("dispatcher");
 
         tx.add_cockpit(cockpit);
@@ -434,6 +1359,27 @@ mod metrix {
         (tx.synced(), rx)
     }
 
+    fn create_stage_metrics() -> (TelemetryTransmitterSync<Stage>, TelemetryProcessor<Stage>) {
+        let mut cockpit: Cockpit<Stage> = Cockpit::without_name(None);
+
+        for stage in &[
+            Stage::Read,
+            Stage::Parse,
+            Stage::QueueWait,
+            Stage::Handler,
+            Stage::Commit,
+        ] {
+            let panel = Panel::with_name(*stage, stage.name());
+            add_us_histogram_instruments_to_cockpit(panel, &mut cockpit);
+        }
+
+        let (tx, rx) = TelemetryProcessor::new_pair("batch_stages");
+
+        tx.add_cockpit(cockpit);
+
+        (tx.synced(), rx)
+    }
+
     fn create_cursor_metrics() -> (
         TelemetryTransmitterSync<CursorMetrics>,
         TelemetryProcessor<CursorMetrics>,
@@ -481,6 +1427,23 @@ mod metrix {
         let time_left_panel = Panel::with_name(CursorMetrics::TimeLeftOnCommit, "time_left");
         add_us_histogram_instruments_to_cockpit(time_left_panel, &mut cockpit);
 
+        let stale_cursors_panel = Panel::with_name(
+            CursorMetrics::StaleCursorsDiscarded,
+            "stale_cursors_discarded",
+        );
+        add_counting_instruments_to_cockpit(stale_cursors_panel, &mut cockpit);
+
+        let outdated_cursors_panel =
+            Panel::with_name(CursorMetrics::OutdatedCursors, "outdated_cursors");
+        add_counting_instruments_to_cockpit(outdated_cursors_panel, &mut cockpit);
+
+        let already_committed_panel =
+            Panel::with_name(CursorMetrics::AlreadyCommitted, "already_committed");
+        add_counting_instruments_to_cockpit(already_committed_panel, &mut cockpit);
+
+        let near_misses_panel = Panel::with_name(CursorMetrics::NearMisses, "near_misses");
+        add_counting_instruments_to_cockpit(near_misses_panel, &mut cockpit);
+
         let (tx, rx) = TelemetryProcessor::new_pair("cursors");
 
         tx.add_cockpit(cockpit);
@@ -488,6 +1451,61 @@ mod metrix {
         (tx.synced(), rx)
     }
 
+    fn create_publisher_cockpit(event_type: &str) -> Cockpit<PublisherLabel> {
+        let label = |metric| (event_type.to_string(), metric);
+        let mut cockpit: Cockpit<PublisherLabel> = Cockpit::new(event_type.to_string(), None);
+
+        let requests_panel = Panel::with_name(label(PublisherMetrics::RequestSent), "requests");
+        add_counting_instruments_to_cockpit(requests_panel, &mut cockpit);
+
+        let mut request_bytes_panel =
+            Panel::with_name(label(PublisherMetrics::RequestBytes), "request_bytes");
+        request_bytes_panel.add_instrument(ValueMeter::new_with_defaults("bytes_per_second"));
+        request_bytes_panel.set_histogram(Histogram::new_with_defaults("bytes_distribution"));
+        cockpit.add_panel(request_bytes_panel);
+
+        let mut events_per_request_panel =
+            Panel::with_name(label(PublisherMetrics::EventsPerRequest), "events");
+        events_per_request_panel.add_instrument(ValueMeter::new_with_defaults("per_second"));
+        events_per_request_panel.set_histogram(Histogram::new_with_defaults("per_request"));
+        cockpit.add_panel(events_per_request_panel);
+
+        let published_panel = Panel::with_name(label(PublisherMetrics::Published), "published");
+        add_counting_and_time_ms_instruments_to_cockpit(published_panel, &mut cockpit);
+
+        let failed_client_error_panel = Panel::with_name(
+            label(PublisherMetrics::FailedClientError),
+            "failed_client_error",
+        );
+        add_counting_and_time_ms_instruments_to_cockpit(failed_client_error_panel, &mut cockpit);
+
+        let failed_server_error_panel = Panel::with_name(
+            label(PublisherMetrics::FailedServerError),
+            "failed_server_error",
+        );
+        add_counting_and_time_ms_instruments_to_cockpit(failed_server_error_panel, &mut cockpit);
+
+        let failed_no_response_panel = Panel::with_name(
+            label(PublisherMetrics::FailedNoResponse),
+            "failed_no_response",
+        );
+        add_counting_and_time_ms_instruments_to_cockpit(failed_no_response_panel, &mut cockpit);
+
+        let events_submitted_panel =
+            Panel::with_name(label(PublisherMetrics::EventsSubmitted), "events_submitted");
+        add_counting_instruments_to_cockpit(events_submitted_panel, &mut cockpit);
+
+        let events_failed_panel =
+            Panel::with_name(label(PublisherMetrics::EventsFailed), "events_failed");
+        add_counting_instruments_to_cockpit(events_failed_panel, &mut cockpit);
+
+        let events_aborted_panel =
+            Panel::with_name(label(PublisherMetrics::EventsAborted), "events_aborted");
+        add_counting_instruments_to_cockpit(events_aborted_panel, &mut cockpit);
+
+        cockpit
+    }
+
     fn add_line_instruments_to_cockpit<L>(mut panel: Panel<L>, cockpit: &mut Cockpit<L>)
     where
         L: Clone + Eq + Send + 'static,
@@ -554,3 +1572,62 @@ mod metrix {
         cockpit.add_panel(panel);
     }
 }
+
+#[test]
+fn throughput_meter_starts_with_the_first_rate() {
+    let start = Instant::now();
+    let mut meter = ThroughputMeter::started_at(start);
+
+    meter.mark_at(50, start);
+    let rates = meter.rates_at(start + Duration::from_secs(THROUGHPUT_TICK_SECS));
+
+    assert_eq!(rates.one_minute, 10.0);
+    assert_eq!(rates.five_minutes, 10.0);
+    assert_eq!(rates.fifteen_minutes, 10.0);
+}
+
+#[test]
+fn throughput_meter_decays_faster_for_shorter_windows() {
+    let start = Instant::now();
+    let mut meter = ThroughputMeter::started_at(start);
+
+    meter.mark_at(50, start);
+    let rates = meter.rates_at(start + Duration::from_secs(5 * 60));
+
+    assert!(rates.one_minute < rates.five_minutes);
+    assert!(rates.five_minutes < rates.fifteen_minutes);
+    assert!(rates.fifteen_minutes < 10.0);
+}
+
+#[test]
+fn counting_collector_counts_and_sums_up() {
+    let collector = CountingMetricsCollector::new();
+    collector.consumer_line_received(10);
+    collector.consumer_line_received(5);
+    collector.consumer_ordering_violation();
+    collector.handler_annotation("orders", 12);
+
+    assert_eq!(collector.count("consumer_line_received"), 2);
+    assert_eq!(collector.total("consumer_line_received"), 15);
+    assert_eq!(collector.count("consumer_ordering_violation"), 1);
+    assert_eq!(collector.total("handler_annotation.orders"), 12);
+    assert_eq!(collector.count("committer_cursor_committed"), 0);
+
+    collector.reset();
+    assert!(collector.names().is_empty());
+}
+
+#[test]
+fn multi_collector_forwards_to_all_collectors() {
+    let a = CountingMetricsCollector::new();
+    let b = CountingMetricsCollector::new();
+    let multi = MultiMetricsCollector::new().with(a.clone()).with(b.clone());
+
+    multi.consumer_batch_line_received(100);
+    multi.committer_cursor_committed(Instant::now());
+
+    for collector in &[a, b] {
+        assert_eq!(collector.total("consumer_batch_line_received"), 100);
+        assert_eq!(collector.count("committer_cursor_committed"), 1);
+    }
+}
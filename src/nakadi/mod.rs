@@ -1,15 +1,17 @@
 /// Describes what to do after a batch has been processed.
 ///
 /// Use to control what should happen next.
+use std::collections::{BTreeMap, HashSet};
 use std::sync::Arc;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::time::Duration;
 use std::thread;
+use std::time::Duration;
 use std::str::FromStr;
 use std::fmt;
 use std::env;
 
 use failure::*;
+use futures::Future;
+use futures::sync::oneshot;
 use serde_json;
 
 pub mod handler;
@@ -24,13 +26,74 @@ pub mod publisher;
 pub mod api_client;
 pub mod events;
 pub mod metrics;
-
-use nakadi::model::SubscriptionId;
+pub mod introspection;
+pub mod quota;
+pub mod scaling;
+pub mod ordering;
+pub mod cluster;
+pub mod response_headers;
+pub mod wire_debug;
+pub mod partition_state;
+pub mod validation;
+pub mod backfill;
+pub mod enrichment;
+pub mod buffer_pool;
+pub mod mailbox;
+pub mod worker_pool;
+pub mod retention;
+pub mod assignment;
+pub mod spool;
+pub mod partitioning;
+pub mod lifecycle;
+pub mod gaps;
+pub mod retry_scheduler;
+pub mod filtering;
+pub mod warm_up;
+pub mod schema_check;
+pub mod commit_policy;
+pub mod adaptive_limits;
+pub mod roundtrip;
+pub mod failover;
+pub mod rebalancing;
+pub mod repartitioning;
+pub mod leasing;
+pub mod stage_timing;
+pub mod batch_stream;
+pub mod compat;
+pub mod testkit;
+
+use nakadi::model::{PartitionId, SubscriptionId};
 use nakadi::api_client::{ApiClient, NakadiApiClient};
-use nakadi::handler::HandlerFactory;
-use nakadi::streaming_client::StreamingClient;
-use auth::ProvidesAccessToken;
+use nakadi::commit_policy::{CommitPolicy, SharedCommitPolicy};
+use nakadi::handler::{HandlerFactory, LossyUtf8HandlerFactory};
+use nakadi::streaming_client::{CustomizesConnect, SharedConnectCustomizer, StreamingClient};
+use auth::{AsyncProvidesToken, BlockingTokenProvider, PrefetchingTokenProvider,
+           ProvidesAccessToken};
 use metrics::{DevNullMetricsCollector, MetricsCollector};
+use nakadi::introspection::{ConfigSummary, ConnectionState, Introspection};
+use nakadi::quota::{QuotaAction, QuotaConfig, QuotaListener, SharedQuotaListener};
+use nakadi::ordering::OrderingValidation;
+use nakadi::mailbox::{DispatchOrder, MailboxConfig, OverflowStrategy};
+use nakadi::cluster::ClusterHandle;
+use nakadi::consumer::ConsumerOutcome;
+use nakadi::validation::{SubscriptionVerification, ValidationReport};
+use nakadi::scaling::{ScalingConfig, ScalingPressureListener, ScalingTargets,
+                      SharedScalingPressureListener};
+use nakadi::retention::{RetentionConfig, RetentionListener, SharedRetentionListener};
+use nakadi::assignment::{AssignmentConfig, AssignmentListener, SharedAssignmentListener};
+use nakadi::consumer::{ConsumerConfig, InfoListener, SharedInfoListener};
+use nakadi::gaps::{GapAction, GapConfig, GapListener, SharedGapListener};
+use nakadi::retry_scheduler::RetryScheduler;
+use nakadi::filtering::EventFilter;
+use nakadi::warm_up::WarmUpConfig;
+use nakadi::adaptive_limits::AdaptiveLimitsConfig;
+use nakadi::failover::{FailoverConfig, NakadiHosts};
+use nakadi::rebalancing::RebalanceConfig;
+use nakadi::leasing::Lease;
+use nakadi::batch_stream::BatchStream;
+use nakadi::repartitioning::{RepartitionConfig, RepartitionListener, SharedRepartitionListener};
+
+pub use nakadi::lifecycle::Lifecycle;
 
 #[cfg(feature = "metrix")]
 use metrix::processor::AggregatesProcessors;
@@ -54,33 +117,60 @@ pub enum CommitStrategy {
     },
 }
 
-#[derive(Clone)]
-pub struct Lifecycle {
-    state: Arc<(AtomicBool, AtomicBool)>,
+/// Defines how a consumer shuts down once the stream has ended or
+/// a stop was requested.
+///
+/// The shutdown always happens in this order:
+///
+/// 1. Stop receiving batches from `Nakadi`
+/// 2. Drain the queues of the workers if `drain_queues` is set.
+/// Otherwise the cursors of the processed batches are committed and
+/// queued batches are abandoned.
+/// 3. Call `BatchHandler::on_shutdown` on every handler
+/// 4. Commit the cursors of all processed batches
+///
+/// If steps 2 and 3 do not finish within `timeout` the remaining
+/// work is abandoned and the cursors of all batches that have not been
+/// processed are logged.
+///
+/// Draining avoids the redelivery of batches already received. If a fast
+/// restart matters more use `ShutdownConfig::commit_first`: Queued batches
+/// are abandoned and the cursors of the processed batches are committed
+/// before waiting for the workers.
+#[derive(Debug, Clone, Copy)]
+pub struct ShutdownConfig {
+    /// Process all batches already received before stopping the workers.
+    ///
+    /// If `false` the processed batches are committed right away and
+    /// queued batches are abandoned.
+    pub drain_queues: bool,
+    /// The maximum time to wait for the workers to stop.
+    pub timeout: Duration,
 }
 
-impl Lifecycle {
-    pub fn abort_requested(&self) -> bool {
-        self.state.0.load(Ordering::Relaxed)
-    }
-
-    pub fn request_abort(&self) {
-        self.state.0.store(true, Ordering::Relaxed)
-    }
-
-    pub fn stopped(&self) {
-        self.state.1.store(false, Ordering::Relaxed)
+impl ShutdownConfig {
+    /// Process all queued batches before stopping.
+    pub fn drain(timeout: Duration) -> ShutdownConfig {
+        ShutdownConfig {
+            drain_queues: true,
+            timeout,
+        }
     }
 
-    pub fn running(&self) -> bool {
-        self.state.1.load(Ordering::Relaxed)
+    /// Commit what has been processed and abandon queued batches.
+    pub fn commit_first(timeout: Duration) -> ShutdownConfig {
+        ShutdownConfig {
+            drain_queues: false,
+            timeout,
+        }
     }
 }
 
-impl Default for Lifecycle {
-    fn default() -> Lifecycle {
-        Lifecycle {
-            state: Arc::new((AtomicBool::new(false), AtomicBool::new(true))),
+impl Default for ShutdownConfig {
+    fn default() -> ShutdownConfig {
+        ShutdownConfig {
+            drain_queues: true,
+            timeout: Duration::from_secs(30),
         }
     }
 }
@@ -141,6 +231,68 @@ impl FromStr for SubscriptionDiscovery {
     }
 }
 
+/// Where the value of a configuration parameter came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum ConfigSource {
+    /// Nothing was set and the default is used
+    Default,
+    /// Read from an environment variable
+    Env,
+    /// Set in code
+    Explicit,
+}
+
+impl fmt::Display for ConfigSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ConfigSource::Default => write!(f, "default"),
+            ConfigSource::Env => write!(f, "env"),
+            ConfigSource::Explicit => write!(f, "explicit"),
+        }
+    }
+}
+
+/// The environment variables read by `NakadionBuilder::from_env`
+/// and the parameters they set.
+const ENV_VARS: &'static [(&'static str, &'static str)] = &[
+    ("NAKADION_STREAM_KEEP_ALIVE_LIMIT", "stream_keep_alive_limit"),
+    ("NAKADION_STREAM_LIMIT", "stream_limit"),
+    ("NAKADION_STREAM_TIMEOUT_SECS", "stream_timeout_secs"),
+    ("NAKADION_BATCH_FLUSH_TIMEOUT_SECS", "batch_flush_timeout_secs"),
+    ("NAKADION_BATCH_LIMIT", "batch_limit"),
+    ("NAKADION_MAX_UNCOMMITED_EVENTS", "max_uncommitted_events"),
+    ("NAKADION_NAKADI_HOST", "nakadi_host"),
+    ("NAKADION_REQUEST_TIMEOUT_MS", "request_timeout_ms"),
+    ("NAKADION_COMMIT_STRATEGY", "commit_strategy"),
+    ("NAKADION_SUBSCRIPTION_DISCOVERY", "subscription_discovery"),
+    (
+        "NAKADION_MIN_IDLE_WORKER_LIFETIME_SECS",
+        "min_idle_worker_lifetime_secs",
+    ),
+    ("NAKADION_HANDLER_TIMEOUT_SECS", "handler_timeout_secs"),
+    ("NAKADION_DRAIN_ON_SHUTDOWN", "drain_on_shutdown"),
+    ("NAKADION_SHUTDOWN_TIMEOUT_SECS", "shutdown_timeout_secs"),
+    ("NAKADION_MAX_EVENTS_PER_HOUR", "max_events_per_hour"),
+    ("NAKADION_MAX_BYTES_PER_HOUR", "max_bytes_per_hour"),
+    ("NAKADION_QUOTA_ACTION", "quota_action"),
+    ("NAKADION_VALIDATE_ORDERING", "validate_ordering"),
+    ("NAKADION_DETECT_GAPS", "detect_gaps"),
+    ("NAKADION_WIRE_DEBUG", "wire_debug"),
+    ("NAKADION_MAX_QUEUED_BYTES", "max_queued_bytes"),
+    (
+        "NAKADION_WORKER_MAILBOX_CAPACITY",
+        "worker_mailbox_capacity",
+    ),
+    (
+        "NAKADION_MAILBOX_OVERFLOW_STRATEGY",
+        "mailbox_overflow_strategy",
+    ),
+    ("NAKADION_DISPATCH_ORDER", "dispatch_order"),
+    ("NAKADION_WORKER_THREADS", "worker_threads"),
+    ("NAKADION_STRICT_STARTUP", "strict_startup"),
+    ("NAKADION_LOSSY_UTF8", "lossy_utf8"),
+];
+
 /// Settings for establishing a connection to `Nakadi`.
 #[derive(Debug, Clone)]
 pub struct NakadionConfig {
@@ -188,17 +340,159 @@ pub struct NakadionConfig {
 
     pub commit_strategy: CommitStrategy,
 
+    /// Decides when cursors are committed instead of `commit_strategy`
+    /// if set.
+    pub commit_policy: Option<SharedCommitPolicy>,
+
     pub subscription_discovery: SubscriptionDiscovery,
 
     pub min_idle_worker_lifetime: Option<Duration>,
+
+    /// The maximum time a handler may work on a batch without reporting
+    /// progress before the stream is aborted. If `None` handlers may take
+    /// as long as they want.
+    pub handler_timeout: Option<Duration>,
+
+    pub shutdown: ShutdownConfig,
+
+    /// Local limits on the amount of data to consume. No limits if `None`.
+    pub quota: Option<QuotaConfig>,
+
+    /// Compute a scaling pressure for autoscalers. Disabled if `None`.
+    pub scaling: Option<ScalingConfig>,
+
+    /// Warn when uncommitted events will soon be deleted due to the
+    /// retention time of their event type. Disabled if `None`.
+    pub retention: Option<RetentionConfig>,
+
+    /// Periodically look at which streams consume which partitions
+    /// of the subscription. Disabled if `None`.
+    pub assignment: Option<AssignmentConfig>,
+
+    /// Periodically check whether the number of partitions of the
+    /// event types changed. Disabled if `None`.
+    pub repartitioning: Option<RepartitionConfig>,
+
+    /// Check that offsets are strictly increasing. Meant for testing
+    /// environments. Disabled if `None`.
+    pub validate_ordering: Option<OrderingValidation>,
+
+    /// Report events skipped between consecutive batches of
+    /// a partition. Disabled if `None`.
+    pub gaps: Option<GapConfig>,
+
+    /// Log all requests to and responses from `Nakadi` on
+    /// the `debug` level. Can be changed at runtime via the
+    /// `wire_debug` module.
+    pub wire_debug: bool,
+
+    /// Stop reading from the stream while the batches received but
+    /// not yet processed take more bytes. Unlimited if `None`.
+    pub max_queued_bytes: Option<usize>,
+
+    /// Bounds the batches queued for each worker.
+    /// Unbounded by default.
+    pub worker_mailbox: MailboxConfig,
+
+    /// Run the workers of all partitions on this many threads.
+    /// Every worker has a thread of its own if `None` which is the default.
+    pub worker_threads: Option<usize>,
+
+    /// Run the preflight checks of the `validation` module on start
+    /// and fail instead of retrying to connect if one of them fails.
+    pub strict_startup: bool,
+
+    /// Replace invalid UTF-8 in the events with `U+FFFD` instead
+    /// of passing it to the handlers.
+    pub lossy_utf8: bool,
+
+    /// Receives the `info` objects `Nakadi` sends along with the batches.
+    /// They are only logged if `None`.
+    pub info_listener: Option<SharedInfoListener>,
+
+    /// Limits the retries of failed commits. Every commit
+    /// retries on its own if `None`.
+    pub commit_retries: Option<RetryScheduler>,
+
+    /// Only events matching the filter are passed to the handlers.
+    /// All events are passed if `None`.
+    pub event_filter: Option<EventFilter>,
+
+    /// Connect with reduced limits first and reconnect with the
+    /// configured limits after warming up. Disabled if `None`.
+    pub warm_up: Option<WarmUpConfig>,
+
+    /// Adapt `batch_limit` and `max_uncommitted_events` to the
+    /// throughput on every reconnect. Disabled if `None`.
+    pub adaptive_limits: Option<AdaptiveLimitsConfig>,
+
+    /// Hosts to fail over to if `nakadi_host` can not be
+    /// connected to. Disabled if `None`.
+    pub failover: Option<FailoverConfig>,
+
+    /// Close streams after a while or when committing gets slow so
+    /// that reconnecting picks up other backends. Disabled if `None`.
+    pub rebalance: Option<RebalanceConfig>,
+
+    /// Keep the last raw batches of each partition for introspection,
+    /// e.g. to see which batches preceded a failure. Disabled if `None`.
+    pub capture_recent_batches: Option<usize>,
+
+    /// Adds query parameters and headers to the requests
+    /// connecting to a stream.
+    pub connect_customizer: Option<SharedConnectCustomizer>,
+
+    /// Where the values of the parameters came from
+    pub sources: BTreeMap<String, ConfigSource>,
 }
 
 pub struct NakadionBuilder {
     pub streaming_client_builder: streaming_client::ConfigBuilder,
     pub request_timeout: Option<Duration>,
     pub commit_strategy: Option<CommitStrategy>,
+    pub commit_policy: Option<SharedCommitPolicy>,
     pub subscription_discovery: Option<SubscriptionDiscovery>,
     pub min_idle_worker_lifetime: Option<Duration>,
+    pub handler_timeout: Option<Duration>,
+    pub drain_on_shutdown: Option<bool>,
+    pub shutdown_timeout: Option<Duration>,
+    pub max_events_per_hour: Option<u64>,
+    pub max_bytes_per_hour: Option<u64>,
+    pub quota_action: Option<QuotaAction>,
+    pub quota_listener: Option<SharedQuotaListener>,
+    pub scaling_targets: Option<ScalingTargets>,
+    pub scaling_interval: Option<Duration>,
+    pub scaling_pressure_listener: Option<SharedScalingPressureListener>,
+    pub retention_warning: Option<Duration>,
+    pub retention_check_interval: Option<Duration>,
+    pub retention_listener: Option<SharedRetentionListener>,
+    pub assignment_interval: Option<Duration>,
+    pub assignment_listener: Option<SharedAssignmentListener>,
+    pub repartition_check_interval: Option<Duration>,
+    pub reconnect_on_repartitioning: Option<bool>,
+    pub repartition_listener: Option<SharedRepartitionListener>,
+    pub validate_ordering: Option<OrderingValidation>,
+    pub detect_gaps: Option<GapAction>,
+    pub gap_listener: Option<SharedGapListener>,
+    pub wire_debug: Option<bool>,
+    pub max_queued_bytes: Option<usize>,
+    pub worker_mailbox_capacity: Option<usize>,
+    pub mailbox_overflow_strategy: Option<OverflowStrategy>,
+    pub dispatch_order: Option<DispatchOrder>,
+    pub worker_threads: Option<usize>,
+    pub strict_startup: Option<bool>,
+    pub lossy_utf8: Option<bool>,
+    pub info_listener: Option<SharedInfoListener>,
+    pub commit_retries: Option<RetryScheduler>,
+    pub event_filter: Option<EventFilter>,
+    pub warm_up: Option<WarmUpConfig>,
+    pub adaptive_limits: Option<AdaptiveLimitsConfig>,
+    pub failover: Option<FailoverConfig>,
+    pub rebalance: Option<RebalanceConfig>,
+    pub capture_recent_batches: Option<usize>,
+    pub connect_customizer: Option<SharedConnectCustomizer>,
+    /// Parameters whose values were read from the environment
+    from_env: HashSet<&'static str>,
 }
 
 impl Default for NakadionBuilder {
@@ -207,8 +501,48 @@ impl Default for NakadionBuilder {
             streaming_client_builder: Default::default(),
             request_timeout: None,
             commit_strategy: None,
+            commit_policy: None,
             subscription_discovery: None,
             min_idle_worker_lifetime: None,
+            handler_timeout: None,
+            drain_on_shutdown: None,
+            shutdown_timeout: None,
+            max_events_per_hour: None,
+            max_bytes_per_hour: None,
+            quota_action: None,
+            quota_listener: None,
+            scaling_targets: None,
+            scaling_interval: None,
+            scaling_pressure_listener: None,
+            retention_warning: None,
+            retention_check_interval: None,
+            retention_listener: None,
+            assignment_interval: None,
+            assignment_listener: None,
+            repartition_check_interval: None,
+            reconnect_on_repartitioning: None,
+            repartition_listener: None,
+            validate_ordering: None,
+            detect_gaps: None,
+            gap_listener: None,
+            wire_debug: None,
+            max_queued_bytes: None,
+            worker_mailbox_capacity: None,
+            mailbox_overflow_strategy: None,
+            dispatch_order: None,
+            worker_threads: None,
+            strict_startup: None,
+            lossy_utf8: None,
+            info_listener: None,
+            commit_retries: None,
+            event_filter: None,
+            warm_up: None,
+            adaptive_limits: None,
+            failover: None,
+            rebalance: None,
+            capture_recent_batches: None,
+            connect_customizer: None,
+            from_env: HashSet::new(),
         }
     }
 }
@@ -218,6 +552,7 @@ impl NakadionBuilder {
     /// connection. If 0 or undefined will send keep alive messages indefinitely.
     pub fn stream_keep_alive_limit(mut self, stream_keep_alive_limit: usize) -> NakadionBuilder {
         self.streaming_client_builder.stream_keep_alive_limit = Some(stream_keep_alive_limit);
+        self.from_env.remove("stream_keep_alive_limit");
         self
     }
     /// Maximum number of `Event`s in this stream (over all partitions being streamed
@@ -228,6 +563,7 @@ impl NakadionBuilder {
     /// * Stream initialization will fail if `stream_limit` is lower than `batch_limit`.
     pub fn stream_limit(mut self, stream_limit: usize) -> NakadionBuilder {
         self.streaming_client_builder.stream_limit = Some(stream_limit);
+        self.from_env.remove("stream_limit");
         self
     }
     /// Maximum time in seconds a stream will live before connection is closed by the
@@ -241,6 +577,7 @@ impl NakadionBuilder {
     /// `batch_flush_timeout`.
     pub fn stream_timeout(mut self, stream_timeout: Duration) -> NakadionBuilder {
         self.streaming_client_builder.stream_timeout = Some(stream_timeout);
+        self.from_env.remove("stream_timeout_secs");
         self
     }
     /// Maximum time in seconds to wait for the flushing of each chunk (per partition).
@@ -251,6 +588,7 @@ impl NakadionBuilder {
     ///  * If 0 or undefined, will assume 30 seconds.
     pub fn batch_flush_timeout(mut self, batch_flush_timeout: Duration) -> NakadionBuilder {
         self.streaming_client_builder.batch_flush_timeout = Some(batch_flush_timeout);
+        self.from_env.remove("batch_flush_timeout_secs");
         self
     }
     /// Maximum number of `Event`s in each chunk (and therefore per partition) of the
@@ -260,6 +598,7 @@ impl NakadionBuilder {
     ///  `batch_flush_timeout`.
     pub fn batch_limit(mut self, batch_limit: usize) -> NakadionBuilder {
         self.streaming_client_builder.batch_limit = Some(batch_limit);
+        self.from_env.remove("batch_limit");
         self
     }
     /// The amount of uncommitted events Nakadi will stream before pausing the stream.
@@ -270,21 +609,36 @@ impl NakadionBuilder {
     /// workers from running dry.
     pub fn max_uncommitted_events(mut self, max_uncommitted_events: usize) -> NakadionBuilder {
         self.streaming_client_builder.max_uncommitted_events = Some(max_uncommitted_events);
+        self.from_env.remove("max_uncommitted_events");
         self
     }
     /// The URI prefix for the Nakadi Host, e.g. "https://my.nakadi.com"
     pub fn nakadi_host<T: Into<String>>(mut self, nakadi_host: T) -> NakadionBuilder {
         self.streaming_client_builder.nakadi_host = Some(nakadi_host.into());
+        self.from_env.remove("nakadi_host");
         self
     }
 
     pub fn request_timeout(mut self, request_timeout: Duration) -> NakadionBuilder {
         self.request_timeout = Some(request_timeout);
+        self.from_env.remove("request_timeout_ms");
         self
     }
 
     pub fn commit_strategy(mut self, commit_strategy: CommitStrategy) -> NakadionBuilder {
         self.commit_strategy = Some(commit_strategy);
+        self.from_env.remove("commit_strategy");
+        self
+    }
+
+    /// Decide when cursors are committed with a custom `CommitPolicy`,
+    /// e.g. one that waits until a downstream system acknowledged the
+    /// events. Takes precedence over the `CommitStrategy`.
+    pub fn commit_policy<P>(mut self, policy: P) -> NakadionBuilder
+    where
+        P: CommitPolicy + Send + Sync + 'static,
+    {
+        self.commit_policy = Some(SharedCommitPolicy::new(policy));
         self
     }
 
@@ -293,6 +647,7 @@ impl NakadionBuilder {
         subscription_discovery: SubscriptionDiscovery,
     ) -> NakadionBuilder {
         self.subscription_discovery = Some(subscription_discovery);
+        self.from_env.remove("subscription_discovery");
         self
     }
 
@@ -301,6 +656,7 @@ impl NakadionBuilder {
         min_idle_worker_lifetime: Option<Duration>,
     ) -> NakadionBuilder {
         self.min_idle_worker_lifetime = min_idle_worker_lifetime;
+        self.from_env.remove("min_idle_worker_lifetime_secs");
         self
     }
 
@@ -309,6 +665,400 @@ impl NakadionBuilder {
         min_idle_worker_lifetime: Duration,
     ) -> NakadionBuilder {
         self.min_idle_worker_lifetime = Some(min_idle_worker_lifetime);
+        self.from_env.remove("min_idle_worker_lifetime_secs");
+        self
+    }
+
+    /// The maximum time a handler may work on a batch without reporting
+    /// progress via its `ProgressReporter` before the stream is aborted.
+    ///
+    /// Disabled by default.
+    pub fn handler_timeout(mut self, handler_timeout: Duration) -> NakadionBuilder {
+        self.handler_timeout = Some(handler_timeout);
+        self.from_env.remove("handler_timeout_secs");
+        self
+    }
+
+    /// Process all batches already received when shutting down.
+    ///
+    /// If false, the processed batches are committed first and batches
+    /// queued for the workers are abandoned on shutdown. This restarts
+    /// faster at the cost of redelivering the abandoned batches.
+    /// The default is `true`.
+    pub fn drain_on_shutdown(mut self, drain_on_shutdown: bool) -> NakadionBuilder {
+        self.drain_on_shutdown = Some(drain_on_shutdown);
+        self.from_env.remove("drain_on_shutdown");
+        self
+    }
+
+    /// The maximum time to wait for the workers to finish when shutting
+    /// down. Afterwards the remaining work is abandoned.
+    ///
+    /// The default is 30 seconds.
+    pub fn shutdown_timeout(mut self, shutdown_timeout: Duration) -> NakadionBuilder {
+        self.shutdown_timeout = Some(shutdown_timeout);
+        self.from_env.remove("shutdown_timeout_secs");
+        self
+    }
+
+    /// The maximum number of events to consume within an hour.
+    ///
+    /// No limit by default.
+    pub fn max_events_per_hour(mut self, max_events_per_hour: u64) -> NakadionBuilder {
+        self.max_events_per_hour = Some(max_events_per_hour);
+        self.from_env.remove("max_events_per_hour");
+        self
+    }
+
+    /// The maximum number of bytes of events to consume within an hour.
+    ///
+    /// No limit by default.
+    pub fn max_bytes_per_hour(mut self, max_bytes_per_hour: u64) -> NakadionBuilder {
+        self.max_bytes_per_hour = Some(max_bytes_per_hour);
+        self.from_env.remove("max_bytes_per_hour");
+        self
+    }
+
+    /// What to do once a quota is exceeded.
+    ///
+    /// The default is `QuotaAction::Alert`.
+    pub fn quota_action(mut self, quota_action: QuotaAction) -> NakadionBuilder {
+        self.quota_action = Some(quota_action);
+        self.from_env.remove("quota_action");
+        self
+    }
+
+    /// Gets notified when a quota is exceeded.
+    pub fn quota_listener<L>(mut self, quota_listener: L) -> NakadionBuilder
+    where
+        L: QuotaListener + Send + Sync + 'static,
+    {
+        self.quota_listener = Some(SharedQuotaListener(Arc::new(quota_listener)));
+        self
+    }
+
+    /// Compute a scaling pressure relative to these targets. The pressure
+    /// is published via the `MetricsCollector`.
+    ///
+    /// Setting this or a `ScalingPressureListener` enables computing
+    /// the scaling pressure.
+    pub fn scaling_targets(mut self, scaling_targets: ScalingTargets) -> NakadionBuilder {
+        self.scaling_targets = Some(scaling_targets);
+        self
+    }
+
+    /// How often to compute the scaling pressure.
+    ///
+    /// The default is 30 seconds.
+    pub fn scaling_interval(mut self, scaling_interval: Duration) -> NakadionBuilder {
+        self.scaling_interval = Some(scaling_interval);
+        self
+    }
+
+    /// Gets notified whenever the scaling pressure has been computed.
+    pub fn scaling_pressure_listener<L>(mut self, listener: L) -> NakadionBuilder
+    where
+        L: ScalingPressureListener + Send + Sync + 'static,
+    {
+        self.scaling_pressure_listener = Some(SharedScalingPressureListener(Arc::new(listener)));
+        self
+    }
+
+    /// Warn if the oldest uncommitted event of a partition will be
+    /// deleted within `warn_within` due to the retention time of its
+    /// event type. The warning is logged and published via the
+    /// `MetricsCollector`.
+    ///
+    /// Setting this or a `RetentionListener` enables the check.
+    pub fn retention_warning(mut self, warn_within: Duration) -> NakadionBuilder {
+        self.retention_warning = Some(warn_within);
+        self
+    }
+
+    /// How often to check for partitions at risk of losing events.
+    ///
+    /// The default is 5 minutes.
+    pub fn retention_check_interval(mut self, interval: Duration) -> NakadionBuilder {
+        self.retention_check_interval = Some(interval);
+        self
+    }
+
+    /// Gets notified after each check for partitions at risk
+    /// of losing events.
+    pub fn retention_listener<L>(mut self, listener: L) -> NakadionBuilder
+    where
+        L: RetentionListener + Send + Sync + 'static,
+    {
+        self.retention_listener = Some(SharedRetentionListener(Arc::new(listener)));
+        self
+    }
+
+    /// How often to look at which streams consume which partitions.
+    /// Partitions that moved to another stream are logged.
+    ///
+    /// Setting this or an `AssignmentListener` enables the monitor.
+    /// The default is 30 seconds.
+    pub fn assignment_interval(mut self, interval: Duration) -> NakadionBuilder {
+        self.assignment_interval = Some(interval);
+        self
+    }
+
+    /// Gets notified whenever the assignment of the partitions
+    /// to streams was looked at.
+    pub fn assignment_listener<L>(mut self, listener: L) -> NakadionBuilder
+    where
+        L: AssignmentListener + Send + Sync + 'static,
+    {
+        self.assignment_listener = Some(SharedAssignmentListener(Arc::new(listener)));
+        self
+    }
+
+    /// How often to check whether the number of partitions of the
+    /// event types of the subscription changed. Changes are logged.
+    ///
+    /// Setting this, `reconnect_on_repartitioning` or a
+    /// `RepartitionListener` enables the check.
+    /// The default is 5 minutes.
+    pub fn repartition_check_interval(mut self, interval: Duration) -> NakadionBuilder {
+        self.repartition_check_interval = Some(interval);
+        self
+    }
+
+    /// Reconnect once the number of partitions of an event type changed
+    /// so that `Nakadi` assigns the new partitions right away.
+    ///
+    /// The default is `false`.
+    pub fn reconnect_on_repartitioning(mut self, reconnect: bool) -> NakadionBuilder {
+        self.reconnect_on_repartitioning = Some(reconnect);
+        self
+    }
+
+    /// Gets notified whenever the number of partitions
+    /// of an event type changed.
+    pub fn repartition_listener<L>(mut self, listener: L) -> NakadionBuilder
+    where
+        L: RepartitionListener + Send + Sync + 'static,
+    {
+        self.repartition_listener = Some(SharedRepartitionListener(Arc::new(listener)));
+        self
+    }
+
+    /// Gets notified about the `info` objects `Nakadi` sends
+    /// along with the batches and keep alive lines.
+    pub fn info_listener<L>(mut self, listener: L) -> NakadionBuilder
+    where
+        L: InfoListener + Send + Sync + 'static,
+    {
+        self.info_listener = Some(SharedInfoListener(Arc::new(listener)));
+        self
+    }
+
+    /// Retry failed commits only with a permit of `scheduler`.
+    ///
+    /// When `Nakadi` has a brownout the commits of all streams fail at
+    /// once. Sharing a scheduler between all consumers of the process
+    /// caps how many retries run at the same time and how fast they start.
+    pub fn commit_retries(mut self, scheduler: RetryScheduler) -> NakadionBuilder {
+        self.commit_retries = Some(scheduler);
+        self
+    }
+
+    /// Only pass events matching `filter` to the handlers.
+    ///
+    /// Meant as a stopgap until `Nakadi` filters subscriptions itself
+    /// since all events are still transferred. The cursors of batches
+    /// without a matching event are committed without calling a handler.
+    pub fn event_filter(mut self, filter: EventFilter) -> NakadionBuilder {
+        self.event_filter = Some(filter);
+        self
+    }
+
+    /// Request smaller batches and fewer uncommitted events for a while
+    /// after each connect so that caches and connection pools can warm up.
+    ///
+    /// Once the warm-up phase is over the stream is closed and the consumer
+    /// reconnects with the configured `batch_limit` and
+    /// `max_uncommitted_events`. Disabled by default.
+    pub fn warm_up(mut self, warm_up: WarmUpConfig) -> NakadionBuilder {
+        self.warm_up = Some(warm_up);
+        self
+    }
+
+    /// Adapt `batch_limit` and `max_uncommitted_events` to the
+    /// throughput of the handlers whenever the consumer reconnects.
+    ///
+    /// The limits stay within the bounds of `adaptive_limits` and never
+    /// exceed the configured `batch_limit` and `max_uncommitted_events`
+    /// unless these are unlimited. A warm-up phase takes precedence over
+    /// the adapted limits. Disabled by default.
+    pub fn adaptive_limits(mut self, adaptive_limits: AdaptiveLimitsConfig) -> NakadionBuilder {
+        self.adaptive_limits = Some(adaptive_limits);
+        self
+    }
+
+    /// Fail over to other hosts of an active/passive `Nakadi` deployment
+    /// if connecting to `nakadi_host` fails repeatedly.
+    ///
+    /// Commits and other requests follow the stream to the host it is
+    /// connected to. Disabled by default.
+    pub fn nakadi_failover(mut self, failover: FailoverConfig) -> NakadionBuilder {
+        self.failover = Some(failover);
+        self
+    }
+
+    /// Close the stream once it reached an age or committing got slow
+    /// and reconnect.
+    ///
+    /// Reconnecting resolves the host again and opens a new connection so
+    /// that rolling replacements of the brokers and changes of the load
+    /// balancing are picked up without restarting the consumer. Disabled
+    /// by default.
+    pub fn rebalance_connections(mut self, rebalance: RebalanceConfig) -> NakadionBuilder {
+        self.rebalance = Some(rebalance);
+        self
+    }
+
+    /// Keep the last `n` raw batches received for each partition.
+    ///
+    /// They are part of the `Introspection` so that the batches that
+    /// preceded a failure can be looked at without consuming them
+    /// again. Since the batches may contain personal data and take
+    /// memory this is disabled by default.
+    pub fn capture_recent_batches(mut self, n: usize) -> NakadionBuilder {
+        self.capture_recent_batches = Some(n);
+        self
+    }
+
+    /// Add query parameters and headers to every request connecting
+    /// to a stream, e.g. hints required by a proxy in front of `Nakadi`.
+    pub fn connect_customizer<C>(mut self, customizer: C) -> NakadionBuilder
+    where
+        C: CustomizesConnect + Send + Sync + 'static,
+    {
+        self.connect_customizer = Some(SharedConnectCustomizer(Arc::new(customizer)));
+        self
+    }
+
+    /// Check that the offsets of each partition are strictly increasing
+    /// and that no events are skipped.
+    ///
+    /// Meant for testing environments. Disabled by default.
+    pub fn validate_ordering(mut self, validation: OrderingValidation) -> NakadionBuilder {
+        self.validate_ordering = Some(validation);
+        self.from_env.remove("validate_ordering");
+        self
+    }
+
+    /// Report when the offset of a partition advanced by more than the
+    /// number of events in a batch. Events were filtered, compacted away
+    /// or lost.
+    ///
+    /// Gaps are logged and published via the `MetricsCollector`.
+    /// `GapAction::Abort` stops the consumer for pipelines that must
+    /// see every event. Setting this or a `GapListener` enables the check.
+    pub fn detect_gaps(mut self, action: GapAction) -> NakadionBuilder {
+        self.detect_gaps = Some(action);
+        self.from_env.remove("detect_gaps");
+        self
+    }
+
+    /// Gets notified about every gap between consecutive batches.
+    pub fn gap_listener<L>(mut self, listener: L) -> NakadionBuilder
+    where
+        L: GapListener + Send + Sync + 'static,
+    {
+        self.gap_listener = Some(SharedGapListener(Arc::new(listener)));
+        self
+    }
+
+    /// Log all requests to and responses from `Nakadi` on the `debug`
+    /// level with the `Authorization` header redacted and bodies truncated.
+    ///
+    /// Disabled by default. Can be changed at runtime via the
+    /// `wire_debug` module.
+    pub fn wire_debug(mut self, wire_debug: bool) -> NakadionBuilder {
+        self.wire_debug = Some(wire_debug);
+        self.from_env.remove("wire_debug");
+        self
+    }
+
+    /// Stop reading from the stream while the batches received but not
+    /// yet processed take more than `max_queued_bytes` bytes.
+    ///
+    /// Limits memory usage when the size of events varies a lot.
+    /// Unlimited by default.
+    pub fn max_queued_bytes(mut self, max_queued_bytes: usize) -> NakadionBuilder {
+        self.max_queued_bytes = Some(max_queued_bytes);
+        self.from_env.remove("max_queued_bytes");
+        self
+    }
+
+    /// The maximum number of batches queued for a single worker.
+    ///
+    /// Keeps a slow partition from taking up all the memory.
+    /// Unbounded by default.
+    pub fn worker_mailbox_capacity(mut self, capacity: usize) -> NakadionBuilder {
+        self.worker_mailbox_capacity = Some(capacity);
+        self.from_env.remove("worker_mailbox_capacity");
+        self
+    }
+
+    /// What to do with a batch for a worker whose mailbox is full.
+    ///
+    /// Defaults to `OverflowStrategy::Block`.
+    pub fn mailbox_overflow_strategy(mut self, strategy: OverflowStrategy) -> NakadionBuilder {
+        self.mailbox_overflow_strategy = Some(strategy);
+        self.from_env.remove("mailbox_overflow_strategy");
+        self
+    }
+
+    /// The order in which batches of different partitions are delivered
+    /// to the workers.
+    ///
+    /// `DispatchOrder::RoundRobin` keeps a hot partition from delaying the
+    /// others when the mailboxes are bounded. Defaults to
+    /// `DispatchOrder::Arrival`.
+    pub fn dispatch_order(mut self, order: DispatchOrder) -> NakadionBuilder {
+        self.dispatch_order = Some(order);
+        self.from_env.remove("dispatch_order");
+        self
+    }
+
+    /// Run the workers of all partitions on a pool of `threads` threads
+    /// instead of a thread per partition.
+    ///
+    /// Useful for subscriptions with hundreds of partitions. A partition
+    /// always stays on the same thread so its batches are still processed
+    /// in order. A slow handler delays the other partitions on its thread.
+    pub fn worker_threads(mut self, threads: usize) -> NakadionBuilder {
+        self.worker_threads = Some(threads);
+        self.from_env.remove("worker_threads");
+        self
+    }
+
+    /// Check on start that the event types and the subscription exist
+    /// and that the subscription can be read.
+    ///
+    /// Starting fails if a check fails. Otherwise a misconfiguration
+    /// only shows in endlessly retried connect attempts.
+    /// Disabled by default.
+    pub fn strict_startup(mut self, strict_startup: bool) -> NakadionBuilder {
+        self.strict_startup = Some(strict_startup);
+        self.from_env.remove("strict_startup");
+        self
+    }
+
+    /// Replace invalid UTF-8 in the events with `U+FFFD` before
+    /// they are passed to the handlers.
+    ///
+    /// Some producers send strings that are not valid UTF-8. Handlers
+    /// deserializing the events fail on them and the partition gets
+    /// stuck. With this the handlers get degraded events instead and
+    /// every repaired batch is reported via the `MetricsCollector`.
+    /// Disabled by default.
+    pub fn lossy_utf8(mut self, lossy_utf8: bool) -> NakadionBuilder {
+        self.lossy_utf8 = Some(lossy_utf8);
+        self.from_env.remove("lossy_utf8");
         self
     }
 
@@ -367,32 +1117,256 @@ impl NakadionBuilder {
             builder
         };
 
-        Ok(builder)
-    }
+        let builder = if let Some(env_val) = env::var("NAKADION_HANDLER_TIMEOUT_SECS").ok() {
+            builder.handler_timeout(Duration::from_secs(env_val
+                .parse::<u64>()
+                .context("Could not parse 'NAKADION_HANDLER_TIMEOUT_SECS'")?))
+        } else {
+            warn!(
+                "Environment variable 'NAKADION_HANDLER_TIMEOUT_SECS' not found. Using \
+                 default."
+            );
+            builder
+        };
 
-    pub fn build_config(self) -> Result<NakadionConfig, Error> {
-        let streaming_client_config = self.streaming_client_builder.build()?;
+        let builder = if let Some(env_val) = env::var("NAKADION_DRAIN_ON_SHUTDOWN").ok() {
+            builder.drain_on_shutdown(env_val
+                .parse::<bool>()
+                .context("Could not parse 'NAKADION_DRAIN_ON_SHUTDOWN'")?)
+        } else {
+            warn!(
+                "Environment variable 'NAKADION_DRAIN_ON_SHUTDOWN' not found. Using \
+                 default."
+            );
+            builder
+        };
 
-        let request_timeout = if let Some(request_timeout) = self.request_timeout {
-            request_timeout
+        let builder = if let Some(env_val) = env::var("NAKADION_SHUTDOWN_TIMEOUT_SECS").ok() {
+            builder.shutdown_timeout(Duration::from_secs(env_val
+                .parse::<u64>()
+                .context("Could not parse 'NAKADION_SHUTDOWN_TIMEOUT_SECS'")?))
         } else {
-            Duration::from_millis(300)
+            warn!(
+                "Environment variable 'NAKADION_SHUTDOWN_TIMEOUT_SECS' not found. Using \
+                 default."
+            );
+            builder
         };
 
-        let commit_strategy = if let Some(commit_strategy) = self.commit_strategy {
-            commit_strategy
+        let builder = if let Some(env_val) = env::var("NAKADION_MAX_EVENTS_PER_HOUR").ok() {
+            builder.max_events_per_hour(env_val
+                .parse::<u64>()
+                .context("Could not parse 'NAKADION_MAX_EVENTS_PER_HOUR'")?)
         } else {
-            CommitStrategy::AllBatches
+            builder
         };
 
-        let subscription_discovery =
-            if let Some(subscription_discovery) = self.subscription_discovery {
-                subscription_discovery
-            } else {
-                return Err(format_err!("Subscription discovery is missing"));
-            };
+        let builder = if let Some(env_val) = env::var("NAKADION_MAX_BYTES_PER_HOUR").ok() {
+            builder.max_bytes_per_hour(env_val
+                .parse::<u64>()
+                .context("Could not parse 'NAKADION_MAX_BYTES_PER_HOUR'")?)
+        } else {
+            builder
+        };
 
-        Ok(NakadionConfig {
+        let builder = if let Some(env_val) = env::var("NAKADION_QUOTA_ACTION").ok() {
+            builder.quota_action(env_val
+                .parse::<QuotaAction>()
+                .context("Could not parse 'NAKADION_QUOTA_ACTION'")?)
+        } else {
+            builder
+        };
+
+        let builder = if let Some(env_val) = env::var("NAKADION_VALIDATE_ORDERING").ok() {
+            builder.validate_ordering(env_val
+                .parse::<OrderingValidation>()
+                .context("Could not parse 'NAKADION_VALIDATE_ORDERING'")?)
+        } else {
+            builder
+        };
+
+        let builder = if let Some(env_val) = env::var("NAKADION_DETECT_GAPS").ok() {
+            builder.detect_gaps(env_val
+                .parse::<GapAction>()
+                .context("Could not parse 'NAKADION_DETECT_GAPS'")?)
+        } else {
+            builder
+        };
+
+        let builder = if let Some(env_val) = env::var("NAKADION_WIRE_DEBUG").ok() {
+            builder.wire_debug(env_val
+                .parse::<bool>()
+                .context("Could not parse 'NAKADION_WIRE_DEBUG'")?)
+        } else {
+            builder
+        };
+
+        let builder = if let Some(env_val) = env::var("NAKADION_MAX_QUEUED_BYTES").ok() {
+            builder.max_queued_bytes(env_val
+                .parse::<usize>()
+                .context("Could not parse 'NAKADION_MAX_QUEUED_BYTES'")?)
+        } else {
+            builder
+        };
+
+        let builder = if let Some(env_val) = env::var("NAKADION_WORKER_MAILBOX_CAPACITY").ok() {
+            builder.worker_mailbox_capacity(env_val
+                .parse::<usize>()
+                .context("Could not parse 'NAKADION_WORKER_MAILBOX_CAPACITY'")?)
+        } else {
+            builder
+        };
+
+        let builder = if let Some(env_val) = env::var("NAKADION_MAILBOX_OVERFLOW_STRATEGY").ok() {
+            builder.mailbox_overflow_strategy(env_val
+                .parse::<OverflowStrategy>()
+                .context("Could not parse 'NAKADION_MAILBOX_OVERFLOW_STRATEGY'")?)
+        } else {
+            builder
+        };
+
+        let builder = if let Some(env_val) = env::var("NAKADION_DISPATCH_ORDER").ok() {
+            builder.dispatch_order(env_val
+                .parse::<DispatchOrder>()
+                .context("Could not parse 'NAKADION_DISPATCH_ORDER'")?)
+        } else {
+            builder
+        };
+
+        let builder = if let Some(env_val) = env::var("NAKADION_WORKER_THREADS").ok() {
+            builder.worker_threads(env_val
+                .parse::<usize>()
+                .context("Could not parse 'NAKADION_WORKER_THREADS'")?)
+        } else {
+            builder
+        };
+
+        let builder = if let Some(env_val) = env::var("NAKADION_STRICT_STARTUP").ok() {
+            builder.strict_startup(env_val
+                .parse::<bool>()
+                .context("Could not parse 'NAKADION_STRICT_STARTUP'")?)
+        } else {
+            builder
+        };
+
+        let mut builder = if let Some(env_val) = env::var("NAKADION_LOSSY_UTF8").ok() {
+            builder.lossy_utf8(env_val
+                .parse::<bool>()
+                .context("Could not parse 'NAKADION_LOSSY_UTF8'")?)
+        } else {
+            builder
+        };
+
+        for &(var, parameter) in ENV_VARS {
+            if env::var(var).is_ok() {
+                builder.from_env.insert(parameter);
+            }
+        }
+
+        Ok(builder)
+    }
+
+    pub fn build_config(self) -> Result<NakadionConfig, Error> {
+        let sources = self.sources();
+        let streaming_client_config = self.streaming_client_builder.build()?;
+
+        let request_timeout = if let Some(request_timeout) = self.request_timeout {
+            request_timeout
+        } else {
+            Duration::from_millis(300)
+        };
+
+        let commit_strategy = if let Some(commit_strategy) = self.commit_strategy {
+            commit_strategy
+        } else {
+            CommitStrategy::AllBatches
+        };
+
+        let subscription_discovery =
+            if let Some(subscription_discovery) = self.subscription_discovery {
+                subscription_discovery
+            } else {
+                return Err(format_err!("Subscription discovery is missing"));
+            };
+
+        let mut shutdown = ShutdownConfig::default();
+        if let Some(drain_on_shutdown) = self.drain_on_shutdown {
+            shutdown.drain_queues = drain_on_shutdown;
+        }
+        if let Some(shutdown_timeout) = self.shutdown_timeout {
+            shutdown.timeout = shutdown_timeout;
+        }
+
+        let quota = if self.max_events_per_hour.is_some() || self.max_bytes_per_hour.is_some() {
+            Some(QuotaConfig {
+                max_events_per_hour: self.max_events_per_hour,
+                max_bytes_per_hour: self.max_bytes_per_hour,
+                action: self.quota_action.unwrap_or(QuotaAction::Alert),
+                listener: self.quota_listener,
+            })
+        } else {
+            None
+        };
+
+        let scaling =
+            if self.scaling_targets.is_some() || self.scaling_pressure_listener.is_some() {
+                Some(ScalingConfig {
+                    targets: self.scaling_targets.unwrap_or_default(),
+                    interval: self.scaling_interval
+                        .unwrap_or_else(|| Duration::from_secs(30)),
+                    listener: self.scaling_pressure_listener,
+                })
+            } else {
+                None
+            };
+
+        let retention = if self.retention_warning.is_some() || self.retention_listener.is_some() {
+            Some(RetentionConfig {
+                warn_within: self.retention_warning
+                    .unwrap_or_else(|| Duration::from_secs(60 * 60)),
+                interval: self.retention_check_interval
+                    .unwrap_or_else(|| Duration::from_secs(5 * 60)),
+                listener: self.retention_listener,
+            })
+        } else {
+            None
+        };
+
+        let assignment =
+            if self.assignment_interval.is_some() || self.assignment_listener.is_some() {
+                Some(AssignmentConfig {
+                    interval: self.assignment_interval
+                        .unwrap_or_else(|| Duration::from_secs(30)),
+                    listener: self.assignment_listener,
+                })
+            } else {
+                None
+            };
+
+        let repartitioning = if self.repartition_check_interval.is_some()
+            || self.reconnect_on_repartitioning == Some(true)
+            || self.repartition_listener.is_some()
+        {
+            Some(RepartitionConfig {
+                interval: self.repartition_check_interval
+                    .unwrap_or_else(|| Duration::from_secs(5 * 60)),
+                reconnect: self.reconnect_on_repartitioning.unwrap_or(false),
+                listener: self.repartition_listener,
+            })
+        } else {
+            None
+        };
+
+        let gaps = if self.detect_gaps.is_some() || self.gap_listener.is_some() {
+            Some(GapConfig {
+                action: self.detect_gaps.unwrap_or(GapAction::Report),
+                listener: self.gap_listener,
+            })
+        } else {
+            None
+        };
+
+        Ok(NakadionConfig {
             stream_keep_alive_limit: streaming_client_config.stream_keep_alive_limit,
             stream_limit: streaming_client_config.stream_limit,
             stream_timeout: streaming_client_config.stream_timeout,
@@ -401,12 +1375,111 @@ impl NakadionBuilder {
             max_uncommitted_events: streaming_client_config.max_uncommitted_events,
             request_timeout,
             commit_strategy,
+            commit_policy: self.commit_policy,
             subscription_discovery,
             nakadi_host: streaming_client_config.nakadi_host,
             min_idle_worker_lifetime: self.min_idle_worker_lifetime,
+            handler_timeout: self.handler_timeout,
+            shutdown,
+            quota,
+            scaling,
+            retention,
+            assignment,
+            repartitioning,
+            validate_ordering: self.validate_ordering,
+            gaps,
+            wire_debug: self.wire_debug.unwrap_or(false),
+            max_queued_bytes: self.max_queued_bytes,
+            worker_mailbox: MailboxConfig {
+                capacity: self.worker_mailbox_capacity,
+                overflow: self.mailbox_overflow_strategy
+                    .unwrap_or(OverflowStrategy::Block),
+                dispatch: self.dispatch_order.unwrap_or(DispatchOrder::Arrival),
+            },
+            worker_threads: self.worker_threads,
+            strict_startup: self.strict_startup.unwrap_or(false),
+            lossy_utf8: self.lossy_utf8.unwrap_or(false),
+            info_listener: self.info_listener,
+            commit_retries: self.commit_retries,
+            event_filter: self.event_filter,
+            warm_up: self.warm_up,
+            adaptive_limits: self.adaptive_limits,
+            failover: self.failover,
+            rebalance: self.rebalance,
+            capture_recent_batches: self.capture_recent_batches,
+            connect_customizer: self.connect_customizer,
+            sources,
         })
     }
 
+    fn sources(&self) -> BTreeMap<String, ConfigSource> {
+        let streaming = &self.streaming_client_builder;
+        let is_set = [
+            (
+                "stream_keep_alive_limit",
+                streaming.stream_keep_alive_limit.is_some(),
+            ),
+            ("stream_limit", streaming.stream_limit.is_some()),
+            ("stream_timeout_secs", streaming.stream_timeout.is_some()),
+            (
+                "batch_flush_timeout_secs",
+                streaming.batch_flush_timeout.is_some(),
+            ),
+            ("batch_limit", streaming.batch_limit.is_some()),
+            (
+                "max_uncommitted_events",
+                streaming.max_uncommitted_events.is_some(),
+            ),
+            ("nakadi_host", streaming.nakadi_host.is_some()),
+            ("request_timeout_ms", self.request_timeout.is_some()),
+            ("commit_strategy", self.commit_strategy.is_some()),
+            (
+                "subscription_discovery",
+                self.subscription_discovery.is_some(),
+            ),
+            (
+                "min_idle_worker_lifetime_secs",
+                self.min_idle_worker_lifetime.is_some(),
+            ),
+            ("handler_timeout_secs", self.handler_timeout.is_some()),
+            ("drain_on_shutdown", self.drain_on_shutdown.is_some()),
+            ("shutdown_timeout_secs", self.shutdown_timeout.is_some()),
+            ("max_events_per_hour", self.max_events_per_hour.is_some()),
+            ("max_bytes_per_hour", self.max_bytes_per_hour.is_some()),
+            ("quota_action", self.quota_action.is_some()),
+            ("validate_ordering", self.validate_ordering.is_some()),
+            ("detect_gaps", self.detect_gaps.is_some()),
+            ("wire_debug", self.wire_debug.is_some()),
+            ("max_queued_bytes", self.max_queued_bytes.is_some()),
+            (
+                "worker_mailbox_capacity",
+                self.worker_mailbox_capacity.is_some(),
+            ),
+            (
+                "mailbox_overflow_strategy",
+                self.mailbox_overflow_strategy.is_some(),
+            ),
+            ("dispatch_order", self.dispatch_order.is_some()),
+            ("worker_threads", self.worker_threads.is_some()),
+            ("strict_startup", self.strict_startup.is_some()),
+            ("lossy_utf8", self.lossy_utf8.is_some()),
+        ];
+
+        is_set
+            .iter()
+            .map(|&(parameter, is_set)| {
+                let source = if self.from_env.contains(parameter) {
+                    ConfigSource::Env
+                } else if is_set {
+                    ConfigSource::Explicit
+                } else {
+                    ConfigSource::Default
+                };
+                (parameter.to_string(), source)
+            })
+            .collect()
+    }
+
     pub fn build_and_start<HF, P>(
         self,
         handler_factory: HF,
@@ -423,6 +1496,48 @@ impl NakadionBuilder {
         )
     }
 
+    /// Start consuming with tokens of an `AsyncProvidesToken`.
+    ///
+    /// The tokens are fetched ahead of time by a `PrefetchingTokenProvider`
+    /// which refreshes them after `refresh_interval`. Connecting to
+    /// `Nakadi` and committing do not wait for a refresh.
+    pub fn build_and_start_with_async_token_provider<HF, P>(
+        self,
+        handler_factory: HF,
+        access_token_provider: P,
+        refresh_interval: Duration,
+    ) -> Result<Nakadion, Error>
+    where
+        HF: HandlerFactory + Sync + Send + 'static,
+        P: AsyncProvidesToken + Send + Sync + 'static,
+    {
+        self.build_and_start(
+            handler_factory,
+            BlockingTokenProvider(PrefetchingTokenProvider::new(
+                access_token_provider,
+                refresh_interval,
+            )),
+        )
+    }
+
+    /// Start consuming and take the batches from the returned
+    /// `BatchStream` instead of handling them with a `BatchHandler`.
+    ///
+    /// Up to `buffer` batches plus one batch per partition
+    /// are queued until they are taken from the stream.
+    pub fn build_and_start_batch_stream<P>(
+        self,
+        access_token_provider: P,
+        buffer: usize,
+    ) -> Result<(Nakadion, BatchStream), Error>
+    where
+        P: ProvidesAccessToken + Send + Sync + 'static,
+    {
+        let (handler_factory, batch_stream) = batch_stream::batch_stream(buffer);
+        let nakadion = self.build_and_start(handler_factory, access_token_provider)?;
+        Ok((nakadion, batch_stream))
+    }
+
     pub fn build_and_start_with_metrics<HF, P, M>(
         self,
         handler_factory: HF,
@@ -468,8 +1583,19 @@ impl NakadionBuilder {
     }
 }
 
+/// The outcome of `Nakadion::run_to_completion`.
+#[derive(Debug, Clone)]
+pub struct CompletionSummary {
+    pub batches_committed: u64,
+    /// Only counts events of batches where the handler reported the number
+    /// of events processed.
+    pub events_committed: u64,
+    pub duration: Duration,
+}
+
 pub struct Nakadion {
     guard: Arc<DropGuard>,
+    config_summary: Option<ConfigSummary>,
 }
 
 impl Nakadion {
@@ -488,18 +1614,50 @@ impl Nakadion {
         HF: HandlerFactory + Sync + Send + 'static,
         M: MetricsCollector + Clone + Send + Sync + 'static,
     {
-        let consumer = consumer::Consumer::start(
+        Nakadion::start_with_config(
+            subscription_id,
+            streaming_client,
+            api_client,
+            handler_factory,
+            metrics_collector,
+            ConsumerConfig {
+                commit_policy: commit_strategy.into(),
+                min_idle_worker_lifetime,
+                ..ConsumerConfig::default()
+            },
+        )
+    }
+
+    /// Start consuming with all settings of the consumer given
+    /// in `consumer_config`.
+    pub fn start_with_config<HF, C, A, M>(
+        subscription_id: SubscriptionId,
+        streaming_client: C,
+        api_client: A,
+        handler_factory: HF,
+        metrics_collector: M,
+        consumer_config: ConsumerConfig,
+    ) -> Result<Nakadion, Error>
+    where
+        C: StreamingClient + Clone + Sync + Send + 'static,
+        A: ApiClient + Clone + Sync + Send + 'static,
+        HF: HandlerFactory + Sync + Send + 'static,
+        M: MetricsCollector + Clone + Send + Sync + 'static,
+    {
+        let consumer = consumer::Consumer::start_with_config(
             streaming_client,
             api_client,
             subscription_id,
             handler_factory,
-            commit_strategy,
             metrics_collector,
-            min_idle_worker_lifetime,
+            consumer_config,
         );
 
         let guard = Arc::new(DropGuard { consumer });
-        Ok(Nakadion { guard })
+        Ok(Nakadion {
+            guard,
+            config_summary: None,
+        })
     }
 
     pub fn start<HF, P, M>(
@@ -508,6 +1666,197 @@ impl Nakadion {
         access_token_provider: P,
         metrics_collector: M,
     ) -> Result<Nakadion, Error>
+    where
+        HF: HandlerFactory + Sync + Send + 'static,
+        P: ProvidesAccessToken + Send + Sync + 'static,
+        M: MetricsCollector + Clone + Send + Sync + 'static,
+    {
+        Nakadion::start_from_config(
+            config,
+            handler_factory,
+            access_token_provider,
+            metrics_collector,
+            false,
+        )
+    }
+
+    /// Start consuming from a cluster of a `ClusterRegistry`.
+    ///
+    /// The host of the cluster takes precedence over the host of
+    /// the config.
+    pub fn start_on_cluster<HF, M>(
+        mut config: NakadionConfig,
+        cluster: &ClusterHandle,
+        handler_factory: HF,
+        metrics_collector: M,
+    ) -> Result<Nakadion, Error>
+    where
+        HF: HandlerFactory + Sync + Send + 'static,
+        M: MetricsCollector + Clone + Send + Sync + 'static,
+    {
+        config.nakadi_host = cluster.nakadi_host().to_string();
+
+        let api_client = cluster.api_client();
+        let streaming_client =
+            cluster.streaming_client(streaming_client_config(&config), metrics_collector.clone());
+
+        info!("Consuming from cluster '{}'", cluster.name());
+
+        Nakadion::start_with_clients(
+            config,
+            api_client,
+            streaming_client,
+            handler_factory,
+            metrics_collector,
+            false,
+        )
+    }
+
+    /// Check the configuration, the access token, the subscription and
+    /// the authorization to consume from it without consuming.
+    ///
+    /// A subscription that would be created on start is not created.
+    /// Only fails if no client could be created. Everything else is
+    /// part of the report.
+    pub fn validate<P>(
+        config: &NakadionConfig,
+        access_token_provider: P,
+    ) -> Result<ValidationReport, Error>
+    where
+        P: ProvidesAccessToken + Send + Sync + 'static,
+    {
+        let access_token_provider = Arc::new(access_token_provider);
+
+        let api_client = NakadiApiClient::with_shared_access_token_provider(
+            api_client::Config {
+                nakadi_host: config.nakadi_host.clone(),
+                request_timeout: config.request_timeout,
+            },
+            access_token_provider.clone(),
+        )?;
+
+        let report = validation::validate(config, &api_client, &*access_token_provider);
+        report.log();
+        Ok(report)
+    }
+
+    /// Connect to the subscription of `config` for a moment and report
+    /// whether a batch or keep alive line arrived, how long it took and
+    /// which partitions were assigned.
+    ///
+    /// Nothing is committed and a subscription that does not exist yet
+    /// is not created. Meant for deployment pipelines. Fails only if no
+    /// client could be created or the subscription could not be found.
+    pub fn verify_subscription<P>(
+        config: &NakadionConfig,
+        access_token_provider: P,
+    ) -> Result<SubscriptionVerification, Error>
+    where
+        P: ProvidesAccessToken + Send + Sync + 'static,
+    {
+        let access_token_provider = Arc::new(access_token_provider);
+
+        let api_client = NakadiApiClient::with_shared_access_token_provider(
+            api_client::Config {
+                nakadi_host: config.nakadi_host.clone(),
+                request_timeout: config.request_timeout,
+            },
+            access_token_provider.clone(),
+        )?;
+
+        // Keep alive lines are sent after the flush timeout
+        let mut streaming_config = streaming_client_config(config);
+        streaming_config.batch_limit = 1;
+        streaming_config.batch_flush_timeout = Duration::from_secs(1);
+        let streaming_client =
+            streaming_client::NakadiStreamingClient::with_shared_access_token_provider(
+                streaming_config,
+                access_token_provider,
+                DevNullMetricsCollector,
+            )?;
+
+        let subscription_id = match config.subscription_discovery {
+            SubscriptionDiscovery::Id(ref id) => id.clone(),
+            SubscriptionDiscovery::OwningApplication(ref app, ref event_types) => {
+                let event_types: Vec<&str> = event_types.iter().map(|et| et.as_str()).collect();
+                match api_client
+                    .list_subscriptions(Some(app), &event_types)
+                    .collect_all()?
+                    .into_iter()
+                    .next()
+                {
+                    Some(subscription) => subscription.id,
+                    None => bail!("There is no subscription of {} yet", app),
+                }
+            }
+        };
+
+        let verification =
+            validation::verify_subscription(&streaming_client, &api_client, &subscription_id);
+        verification.log();
+        Ok(verification)
+    }
+
+    /// Consume a finite stream until `Nakadi` closes it, commit everything
+    /// and return a summary instead of reconnecting.
+    ///
+    /// This blocks the current thread and is meant for batch jobs.
+    /// `stream_limit` or `stream_timeout` must be set in the config.
+    /// If the connection breaks before `Nakadi` closes the stream
+    /// a new stream will be consumed.
+    pub fn run_to_completion<HF, P, M>(
+        config: NakadionConfig,
+        handler_factory: HF,
+        access_token_provider: P,
+        metrics_collector: M,
+    ) -> Result<CompletionSummary, Error>
+    where
+        HF: HandlerFactory + Sync + Send + 'static,
+        P: ProvidesAccessToken + Send + Sync + 'static,
+        M: MetricsCollector + Clone + Send + Sync + 'static,
+    {
+        if config.stream_limit == 0 && config.stream_timeout == Duration::from_secs(0) {
+            return Err(format_err!(
+                "Running to completion requires 'stream_limit' or 'stream_timeout' to be set"
+            ));
+        }
+
+        let started = ::std::time::Instant::now();
+        let nakadion = Nakadion::start_from_config(
+            config,
+            handler_factory,
+            access_token_provider,
+            metrics_collector,
+            true,
+        )?;
+
+        nakadion.block_until_stopped();
+
+        match nakadion.outcome() {
+            Some(ConsumerOutcome::AuthBroken(msg)) => {
+                return Err(format_err!("Authentication broken: {}", msg))
+            }
+            Some(ConsumerOutcome::ConnectFailed(msg)) => {
+                return Err(format_err!("Could not connect: {}", msg))
+            }
+            _ => (),
+        }
+
+        let commits = nakadion.introspection().commits;
+        Ok(CompletionSummary {
+            batches_committed: commits.batches_committed,
+            events_committed: commits.events_committed,
+            duration: started.elapsed(),
+        })
+    }
+
+    fn start_from_config<HF, P, M>(
+        config: NakadionConfig,
+        handler_factory: HF,
+        access_token_provider: P,
+        metrics_collector: M,
+        stop_when_stream_ends: bool,
+    ) -> Result<Nakadion, Error>
     where
         HF: HandlerFactory + Sync + Send + 'static,
         P: ProvidesAccessToken + Send + Sync + 'static,
@@ -522,6 +1871,86 @@ impl Nakadion {
             },
             access_token_provider.clone(),
         )?;
+        let api_client = match config.commit_retries {
+            Some(ref scheduler) => api_client.with_commit_retries(scheduler.clone()),
+            None => api_client,
+        };
+
+        let streaming_client =
+            streaming_client::NakadiStreamingClient::with_shared_access_token_provider(
+                streaming_client_config(&config),
+                access_token_provider,
+                metrics_collector.clone(),
+            )?;
+        let streaming_client = match config.connect_customizer {
+            Some(ref customizer) => streaming_client.with_connect_customizer(customizer.clone()),
+            None => streaming_client,
+        };
+
+        let (api_client, streaming_client) = match config.failover {
+            Some(ref failover) => {
+                let hosts = NakadiHosts::new(config.nakadi_host.clone(), failover.clone());
+                (
+                    api_client.with_failover(hosts.clone()),
+                    streaming_client.with_failover(hosts),
+                )
+            }
+            None => (api_client, streaming_client),
+        };
+
+        Nakadion::start_with_clients(
+            config,
+            api_client,
+            streaming_client,
+            handler_factory,
+            metrics_collector,
+            stop_when_stream_ends,
+        )
+    }
+
+    fn start_with_clients<HF, M>(
+        config: NakadionConfig,
+        api_client: NakadiApiClient,
+        streaming_client: streaming_client::NakadiStreamingClient<M>,
+        handler_factory: HF,
+        metrics_collector: M,
+        stop_when_stream_ends: bool,
+    ) -> Result<Nakadion, Error>
+    where
+        HF: HandlerFactory + Sync + Send + 'static,
+        M: MetricsCollector + Clone + Send + Sync + 'static,
+    {
+        let config_summary = ConfigSummary::from(&config);
+        config_summary.log();
+
+        if config.wire_debug {
+            wire_debug::enable();
+        }
+
+        let handler_factory = LossyUtf8HandlerFactory::new(
+            handler_factory,
+            config.lossy_utf8,
+            metrics_collector.clone(),
+        );
+
+        if config.strict_startup {
+            let report = validation::preflight_checks(&config, &api_client);
+            report.log();
+            if !report.is_ok() {
+                let failures: Vec<String> = report
+                    .failures()
+                    .iter()
+                    .map(|check| {
+                        format!(
+                            "{}: {}",
+                            check.name,
+                            check.message.as_ref().map(|m| m.as_str()).unwrap_or("")
+                        )
+                    })
+                    .collect();
+                bail!("Preflight checks failed: {}", failures.join("; "));
+            }
+        }
 
         info!(
             "Discovering subscription with {}",
@@ -535,6 +1964,8 @@ impl Nakadion {
                     owning_application: app,
                     event_types: event_types,
                     read_from: None,
+                    initial_cursors: None,
+                    unknown_fields: Default::default(),
                 };
 
                 match api_client.create_subscription(&request)? {
@@ -550,53 +1981,192 @@ impl Nakadion {
             }
         };
 
-        let streaming_client_config = streaming_client::Config {
-            stream_keep_alive_limit: config.stream_keep_alive_limit,
-            stream_limit: config.stream_limit,
-            stream_timeout: config.stream_timeout,
-            batch_flush_timeout: config.batch_flush_timeout,
-            batch_limit: config.batch_limit,
-            max_uncommitted_events: config.max_uncommitted_events,
-            nakadi_host: config.nakadi_host,
+        let commit_strategy = config.commit_strategy;
+        let consumer_config = ConsumerConfig {
+            commit_policy: config
+                .commit_policy
+                .clone()
+                .unwrap_or_else(|| commit_strategy.into()),
+            min_idle_worker_lifetime: config.min_idle_worker_lifetime,
+            handler_timeout: config.handler_timeout,
+            shutdown: config.shutdown,
+            quota: config.quota,
+            validate_ordering: config.validate_ordering,
+            max_queued_bytes: config.max_queued_bytes,
+            mailbox_config: config.worker_mailbox,
+            worker_threads: config.worker_threads,
+            info_listener: config.info_listener,
+            gaps: config.gaps,
+            event_filter: config.event_filter,
+            warm_up: config.warm_up,
+            adaptive_limits: config.adaptive_limits,
+            rebalance: config.rebalance,
+            capture_recent_batches: config.capture_recent_batches,
+            stop_when_stream_ends,
         };
 
-        let streaming_client =
-            streaming_client::NakadiStreamingClient::with_shared_access_token_provider(
-                streaming_client_config,
-                access_token_provider,
-                metrics_collector.clone(),
-            )?;
-
-        Nakadion::start_with(
-            subscription_id,
+        let mut nakadion = Nakadion::start_with_config(
+            subscription_id.clone(),
             streaming_client,
-            api_client,
+            api_client.clone(),
             handler_factory,
-            config.commit_strategy,
-            metrics_collector,
-            config.min_idle_worker_lifetime,
-        )
+            metrics_collector.clone(),
+            consumer_config,
+        )?;
+        nakadion.config_summary = Some(config_summary);
+
+        if let Some(retention_config) = config.retention {
+            retention::start_monitor(
+                api_client.clone(),
+                subscription_id.clone(),
+                nakadion.guard.consumer.clone(),
+                retention_config,
+                metrics_collector.clone(),
+            );
+        }
+
+        if let Some(assignment_config) = config.assignment {
+            assignment::start_monitor(
+                api_client.clone(),
+                subscription_id.clone(),
+                nakadion.guard.consumer.clone(),
+                assignment_config,
+            );
+        }
+
+        if let Some(repartition_config) = config.repartitioning {
+            repartitioning::start_monitor(
+                api_client.clone(),
+                subscription_id.clone(),
+                nakadion.guard.consumer.clone(),
+                repartition_config,
+            );
+        }
+
+        if let Some(scaling_config) = config.scaling {
+            scaling::start_monitor(
+                api_client,
+                subscription_id,
+                nakadion.guard.consumer.clone(),
+                scaling_config,
+                metrics_collector,
+            );
+        }
+
+        Ok(nakadion)
     }
 
     pub fn running(&self) -> bool {
         self.guard.running()
     }
 
+    /// Stop consuming and wait until all threads of `Nakadion` stopped.
+    ///
+    /// When this returns the queued batches have been handled according
+    /// to the `ShutdownConfig` and the cursors have been committed.
+    /// Workers that did not stop within the shutdown timeout are
+    /// abandoned. Must not be called from within a handler since it
+    /// would wait for itself. Use `request_stop` there.
     pub fn stop(&self) {
         self.guard.consumer.stop()
     }
 
+    /// Ask `Nakadion` to stop without waiting for it.
+    pub fn request_stop(&self) {
+        self.guard.consumer.request_stop()
+    }
+
+    /// Renew an acquired `Lease` while `Nakadion` is running and
+    /// release it once `Nakadion` stopped.
+    ///
+    /// `Nakadion` stops if the lease is lost.
+    pub fn hold_lease(&self, lease: Lease) {
+        lease.hold_while_running(&self.guard.consumer)
+    }
+
+    /// Stop processing the batches of a partition while the other
+    /// partitions keep flowing.
+    ///
+    /// Batches of the partition are held back until it is resumed.
+    /// Since their cursors do not get committed `Nakadi` will stop
+    /// sending batches for the partition and might eventually close
+    /// the stream. The held back batches will then be sent again.
+    pub fn pause_partition(&self, partition: PartitionId) {
+        self.guard.consumer.pause_partition(partition)
+    }
+
+    /// Continue processing a paused partition.
+    pub fn resume_partition(&self, partition: &PartitionId) {
+        self.guard.consumer.resume_partition(partition)
+    }
+
+    /// The partitions currently paused
+    pub fn paused_partitions(&self) -> Vec<PartitionId> {
+        self.guard.consumer.paused_partitions()
+    }
+
+    /// Get a snapshot of the current state of `Nakadion` e.g. to
+    /// be served by an admin endpoint.
+    ///
+    /// The snapshot can be serialized.
+    pub fn introspection(&self) -> Introspection {
+        self.guard
+            .consumer
+            .introspect(self.config_summary.clone())
+    }
+
+    /// Whether `Nakadion` is connected, connecting, backing off because
+    /// `Nakadi` is overloaded or can not reach `Nakadi`.
+    ///
+    /// Cheap enough to be used for health checks.
+    pub fn connection_state(&self) -> ConnectionState {
+        self.guard.consumer.connection_state()
+    }
+
+    /// Why `Nakadion` stopped. `None` while it is running.
+    pub fn outcome(&self) -> Option<ConsumerOutcome> {
+        self.guard.consumer.outcome()
+    }
+
+    /// Wait until all threads of `Nakadion` stopped.
     pub fn block_until_stopped(&self) {
-        self.block_until_stopped_with_interval(Duration::from_secs(1))
+        self.guard.consumer.wait_until_stopped()
     }
 
+    /// Wait until all threads of `Nakadion` stopped.
+    ///
+    /// `Nakadion` is notified when its threads stopped so
+    /// the interval is only an upper bound for each wait.
     pub fn block_until_stopped_with_interval(&self, poll_interval: Duration) {
-        while self.running() {
-            thread::sleep(poll_interval);
+        while !self.guard.consumer.wait_until_stopped_timeout(poll_interval) {}
+    }
+
+    /// Ask `Nakadion` to stop and get a future that resolves once
+    /// all of its threads stopped or `deadline` elapsed.
+    ///
+    /// Resolves to true if all threads stopped in time. Then the queued
+    /// batches have been handled according to the `ShutdownConfig` and
+    /// the cursors have been committed. The waiting is done on a thread
+    /// of its own.
+    pub fn shutdown(&self, deadline: Duration) -> ShutdownFuture {
+        self.request_stop();
+        let consumer = self.guard.consumer.clone();
+        let (sender, stopped) = oneshot::channel();
+        let spawned = thread::Builder::new()
+            .name("nakadion-shutdown".to_string())
+            .spawn(move || {
+                let _ = sender.send(consumer.wait_until_stopped_timeout(deadline));
+            });
+        if let Err(err) = spawned {
+            warn!("Could not start a thread to wait for the shutdown: {}", err);
         }
+        Box::new(stopped.or_else(|_| Ok::<bool, ()>(false)))
     }
 }
 
+/// Resolves to true once `Nakadion` stopped before the deadline
+pub type ShutdownFuture = Box<Future<Item = bool, Error = ()> + Send>;
+
 struct DropGuard {
     consumer: consumer::Consumer,
 }
@@ -612,3 +2182,20 @@ impl Drop for DropGuard {
         self.consumer.stop()
     }
 }
+
+/// The whole milliseconds of a duration
+pub(crate) fn millis(d: Duration) -> u64 {
+    d.as_secs() * 1000 + u64::from(d.subsec_nanos() / 1_000_000)
+}
+
+fn streaming_client_config(config: &NakadionConfig) -> streaming_client::Config {
+    streaming_client::Config {
+        stream_keep_alive_limit: config.stream_keep_alive_limit,
+        stream_limit: config.stream_limit,
+        stream_timeout: config.stream_timeout,
+        batch_flush_timeout: config.batch_flush_timeout,
+        batch_limit: config.batch_limit,
+        max_uncommitted_events: config.max_uncommitted_events,
+        nakadi_host: config.nakadi_host.clone(),
+    }
+}
@@ -1,6 +1,8 @@
 //! Some common types
+use std::cmp::Ordering;
 use std::fmt;
 
+use serde_json;
 use uuid::Uuid;
 
 /// A `SubscriptionId` is used to guarantee a continous flow of events for a
@@ -15,7 +17,7 @@ impl fmt::Display for SubscriptionId {
 }
 
 /// A partition id that comes with a `Cursor`
-#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct PartitionId(pub String);
 
 impl fmt::Display for PartitionId {
@@ -26,7 +28,7 @@ impl fmt::Display for PartitionId {
 
 /// A `StreamId` identifies a subscription. It must be provided for checkpointing with
 /// a `Cursor`.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct StreamId(pub String);
 
 impl StreamId {
@@ -64,6 +66,103 @@ impl Default for FlowId {
     }
 }
 
+/// The offset of a partition as sent by `Nakadi`
+///
+/// Offsets are opaque strings. `BEGIN` is before the first event and
+/// `END` after the last one. Other offsets are zero padded positions,
+/// optionally prefixed with a timeline like `001-0001-000000000000000009`.
+/// Offsets must not be compared as plain strings since padding and
+/// timelines differ between event types and `Nakadi` versions.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Offset(pub String);
+
+impl Offset {
+    pub const BEGIN: &'static str = "BEGIN";
+    pub const END: &'static str = "END";
+
+    pub fn new<T: Into<String>>(offset: T) -> Offset {
+        Offset(offset.into())
+    }
+
+    pub fn is_begin(&self) -> bool {
+        self.0 == Offset::BEGIN
+    }
+
+    pub fn is_end(&self) -> bool {
+        self.0 == Offset::END
+    }
+
+    /// Returns true for offsets before the first event of a partition.
+    ///
+    /// Besides `BEGIN` this is the position `-1` which `Nakadi`
+    /// reports as the newest offset of an empty partition,
+    /// e.g. `001-0001--1`.
+    pub fn is_before_first_event(&self) -> bool {
+        self.is_begin() || self.0 == "-1" || self.0.ends_with("--1")
+    }
+
+    /// Compares two offsets of the same partition.
+    ///
+    /// Offsets on different timelines are compared by their length
+    /// since a later timeline has a longer prefix.
+    pub fn compare(&self, other: &Offset) -> Option<Ordering> {
+        if self.0 == other.0 {
+            return Some(Ordering::Equal);
+        }
+        if self.is_begin() || other.is_end() {
+            return Some(Ordering::Less);
+        }
+        if self.is_end() || other.is_begin() {
+            return Some(Ordering::Greater);
+        }
+        match (self.position(), other.position()) {
+            (Some((timeline_a, a)), Some((timeline_b, b))) if timeline_a == timeline_b => {
+                Some(a.cmp(&b))
+            }
+            // Zero padded offsets of the same length
+            _ if self.0.len() == other.0.len() => Some(self.0.cmp(&other.0)),
+            _ => Some(self.0.len().cmp(&other.0.len())),
+        }
+    }
+
+    /// Returns true if this offset is known to be after `other`.
+    pub fn is_after(&self, other: &Offset) -> bool {
+        self.compare(other) == Some(Ordering::Greater)
+    }
+
+    /// The number of events from `earlier` to this offset.
+    ///
+    /// Returns `None` unless both offsets are positions on the same
+    /// timeline and this offset is after `earlier`.
+    pub fn distance_from(&self, earlier: &Offset) -> Option<u64> {
+        match (earlier.position(), self.position()) {
+            (Some((timeline_a, a)), Some((timeline_b, b))) if timeline_a == timeline_b && b > a => {
+                Some(b - a)
+            }
+            _ => None,
+        }
+    }
+
+    /// Splits an offset into the timeline and the position within the
+    /// timeline. Returns `None` for `BEGIN` and `END`.
+    fn position(&self) -> Option<(&str, u64)> {
+        if self.is_before_first_event() {
+            return None;
+        }
+        let (timeline, position) = match self.0.rfind('-') {
+            Some(idx) => (&self.0[..idx], &self.0[idx + 1..]),
+            None => ("", self.0.as_str()),
+        };
+        position.parse().ok().map(|position| (timeline, position))
+    }
+}
+
+impl fmt::Display for Offset {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "{}", self.0)
+    }
+}
+
 /// Information on a current batch. This might be
 /// useful for a `Handler` that wants to do checkpointing on its own.
 #[derive(Clone, Debug)]
@@ -72,6 +171,43 @@ pub struct BatchCommitData<'a> {
     pub cursor: &'a [u8],
 }
 
+/// The cursor of a batch exactly as `Nakadi` sent it.
+///
+/// Handlers that store cursors to commit or replay them later should
+/// keep the raw bytes. Parsing and serializing the cursor again might
+/// reorder its fields or drop fields unknown to `Nakadion`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RawCursor(pub Vec<u8>);
+
+impl RawCursor {
+    /// The untouched JSON of the cursor
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// The untouched JSON of the cursor as a string
+    pub fn as_str(&self) -> Result<&str, String> {
+        ::std::str::from_utf8(&self.0).map_err(|err| format!("Cursor is not UTF-8: {}", err))
+    }
+
+    /// Parse the cursor for reading its fields.
+    pub fn parse(&self) -> Result<StreamCursor, String> {
+        serde_json::from_slice(&self.0).map_err(|err| format!("Could not parse cursor: {}", err))
+    }
+}
+
+/// The fields of a cursor of a subscription stream
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StreamCursor {
+    pub partition: PartitionId,
+    pub offset: String,
+    pub event_type: String,
+    pub cursor_token: String,
+    /// Fields not known to `Nakadion`
+    #[serde(flatten)]
+    pub unknown_fields: serde_json::Map<String, serde_json::Value>,
+}
+
 /// The [`Nakadi Event Type`](https://github.com/zalando/nakadi#creating-event-types).
 /// Similiar to a topic.
 #[derive(Clone, Debug)]
@@ -84,3 +220,42 @@ impl<'a> EventType<'a> {
         EventType(value)
     }
 }
+
+#[test]
+fn raw_cursors_keep_unknown_fields() {
+    let raw = RawCursor(
+        br#"{"offset":"543","partition":"5","event_type":"et","cursor_token":"t","x":1}"#
+            .to_vec(),
+    );
+    let parsed = raw.parse().unwrap();
+    assert_eq!(parsed.partition, PartitionId("5".to_string()));
+    assert_eq!(parsed.offset, "543");
+    assert_eq!(parsed.unknown_fields.get("x"), Some(&json!(1)));
+    assert!(raw.as_str().unwrap().ends_with(r#""x":1}"#));
+}
+
+#[test]
+fn offsets_are_compared_by_position() {
+    let offset = |o: &str| Offset::new(o);
+
+    assert!(offset("10").is_after(&offset("9")));
+    assert!(offset("001-0001-000000000000000010").is_after(&offset("001-0001-9")));
+    assert!(offset("000005").is_after(&offset("BEGIN")));
+    assert!(!offset("BEGIN").is_after(&offset("00005")));
+    assert!(offset("END").is_after(&offset("000005")));
+    assert!(offset("001-0002-xyz").is_after(&offset("001-0001-x")));
+    assert!(offset("001-0001-000000000000000001").is_after(&offset("000000000000000009")));
+
+    assert_eq!(offset("19").distance_from(&offset("9")), Some(10));
+    assert_eq!(offset("9").distance_from(&offset("19")), None);
+    assert_eq!(offset("9").distance_from(&offset("BEGIN")), None);
+}
+
+#[test]
+fn empty_partitions_have_a_newest_offset_before_the_first_event() {
+    assert!(Offset::new("BEGIN").is_before_first_event());
+    assert!(Offset::new("-1").is_before_first_event());
+    assert!(Offset::new("001-0001--1").is_before_first_event());
+    assert!(!Offset::new("001-0001-000000000000000001").is_before_first_event());
+    assert_eq!(Offset::new("001-0001-1").distance_from(&Offset::new("001-0001--1")), None);
+}
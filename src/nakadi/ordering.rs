@@ -0,0 +1,247 @@
+//! Assertions on the order of the received batches
+//!
+//! Meant for testing environments to catch broker or configuration issues
+//! and bugs in custom connectors.
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+use failure::Error;
+use serde_json;
+
+use nakadi::model::Offset;
+
+/// What to do when the offsets of a partition are not strictly increasing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OrderingValidation {
+    /// Log the violation and keep consuming
+    Warn,
+    /// Log the violation and stop the consumer
+    Abort,
+}
+
+impl fmt::Display for OrderingValidation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            OrderingValidation::Warn => write!(f, "warn"),
+            OrderingValidation::Abort => write!(f, "abort"),
+        }
+    }
+}
+
+impl FromStr for OrderingValidation {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_ref() {
+            "warn" => Ok(OrderingValidation::Warn),
+            "abort" => Ok(OrderingValidation::Abort),
+            _ => Err(format_err!("'{}' is not an ordering validation", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OrderingViolation {
+    /// The offset did not increase
+    Regression {
+        event_type: String,
+        partition: String,
+        previous: String,
+        current: String,
+    },
+    /// The offset increased by more than the number of events in the batch
+    Gap {
+        event_type: String,
+        partition: String,
+        previous: String,
+        current: String,
+        num_events: usize,
+    },
+}
+
+impl fmt::Display for OrderingViolation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            OrderingViolation::Regression {
+                ref event_type,
+                ref partition,
+                ref previous,
+                ref current,
+            } => write!(
+                f,
+                "Offset of partition {} of event type {} did not increase: {} -> {}",
+                partition, event_type, previous, current
+            ),
+            OrderingViolation::Gap {
+                ref event_type,
+                ref partition,
+                ref previous,
+                ref current,
+                num_events,
+            } => write!(
+                f,
+                "Gap in partition {} of event type {}: {} -> {} with {} events",
+                partition, event_type, previous, current, num_events
+            ),
+        }
+    }
+}
+
+/// Checks that the offsets of each partition are strictly increasing
+/// within a stream.
+pub struct OrderingValidator {
+    validation: OrderingValidation,
+    last_offsets: HashMap<(String, String), String>,
+    regressions: u64,
+    gaps: u64,
+}
+
+impl OrderingValidator {
+    pub fn new(validation: OrderingValidation) -> OrderingValidator {
+        OrderingValidator {
+            validation,
+            last_offsets: HashMap::new(),
+            regressions: 0,
+            gaps: 0,
+        }
+    }
+
+    pub fn validation(&self) -> OrderingValidation {
+        self.validation
+    }
+
+    /// Forget all offsets. Must be called when a new stream is consumed
+    /// since `Nakadi` sends all uncommitted batches again.
+    pub fn reset(&mut self) {
+        self.last_offsets.clear();
+    }
+
+    /// The number of times an offset did not increase
+    pub fn regressions(&self) -> u64 {
+        self.regressions
+    }
+
+    /// The number of times events were skipped
+    pub fn gaps(&self) -> u64 {
+        self.gaps
+    }
+
+    /// Check the offset of a batch with `num_events` events.
+    pub fn check(
+        &mut self,
+        event_type: &str,
+        partition: &str,
+        offset: &str,
+        num_events: usize,
+    ) -> Option<OrderingViolation> {
+        let key = (event_type.to_string(), partition.to_string());
+        let previous = match self.last_offsets.insert(key, offset.to_string()) {
+            Some(previous) => previous,
+            None => return None,
+        };
+
+        if !is_after(&previous, offset) {
+            self.regressions += 1;
+            return Some(OrderingViolation::Regression {
+                event_type: event_type.to_string(),
+                partition: partition.to_string(),
+                previous,
+                current: offset.to_string(),
+            });
+        }
+
+        match distance(&previous, offset) {
+            Some(distance) if distance > num_events as u64 => {
+                self.gaps += 1;
+                Some(OrderingViolation::Gap {
+                    event_type: event_type.to_string(),
+                    partition: partition.to_string(),
+                    previous,
+                    current: offset.to_string(),
+                    num_events,
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct CursorOffset {
+    offset: String,
+}
+
+/// Extracts the offset from the JSON of a cursor.
+pub fn offset_of(cursor: &[u8]) -> Result<String, String> {
+    serde_json::from_slice::<CursorOffset>(cursor)
+        .map(|cursor| cursor.offset)
+        .map_err(|err| format!("Could not read offset of cursor: {}", err))
+}
+
+/// Returns true if `current` is known to be after `previous`.
+///
+/// See `Offset::compare`.
+pub fn is_after(previous: &str, current: &str) -> bool {
+    Offset::new(current).is_after(&Offset::new(previous))
+}
+
+/// The number of events between two offsets on the same timeline
+pub fn distance(previous: &str, current: &str) -> Option<u64> {
+    Offset::new(current).distance_from(&Offset::new(previous))
+}
+
+#[test]
+fn increasing_offsets_are_valid() {
+    let mut validator = OrderingValidator::new(OrderingValidation::Warn);
+    assert_eq!(
+        validator.check("et", "0", "001-0001-000000000000000009", 10),
+        None
+    );
+    assert_eq!(
+        validator.check("et", "0", "001-0001-000000000000000019", 10),
+        None
+    );
+    assert_eq!(validator.regressions(), 0);
+    assert_eq!(validator.gaps(), 0);
+}
+
+#[test]
+fn detects_regressions() {
+    let mut validator = OrderingValidator::new(OrderingValidation::Warn);
+    validator.check("et", "0", "001-0001-000000000000000009", 10);
+    let violation = validator.check("et", "0", "001-0001-000000000000000009", 1);
+    assert!(match violation {
+        Some(OrderingViolation::Regression { .. }) => true,
+        _ => false,
+    });
+    assert_eq!(validator.regressions(), 1);
+}
+
+#[test]
+fn detects_gaps() {
+    let mut validator = OrderingValidator::new(OrderingValidation::Warn);
+    validator.check("et", "0", "001-0001-000000000000000009", 10);
+    let violation = validator.check("et", "0", "001-0001-000000000000000029", 10);
+    assert!(match violation {
+        Some(OrderingViolation::Gap { .. }) => true,
+        _ => false,
+    });
+    assert_eq!(validator.gaps(), 1);
+}
+
+#[test]
+fn partitions_are_checked_independently() {
+    let mut validator = OrderingValidator::new(OrderingValidation::Warn);
+    validator.check("et", "0", "001-0001-000000000000000009", 10);
+    assert_eq!(
+        validator.check("et", "1", "001-0001-000000000000000002", 3),
+        None
+    );
+}
+
+#[test]
+fn read_offset_of_cursor() {
+    let cursor = br#"{"partition":"5","offset":"543","event_type":"order.ORDER_RECEIVED","cursor_token":"b75c3102-98a4-4385-a5fd-b96f1d7872f2"}"#;
+    assert_eq!(offset_of(cursor), Ok("543".to_string()));
+}
@@ -0,0 +1,298 @@
+//! State kept per partition by stateful handlers
+//!
+//! Handlers that aggregate or deduplicate events need state per partition
+//! that survives a restart of their worker. A `WithPartitionState` factory
+//! creates a `PartitionState` whenever the dispatcher starts a worker for a
+//! partition, hands it to the handler with every batch and saves it to a
+//! `PartitionStateStore` when the worker shuts down.
+use std::collections::HashMap;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex};
+
+use failure::Error;
+
+use nakadi::handler::{BatchHandler, CreateHandlerError, HandlerFactory, ProcessingStatus,
+                      ProgressReporter};
+use nakadi::model::{EventType, PartitionId};
+
+/// The state of a handler for a single partition
+#[derive(Debug, Clone)]
+pub struct PartitionState<T> {
+    partition: PartitionId,
+    state: T,
+}
+
+impl<T> PartitionState<T> {
+    pub fn new(partition: PartitionId, state: T) -> PartitionState<T> {
+        PartitionState { partition, state }
+    }
+
+    /// The partition this state belongs to
+    pub fn partition(&self) -> &PartitionId {
+        &self.partition
+    }
+
+    pub fn into_inner(self) -> T {
+        self.state
+    }
+}
+
+impl<T> Deref for PartitionState<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.state
+    }
+}
+
+impl<T> DerefMut for PartitionState<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.state
+    }
+}
+
+/// Loads and saves the states of partitions.
+pub trait PartitionStateStore<T> {
+    /// Load the state of a partition.
+    ///
+    /// Return `None` if there is no state so that
+    /// the handler starts with the default state.
+    fn load(&self, partition: &PartitionId) -> Result<Option<T>, Error>;
+
+    /// Save the state of a partition after its worker shut down.
+    fn save(&self, partition: &PartitionId, state: &T) -> Result<(), Error>;
+}
+
+/// Does not save anything. Every worker starts with the default state.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoPersistence;
+
+impl<T> PartitionStateStore<T> for NoPersistence {
+    fn load(&self, _partition: &PartitionId) -> Result<Option<T>, Error> {
+        Ok(None)
+    }
+
+    fn save(&self, _partition: &PartitionId, _state: &T) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// Keeps the states in memory.
+///
+/// A worker restarted for a partition continues with the state of its
+/// predecessor. The states are lost once the process exits.
+pub struct InMemoryStateStore<T> {
+    states: Arc<Mutex<HashMap<PartitionId, T>>>,
+}
+
+impl<T> InMemoryStateStore<T> {
+    pub fn new() -> InMemoryStateStore<T> {
+        InMemoryStateStore {
+            states: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+}
+
+impl<T> Default for InMemoryStateStore<T> {
+    fn default() -> InMemoryStateStore<T> {
+        InMemoryStateStore::new()
+    }
+}
+
+impl<T> Clone for InMemoryStateStore<T> {
+    fn clone(&self) -> InMemoryStateStore<T> {
+        InMemoryStateStore {
+            states: self.states.clone(),
+        }
+    }
+}
+
+impl<T: Clone> PartitionStateStore<T> for InMemoryStateStore<T> {
+    fn load(&self, partition: &PartitionId) -> Result<Option<T>, Error> {
+        match self.states.lock() {
+            Ok(states) => Ok(states.get(partition).cloned()),
+            Err(poisoned) => Ok(poisoned.into_inner().get(partition).cloned()),
+        }
+    }
+
+    fn save(&self, partition: &PartitionId, state: &T) -> Result<(), Error> {
+        match self.states.lock() {
+            Ok(mut states) => states.insert(partition.clone(), state.clone()),
+            Err(poisoned) => poisoned
+                .into_inner()
+                .insert(partition.clone(), state.clone()),
+        };
+        Ok(())
+    }
+}
+
+/// A handler that gets the state of its partition with every batch.
+pub trait StatefulBatchHandler {
+    type State: Default + Send + 'static;
+
+    /// Handle the events.
+    ///
+    /// Calling this method may never panic!
+    fn handle(
+        &mut self,
+        state: &mut PartitionState<Self::State>,
+        event_type: EventType,
+        events: &[u8],
+    ) -> ProcessingStatus;
+
+    /// Called once before the first batch is handled.
+    ///
+    /// Keep the `ProgressReporter` if handling a batch might take long.
+    fn attach_progress_reporter(&mut self, _reporter: ProgressReporter) {}
+
+    /// Called once when the worker owning this handler shuts down
+    /// right before the state is saved.
+    fn on_shutdown(&mut self, _state: &mut PartitionState<Self::State>) {}
+}
+
+pub trait StatefulHandlerFactory {
+    type Handler: StatefulBatchHandler + Send + 'static;
+    fn create_handler(&self, partition: &PartitionId) -> Result<Self::Handler, CreateHandlerError>;
+}
+
+/// A `HandlerFactory` that manages the states of the handlers
+/// created by a `StatefulHandlerFactory`.
+pub struct WithPartitionState<HF, S> {
+    factory: HF,
+    store: Arc<S>,
+}
+
+impl<HF, S> WithPartitionState<HF, S> {
+    pub fn new(factory: HF, store: S) -> WithPartitionState<HF, S> {
+        WithPartitionState {
+            factory,
+            store: Arc::new(store),
+        }
+    }
+}
+
+impl<HF> WithPartitionState<HF, NoPersistence> {
+    /// Start every worker with the default state.
+    pub fn without_persistence(factory: HF) -> WithPartitionState<HF, NoPersistence> {
+        WithPartitionState::new(factory, NoPersistence)
+    }
+}
+
+impl<HF, S> HandlerFactory for WithPartitionState<HF, S>
+where
+    HF: StatefulHandlerFactory,
+    S: PartitionStateStore<<HF::Handler as StatefulBatchHandler>::State> + Send + Sync + 'static,
+{
+    type Handler = StatefulHandler<HF::Handler, S>;
+
+    fn create_handler(&self, partition: &PartitionId) -> Result<Self::Handler, CreateHandlerError> {
+        let state = self.store
+            .load(partition)
+            .map_err(|err| CreateHandlerError {
+                message: format!("Could not load the state of partition {}: {}", partition, err),
+            })?
+            .unwrap_or_default();
+
+        let handler = self.factory.create_handler(partition)?;
+
+        Ok(StatefulHandler {
+            handler,
+            state: PartitionState::new(partition.clone(), state),
+            store: self.store.clone(),
+        })
+    }
+}
+
+/// Passes the state of its partition to a `StatefulBatchHandler`.
+pub struct StatefulHandler<H: StatefulBatchHandler, S> {
+    handler: H,
+    state: PartitionState<H::State>,
+    store: Arc<S>,
+}
+
+impl<H, S> BatchHandler for StatefulHandler<H, S>
+where
+    H: StatefulBatchHandler,
+    S: PartitionStateStore<H::State>,
+{
+    fn handle(&mut self, event_type: EventType, events: &[u8]) -> ProcessingStatus {
+        self.handler.handle(&mut self.state, event_type, events)
+    }
+
+    fn attach_progress_reporter(&mut self, reporter: ProgressReporter) {
+        self.handler.attach_progress_reporter(reporter)
+    }
+
+    fn on_shutdown(&mut self) {
+        self.handler.on_shutdown(&mut self.state);
+        if let Err(err) = self.store.save(self.state.partition(), &*self.state) {
+            error!(
+                "[Worker, partition={}] Could not save the state: {}",
+                self.state.partition(),
+                err
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+struct CountingHandler;
+
+#[cfg(test)]
+impl StatefulBatchHandler for CountingHandler {
+    type State = usize;
+
+    fn handle(
+        &mut self,
+        state: &mut PartitionState<usize>,
+        _event_type: EventType,
+        _events: &[u8],
+    ) -> ProcessingStatus {
+        **state += 1;
+        ProcessingStatus::processed_no_hint()
+    }
+}
+
+#[cfg(test)]
+struct CountingHandlerFactory;
+
+#[cfg(test)]
+impl StatefulHandlerFactory for CountingHandlerFactory {
+    type Handler = CountingHandler;
+
+    fn create_handler(&self, _partition: &PartitionId) -> Result<CountingHandler, CreateHandlerError> {
+        Ok(CountingHandler)
+    }
+}
+
+#[test]
+fn state_survives_a_restart_of_the_worker() {
+    let store = InMemoryStateStore::new();
+    let factory = WithPartitionState::new(CountingHandlerFactory, store.clone());
+    let partition = PartitionId("0".to_string());
+
+    let mut handler = factory.create_handler(&partition).unwrap();
+    handler.handle(EventType::new("et"), b"[]");
+    handler.handle(EventType::new("et"), b"[]");
+    handler.on_shutdown();
+
+    let mut handler = factory.create_handler(&partition).unwrap();
+    handler.handle(EventType::new("et"), b"[]");
+    handler.on_shutdown();
+
+    assert_eq!(store.load(&partition).unwrap(), Some(3));
+    assert_eq!(store.load(&PartitionId("1".to_string())).unwrap(), None);
+}
+
+#[test]
+fn without_persistence_every_worker_starts_fresh() {
+    let factory = WithPartitionState::without_persistence(CountingHandlerFactory);
+    let partition = PartitionId("0".to_string());
+
+    let mut handler = factory.create_handler(&partition).unwrap();
+    handler.handle(EventType::new("et"), b"[]");
+    handler.on_shutdown();
+
+    let handler = factory.create_handler(&partition).unwrap();
+    assert_eq!(*handler.state, 0);
+}
@@ -0,0 +1,208 @@
+//! Predicting the partition `Nakadi` assigns an event to
+//!
+//! With the `hash` partition strategy `Nakadi` takes the values of the
+//! `partition_key_fields` of an event, sums up their Java `hashCode`s and
+//! uses the absolute remainder of dividing the sum by the number of
+//! partitions as the index into the partitions sorted by their id.
+//!
+//! The functions here do the same so that producers can predict the
+//! partition of an event and consumers can route by partition key.
+use std::cmp::Ordering;
+
+use serde_json::Value;
+
+use nakadi::api_client::{EventCategory, EventTypeDefinition, NakadiApiClient, PartitionStrategy};
+use nakadi::model::PartitionId;
+
+#[derive(Fail, Debug)]
+pub enum PartitioningError {
+    #[fail(display = "Event type {} is not partitioned by hash", _0)]
+    NotHashPartitioned(String),
+    #[fail(display = "The event has no partition key field {}", _0)]
+    MissingField(String),
+    #[fail(display = "Event type {} has no partitions", _0)]
+    NoPartitions(String),
+    #[fail(display = "Could not look up the event type: {}", _0)]
+    Api(String),
+}
+
+/// The equivalent of Java's `String.hashCode()` which `Nakadi` uses.
+pub fn java_string_hash(value: &str) -> i32 {
+    value
+        .encode_utf16()
+        .fold(0i32, |h, c| h.wrapping_mul(31).wrapping_add(c as i32))
+}
+
+/// Returns the index of the partition `Nakadi` would assign an event with
+/// the given partition key values to when using the `hash` partition
+/// strategy.
+///
+/// The index refers to the partitions of the event type sorted
+/// with `sort_partitions`.
+///
+/// Panics if `num_partitions` is 0.
+pub fn hash_partition_index<T: AsRef<str>>(key_values: &[T], num_partitions: usize) -> usize {
+    assert!(num_partitions > 0, "There must be at least one partition");
+    let hash = key_values.iter().fold(0i32, |acc, value| {
+        acc.wrapping_add(java_string_hash(value.as_ref()))
+    });
+    (hash % num_partitions as i32).abs() as usize
+}
+
+/// Sort partitions the way `Nakadi` does before picking one by index.
+///
+/// Numeric ids are sorted by their value and come before other ids
+/// which are sorted lexicographically.
+pub fn sort_partitions(partitions: &mut [PartitionId]) {
+    partitions.sort_by(|a, b| match (a.0.parse::<u64>(), b.0.parse::<u64>()) {
+        (Ok(a), Ok(b)) => a.cmp(&b),
+        (Ok(_), Err(_)) => Ordering::Less,
+        (Err(_), Ok(_)) => Ordering::Greater,
+        (Err(_), Err(_)) => a.0.cmp(&b.0),
+    });
+}
+
+/// Extract the values of the partition key fields from an event.
+///
+/// The fields are paths separated by dots. For data change events
+/// they are looked up in the `data` of the event. Strings are taken
+/// as they are while all other values are rendered as JSON.
+pub fn partition_key_values(
+    event: &Value,
+    key_fields: &[String],
+    category: &EventCategory,
+) -> Result<Vec<String>, PartitioningError> {
+    let root = match *category {
+        EventCategory::Data => event
+            .get("data")
+            .ok_or_else(|| PartitioningError::MissingField("data".to_string()))?,
+        _ => event,
+    };
+
+    key_fields
+        .iter()
+        .map(|field| {
+            let value = field
+                .split('.')
+                .fold(Some(root), |value, segment| value.and_then(|v| v.get(segment)))
+                .ok_or_else(|| PartitioningError::MissingField(field.clone()))?;
+            Ok(match *value {
+                Value::String(ref s) => s.clone(),
+                ref other => other.to_string(),
+            })
+        })
+        .collect()
+}
+
+/// Resolves the partition of events of an event type
+/// partitioned with the `hash` partition strategy
+#[derive(Debug, Clone)]
+pub struct PartitionResolver {
+    key_fields: Vec<String>,
+    category: EventCategory,
+    partitions: Vec<PartitionId>,
+}
+
+impl PartitionResolver {
+    /// Create a resolver from the definition of the event type and
+    /// its partitions in any order.
+    pub fn new(
+        definition: &EventTypeDefinition,
+        mut partitions: Vec<PartitionId>,
+    ) -> Result<PartitionResolver, PartitioningError> {
+        match definition.partition_strategy {
+            Some(PartitionStrategy::Hash) => (),
+            _ => {
+                return Err(PartitioningError::NotHashPartitioned(
+                    definition.name.clone(),
+                ))
+            }
+        }
+        if partitions.is_empty() {
+            return Err(PartitioningError::NoPartitions(definition.name.clone()));
+        }
+
+        sort_partitions(&mut partitions);
+        Ok(PartitionResolver {
+            key_fields: definition.partition_key_fields.clone().unwrap_or_default(),
+            category: definition.category,
+            partitions,
+        })
+    }
+
+    /// Look up the definition and the partitions of the event type.
+    ///
+    /// The resolver must be created again once partitions were added
+    /// to the event type.
+    pub fn for_event_type(
+        api_client: &NakadiApiClient,
+        event_type: &str,
+    ) -> Result<PartitionResolver, PartitioningError> {
+        let definition = api_client
+            .get_event_type(event_type)
+            .map_err(|err| PartitioningError::Api(err.to_string()))?;
+        let partitions = api_client
+            .list_partitions(event_type)
+            .collect_all()
+            .map_err(|err| PartitioningError::Api(err.to_string()))?
+            .into_iter()
+            .map(|partition| partition.partition)
+            .collect();
+        PartitionResolver::new(&definition, partitions)
+    }
+
+    /// The partitions sorted the way `Nakadi` does
+    pub fn partitions(&self) -> &[PartitionId] {
+        &self.partitions
+    }
+
+    /// The partition for the values of the partition key fields
+    /// in the order the fields are defined on the event type
+    pub fn partition_for_key<T: AsRef<str>>(&self, key_values: &[T]) -> &PartitionId {
+        &self.partitions[hash_partition_index(key_values, self.partitions.len())]
+    }
+
+    /// The partition `Nakadi` will assign the event to
+    pub fn partition_for_event(&self, event: &Value) -> Result<&PartitionId, PartitioningError> {
+        let key_values = partition_key_values(event, &self.key_fields, &self.category)?;
+        Ok(self.partition_for_key(&key_values))
+    }
+}
+
+#[test]
+fn java_string_hash_matches_java() {
+    assert_eq!(java_string_hash(""), 0);
+    assert_eq!(java_string_hash("hello"), 99162322);
+    assert_eq!(java_string_hash("fortune-teller"), -1125746902);
+}
+
+#[test]
+fn hash_partition_index_sums_key_values() {
+    assert_eq!(hash_partition_index(&["fortune-teller"], 8), 6);
+    assert_eq!(hash_partition_index(&["abc", "def"], 3), 0);
+}
+
+#[test]
+fn partitions_are_sorted_numerically() {
+    let mut partitions: Vec<PartitionId> = vec!["10", "2", "a", "0"]
+        .into_iter()
+        .map(|p| PartitionId(p.to_string()))
+        .collect();
+    sort_partitions(&mut partitions);
+    let ids: Vec<&str> = partitions.iter().map(|p| p.0.as_ref()).collect();
+    assert_eq!(ids, vec!["0", "2", "10", "a"]);
+}
+
+#[test]
+fn key_values_are_taken_from_nested_fields() {
+    let event = json!({"data": {"order": {"id": "fortune-teller", "n": 12}}});
+    let fields = vec!["order.id".to_string(), "order.n".to_string()];
+
+    let values = partition_key_values(&event, &fields, &EventCategory::Data).unwrap();
+    assert_eq!(values, vec!["fortune-teller".to_string(), "12".to_string()]);
+
+    match partition_key_values(&event, &fields, &EventCategory::Business) {
+        Err(PartitioningError::MissingField(field)) => assert_eq!(field, "order.id"),
+        other => panic!("expected a missing field but got {:?}", other),
+    }
+}
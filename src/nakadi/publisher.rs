@@ -1,17 +1,30 @@
 //! Publish events to Nakadi
-use std::sync::Arc;
-use std::time::Duration;
-use std::io::Read;
+use std::sync::{mpsc, Arc, Mutex};
+use std::collections::BTreeMap;
+use std::thread;
+use std::time::{Duration, Instant};
+use std::io::{self, Read, Write};
+use std::fmt;
 
-use serde::Serialize;
-use serde_json;
+use serde::{Deserialize, Deserializer, Serialize};
+use serde::de::IgnoredAny;
+use serde_json::{self, Value};
 use reqwest::{Client as HttpClient, Response};
 use reqwest::StatusCode;
 use reqwest::header::{Authorization, Bearer};
 use backoff::{Error as BackoffError, ExponentialBackoff, Operation};
+use futures::Future;
+use futures::sync::oneshot;
 
-use auth::{AccessToken, ProvidesAccessToken};
+use auth::{send_with_fresh_token_on_401, AccessToken, ProvidesAccessToken};
+use nakadi::enrichment::{EnrichmentPipeline, EventEnricher};
+use nakadi::metrics::{DevNullMetricsCollector, PublisherMetricsCollector};
 use nakadi::model::FlowId;
+use nakadi::response_headers::{self, CapturedHeaders};
+use nakadi::spool::{Spool, SpooledEvents};
+use nakadi::wire_debug;
+
+pub use nakadi::partitioning::hash_partition_index;
 
 header! { (XFlowId, "X-Flow-Id") => [String] }
 
@@ -25,6 +38,10 @@ pub struct NakadiPublisher {
     nakadi_base_url: String,
     http_client: HttpClient,
     token_provider: Arc<ProvidesAccessToken>,
+    metrics_collector: Arc<PublisherMetricsCollector + Send + Sync>,
+    enrichment: EnrichmentPipeline,
+    spool: Option<Spool>,
+    max_request_bytes: Option<usize>,
 }
 
 impl NakadiPublisher {
@@ -37,6 +54,10 @@ impl NakadiPublisher {
             nakadi_base_url: nakadi_base_url.into(),
             http_client: HttpClient::new(),
             token_provider: Arc::new(token_provider),
+            metrics_collector: Arc::new(DevNullMetricsCollector),
+            enrichment: EnrichmentPipeline::new(),
+            spool: None,
+            max_request_bytes: None,
         }
     }
 
@@ -49,30 +70,222 @@ impl NakadiPublisher {
             nakadi_base_url: nakadi_base_url.into(),
             http_client: HttpClient::new(),
             token_provider: token_provider,
+            metrics_collector: Arc::new(DevNullMetricsCollector),
+            enrichment: EnrichmentPipeline::new(),
+            spool: None,
+            max_request_bytes: None,
         }
     }
 
+    /// Create a new `NakadiPublisher` that uses an already configured
+    /// HTTP client.
+    pub fn with_http_client<U: Into<String>>(
+        nakadi_base_url: U,
+        http_client: HttpClient,
+        token_provider: Arc<ProvidesAccessToken>,
+    ) -> NakadiPublisher {
+        NakadiPublisher {
+            nakadi_base_url: nakadi_base_url.into(),
+            http_client,
+            token_provider,
+            metrics_collector: Arc::new(DevNullMetricsCollector),
+            enrichment: EnrichmentPipeline::new(),
+            spool: None,
+            max_request_bytes: None,
+        }
+    }
+
+    /// Report the metrics of publishing to `metrics_collector`.
+    ///
+    /// By default no metrics are collected.
+    pub fn metrics_collector<M>(mut self, metrics_collector: M) -> NakadiPublisher
+    where
+        M: PublisherMetricsCollector + Send + Sync + 'static,
+    {
+        self.metrics_collector = Arc::new(metrics_collector);
+        self
+    }
+
+    /// Add an `EventEnricher` that every published event is passed through.
+    ///
+    /// Enrichers run in the order they were added.
+    pub fn enricher<E>(mut self, enricher: E) -> NakadiPublisher
+    where
+        E: EventEnricher + Send + Sync + 'static,
+    {
+        self.enrichment.add(enricher);
+        self
+    }
+
+    /// Write events to `spool` instead of failing while `Nakadi`
+    /// is unavailable.
+    ///
+    /// Spooled events are published before any other events once
+    /// `Nakadi` is available again. Publishing then returns
+    /// `PublishStatus::Spooled`.
+    pub fn spool(mut self, spool: Spool) -> NakadiPublisher {
+        self.spool = Some(spool);
+        self
+    }
+
+    /// Split the events passed to `publish_events`, `publish_raw`,
+    /// `publish_group` and `publish_stream` into requests of at most
+    /// `max_request_bytes` bytes.
+    ///
+    /// Should be set to the request size limit of `Nakadi` which otherwise
+    /// rejects the whole request. An event larger than the limit is sent
+    /// in a request of its own. By default all events are sent in a
+    /// single request.
+    pub fn max_request_bytes(mut self, max_request_bytes: usize) -> NakadiPublisher {
+        self.max_request_bytes = Some(max_request_bytes);
+        self
+    }
+
     /// Publish events packed into a vector of bytes.
     ///
     /// The events must be encoded in a way that `Nakadi`
-    /// can understand.
+    /// can understand. If there are enrichers the bytes
+    /// must be a JSON array of events.
     pub fn publish_raw(
         &self,
         event_type: &str,
         bytes: Vec<u8>,
         flow_id: Option<FlowId>,
         budget: Duration,
+    ) -> Result<PublishStatus, PublishError> {
+        let flow_id = flow_id.unwrap_or_else(|| FlowId::default());
+
+        if let Some(max_request_bytes) = self.max_request_bytes {
+            if bytes.len() > max_request_bytes {
+                let events: Vec<Value> = serde_json::from_slice(&bytes)
+                    .map_err(|err| PublishError::Serialization(err.to_string()))?;
+                return self.publish_stream(event_type, events, Some(flow_id), budget);
+            }
+        }
+
+        let bytes = self.enrichment
+            .enrich_raw(event_type, bytes)
+            .map_err(|err| PublishError::Enrichment(err.to_string()))?;
+
+        self.publish_enriched(event_type, bytes, flow_id, budget)
+    }
+
+    /// Publish events that already went through the enrichers,
+    /// spooling them if `Nakadi` is unavailable.
+    fn publish_enriched(
+        &self,
+        event_type: &str,
+        bytes: Vec<u8>,
+        flow_id: FlowId,
+        budget: Duration,
+    ) -> Result<PublishStatus, PublishError> {
+        let spool = match self.spool {
+            Some(ref spool) => spool,
+            None => return self.send(event_type, bytes, &flow_id, budget),
+        };
+
+        let result = match self.drain_spool(budget) {
+            Ok(_) => self.send(event_type, bytes.clone(), &flow_id, budget),
+            Err(err) => Err(err),
+        };
+
+        match result {
+            Err(ref err) if err.is_unavailable() => {
+                let events = SpooledEvents {
+                    event_type: event_type.to_string(),
+                    flow_id: flow_id.0.clone(),
+                    events: bytes,
+                };
+                match spool.append(&events) {
+                    Ok(()) => {
+                        warn!(
+                            "Spooled events for {}(FlowId: {}) since Nakadi is unavailable: {}",
+                            event_type, flow_id, err
+                        );
+                        Ok(PublishStatus::Spooled)
+                    }
+                    Err(spool_err) => {
+                        error!("Could not spool events for {}: {}", event_type, spool_err);
+                        result
+                    }
+                }
+            }
+            result => result,
+        }
+    }
+
+    /// Publish the spooled events in the order they were spooled.
+    ///
+    /// Stops at the first events that can not be published because
+    /// `Nakadi` is unavailable. Events `Nakadi` rejects are dropped
+    /// from the spool. Returns the number of requests published.
+    pub fn drain_spool(&self, budget: Duration) -> Result<usize, PublishError> {
+        let spool = match self.spool {
+            Some(ref spool) if !spool.is_empty() => spool,
+            _ => return Ok(0),
+        };
+
+        let mut unavailable = None;
+        let drained = spool
+            .drain(|spooled| {
+                match self.send(
+                    &spooled.event_type,
+                    spooled.events.clone(),
+                    &FlowId::new(spooled.flow_id.clone()),
+                    budget,
+                ) {
+                    Ok(_) => true,
+                    Err(ref err) if err.is_unavailable() => {
+                        unavailable = Some(err.to_string());
+                        false
+                    }
+                    Err(err) => {
+                        error!(
+                            "Dropping spooled events for {}(FlowId: {}): {}",
+                            spooled.event_type, spooled.flow_id, err
+                        );
+                        true
+                    }
+                }
+            })
+            .map_err(|err| PublishError::Spool(err.to_string()))?;
+
+        if drained > 0 {
+            info!("Published {} spooled requests", drained);
+        }
+
+        match unavailable {
+            Some(msg) => Err(PublishError::Unavailable(
+                format!("Nakadi is still unavailable: {}", msg),
+                FlowId::default(),
+            )),
+            None => Ok(drained),
+        }
+    }
+
+    /// Send the events retrying within `budget`.
+    fn send(
+        &self,
+        event_type: &str,
+        bytes: Vec<u8>,
+        flow_id: &FlowId,
+        budget: Duration,
     ) -> Result<PublishStatus, PublishError> {
         let url = format!("{}/event-types/{}/events", self.nakadi_base_url, event_type);
 
-        let flow_id = flow_id.unwrap_or_else(|| FlowId::default());
+        let num_events = serde_json::from_slice::<Vec<IgnoredAny>>(&bytes)
+            .map(|events| events.len())
+            .unwrap_or(0);
 
         let mut op = || match publish_events(
             &self.http_client,
             &url,
             &*self.token_provider,
             bytes.clone(),
-            &flow_id,
+            flow_id,
+            event_type,
+            num_events,
+            &*self.metrics_collector,
         ) {
             Ok(publish_status) => Ok(publish_status),
             Err(err) => {
@@ -101,6 +314,9 @@ impl NakadiPublisher {
     }
 
     /// Publish the given events to `Nakadi`
+    ///
+    /// The events are split into several requests if
+    /// `max_request_bytes` is set.
     pub fn publish_events<T: Serialize>(
         &self,
         event_type: &str,
@@ -108,12 +324,735 @@ impl NakadiPublisher {
         flow_id: Option<FlowId>,
         budget: Duration,
     ) -> Result<PublishStatus, PublishError> {
+        if let Some(max_request_bytes) = self.max_request_bytes {
+            // Enrichers make events larger so the estimation is only
+            // good enough without them.
+            if !self.enrichment.is_empty()
+                || estimate_request_bytes(events)? > max_request_bytes
+            {
+                return self.publish_stream(event_type, events, flow_id, budget);
+            }
+        }
+
         let bytes = match serde_json::to_vec(events) {
             Ok(bytes) => bytes,
             Err(err) => return Err(PublishError::Serialization(err.to_string())),
         };
         self.publish_raw(event_type, bytes, flow_id, budget)
     }
+
+    /// Publish events serialized one by one as they are taken from
+    /// `events`.
+    ///
+    /// A request is sent whenever the next event would exceed
+    /// `max_request_bytes` so that only a single request is held in
+    /// memory. Meant for large backfills. All requests share the
+    /// `FlowId` and each of them gets the full `budget`.
+    ///
+    /// Stops at the first request that fails. The error tells how many
+    /// events have been published before.
+    pub fn publish_stream<T, I>(
+        &self,
+        event_type: &str,
+        events: I,
+        flow_id: Option<FlowId>,
+        budget: Duration,
+    ) -> Result<PublishStatus, PublishError>
+    where
+        T: Serialize,
+        I: IntoIterator<Item = T>,
+    {
+        let flow_id = flow_id.unwrap_or_else(|| FlowId::default());
+        let max_bytes = self.max_request_bytes.unwrap_or_else(usize::max_value);
+        let mut status = PublishStatus::AllEventsPublished;
+        let mut published = 0;
+
+        let result = {
+            let mut publish_chunk = |(chunk, num_events): (Vec<u8>, usize)| {
+                let chunk_status =
+                    self.publish_enriched(event_type, chunk, flow_id.clone(), budget)?;
+                let previous = ::std::mem::replace(&mut status, PublishStatus::AllEventsPublished);
+                status = previous.combine(chunk_status);
+                published += num_events;
+                Ok(())
+            };
+
+            let mut publish_all = || {
+                let mut chunks = JsonArrayChunks::new(max_bytes);
+                let mut encoded = Vec::new();
+                for event in events {
+                    encoded.clear();
+                    self.encode_event(event_type, &event, &mut encoded)?;
+                    if let Some(chunk) = chunks.push(&encoded) {
+                        publish_chunk(chunk)?;
+                    }
+                }
+                match chunks.finish() {
+                    Some(chunk) => publish_chunk(chunk),
+                    None => Ok(()),
+                }
+            };
+            publish_all()
+        };
+
+        match result {
+            Ok(()) => Ok(status),
+            Err(err) if published == 0 => Err(err),
+            Err(err) => Err(PublishError::Partial(published, Box::new(err))),
+        }
+    }
+
+    fn encode_event<T: Serialize>(
+        &self,
+        event_type: &str,
+        event: &T,
+        buffer: &mut Vec<u8>,
+    ) -> Result<(), PublishError> {
+        if self.enrichment.is_empty() {
+            serde_json::to_writer(buffer, event)
+                .map_err(|err| PublishError::Serialization(err.to_string()))
+        } else {
+            let mut value = serde_json::to_value(event)
+                .map_err(|err| PublishError::Serialization(err.to_string()))?;
+            self.enrichment
+                .enrich(event_type, &mut value)
+                .map_err(|err| PublishError::Enrichment(err.to_string()))?;
+            serde_json::to_writer(buffer, &value)
+                .map_err(|err| PublishError::Serialization(err.to_string()))
+        }
+    }
+
+    /// Publish related events of several event types in the given order.
+    ///
+    /// All requests share the same `FlowId`. The `GroupStrategy` decides
+    /// whether the remaining event types are still published once one
+    /// of them failed. Each request gets the full `budget`.
+    pub fn publish_group(
+        &self,
+        group: Vec<GroupEvents>,
+        strategy: GroupStrategy,
+        flow_id: Option<FlowId>,
+        budget: Duration,
+    ) -> GroupReport {
+        let flow_id = flow_id.unwrap_or_else(|| FlowId::default());
+        publish_group_with(group, strategy, flow_id, |event_type, events, flow_id| {
+            self.publish_raw(event_type, events, Some(flow_id), budget)
+        })
+    }
+}
+
+/// Publish the events of a group one event type after the
+/// other with `publish` as told by the `strategy`.
+fn publish_group_with<F>(
+    group: Vec<GroupEvents>,
+    strategy: GroupStrategy,
+    flow_id: FlowId,
+    mut publish: F,
+) -> GroupReport
+where
+    F: FnMut(&str, Vec<u8>, FlowId) -> Result<PublishStatus, PublishError>,
+{
+    let mut report = GroupReport {
+        flow_id: flow_id.clone(),
+        published: Vec::new(),
+        spooled: Vec::new(),
+        failed: Vec::new(),
+        held_back: Vec::new(),
+    };
+
+    for events in group {
+        if !report.failed.is_empty() && strategy == GroupStrategy::HoldBack {
+            report.held_back.push(events.event_type);
+            continue;
+        }
+
+        match publish(&events.event_type, events.events, flow_id.clone()) {
+            Ok(PublishStatus::NotAllEventsPublished(items)) => report.failed.push((
+                events.event_type,
+                GroupFailure::NotAllEventsPublished(items),
+            )),
+            Ok(PublishStatus::Spooled) => report.spooled.push(events.event_type),
+            Ok(status) => report.published.push((events.event_type, status)),
+            Err(err) => report
+                .failed
+                .push((events.event_type, GroupFailure::Error(err))),
+        }
+    }
+
+    if !report.failed.is_empty() {
+        warn!(
+            "Publishing the group(FlowId: {}) failed for {} event types and held back {}",
+            report.flow_id,
+            report.failed.len(),
+            report.held_back.len()
+        );
+    }
+
+    report
+}
+
+/// The outcome of publishing with an `AsyncPublisher`
+pub type PublishFuture = Box<Future<Item = PublishStatus, Error = PublishError> + Send>;
+
+/// Publishes events without blocking the calling thread.
+///
+/// The events are published by `NakadiPublisher`s running on threads of
+/// their own and the calls return futures of the outcome. This way events
+/// can be published from an event loop, e.g. in an async request handler.
+///
+/// Events can also be enqueued without waiting for them to be published.
+/// With `batching` the events enqueued within a short time are published
+/// together.
+///
+/// The threads stop once the `AsyncPublisher` and all of its clones
+/// were dropped and all events have been published.
+#[derive(Clone)]
+pub struct AsyncPublisher {
+    sender: mpsc::Sender<PublishJob>,
+    batcher: Option<mpsc::Sender<EnqueuedEvents>>,
+}
+
+/// Settings for publishing enqueued events together
+#[derive(Debug, Clone)]
+pub struct EnqueueBatching {
+    /// How long to wait for more events of an event type
+    /// before publishing them
+    pub linger: Duration,
+    /// Publish once this many events of an event type were enqueued
+    pub max_events: usize,
+    /// The budget for publishing a batch including retries
+    pub budget: Duration,
+}
+
+impl Default for EnqueueBatching {
+    fn default() -> EnqueueBatching {
+        EnqueueBatching {
+            linger: Duration::from_millis(50),
+            max_events: 500,
+            budget: Duration::from_secs(10),
+        }
+    }
+}
+
+type Answer = oneshot::Sender<Result<PublishStatus, PublishError>>;
+
+struct PublishJob {
+    event_type: String,
+    bytes: Vec<u8>,
+    flow_id: FlowId,
+    budget: Duration,
+    /// The answer for each of the groups of events in the
+    /// request together with their number of events
+    answers: Vec<(usize, Answer)>,
+}
+
+impl PublishJob {
+    fn answer(self, result: Result<PublishStatus, PublishError>) {
+        let mut answers = self.answers;
+        if answers.len() == 1 {
+            let (_, answer) = answers.remove(0);
+            let _ = answer.send(result);
+            return;
+        }
+        let sizes: Vec<usize> = answers.iter().map(|&(n, _)| n).collect();
+        let results = split_outcome(result, &sizes);
+        for ((_, answer), result) in answers.into_iter().zip(results) {
+            let _ = answer.send(result);
+        }
+    }
+}
+
+struct EnqueuedEvents {
+    event_type: String,
+    /// Each event serialized on its own
+    events: Vec<Vec<u8>>,
+    answer: Answer,
+}
+
+impl AsyncPublisher {
+    /// Start `num_threads` threads each publishing with a
+    /// `NakadiPublisher` created by `create_publisher`.
+    ///
+    /// At most `num_threads` requests are sent at the same time. The
+    /// others wait until a thread becomes available.
+    pub fn new<F>(num_threads: usize, create_publisher: F) -> AsyncPublisher
+    where
+        F: Fn() -> NakadiPublisher + Send + Sync + 'static,
+    {
+        let (sender, receiver) = mpsc::channel::<PublishJob>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let create_publisher = Arc::new(create_publisher);
+
+        for n in 0..::std::cmp::max(num_threads, 1) {
+            let receiver = receiver.clone();
+            let create_publisher = create_publisher.clone();
+            let spawned = thread::Builder::new()
+                .name(format!("nakadion-publisher-{}", n))
+                .spawn(move || {
+                    let publisher = create_publisher();
+                    loop {
+                        let next = match receiver.lock() {
+                            Ok(receiver) => receiver.recv(),
+                            Err(poisoned) => poisoned.into_inner().recv(),
+                        };
+                        let mut job = match next {
+                            Ok(job) => job,
+                            Err(_) => break,
+                        };
+                        let bytes = ::std::mem::replace(&mut job.bytes, Vec::new());
+                        let result = publisher.publish_raw(
+                            &job.event_type,
+                            bytes,
+                            Some(job.flow_id.clone()),
+                            job.budget,
+                        );
+                        job.answer(result);
+                    }
+                });
+            if let Err(err) = spawned {
+                error!("Could not start publisher thread {}: {}", n, err);
+            }
+        }
+
+        AsyncPublisher {
+            sender,
+            batcher: None,
+        }
+    }
+
+    /// Publish the events passed to `enqueue` together with
+    /// other events of the same event type.
+    pub fn batching(mut self, config: EnqueueBatching) -> AsyncPublisher {
+        let (batcher, receiver) = mpsc::channel();
+        let sender = self.sender.clone();
+        let spawned = thread::Builder::new()
+            .name("nakadion-publisher-batching".to_string())
+            .spawn(move || run_batching(receiver, sender, config));
+        match spawned {
+            Ok(_) => self.batcher = Some(batcher),
+            Err(err) => error!(
+                "Could not start the batching thread. Not batching. {}",
+                err
+            ),
+        }
+        self
+    }
+
+    /// Publish events packed into a vector of bytes.
+    ///
+    /// See `NakadiPublisher::publish_raw`.
+    pub fn publish_raw(
+        &self,
+        event_type: &str,
+        bytes: Vec<u8>,
+        flow_id: Option<FlowId>,
+        budget: Duration,
+    ) -> PublishFuture {
+        let flow_id = flow_id.unwrap_or_else(|| FlowId::default());
+        let (answer, outcome) = oneshot::channel();
+        let job = PublishJob {
+            event_type: event_type.to_string(),
+            bytes,
+            flow_id: flow_id.clone(),
+            budget,
+            answers: vec![(1, answer)],
+        };
+        if self.sender.send(job).is_err() {
+            return Box::new(::futures::future::err(PublishError::Other(
+                "The publisher threads stopped".to_string(),
+                flow_id,
+            )));
+        }
+        Box::new(outcome.then(move |result| match result {
+            Ok(result) => result,
+            Err(_) => Err(PublishError::Other(
+                "The publisher thread stopped without publishing the events".to_string(),
+                flow_id,
+            )),
+        }))
+    }
+
+    /// Publish the given events to `Nakadi`
+    ///
+    /// The events are serialized on the calling thread.
+    pub fn publish_events<T: Serialize>(
+        &self,
+        event_type: &str,
+        events: &[T],
+        flow_id: Option<FlowId>,
+        budget: Duration,
+    ) -> PublishFuture {
+        match serde_json::to_vec(events) {
+            Ok(bytes) => self.publish_raw(event_type, bytes, flow_id, budget),
+            Err(err) => Box::new(::futures::future::err(PublishError::Serialization(
+                err.to_string(),
+            ))),
+        }
+    }
+
+    /// Enqueue events to be published in the background.
+    ///
+    /// Returns right away. The future resolves once the events were
+    /// published and can simply be dropped if the outcome is of no
+    /// interest. With `batching` the events are published together
+    /// with other enqueued events of the same event type. Otherwise
+    /// they are published on their own with the default budget.
+    pub fn enqueue<T: Serialize>(&self, event_type: &str, events: &[T]) -> PublishFuture {
+        let batcher = match self.batcher {
+            Some(ref batcher) => batcher,
+            None => {
+                return self.publish_events(
+                    event_type,
+                    events,
+                    None,
+                    EnqueueBatching::default().budget,
+                )
+            }
+        };
+
+        let mut serialized = Vec::with_capacity(events.len());
+        for event in events {
+            match serde_json::to_vec(event) {
+                Ok(bytes) => serialized.push(bytes),
+                Err(err) => {
+                    return Box::new(::futures::future::err(PublishError::Serialization(
+                        err.to_string(),
+                    )))
+                }
+            }
+        }
+
+        let (answer, outcome) = oneshot::channel();
+        let enqueued = EnqueuedEvents {
+            event_type: event_type.to_string(),
+            events: serialized,
+            answer,
+        };
+        if batcher.send(enqueued).is_err() {
+            return Box::new(::futures::future::err(PublishError::Other(
+                "The batching thread stopped".to_string(),
+                FlowId::default(),
+            )));
+        }
+        Box::new(outcome.then(|result| match result {
+            Ok(result) => result,
+            Err(_) => Err(PublishError::Other(
+                "The publisher stopped without publishing the events".to_string(),
+                FlowId::default(),
+            )),
+        }))
+    }
+}
+
+/// The events of an event type waiting to be published
+struct PendingEvents {
+    since: Instant,
+    events: Vec<Vec<u8>>,
+    answers: Vec<(usize, Answer)>,
+}
+
+fn run_batching(
+    receiver: mpsc::Receiver<EnqueuedEvents>,
+    sender: mpsc::Sender<PublishJob>,
+    config: EnqueueBatching,
+) {
+    let mut pending: BTreeMap<String, PendingEvents> = BTreeMap::new();
+    loop {
+        let wait = pending
+            .values()
+            .map(|p| {
+                let elapsed = p.since.elapsed();
+                if elapsed >= config.linger {
+                    Duration::from_secs(0)
+                } else {
+                    config.linger - elapsed
+                }
+            })
+            .min()
+            .unwrap_or_else(|| Duration::from_secs(1));
+
+        let disconnected = match receiver.recv_timeout(wait) {
+            Ok(enqueued) => {
+                let full = {
+                    let entry = pending
+                        .entry(enqueued.event_type.clone())
+                        .or_insert_with(|| PendingEvents {
+                            since: Instant::now(),
+                            events: Vec::new(),
+                            answers: Vec::new(),
+                        });
+                    entry
+                        .answers
+                        .push((enqueued.events.len(), enqueued.answer));
+                    entry.events.extend(enqueued.events);
+                    entry.events.len() >= config.max_events
+                };
+                if full {
+                    if let Some(events) = pending.remove(&enqueued.event_type) {
+                        send_pending(&sender, enqueued.event_type, events, &config);
+                    }
+                }
+                false
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => false,
+            Err(mpsc::RecvTimeoutError::Disconnected) => true,
+        };
+
+        let due: Vec<String> = pending
+            .iter()
+            .filter(|&(_, p)| disconnected || p.since.elapsed() >= config.linger)
+            .map(|(event_type, _)| event_type.clone())
+            .collect();
+        for event_type in due {
+            if let Some(events) = pending.remove(&event_type) {
+                send_pending(&sender, event_type, events, &config);
+            }
+        }
+
+        if disconnected {
+            break;
+        }
+    }
+}
+
+fn send_pending(
+    sender: &mpsc::Sender<PublishJob>,
+    event_type: String,
+    pending: PendingEvents,
+    config: &EnqueueBatching,
+) {
+    let mut bytes = Vec::new();
+    bytes.push(b'[');
+    for (i, event) in pending.events.iter().enumerate() {
+        if i > 0 {
+            bytes.push(b',');
+        }
+        bytes.extend_from_slice(event);
+    }
+    bytes.push(b']');
+
+    let job = PublishJob {
+        event_type,
+        bytes,
+        flow_id: FlowId::default(),
+        budget: config.budget,
+        answers: pending.answers,
+    };
+    if let Err(mpsc::SendError(job)) = sender.send(job) {
+        job.answer(Err(PublishError::Other(
+            "The publisher threads stopped".to_string(),
+            FlowId::default(),
+        )));
+    }
+}
+
+/// Splits the outcome of publishing several groups of events
+/// of the given sizes in a single request.
+///
+/// The outcomes of the single events are assigned to the groups
+/// in order if `Nakadi` returned one for each event.
+fn split_outcome(
+    result: Result<PublishStatus, PublishError>,
+    sizes: &[usize],
+) -> Vec<Result<PublishStatus, PublishError>> {
+    let total: usize = sizes.iter().sum();
+    let split_items = |items: &[BatchItemResponse]| -> Option<Vec<Vec<BatchItemResponse>>> {
+        if items.len() != total {
+            return None;
+        }
+        let mut offset = 0;
+        Some(
+            sizes
+                .iter()
+                .map(|&n| {
+                    let group = items[offset..offset + n].to_vec();
+                    offset += n;
+                    group
+                })
+                .collect(),
+        )
+    };
+
+    match result {
+        Ok(PublishStatus::NotAllEventsPublished(items)) => match split_items(&items) {
+            Some(groups) => groups
+                .into_iter()
+                .map(|group| {
+                    if group.iter().all(|item| item.is_submitted()) {
+                        Ok(PublishStatus::AllEventsPublished)
+                    } else {
+                        Ok(PublishStatus::NotAllEventsPublished(group))
+                    }
+                })
+                .collect(),
+            None => sizes
+                .iter()
+                .map(|_| Ok(PublishStatus::NotAllEventsPublished(items.clone())))
+                .collect(),
+        },
+        Err(PublishError::UnprocessableEntity(message, flow_id, items)) => {
+            match split_items(&items) {
+                Some(groups) => groups
+                    .into_iter()
+                    .map(|group| {
+                        Err(PublishError::UnprocessableEntity(
+                            message.clone(),
+                            flow_id.clone(),
+                            group,
+                        ))
+                    })
+                    .collect(),
+                None => sizes
+                    .iter()
+                    .map(|_| {
+                        Err(PublishError::UnprocessableEntity(
+                            message.clone(),
+                            flow_id.clone(),
+                            items.clone(),
+                        ))
+                    })
+                    .collect(),
+            }
+        }
+        other => sizes.iter().map(|_| other.clone()).collect(),
+    }
+}
+
+/// The events of a single event type published with `publish_group`
+#[derive(Debug, Clone)]
+pub struct GroupEvents {
+    pub event_type: String,
+    /// The events encoded as a JSON array
+    pub events: Vec<u8>,
+}
+
+impl GroupEvents {
+    /// Encode the events for the event type.
+    pub fn new<T: Serialize>(event_type: &str, events: &[T]) -> Result<GroupEvents, PublishError> {
+        let events =
+            serde_json::to_vec(events).map_err(|err| PublishError::Serialization(err.to_string()))?;
+        Ok(GroupEvents {
+            event_type: event_type.to_string(),
+            events,
+        })
+    }
+}
+
+/// What `publish_group` does once publishing an event type failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GroupStrategy {
+    /// Do not publish the remaining event types.
+    ///
+    /// They show up as held back in the report so that
+    /// they can be published later or compensated.
+    HoldBack,
+    /// Publish the remaining event types anyway.
+    BestEffort,
+}
+
+/// Why the events of an event type of a group were not published
+#[derive(Debug)]
+pub enum GroupFailure {
+    /// `Nakadi` accepted only some of the events
+    NotAllEventsPublished(Vec<BatchItemResponse>),
+    Error(PublishError),
+}
+
+/// The outcome of `publish_group` for each event type
+#[derive(Debug)]
+pub struct GroupReport {
+    /// The `FlowId` used for all requests
+    pub flow_id: FlowId,
+    pub published: Vec<(String, PublishStatus)>,
+    /// Event types whose events were spooled since `Nakadi` was
+    /// unavailable. They are published once it is available again.
+    pub spooled: Vec<String>,
+    pub failed: Vec<(String, GroupFailure)>,
+    /// Event types not published because an earlier one failed
+    pub held_back: Vec<String>,
+}
+
+impl GroupReport {
+    /// Returns true if the events of all event types were published.
+    pub fn is_complete(&self) -> bool {
+        self.spooled.is_empty() && self.failed.is_empty() && self.held_back.is_empty()
+    }
+}
+
+/// The size of the request body the events would be encoded to.
+///
+/// The events are encoded without keeping the result.
+pub fn estimate_request_bytes<T: Serialize>(events: &[T]) -> Result<usize, PublishError> {
+    let mut counter = ByteCounter(0);
+    serde_json::to_writer(&mut counter, events)
+        .map_err(|err| PublishError::Serialization(err.to_string()))?;
+    Ok(counter.0)
+}
+
+struct ByteCounter(usize);
+
+impl Write for ByteCounter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0 += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Collects encoded events into JSON arrays of a limited size
+struct JsonArrayChunks {
+    max_bytes: usize,
+    buffer: Vec<u8>,
+    num_events: usize,
+}
+
+impl JsonArrayChunks {
+    fn new(max_bytes: usize) -> JsonArrayChunks {
+        JsonArrayChunks {
+            max_bytes,
+            buffer: vec![b'['],
+            num_events: 0,
+        }
+    }
+
+    /// Add an encoded event.
+    ///
+    /// Returns the events added before and their number if the
+    /// event did not fit anymore.
+    fn push(&mut self, event: &[u8]) -> Option<(Vec<u8>, usize)> {
+        let full = if self.num_events > 0 && self.buffer.len() + event.len() + 2 > self.max_bytes
+        {
+            Some(self.take())
+        } else {
+            None
+        };
+
+        if self.num_events > 0 {
+            self.buffer.push(b',');
+        }
+        self.buffer.extend_from_slice(event);
+        self.num_events += 1;
+        full
+    }
+
+    /// The remaining events if there are any
+    fn finish(mut self) -> Option<(Vec<u8>, usize)> {
+        if self.num_events > 0 {
+            Some(self.take())
+        } else {
+            None
+        }
+    }
+
+    fn take(&mut self) -> (Vec<u8>, usize) {
+        let mut chunk = ::std::mem::replace(&mut self.buffer, vec![b'[']);
+        chunk.push(b']');
+        let num_events = ::std::mem::replace(&mut self.num_events, 0);
+        (chunk, num_events)
+    }
 }
 
 fn publish_events(
@@ -122,77 +1061,390 @@ fn publish_events(
     token_provider: &ProvidesAccessToken,
     bytes: Vec<u8>,
     flow_id: &FlowId,
+    event_type: &str,
+    num_events: usize,
+    metrics_collector: &PublisherMetricsCollector,
 ) -> Result<PublishStatus, PublishError> {
-    let mut request_builder = client.post(url);
+    let request_started = Instant::now();
+    metrics_collector.publisher_request_sent(event_type, bytes.len(), num_events);
 
-    match token_provider.get_token() {
-        Ok(Some(AccessToken(token))) => {
-            request_builder.header(Authorization(Bearer { token }));
+    let result = send_with_fresh_token_on_401(token_provider, || {
+        let mut request_builder = client.post(url);
+
+        match token_provider.get_token() {
+            Ok(Some(AccessToken(token))) => {
+                request_builder.header(Authorization(Bearer { token }));
+            }
+            Ok(None) => (),
+            Err(err) => return Err(PublishError::Token(err.to_string())),
+        };
+
+        request_builder.header(XFlowId(flow_id.0.clone()));
+
+        request_builder.body(bytes.clone());
+
+        wire_debug::send(client, &mut request_builder, Some(&bytes))
+            .map_err(|err| PublishError::Unavailable(format!("{}", err), flow_id.clone()))
+    });
+
+    let mut response = match result {
+        Ok(response) => response,
+        Err(err) => {
+            metrics_collector.publisher_publish_failed(event_type, None, request_started);
+            return Err(err);
         }
-        Ok(None) => (),
-        Err(err) => return Err(PublishError::Token(err.to_string())),
     };
 
-    request_builder.header(XFlowId(flow_id.0.clone()));
+    match response.status() {
+        StatusCode::Ok | StatusCode::MultiStatus => {
+            metrics_collector.publisher_published(event_type, request_started)
+        }
+        status => metrics_collector.publisher_publish_failed(
+            event_type,
+            Some(status.as_u16()),
+            request_started,
+        ),
+    }
 
-    match request_builder.body(bytes).send() {
-        Ok(ref mut response) => match response.status() {
-            StatusCode::Ok => Ok(PublishStatus::AllEventsPublished),
-            StatusCode::MultiStatus => Ok(PublishStatus::NotAllEventsPublished),
-            StatusCode::Unauthorized => {
-                let msg = read_response_body(response);
-                Err(PublishError::Unauthorized(msg, flow_id.clone()))
-            }
-            StatusCode::Forbidden => {
-                let msg = read_response_body(response);
-                Err(PublishError::Forbidden(msg, flow_id.clone()))
-            }
-            StatusCode::UnprocessableEntity => {
-                let msg = read_response_body(response);
-                Err(PublishError::UnprocessableEntity(msg, flow_id.clone()))
-            }
-            _ => {
-                let msg = read_response_body(response);
-                Err(PublishError::Other(msg, flow_id.clone()))
-            }
-        },
-        Err(err) => Err(PublishError::Other(format!("{}", err), flow_id.clone())),
+    match response.status() {
+        StatusCode::Ok => Ok(PublishStatus::AllEventsPublished),
+        StatusCode::MultiStatus => {
+            let (body, _) = read_body(&mut response);
+            let items = parse_batch_items(&body);
+            report_publishing_statuses(event_type, &items, metrics_collector);
+            Ok(PublishStatus::NotAllEventsPublished(items))
+        }
+        StatusCode::Unauthorized => {
+            let msg = read_response_body(&mut response);
+            Err(PublishError::Unauthorized(msg, flow_id.clone()))
+        }
+        StatusCode::Forbidden => {
+            let msg = read_response_body(&mut response);
+            Err(PublishError::Forbidden(msg, flow_id.clone()))
+        }
+        StatusCode::PayloadTooLarge => {
+            let msg = read_response_body(&mut response);
+            Err(PublishError::PayloadTooLarge(
+                bytes.len(),
+                msg,
+                flow_id.clone(),
+            ))
+        }
+        StatusCode::UnprocessableEntity => {
+            let (body, headers) = read_body(&mut response);
+            let items = parse_batch_items(&body);
+            report_publishing_statuses(event_type, &items, metrics_collector);
+            let msg = response_headers::with_headers(body, &headers);
+            Err(PublishError::UnprocessableEntity(
+                msg,
+                flow_id.clone(),
+                items,
+            ))
+        }
+        status if status.is_server_error() => {
+            let msg = read_response_body(&mut response);
+            Err(PublishError::Unavailable(msg, flow_id.clone()))
+        }
+        _ => {
+            let msg = read_response_body(&mut response);
+            Err(PublishError::Other(msg, flow_id.clone()))
+        }
     }
 }
 
 fn read_response_body(response: &mut Response) -> String {
+    let (body, headers) = read_body(response);
+    response_headers::with_headers(body, &headers)
+}
+
+fn read_body(response: &mut Response) -> (String, CapturedHeaders) {
+    let headers = CapturedHeaders::from_headers(response.headers());
     let mut buf = String::new();
-    response
+    let body = response
         .read_to_string(&mut buf)
         .map(|_| buf)
-        .unwrap_or("<Could not read body.>".to_string())
+        .unwrap_or("<Could not read body.>".to_string());
+    wire_debug::log_response_body(&body);
+    (body, headers)
+}
+
+/// The step of publishing at which `Nakadi` stopped processing an event
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PublishingStep {
+    /// The event was not processed at all
+    None,
+    /// The event did not match the schema of the event type
+    Validating,
+    Partitioning,
+    Enriching,
+    /// Storing the event failed
+    Publishing,
+    /// A step unknown to this version of the client
+    Unknown(String),
+}
+
+impl PublishingStep {
+    /// Returns true if publishing the event again might succeed.
+    ///
+    /// Only failures while storing the event are worth retrying.
+    /// An event that could not be validated, partitioned or enriched
+    /// will fail the same way again.
+    pub fn is_retry_suggested(&self) -> bool {
+        match *self {
+            PublishingStep::None | PublishingStep::Publishing => true,
+            _ => false,
+        }
+    }
+}
+
+impl<'a> From<&'a str> for PublishingStep {
+    fn from(step: &'a str) -> PublishingStep {
+        match step {
+            "none" => PublishingStep::None,
+            "validating" => PublishingStep::Validating,
+            "partitioning" => PublishingStep::Partitioning,
+            "enriching" => PublishingStep::Enriching,
+            "publishing" => PublishingStep::Publishing,
+            other => PublishingStep::Unknown(other.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for PublishingStep {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PublishingStep::None => write!(f, "none"),
+            PublishingStep::Validating => write!(f, "validating"),
+            PublishingStep::Partitioning => write!(f, "partitioning"),
+            PublishingStep::Enriching => write!(f, "enriching"),
+            PublishingStep::Publishing => write!(f, "publishing"),
+            PublishingStep::Unknown(ref step) => write!(f, "{}", step),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for PublishingStep {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let step: String = Deserialize::deserialize(deserializer)?;
+        Ok(PublishingStep::from(step.as_ref()))
+    }
+}
+
+/// The outcome for a single event `Nakadi` returns if
+/// not all events were published
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct BatchItemResponse {
+    pub eid: Option<String>,
+    /// One of `submitted`, `failed` or `aborted`
+    pub publishing_status: String,
+    pub step: Option<PublishingStep>,
+    pub detail: Option<String>,
+}
+
+impl BatchItemResponse {
+    /// Returns true if the event has been published.
+    pub fn is_submitted(&self) -> bool {
+        self.publishing_status == "submitted"
+    }
+
+    /// Returns true if publishing the event again might succeed.
+    ///
+    /// Aborted events did not fail themselves but were not published
+    /// because other events of the batch failed.
+    pub fn is_retry_suggested(&self) -> bool {
+        match self.publishing_status.as_ref() {
+            "aborted" => true,
+            "failed" => self.step
+                .as_ref()
+                .map(PublishingStep::is_retry_suggested)
+                .unwrap_or(true),
+            _ => false,
+        }
+    }
+}
+
+/// The batch items of a response.
+///
+/// Empty if the body is not a list of batch items.
+fn parse_batch_items(body: &str) -> Vec<BatchItemResponse> {
+    serde_json::from_str(body).unwrap_or_else(|_| Vec::new())
+}
+
+/// Counts the events of a response by their `publishing_status`.
+fn count_publishing_statuses(items: &[BatchItemResponse]) -> BTreeMap<String, usize> {
+    let mut counts = BTreeMap::new();
+    for item in items {
+        *counts.entry(item.publishing_status.clone()).or_insert(0) += 1;
+    }
+    counts
+}
+
+fn report_publishing_statuses(
+    event_type: &str,
+    items: &[BatchItemResponse],
+    metrics_collector: &PublisherMetricsCollector,
+) {
+    for (publishing_status, n) in count_publishing_statuses(items) {
+        metrics_collector.publisher_publishing_status(event_type, &publishing_status, n);
+    }
+}
+
+/// The threshold for the ratio of the most used partition to the average
+/// partition usage above which a distribution is considered skewed.
+pub const DEFAULT_MAX_PARTITION_SKEW: f64 = 2.0;
+
+/// The distribution of sampled partition keys over the partitions of an
+/// event type as `Nakadi` would do it with the `hash` partition strategy.
+#[derive(Debug, Clone)]
+pub struct PartitionDistribution {
+    /// The number of sampled keys that were assigned to the partition
+    /// with the same index.
+    pub keys_per_partition: Vec<usize>,
+    /// The number of keys sampled.
+    pub num_samples: usize,
+}
+
+impl PartitionDistribution {
+    /// The ratio of the keys on the most used partition to the average
+    /// number of keys per partition.
+    ///
+    /// 1.0 means the keys are evenly distributed. The maximum is the number
+    /// of partitions which means all keys went to a single partition.
+    pub fn skew(&self) -> f64 {
+        if self.num_samples == 0 || self.keys_per_partition.is_empty() {
+            return 1.0;
+        }
+
+        let max = *self.keys_per_partition.iter().max().unwrap_or(&0) as f64;
+        let mean = self.num_samples as f64 / self.keys_per_partition.len() as f64;
+        max / mean
+    }
+
+    /// The number of partitions that did not receive any key.
+    pub fn unused_partitions(&self) -> usize {
+        self.keys_per_partition.iter().filter(|&&n| n == 0).count()
+    }
+
+    /// Returns true if the skew is greater than `max_skew`.
+    pub fn is_skewed(&self, max_skew: f64) -> bool {
+        self.skew() > max_skew
+    }
+}
+
+/// Computes the distribution of the given sample of partition keys over
+/// `num_partitions` partitions and warns if it is heavily skewed.
+///
+/// Each key consists of the values of the `partition_key_fields` of an event
+/// in the order they are defined on the event type.
+///
+/// Panics if `num_partitions` is 0.
+pub fn check_partition_distribution<I, K, T>(
+    sample_keys: I,
+    num_partitions: usize,
+) -> PartitionDistribution
+where
+    I: IntoIterator<Item = K>,
+    K: AsRef<[T]>,
+    T: AsRef<str>,
+{
+    let mut keys_per_partition = vec![0; num_partitions];
+    let mut num_samples = 0;
+    for key in sample_keys {
+        keys_per_partition[hash_partition_index(key.as_ref(), num_partitions)] += 1;
+        num_samples += 1;
+    }
+
+    let distribution = PartitionDistribution {
+        keys_per_partition,
+        num_samples,
+    };
+
+    if distribution.is_skewed(DEFAULT_MAX_PARTITION_SKEW) {
+        warn!(
+            "Partition keys are skewed: The busiest partition gets {:.1} times the average \
+             load. {} of {} partitions are not used. Distribution: {:?}",
+            distribution.skew(),
+            distribution.unused_partitions(),
+            num_partitions,
+            distribution.keys_per_partition
+        );
+    }
+
+    distribution
 }
 
 /// A status for (almos) successful publishing
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum PublishStatus {
     /// All events were written send and accepted by `Nakadi`
     AllEventsPublished,
-    /// Not all events were accepted by `Nakadi`
-    NotAllEventsPublished,
+    /// Not all events were accepted by `Nakadi`.
+    ///
+    /// Contains the outcome for each event. Only the events
+    /// that were not submitted should be published again.
+    NotAllEventsPublished(Vec<BatchItemResponse>),
+    /// `Nakadi` was unavailable and the events were written to the spool.
+    /// They will be published once `Nakadi` is available again.
+    Spooled,
+}
+
+impl PublishStatus {
+    /// The status of several requests
+    fn combine(self, other: PublishStatus) -> PublishStatus {
+        match (self, other) {
+            (
+                PublishStatus::NotAllEventsPublished(mut items),
+                PublishStatus::NotAllEventsPublished(more_items),
+            ) => {
+                items.extend(more_items);
+                PublishStatus::NotAllEventsPublished(items)
+            }
+            (PublishStatus::NotAllEventsPublished(items), _)
+            | (_, PublishStatus::NotAllEventsPublished(items)) => {
+                PublishStatus::NotAllEventsPublished(items)
+            }
+            (PublishStatus::Spooled, _) | (_, PublishStatus::Spooled) => PublishStatus::Spooled,
+            _ => PublishStatus::AllEventsPublished,
+        }
+    }
 }
 
 /// Errors that can happen when publishing to `Nakadi`.
-#[derive(Fail, Debug)]
+#[derive(Fail, Debug, Clone)]
 pub enum PublishError {
     #[fail(display = "Unauthorized(FlowId: {}): {}", _1, _0)]
     Unauthorized(String, FlowId),
     /// Already exists
     #[fail(display = "Forbidden(FlowId: {}): {}", _1, _0)]
     Forbidden(String, FlowId),
+    /// `Nakadi` rejected the events. Contains the outcome for each
+    /// event if `Nakadi` returned them.
     #[fail(display = "Unprocessable Entity(FlowId: {}): {}", _1, _0)]
-    UnprocessableEntity(String, FlowId),
+    UnprocessableEntity(String, FlowId, Vec<BatchItemResponse>),
+    /// `Nakadi` rejected a request of the given number of bytes.
+    /// Setting `max_request_bytes` splits the events into smaller requests.
+    #[fail(display = "Payload of {} bytes too large(FlowId: {}): {}", _0, _2, _1)]
+    PayloadTooLarge(usize, String, FlowId),
     #[fail(display = "Could not serialize events: {}", _0)]
     Serialization(String),
+    #[fail(display = "Could not enrich events: {}", _0)]
+    Enrichment(String),
+    #[fail(display = "Could not read the spool: {}", _0)]
+    Spool(String),
     #[fail(display = "An error occured: {}", _0)]
     Token(String),
+    /// `Nakadi` could not be reached or answered with a server error.
+    #[fail(display = "Nakadi is unavailable(FlowId: {}): {}", _1, _0)]
+    Unavailable(String, FlowId),
     #[fail(display = "An error occured(FlowId: {}): {}", _1, _0)]
     Other(String, FlowId),
+    /// Publishing events split into several requests failed after
+    /// the given number of events had been published.
+    #[fail(display = "Published only the first {} events: {}", _0, _1)]
+    Partial(usize, Box<PublishError>),
 }
 
 impl PublishError {
@@ -200,10 +1452,247 @@ impl PublishError {
         match *self {
             PublishError::Unauthorized(_, _) => true,
             PublishError::Forbidden(_, _) => false,
-            PublishError::UnprocessableEntity(_, _) => false,
+            PublishError::UnprocessableEntity(_, _, _) => false,
+            PublishError::PayloadTooLarge(_, _, _) => false,
             PublishError::Serialization(_) => false,
+            PublishError::Enrichment(_) => false,
+            PublishError::Spool(_) => false,
             PublishError::Token(_) => true,
+            PublishError::Unavailable(_, _) => true,
             PublishError::Other(_, _) => true,
+            PublishError::Partial(_, _) => false,
+        }
+    }
+
+    /// The outcome for each event if `Nakadi` rejected them
+    pub fn batch_items(&self) -> &[BatchItemResponse] {
+        match *self {
+            PublishError::UnprocessableEntity(_, _, ref items) => items,
+            PublishError::Partial(_, ref err) => err.batch_items(),
+            _ => &[],
+        }
+    }
+
+    /// Returns true if events failed at the given step.
+    pub fn failed_at(&self, step: &PublishingStep) -> bool {
+        self.batch_items()
+            .iter()
+            .any(|item| !item.is_submitted() && item.step.as_ref() == Some(step))
+    }
+
+    /// Returns true if `Nakadi` could not be reached or answered
+    /// with a server error. Only then are events worth spooling.
+    pub fn is_unavailable(&self) -> bool {
+        match *self {
+            PublishError::Unavailable(_, _) => true,
+            _ => false,
+        }
+    }
+}
+
+#[test]
+fn only_unreachable_nakadi_is_unavailable() {
+    let unavailable = PublishError::Unavailable("503".to_string(), FlowId::default());
+    let rejected = PublishError::Other("404".to_string(), FlowId::default());
+    let partial = PublishError::Partial(1, Box::new(unavailable.clone()));
+
+    assert!(unavailable.is_unavailable());
+    assert!(!rejected.is_unavailable());
+    assert!(!partial.is_unavailable());
+}
+
+#[test]
+fn publishing_statuses_are_counted() {
+    let body = r#"[
+        {"eid": "1", "publishing_status": "submitted", "step": "publishing"},
+        {"eid": "2", "publishing_status": "failed", "step": "validating", "detail": "bad"},
+        {"eid": "3", "publishing_status": "aborted", "step": "validating"},
+        {"eid": "4", "publishing_status": "aborted", "step": "validating"}
+    ]"#;
+
+    let counts = count_publishing_statuses(&parse_batch_items(body));
+
+    assert_eq!(counts.get("submitted"), Some(&1));
+    assert_eq!(counts.get("failed"), Some(&1));
+    assert_eq!(counts.get("aborted"), Some(&2));
+    assert!(parse_batch_items(r#"{"title": "Unprocessable"}"#).is_empty());
+}
+
+#[test]
+fn batch_items_tell_whether_to_retry() {
+    let body = r#"[
+        {"eid": "1", "publishing_status": "failed", "step": "validating", "detail": "bad"},
+        {"eid": "2", "publishing_status": "failed", "step": "publishing"},
+        {"eid": "3", "publishing_status": "aborted", "step": "none"},
+        {"eid": "4", "publishing_status": "submitted", "step": "publishing"},
+        {"publishing_status": "failed", "step": "compacting"}
+    ]"#;
+
+    let items = parse_batch_items(body);
+    assert_eq!(items[0].step, Some(PublishingStep::Validating));
+    assert_eq!(items[0].detail, Some("bad".to_string()));
+    assert_eq!(
+        items.iter().map(|item| item.is_retry_suggested()).collect::<Vec<_>>(),
+        vec![false, true, true, false, false]
+    );
+    assert_eq!(
+        items[4].step,
+        Some(PublishingStep::Unknown("compacting".to_string()))
+    );
+
+    let err = PublishError::UnprocessableEntity("rejected".to_string(), FlowId::default(), items);
+    assert!(err.failed_at(&PublishingStep::Validating));
+    assert!(!err.failed_at(&PublishingStep::Enriching));
+}
+
+#[test]
+fn group_events_are_encoded_as_json_arrays() {
+    let events = GroupEvents::new("order.created", &[1, 2, 3]).unwrap();
+    assert_eq!(events.event_type, "order.created");
+    assert_eq!(events.events, b"[1,2,3]".to_vec());
+
+    let report = GroupReport {
+        flow_id: FlowId::new("flow"),
+        published: vec![(events.event_type, PublishStatus::AllEventsPublished)],
+        spooled: Vec::new(),
+        failed: Vec::new(),
+        held_back: vec!["order.shipped".to_string()],
+    };
+    assert!(!report.is_complete());
+}
+
+#[test]
+fn a_single_key_is_skewed() {
+    let keys = vec![vec!["a"]; 100];
+    let distribution = check_partition_distribution(keys, 4);
+    assert_eq!(distribution.num_samples, 100);
+    assert_eq!(distribution.unused_partitions(), 3);
+    assert_eq!(distribution.skew(), 4.0);
+    assert!(distribution.is_skewed(DEFAULT_MAX_PARTITION_SKEW));
+}
+
+#[test]
+fn events_are_split_into_chunks() {
+    let mut chunks = JsonArrayChunks::new(10);
+    assert_eq!(chunks.push(b"111"), None);
+    assert_eq!(chunks.push(b"222"), None);
+    assert_eq!(chunks.push(b"333"), Some((b"[111,222]".to_vec(), 2)));
+    assert_eq!(chunks.push(b"4444444444"), Some((b"[333]".to_vec(), 1)));
+    assert_eq!(chunks.finish(), Some((b"[4444444444]".to_vec(), 1)));
+
+    assert_eq!(JsonArrayChunks::new(10).finish(), None);
+}
+
+#[test]
+fn the_request_size_is_estimated_exactly() {
+    let events = vec![json!({"a": "b"}), json!({"c": [1, 2, 3]})];
+    assert_eq!(
+        estimate_request_bytes(&events).unwrap(),
+        serde_json::to_vec(&events).unwrap().len()
+    );
+}
+
+#[test]
+fn the_async_publisher_resolves_to_the_outcome() {
+    use failure::err_msg;
+    use auth::TokenError;
+
+    struct NoToken;
+
+    impl ProvidesAccessToken for NoToken {
+        fn get_token(&self) -> Result<Option<AccessToken>, TokenError> {
+            Ok(None)
         }
     }
+
+    let publisher = AsyncPublisher::new(2, || {
+        NakadiPublisher::new("http://localhost:1", NoToken)
+            .enricher(|_: &str, _: &mut Value| Err(err_msg("rejected")))
+    });
+
+    let outcome = publisher
+        .publish_events("et", &[json!({"a": 1})], None, Duration::from_secs(1))
+        .wait();
+
+    match outcome {
+        Err(PublishError::Enrichment(_)) => (),
+        other => panic!("{:?}", other),
+    }
+}
+
+#[test]
+fn the_outcome_of_a_batch_is_split_into_its_groups() {
+    let item = |status: &str| BatchItemResponse {
+        eid: None,
+        publishing_status: status.to_string(),
+        step: None,
+        detail: None,
+    };
+    let items = vec![
+        item("submitted"),
+        item("submitted"),
+        item("failed"),
+        item("aborted"),
+    ];
+
+    let results = split_outcome(Ok(PublishStatus::NotAllEventsPublished(items)), &[2, 2]);
+    assert_eq!(results.len(), 2);
+    assert_eq!(results[0].as_ref().ok(), Some(&PublishStatus::AllEventsPublished));
+    match results[1] {
+        Ok(PublishStatus::NotAllEventsPublished(ref group)) => assert_eq!(group.len(), 2),
+        ref other => panic!("{:?}", other),
+    }
+
+    let results = split_outcome(Ok(PublishStatus::Spooled), &[1, 3, 2]);
+    assert!(
+        results
+            .iter()
+            .all(|r| r.as_ref().ok() == Some(&PublishStatus::Spooled))
+    );
+}
+
+#[cfg(test)]
+fn test_group() -> Vec<GroupEvents> {
+    ["a", "b", "c"]
+        .iter()
+        .map(|event_type| GroupEvents::new(event_type, &[1]).unwrap())
+        .collect()
+}
+
+#[cfg(test)]
+fn publish_test_group(strategy: GroupStrategy) -> GroupReport {
+    publish_group_with(
+        test_group(),
+        strategy,
+        FlowId::new("group"),
+        |event_type, _, flow_id| match event_type {
+            "a" => Ok(PublishStatus::Spooled),
+            "b" => Err(PublishError::Other("rejected".to_string(), flow_id)),
+            _ => Ok(PublishStatus::AllEventsPublished),
+        },
+    )
+}
+
+#[test]
+fn later_event_types_are_held_back_after_a_failure() {
+    let report = publish_test_group(GroupStrategy::HoldBack);
+
+    assert_eq!(report.spooled, vec!["a".to_string()]);
+    assert!(report.published.is_empty());
+    assert_eq!(report.failed.len(), 1);
+    assert_eq!(report.failed[0].0, "b");
+    assert_eq!(report.held_back, vec!["c".to_string()]);
+    assert!(!report.is_complete());
+}
+
+#[test]
+fn later_event_types_are_still_published_on_best_effort() {
+    let report = publish_test_group(GroupStrategy::BestEffort);
+
+    assert_eq!(report.spooled, vec!["a".to_string()]);
+    assert_eq!(report.published.len(), 1);
+    assert_eq!(report.published[0].0, "c");
+    assert_eq!(report.failed.len(), 1);
+    assert!(report.held_back.is_empty());
+    assert!(!report.is_complete());
 }
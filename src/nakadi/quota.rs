@@ -0,0 +1,296 @@
+//! Local quotas on the amount of data a consumer processes
+//!
+//! Quotas protect the downstream of a consumer from a runaway publisher.
+//! They are enforced over a sliding window of one hour.
+use std::collections::VecDeque;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use failure::Error;
+
+const WINDOW_SECS: u64 = 3600;
+const BUCKET_SECS: u64 = 60;
+
+/// What to do once a quota has been exceeded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum QuotaAction {
+    /// Keep consuming but notify the `QuotaListener`
+    Alert,
+    /// Notify the `QuotaListener` and stop reading from the stream
+    /// until the quota allows consuming again
+    Pause,
+}
+
+impl fmt::Display for QuotaAction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            QuotaAction::Alert => write!(f, "alert"),
+            QuotaAction::Pause => write!(f, "pause"),
+        }
+    }
+}
+
+impl FromStr for QuotaAction {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_ref() {
+            "alert" => Ok(QuotaAction::Alert),
+            "pause" => Ok(QuotaAction::Pause),
+            _ => Err(format_err!("'{}' is not a quota action", s)),
+        }
+    }
+}
+
+/// Gets notified when a quota has been exceeded.
+pub trait QuotaListener {
+    /// Called once each time the consumed amount of data goes beyond
+    /// the quota.
+    fn quota_exceeded(&self, exceeded: &QuotaExceeded);
+}
+
+/// A `QuotaListener` that can be shared between threads.
+#[derive(Clone)]
+pub struct SharedQuotaListener(pub Arc<QuotaListener + Send + Sync>);
+
+impl fmt::Debug for SharedQuotaListener {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SharedQuotaListener")
+    }
+}
+
+/// Limits on the amount of data consumed within an hour.
+#[derive(Debug, Clone)]
+pub struct QuotaConfig {
+    /// The maximum number of events to consume within an hour
+    pub max_events_per_hour: Option<u64>,
+    /// The maximum number of bytes to consume within an hour
+    pub max_bytes_per_hour: Option<u64>,
+    pub action: QuotaAction,
+    pub listener: Option<SharedQuotaListener>,
+}
+
+/// The amounts consumed when a quota got exceeded.
+#[derive(Debug, Clone)]
+pub struct QuotaExceeded {
+    /// Events consumed within the last hour
+    pub events_last_hour: u64,
+    /// Bytes consumed within the last hour
+    pub bytes_last_hour: u64,
+    pub max_events_per_hour: Option<u64>,
+    pub max_bytes_per_hour: Option<u64>,
+    pub action: QuotaAction,
+}
+
+struct Bucket {
+    started: Instant,
+    events: u64,
+    bytes: u64,
+}
+
+/// Keeps track of the consumed amounts and checks them
+/// against a `QuotaConfig`.
+pub struct QuotaTracker {
+    config: QuotaConfig,
+    buckets: VecDeque<Bucket>,
+    exceeded: bool,
+}
+
+impl QuotaTracker {
+    pub fn new(config: QuotaConfig) -> QuotaTracker {
+        QuotaTracker {
+            config,
+            buckets: VecDeque::new(),
+            exceeded: false,
+        }
+    }
+
+    pub fn action(&self) -> QuotaAction {
+        self.config.action
+    }
+
+    /// Record consumed events and notify the listener
+    /// if the quota has just been exceeded.
+    pub fn record(&mut self, events: u64, bytes: u64) {
+        if let Some(exceeded) = self.record_at(events, bytes, Instant::now()) {
+            warn!(
+                "Quota exceeded: {} events and {} bytes consumed within the last hour. \
+                 Action: {}",
+                exceeded.events_last_hour, exceeded.bytes_last_hour, exceeded.action
+            );
+            if let Some(ref listener) = self.config.listener {
+                listener.0.quota_exceeded(&exceeded);
+            }
+        }
+    }
+
+    /// Returns true if the amounts consumed within the last hour
+    /// are beyond the quota.
+    pub fn is_exceeded(&mut self) -> bool {
+        self.is_exceeded_at(Instant::now())
+    }
+
+    fn record_at(&mut self, events: u64, bytes: u64, now: Instant) -> Option<QuotaExceeded> {
+        self.evict(now);
+
+        let start_new_bucket = match self.buckets.back() {
+            Some(bucket) => now.duration_since(bucket.started) >= Duration::from_secs(BUCKET_SECS),
+            None => true,
+        };
+        if start_new_bucket {
+            self.buckets.push_back(Bucket {
+                started: now,
+                events: 0,
+                bytes: 0,
+            });
+        }
+        if let Some(bucket) = self.buckets.back_mut() {
+            bucket.events += events;
+            bucket.bytes += bytes;
+        }
+
+        let was_exceeded = self.exceeded;
+        if self.is_exceeded_at(now) && !was_exceeded {
+            let (events_last_hour, bytes_last_hour) = self.totals();
+            Some(QuotaExceeded {
+                events_last_hour,
+                bytes_last_hour,
+                max_events_per_hour: self.config.max_events_per_hour,
+                max_bytes_per_hour: self.config.max_bytes_per_hour,
+                action: self.config.action,
+            })
+        } else {
+            None
+        }
+    }
+
+    fn is_exceeded_at(&mut self, now: Instant) -> bool {
+        self.evict(now);
+        let (events, bytes) = self.totals();
+        let events_exceeded = self.config
+            .max_events_per_hour
+            .map(|max| events > max)
+            .unwrap_or(false);
+        let bytes_exceeded = self.config
+            .max_bytes_per_hour
+            .map(|max| bytes > max)
+            .unwrap_or(false);
+        self.exceeded = events_exceeded || bytes_exceeded;
+        self.exceeded
+    }
+
+    fn totals(&self) -> (u64, u64) {
+        self.buckets
+            .iter()
+            .fold((0, 0), |(events, bytes), bucket| {
+                (events + bucket.events, bytes + bucket.bytes)
+            })
+    }
+
+    fn evict(&mut self, now: Instant) {
+        let window = Duration::from_secs(WINDOW_SECS);
+        while self.buckets
+            .front()
+            .map(|bucket| now.duration_since(bucket.started) >= window)
+            .unwrap_or(false)
+        {
+            self.buckets.pop_front();
+        }
+    }
+}
+
+/// Counts the elements of the JSON array of events
+/// without parsing the events.
+pub fn count_events(events: &[u8]) -> usize {
+    let mut depth = 0usize;
+    let mut in_string = false;
+    let mut escaped = false;
+    let mut count = 0;
+    let mut element_started = false;
+
+    for &b in events {
+        if in_string {
+            if escaped {
+                escaped = false;
+            } else if b == b'\\' {
+                escaped = true;
+            } else if b == b'"' {
+                in_string = false;
+            }
+            continue;
+        }
+
+        match b {
+            b'"' => {
+                in_string = true;
+                if depth == 1 && !element_started {
+                    element_started = true;
+                    count += 1;
+                }
+            }
+            b'[' | b'{' => {
+                if depth == 1 && !element_started {
+                    element_started = true;
+                    count += 1;
+                }
+                depth += 1;
+            }
+            b']' | b'}' => depth = depth.saturating_sub(1),
+            b',' if depth == 1 => element_started = false,
+            b' ' | b'\t' | b'\n' | b'\r' => (),
+            _ => {
+                if depth == 1 && !element_started {
+                    element_started = true;
+                    count += 1;
+                }
+            }
+        }
+    }
+
+    count
+}
+
+#[cfg(test)]
+fn test_config(max_events: Option<u64>, max_bytes: Option<u64>) -> QuotaConfig {
+    QuotaConfig {
+        max_events_per_hour: max_events,
+        max_bytes_per_hour: max_bytes,
+        action: QuotaAction::Pause,
+        listener: None,
+    }
+}
+
+#[test]
+fn count_events_in_array() {
+    let events = br#"[{"a":"}]{,"},{"b":[1,2,3]}, {"c":{"d":"\"],"}}]"#;
+    assert_eq!(count_events(events), 3);
+}
+
+#[test]
+fn count_events_in_empty_array() {
+    assert_eq!(count_events(b"[ ]"), 0);
+}
+
+#[test]
+fn events_quota_is_exceeded_once() {
+    let mut tracker = QuotaTracker::new(test_config(Some(10), None));
+    let now = Instant::now();
+
+    assert!(tracker.record_at(10, 100, now).is_none());
+    let exceeded = tracker.record_at(1, 10, now).unwrap();
+    assert_eq!(exceeded.events_last_hour, 11);
+    assert_eq!(exceeded.bytes_last_hour, 110);
+    assert!(tracker.record_at(1, 10, now).is_none());
+}
+
+#[test]
+fn bytes_quota_recovers_after_an_hour() {
+    let mut tracker = QuotaTracker::new(test_config(None, Some(100)));
+    let now = Instant::now();
+
+    assert!(tracker.record_at(1, 101, now).is_some());
+    assert!(tracker.is_exceeded_at(now + Duration::from_secs(WINDOW_SECS - 1)));
+    assert!(!tracker.is_exceeded_at(now + Duration::from_secs(WINDOW_SECS)));
+}
@@ -0,0 +1,256 @@
+//! Reconnecting to spread consumers over the backends of `Nakadi`
+//!
+//! A stream stays on the backend it was connected to for as long as it
+//! lives. Once a load balancer or DNS points to new backends, e.g. during
+//! a rolling replacement of the brokers, long lived streams keep using the
+//! old ones.
+//!
+//! With rebalancing enabled the consumer closes a stream that is older than
+//! `max_connection_age` or whose commits got slower than
+//! `max_commit_latency` on average. It then reconnects which resolves the
+//! host again and opens a new connection, possibly to another backend.
+//!
+//! The age of each connection is shortened by a random part of `jitter`
+//! so that consumers started together do not all reconnect at the same
+//! time. Streams younger than `min_connection_age` are never closed.
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use nakadi::millis;
+
+/// Settings for closing streams to rebalance connections
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RebalanceConfig {
+    /// Reconnect once a stream is this old
+    pub max_connection_age: Option<Duration>,
+    /// Reconnect earlier by up to this time chosen at random
+    pub jitter: Duration,
+    /// Reconnect once committing takes longer than this on average
+    pub max_commit_latency: Option<Duration>,
+    /// Never close streams younger than this
+    pub min_connection_age: Duration,
+}
+
+impl RebalanceConfig {
+    /// Reconnect after `max_connection_age` with a jitter of a tenth of it.
+    ///
+    /// Streams must be connected for at least a minute to be closed.
+    pub fn after(max_connection_age: Duration) -> RebalanceConfig {
+        RebalanceConfig {
+            max_connection_age: Some(max_connection_age),
+            jitter: max_connection_age / 10,
+            max_commit_latency: None,
+            min_connection_age: Duration::from_secs(60),
+        }
+    }
+
+    /// Only reconnect once committing takes longer than `max_commit_latency`.
+    ///
+    /// Streams must be connected for at least a minute to be closed.
+    pub fn on_commit_latency(max_commit_latency: Duration) -> RebalanceConfig {
+        RebalanceConfig {
+            max_connection_age: None,
+            jitter: Duration::from_secs(0),
+            max_commit_latency: Some(max_commit_latency),
+            min_connection_age: Duration::from_secs(60),
+        }
+    }
+
+    pub fn jitter(mut self, jitter: Duration) -> RebalanceConfig {
+        self.jitter = jitter;
+        self
+    }
+
+    pub fn max_commit_latency(mut self, max_commit_latency: Duration) -> RebalanceConfig {
+        self.max_commit_latency = Some(max_commit_latency);
+        self
+    }
+
+    pub fn min_connection_age(mut self, min_connection_age: Duration) -> RebalanceConfig {
+        self.min_connection_age = min_connection_age;
+        self
+    }
+}
+
+/// Why a stream is closed for rebalancing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RebalanceReason {
+    /// The stream reached the age it was allowed to live
+    MaxAgeReached(Duration),
+    /// Committing got slow
+    CommitLatency(Duration),
+}
+
+impl fmt::Display for RebalanceReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RebalanceReason::MaxAgeReached(age) => {
+                write!(f, "the connection is {} seconds old", age.as_secs())
+            }
+            RebalanceReason::CommitLatency(latency) => {
+                write!(f, "committing takes {} ms on average", millis(latency))
+            }
+        }
+    }
+}
+
+/// Decides when the current stream is closed to rebalance
+#[derive(Debug)]
+pub struct Rebalancer {
+    config: RebalanceConfig,
+    /// When the current stream was connected and
+    /// when it must be closed for its age
+    connected: Option<(Instant, Option<Instant>)>,
+}
+
+impl Rebalancer {
+    pub fn new(config: RebalanceConfig) -> Rebalancer {
+        Rebalancer {
+            config,
+            connected: None,
+        }
+    }
+
+    pub fn config(&self) -> &RebalanceConfig {
+        &self.config
+    }
+
+    /// A new stream has been connected at `now`.
+    pub fn connected(&mut self, now: Instant) {
+        let jitter = random_part(self.config.jitter);
+        self.connected = Some((
+            now,
+            self.config.max_connection_age.map(|max_age| {
+                if max_age > jitter {
+                    now + (max_age - jitter)
+                } else {
+                    now
+                }
+            }),
+        ));
+    }
+
+    /// Returns the reason if the stream should be closed at `now`.
+    ///
+    /// `avg_commit_latency` is the average time commits
+    /// of the current stream took if any were made.
+    pub fn check(
+        &self,
+        now: Instant,
+        avg_commit_latency: Option<Duration>,
+    ) -> Option<RebalanceReason> {
+        let (since, close_at) = match self.connected {
+            Some(connected) => connected,
+            None => return None,
+        };
+        if now < since + self.config.min_connection_age {
+            return None;
+        }
+
+        if let Some(close_at) = close_at {
+            if now >= close_at {
+                return Some(RebalanceReason::MaxAgeReached(now - since));
+            }
+        }
+        match (self.config.max_commit_latency, avg_commit_latency) {
+            (Some(max), Some(latency)) if latency > max => {
+                Some(RebalanceReason::CommitLatency(latency))
+            }
+            _ => None,
+        }
+    }
+
+    /// The stream has been closed.
+    pub fn disconnected(&mut self) {
+        self.connected = None;
+    }
+}
+
+/// Asks the consumer to close the current stream and reconnect.
+///
+/// Used by other threads, e.g. monitors, which can not
+/// close the stream themselves.
+#[derive(Debug, Clone, Default)]
+pub struct ReconnectRequest {
+    why: Arc<Mutex<Option<String>>>,
+}
+
+impl ReconnectRequest {
+    /// Reconnect once the next line was received.
+    pub fn request<T: Into<String>>(&self, why: T) {
+        let mut requested = match self.why.lock() {
+            Ok(requested) => requested,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        *requested = Some(why.into());
+    }
+
+    /// Returns why a reconnect was requested and resets the request.
+    pub fn take(&self) -> Option<String> {
+        let mut requested = match self.why.lock() {
+            Ok(requested) => requested,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        requested.take()
+    }
+}
+
+/// A random duration between zero and `max`
+fn random_part(max: Duration) -> Duration {
+    let max_ms = millis(max);
+    if max_ms == 0 {
+        return Duration::from_secs(0);
+    }
+    // Every `RandomState` is seeded differently
+    let random = RandomState::new().build_hasher().finish();
+    Duration::from_millis(random % (max_ms + 1))
+}
+
+#[test]
+fn old_streams_are_closed() {
+    let config = RebalanceConfig::after(Duration::from_secs(600)).jitter(Duration::from_secs(0));
+    let mut rebalancer = Rebalancer::new(config);
+    let now = Instant::now();
+    assert_eq!(rebalancer.check(now, None), None);
+
+    rebalancer.connected(now);
+    assert_eq!(rebalancer.check(now + Duration::from_secs(599), None), None);
+    assert_eq!(
+        rebalancer.check(now + Duration::from_secs(600), None),
+        Some(RebalanceReason::MaxAgeReached(Duration::from_secs(600)))
+    );
+
+    rebalancer.disconnected();
+    assert_eq!(rebalancer.check(now + Duration::from_secs(600), None), None);
+}
+
+#[test]
+fn slow_commits_close_streams_old_enough() {
+    let config = RebalanceConfig::on_commit_latency(Duration::from_millis(500));
+    let mut rebalancer = Rebalancer::new(config);
+    let now = Instant::now();
+    rebalancer.connected(now);
+
+    let slow = Some(Duration::from_millis(800));
+    assert_eq!(rebalancer.check(now + Duration::from_secs(30), slow), None);
+    assert_eq!(
+        rebalancer.check(now + Duration::from_secs(60), Some(Duration::from_millis(200))),
+        None
+    );
+    assert_eq!(
+        rebalancer.check(now + Duration::from_secs(60), slow),
+        Some(RebalanceReason::CommitLatency(Duration::from_millis(800)))
+    );
+}
+
+#[test]
+fn the_jitter_never_exceeds_the_configured_one() {
+    let config = RebalanceConfig::after(Duration::from_secs(600));
+    for _ in 0..100 {
+        let jitter = random_part(config.jitter);
+        assert!(jitter <= Duration::from_secs(60));
+    }
+}
@@ -0,0 +1,166 @@
+//! Detecting event types that were repartitioned
+//!
+//! The number of partitions of an event type can be increased while
+//! consumers are running. `Nakadi` only assigns the new partitions to
+//! streams connected after the change, so without a reconnect they are
+//! not consumed until the stream is closed for some other reason.
+//!
+//! The monitor periodically looks up the number of partitions of all
+//! event types of the subscription, logs changes and passes them to a
+//! `RepartitionListener`. Optionally it makes the consumer reconnect.
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use nakadi::api_client::NakadiApiClient;
+use nakadi::consumer::Consumer;
+use nakadi::model::SubscriptionId;
+
+/// The number of partitions of an event type changed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PartitionCountChange {
+    pub event_type: String,
+    pub before: usize,
+    pub after: usize,
+}
+
+impl fmt::Display for PartitionCountChange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "the partitions of event type {} changed from {} to {}",
+            self.event_type, self.before, self.after
+        )
+    }
+}
+
+/// Gets notified about every change of the number of partitions.
+pub trait RepartitionListener {
+    fn on_partition_count_changed(&self, change: &PartitionCountChange);
+}
+
+/// A `RepartitionListener` that can be shared between threads.
+#[derive(Clone)]
+pub struct SharedRepartitionListener(pub Arc<RepartitionListener + Send + Sync>);
+
+impl fmt::Debug for SharedRepartitionListener {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SharedRepartitionListener")
+    }
+}
+
+/// Settings for periodically checking the number of partitions
+#[derive(Debug, Clone)]
+pub struct RepartitionConfig {
+    /// How often to look up the number of partitions
+    pub interval: Duration,
+    /// Make the consumer reconnect once the number changed
+    pub reconnect: bool,
+    pub listener: Option<SharedRepartitionListener>,
+}
+
+/// The changes between two lookups of the partition counts.
+///
+/// Event types only present in one of the lookups are not changes.
+pub fn changes(
+    before: &BTreeMap<String, usize>,
+    after: &BTreeMap<String, usize>,
+) -> Vec<PartitionCountChange> {
+    after
+        .iter()
+        .filter_map(|(event_type, &count)| match before.get(event_type) {
+            Some(&previous) if previous != count => Some(PartitionCountChange {
+                event_type: event_type.clone(),
+                before: previous,
+                after: count,
+            }),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Periodically checks the number of partitions while the consumer is running.
+pub fn start_monitor(
+    api_client: NakadiApiClient,
+    subscription_id: SubscriptionId,
+    consumer: Consumer,
+    config: RepartitionConfig,
+) {
+    let lifecycle = consumer.lifecycle().clone();
+    lifecycle.spawn_named("nakadion-repartitioning", move |lifecycle| {
+        let mut previous: Option<BTreeMap<String, usize>> = None;
+        while !lifecycle.wait_for_abort(config.interval) {
+            let counts = match partition_counts(&api_client, &subscription_id) {
+                Ok(counts) => counts,
+                Err(err) => {
+                    warn!(
+                        "[Repartitioning, subscription={}] Could not get the partitions: {}",
+                        subscription_id, err
+                    );
+                    continue;
+                }
+            };
+
+            if let Some(ref previous) = previous {
+                let changes = changes(previous, &counts);
+                for change in &changes {
+                    warn!(
+                        "[Repartitioning, subscription={}] The partitions of event type {} \
+                         changed from {} to {}",
+                        subscription_id, change.event_type, change.before, change.after
+                    );
+                    if let Some(ref listener) = config.listener {
+                        listener.0.on_partition_count_changed(change);
+                    }
+                }
+                if config.reconnect && !changes.is_empty() {
+                    consumer.request_reconnect(changes[0].to_string());
+                }
+            }
+            previous = Some(counts);
+        }
+    });
+}
+
+fn partition_counts(
+    api_client: &NakadiApiClient,
+    subscription_id: &SubscriptionId,
+) -> Result<BTreeMap<String, usize>, String> {
+    let subscription = api_client
+        .get_subscription(subscription_id)
+        .map_err(|err| format!("Could not get the subscription: {}", err))?;
+
+    let mut counts = BTreeMap::new();
+    for event_type in subscription.event_types {
+        let partitions = api_client
+            .list_partitions(&event_type)
+            .collect_all()
+            .map_err(|err| format!("Could not get partitions of {}: {}", event_type, err))?;
+        counts.insert(event_type, partitions.len());
+    }
+    Ok(counts)
+}
+
+#[test]
+fn only_changed_counts_are_changes() {
+    let before: BTreeMap<String, usize> = vec![("a".to_string(), 4), ("b".to_string(), 8)]
+        .into_iter()
+        .collect();
+    let after: BTreeMap<String, usize> = vec![
+        ("a".to_string(), 4),
+        ("b".to_string(), 16),
+        ("c".to_string(), 2),
+    ].into_iter()
+        .collect();
+
+    assert_eq!(
+        changes(&before, &after),
+        vec![PartitionCountChange {
+            event_type: "b".to_string(),
+            before: 8,
+            after: 16,
+        }]
+    );
+    assert!(changes(&after, &after).is_empty());
+}
@@ -0,0 +1,160 @@
+//! Response headers of `Nakadi` that help diagnosing problems
+//!
+//! Throttling and routing issues on the broker side are often only
+//! visible in the headers of a response.
+use std::fmt;
+use std::str;
+use std::time::Duration;
+
+use chrono::DateTime;
+use chrono::offset::Utc;
+use reqwest::header::Headers;
+
+/// Selected headers of a response
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CapturedHeaders {
+    /// The value of `X-RateLimit-Limit`
+    pub rate_limit_limit: Option<String>,
+    /// The value of `X-RateLimit-Remaining`
+    pub rate_limit_remaining: Option<String>,
+    /// The value of `X-RateLimit-Reset`
+    pub rate_limit_reset: Option<String>,
+    /// The value of `Retry-After`
+    pub retry_after: Option<String>,
+    /// The value of `Server`
+    pub server: Option<String>,
+    /// The value of `X-Trace-Id` or `X-B3-TraceId`
+    pub trace_id: Option<String>,
+    /// The value of `X-Flow-Id`
+    pub flow_id: Option<String>,
+}
+
+impl CapturedHeaders {
+    pub fn from_headers(headers: &Headers) -> CapturedHeaders {
+        CapturedHeaders {
+            rate_limit_limit: header_value(headers, "X-RateLimit-Limit"),
+            rate_limit_remaining: header_value(headers, "X-RateLimit-Remaining"),
+            rate_limit_reset: header_value(headers, "X-RateLimit-Reset"),
+            retry_after: header_value(headers, "Retry-After"),
+            server: header_value(headers, "Server"),
+            trace_id: header_value(headers, "X-Trace-Id")
+                .or_else(|| header_value(headers, "X-B3-TraceId")),
+            flow_id: header_value(headers, "X-Flow-Id"),
+        }
+    }
+
+    /// The remaining requests within the current rate limit window
+    /// if `Nakadi` sent it as a number.
+    pub fn rate_limit_remaining(&self) -> Option<u64> {
+        self.rate_limit_remaining
+            .as_ref()
+            .and_then(|v| v.trim().parse().ok())
+    }
+
+    /// The time `Nakadi` asked to wait before trying again.
+    ///
+    /// `Retry-After` can either be a number of seconds or an HTTP date.
+    /// A date in the past means retrying right away.
+    pub fn retry_after(&self) -> Option<Duration> {
+        let value = self.retry_after.as_ref()?.trim();
+        if let Ok(secs) = value.parse() {
+            return Some(Duration::from_secs(secs));
+        }
+        DateTime::parse_from_rfc2822(value).ok().map(|at| {
+            (at.with_timezone(&Utc) - Utc::now())
+                .to_std()
+                .unwrap_or_else(|_| Duration::from_secs(0))
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields().is_empty()
+    }
+
+    fn fields(&self) -> Vec<(&'static str, &str)> {
+        let all = [
+            ("rate_limit_limit", &self.rate_limit_limit),
+            ("rate_limit_remaining", &self.rate_limit_remaining),
+            ("rate_limit_reset", &self.rate_limit_reset),
+            ("retry_after", &self.retry_after),
+            ("server", &self.server),
+            ("trace_id", &self.trace_id),
+            ("flow_id", &self.flow_id),
+        ];
+
+        all.iter()
+            .filter_map(|&(name, value)| value.as_ref().map(|v| (name, v.as_str())))
+            .collect()
+    }
+}
+
+impl fmt::Display for CapturedHeaders {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let fields = self.fields()
+            .iter()
+            .map(|&(name, value)| format!("{}={}", name, value))
+            .collect::<Vec<_>>()
+            .join(", ");
+        write!(f, "[{}]", fields)
+    }
+}
+
+/// Appends the captured headers to a message unless none were captured.
+pub fn with_headers(message: String, headers: &CapturedHeaders) -> String {
+    if headers.is_empty() {
+        message
+    } else {
+        format!("{} {}", message, headers)
+    }
+}
+
+fn header_value(headers: &Headers, name: &str) -> Option<String> {
+    headers
+        .get_raw(name)
+        .and_then(|raw| raw.one())
+        .and_then(|bytes| str::from_utf8(bytes).ok())
+        .map(|v| v.to_string())
+}
+
+#[test]
+fn captures_selected_headers() {
+    let mut headers = Headers::new();
+    headers.set_raw("X-RateLimit-Remaining", "42");
+    headers.set_raw("Server", "nakadi");
+    headers.set_raw("X-B3-TraceId", "abc");
+    headers.set_raw("X-Unrelated", "ignored");
+
+    let captured = CapturedHeaders::from_headers(&headers);
+
+    assert_eq!(captured.rate_limit_remaining(), Some(42));
+    assert_eq!(captured.server, Some("nakadi".to_string()));
+    assert_eq!(captured.trace_id, Some("abc".to_string()));
+    assert_eq!(
+        captured.to_string(),
+        "[rate_limit_remaining=42, server=nakadi, trace_id=abc]"
+    );
+}
+
+#[test]
+fn messages_are_unchanged_without_headers() {
+    let captured = CapturedHeaders::from_headers(&Headers::new());
+
+    assert!(captured.is_empty());
+    assert_eq!(with_headers("boom".to_string(), &captured), "boom");
+}
+
+#[test]
+fn retry_after_is_read_as_seconds_or_date() {
+    let mut headers = Headers::new();
+    headers.set_raw("Retry-After", "120");
+    let captured = CapturedHeaders::from_headers(&headers);
+    assert_eq!(captured.retry_after(), Some(Duration::from_secs(120)));
+
+    headers.set_raw("Retry-After", "Wed, 21 Oct 2015 07:28:00 GMT");
+    let captured = CapturedHeaders::from_headers(&headers);
+    assert_eq!(captured.retry_after(), Some(Duration::from_secs(0)));
+
+    headers.set_raw("Retry-After", "soon");
+    let captured = CapturedHeaders::from_headers(&headers);
+    assert_eq!(captured.retry_after(), None);
+}
@@ -0,0 +1,202 @@
+//! Warnings for consumers lagging behind the retention of their event types
+//!
+//! `Nakadi` deletes events once the retention time of their event type has
+//! elapsed. A consumer that lags too far behind loses the events it did not
+//! consume in time. The monitor periodically looks up when the oldest event
+//! not yet committed on each partition was received and warns if it will
+//! be deleted soon.
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::DateTime;
+use chrono::offset::Utc;
+
+use nakadi::api_client::{InitialCursor, NakadiApiClient};
+use nakadi::consumer::Consumer;
+use nakadi::metrics::MetricsCollector;
+use nakadi::model::{PartitionId, SubscriptionId};
+
+/// A partition whose oldest uncommitted event will soon be deleted
+#[derive(Debug, Clone)]
+pub struct RetentionRisk {
+    pub event_type: String,
+    pub partition: PartitionId,
+    /// When the oldest event not yet committed was received by `Nakadi`
+    pub oldest_uncommitted_received_at: DateTime<Utc>,
+    /// The time until the event will be deleted. Zero if the
+    /// retention time has already elapsed.
+    pub time_left: Duration,
+}
+
+/// Gets notified after each check with all partitions at risk.
+///
+/// The partitions are empty if no partition is at risk.
+pub trait RetentionListener {
+    fn on_retention_risks(&self, risks: &[RetentionRisk]);
+}
+
+/// A `RetentionListener` that can be shared between threads.
+#[derive(Clone)]
+pub struct SharedRetentionListener(pub Arc<RetentionListener + Send + Sync>);
+
+impl fmt::Debug for SharedRetentionListener {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SharedRetentionListener")
+    }
+}
+
+/// Settings for periodically checking the partitions for retention risks
+#[derive(Debug, Clone)]
+pub struct RetentionConfig {
+    /// Warn if the oldest uncommitted event will be deleted within this time.
+    pub warn_within: Duration,
+    /// How often to check
+    pub interval: Duration,
+    pub listener: Option<SharedRetentionListener>,
+}
+
+/// The time left until an event received at `received_at` is deleted.
+///
+/// Zero if the retention time has already elapsed.
+pub fn time_left(received_at: DateTime<Utc>, retention: Duration, now: DateTime<Utc>) -> Duration {
+    let age = (now - received_at).to_std().unwrap_or_else(|_| Duration::from_secs(0));
+    retention
+        .checked_sub(age)
+        .unwrap_or_else(|| Duration::from_secs(0))
+}
+
+/// Periodically checks for retention risks while the consumer is running.
+pub fn start_monitor<M>(
+    api_client: NakadiApiClient,
+    subscription_id: SubscriptionId,
+    consumer: Consumer,
+    config: RetentionConfig,
+    metrics_collector: M,
+) where
+    M: MetricsCollector + Send + 'static,
+{
+    let lifecycle = consumer.lifecycle().clone();
+    lifecycle.spawn_named("nakadion-retention", move |lifecycle| {
+        while !lifecycle.wait_for_abort(config.interval) {
+            let risks = match find_risks(&api_client, &subscription_id, config.warn_within) {
+                Ok(risks) => risks,
+                Err(err) => {
+                    warn!(
+                        "[Retention, subscription={}] Could not check retention: {}",
+                        subscription_id, err
+                    );
+                    continue;
+                }
+            };
+
+            for risk in &risks {
+                warn!(
+                    "[Retention, subscription={}] The oldest uncommitted event of partition {} \
+                     of event type {} was received at {} and will be deleted in {} seconds.",
+                    subscription_id,
+                    risk.partition,
+                    risk.event_type,
+                    risk.oldest_uncommitted_received_at,
+                    risk.time_left.as_secs()
+                );
+            }
+
+            metrics_collector.consumer_partitions_at_retention_risk(risks.len());
+            if let Some(ref listener) = config.listener {
+                listener.0.on_retention_risks(&risks);
+            }
+        }
+    });
+}
+
+/// Partitions without a retention time or without uncommitted
+/// events are never at risk.
+fn find_risks(
+    api_client: &NakadiApiClient,
+    subscription_id: &SubscriptionId,
+    warn_within: Duration,
+) -> Result<Vec<RetentionRisk>, String> {
+    let cursors = api_client
+        .subscription_cursors(subscription_id)
+        .map_err(|err| format!("Could not get the committed cursors: {}", err))?;
+
+    let mut by_event_type: HashMap<String, Vec<InitialCursor>> = HashMap::new();
+    for cursor in cursors {
+        by_event_type
+            .entry(cursor.event_type.clone())
+            .or_insert_with(Vec::new)
+            .push(cursor);
+    }
+
+    let mut risks = Vec::new();
+    for (event_type, cursors) in by_event_type {
+        let retention = match api_client
+            .get_event_type(&event_type)
+            .map_err(|err| format!("Could not get event type {}: {}", event_type, err))?
+            .options
+            .and_then(|options| options.retention())
+        {
+            Some(retention) => retention,
+            None => continue,
+        };
+
+        let newest_offsets: HashMap<PartitionId, String> = api_client
+            .list_partitions(&event_type)
+            .collect_all()
+            .map_err(|err| format!("Could not get partitions of {}: {}", event_type, err))?
+            .into_iter()
+            .map(|partition| (partition.partition, partition.newest_available_offset))
+            .collect();
+
+        for cursor in cursors {
+            if newest_offsets.get(&cursor.partition) == Some(&cursor.offset) {
+                // Everything has been committed
+                continue;
+            }
+
+            let received_at = api_client
+                .received_at_after(&event_type, &cursor.partition, &cursor.offset)
+                .map_err(|err| {
+                    format!(
+                        "Could not read the oldest uncommitted event of partition {} of {}: {}",
+                        cursor.partition, event_type, err
+                    )
+                })?;
+
+            let time_left = time_left(received_at, retention, Utc::now());
+            if time_left <= warn_within {
+                risks.push(RetentionRisk {
+                    event_type: event_type.clone(),
+                    partition: cursor.partition,
+                    oldest_uncommitted_received_at: received_at,
+                    time_left,
+                });
+            }
+        }
+    }
+
+    Ok(risks)
+}
+
+#[test]
+fn time_left_is_the_retention_minus_the_age() {
+    use chrono::Duration as ChronoDuration;
+
+    let now = Utc::now();
+    let retention = Duration::from_secs(3600);
+
+    assert_eq!(
+        time_left(now - ChronoDuration::seconds(600), retention, now),
+        Duration::from_secs(3000)
+    );
+    assert_eq!(
+        time_left(now - ChronoDuration::seconds(7200), retention, now),
+        Duration::from_secs(0)
+    );
+    assert_eq!(
+        time_left(now + ChronoDuration::seconds(10), retention, now),
+        retention
+    );
+}
@@ -0,0 +1,175 @@
+//! Coordinating commit retries while `Nakadi` is struggling
+//!
+//! Every committer retries failed commits on its own. When `Nakadi` has a
+//! brownout all of them fail at the same time and their retries multiply
+//! the load on it. A `RetryScheduler` shared by all committers limits how
+//! many retries may run at the same time and how many may start per
+//! second with a token bucket.
+//!
+//! The first attempt of a commit is never limited. A retry that does not
+//! get a permit within the time budget of the commit fails the commit.
+use std::fmt;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// Limits the retries of all clones
+#[derive(Clone)]
+pub struct RetryScheduler {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    max_concurrent: usize,
+    retries_per_second: f64,
+    burst: f64,
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+struct State {
+    tokens: f64,
+    last_refill: Instant,
+    in_flight: usize,
+    denied: u64,
+}
+
+impl RetryScheduler {
+    /// At most `max_concurrent` retries run at the same time and on
+    /// average at most `retries_per_second` start per second.
+    /// Up to `burst` retries may start at once after a quiet period.
+    pub fn new(max_concurrent: usize, retries_per_second: f64, burst: usize) -> RetryScheduler {
+        let burst = ::std::cmp::max(burst, 1) as f64;
+        RetryScheduler {
+            inner: Arc::new(Inner {
+                max_concurrent: ::std::cmp::max(max_concurrent, 1),
+                retries_per_second: retries_per_second.max(0.0),
+                burst,
+                state: Mutex::new(State {
+                    tokens: burst,
+                    last_refill: Instant::now(),
+                    in_flight: 0,
+                    denied: 0,
+                }),
+                changed: Condvar::new(),
+            }),
+        }
+    }
+
+    /// Wait at most `timeout` for a permit to retry.
+    ///
+    /// The retry counts as running until the permit is dropped.
+    /// Returns `None` if no permit could be acquired in time.
+    pub fn acquire(&self, timeout: Duration) -> Option<RetryPermit> {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.inner.lock();
+        loop {
+            let now = Instant::now();
+            self.inner.refill(&mut state, now);
+            if state.in_flight < self.inner.max_concurrent && state.tokens >= 1.0 {
+                state.tokens -= 1.0;
+                state.in_flight += 1;
+                return Some(RetryPermit {
+                    scheduler: self.clone(),
+                });
+            }
+            if now >= deadline {
+                state.denied += 1;
+                return None;
+            }
+
+            let mut wait = deadline - now;
+            if state.in_flight < self.inner.max_concurrent && self.inner.retries_per_second > 0.0
+            {
+                let secs = (1.0 - state.tokens) / self.inner.retries_per_second;
+                let until_token =
+                    Duration::new(secs as u64, (secs.fract() * 1_000_000_000.0) as u32);
+                wait = ::std::cmp::min(wait, until_token + Duration::from_millis(1));
+            }
+            state = match self.inner.changed.wait_timeout(state, wait) {
+                Ok((state, _)) => state,
+                Err(poisoned) => poisoned.into_inner().0,
+            };
+        }
+    }
+
+    /// The number of retries running right now
+    pub fn in_flight(&self) -> usize {
+        self.inner.lock().in_flight
+    }
+
+    /// The number of retries that did not get a permit in time
+    pub fn denied(&self) -> u64 {
+        self.inner.lock().denied
+    }
+}
+
+impl fmt::Debug for RetryScheduler {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "RetryScheduler(max_concurrent={}, retries_per_second={}, burst={})",
+            self.inner.max_concurrent, self.inner.retries_per_second, self.inner.burst
+        )
+    }
+}
+
+impl Inner {
+    fn refill(&self, state: &mut State, now: Instant) {
+        let elapsed = now - state.last_refill;
+        let elapsed_secs =
+            elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1_000_000_000.0;
+        state.tokens = (state.tokens + elapsed_secs * self.retries_per_second).min(self.burst);
+        state.last_refill = now;
+    }
+
+    fn lock(&self) -> MutexGuard<State> {
+        match self.state.lock() {
+            Ok(state) => state,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+}
+
+/// Allows a single retry. Dropping it lets the next retry run.
+pub struct RetryPermit {
+    scheduler: RetryScheduler,
+}
+
+impl Drop for RetryPermit {
+    fn drop(&mut self) {
+        let inner = &self.scheduler.inner;
+        inner.lock().in_flight -= 1;
+        inner.changed.notify_all();
+    }
+}
+
+#[test]
+fn retries_are_limited_by_the_burst() {
+    let scheduler = RetryScheduler::new(10, 0.001, 2);
+    let first = scheduler.acquire(Duration::from_millis(0));
+    let second = scheduler.acquire(Duration::from_millis(0));
+    assert!(first.is_some() && second.is_some());
+
+    drop(first);
+    assert!(scheduler.acquire(Duration::from_millis(10)).is_none());
+    assert_eq!(scheduler.denied(), 1);
+}
+
+#[test]
+fn retries_are_limited_by_concurrency() {
+    let scheduler = RetryScheduler::new(1, 1000.0, 10);
+    let permit = scheduler.acquire(Duration::from_millis(0)).unwrap();
+    assert!(scheduler.acquire(Duration::from_millis(10)).is_none());
+    assert_eq!(scheduler.in_flight(), 1);
+
+    drop(permit);
+    assert!(scheduler.acquire(Duration::from_millis(10)).is_some());
+    assert_eq!(scheduler.in_flight(), 0);
+}
+
+#[test]
+fn tokens_are_refilled_over_time() {
+    let scheduler = RetryScheduler::new(10, 100.0, 1);
+    assert!(scheduler.acquire(Duration::from_millis(0)).is_some());
+    assert!(scheduler.acquire(Duration::from_secs(1)).is_some());
+}
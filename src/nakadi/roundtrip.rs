@@ -0,0 +1,287 @@
+//! Contract tests for publishing and consuming the same types
+//!
+//! When the publishing and the consuming side of an event type share
+//! Rust types a change to a type can break the other side. A roundtrip
+//! test publishes instances of a type and checks that they arrive and
+//! deserialize to the same values:
+//!
+//! ```rust,ignore
+//! #[test]
+//! fn orders_survive_the_roundtrip() {
+//!     let orders = vec![Order::sample(), Order::cancelled_sample()];
+//!     roundtrip::roundtrip_test("order.created", &orders).unwrap();
+//! }
+//! ```
+//!
+//! `roundtrip_test` plays the events through a `testkit::Scenario` and
+//! needs no `Nakadi`. `roundtrip_test_on_cluster` publishes with a
+//! `NakadiPublisher` and consumes with a `Nakadion` configured for a
+//! subscription of a test cluster. Both consume with the same
+//! deserialization as a `TypedBatchHandler`.
+use std::fmt::Debug;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json;
+
+use auth::ProvidesAccessToken;
+use metrics::DevNullMetricsCollector;
+use nakadi::handler::{BatchHandler, CreateHandlerError, HandlerFactory, ProcessingStatus};
+use nakadi::model::{EventType, PartitionId};
+use nakadi::publisher::NakadiPublisher;
+use nakadi::testkit::Scenario;
+use nakadi::{Nakadion, NakadionConfig};
+
+/// The number of events in each batch of `roundtrip_test`
+const EVENTS_PER_BATCH: usize = 10;
+
+/// Why a roundtrip failed
+#[derive(Fail, Debug)]
+pub enum RoundtripError {
+    #[fail(display = "Could not publish the events: {}", _0)]
+    Publish(String),
+    #[fail(display = "Could not consume the events: {}", _0)]
+    Consume(String),
+    /// Received events could not be deserialized
+    #[fail(display = "Could not deserialize received events: {}", _0)]
+    Deserialize(String),
+    #[fail(display = "Only {} of {} events arrived", received, sent)]
+    Missing { sent: usize, received: usize },
+    /// An event arrived different from how it was published
+    #[fail(display = "Event {} was published as {} but arrived as {}", index, sent, received)]
+    Mismatch {
+        index: usize,
+        sent: String,
+        received: String,
+    },
+}
+
+/// Publish `events` to a scripted stream and consume them again.
+///
+/// The events arrive in order in batches on a single partition.
+/// Fails unless exactly the same events arrive.
+pub fn roundtrip_test<T>(event_type: &str, events: &[T]) -> Result<(), RoundtripError>
+where
+    T: Serialize + DeserializeOwned + PartialEq + Debug + Send + 'static,
+{
+    let mut scenario = Scenario::new().event_type(event_type);
+    for chunk in events.chunks(EVENTS_PER_BATCH) {
+        let encoded =
+            serde_json::to_string(chunk).map_err(|err| RoundtripError::Publish(err.to_string()))?;
+        scenario = scenario.batch("0", &encoded);
+    }
+
+    let collector = Collector::new();
+    scenario.run(collector.clone(), Duration::from_secs(10));
+    collector.check_error()?;
+
+    let received = collector.take();
+    if received.len() < events.len() {
+        return Err(RoundtripError::Missing {
+            sent: events.len(),
+            received: received.len(),
+        });
+    }
+    match events
+        .iter()
+        .zip(received.iter())
+        .position(|(sent, received)| sent != received)
+    {
+        Some(index) => Err(RoundtripError::Mismatch {
+            index,
+            sent: format!("{:?}", events[index]),
+            received: format!("{:?}", received[index]),
+        }),
+        None => Ok(()),
+    }
+}
+
+/// Publish `events` with `publisher` and consume them from the
+/// subscription of `config`.
+///
+/// The subscription must contain `event_type` and must not have been
+/// consumed past the published events. Other events of the
+/// subscription must deserialize to `T` too but are ignored.
+/// Waits at most `timeout` for all events to arrive.
+pub fn roundtrip_test_on_cluster<T, P>(
+    event_type: &str,
+    events: &[T],
+    publisher: &NakadiPublisher,
+    config: NakadionConfig,
+    access_token_provider: P,
+    timeout: Duration,
+) -> Result<(), RoundtripError>
+where
+    T: Serialize + DeserializeOwned + PartialEq + Debug + Send + 'static,
+    P: ProvidesAccessToken + Send + Sync + 'static,
+{
+    publisher
+        .publish_events(event_type, events, None, timeout)
+        .map_err(|err| RoundtripError::Publish(err.to_string()))?;
+
+    let collector = Collector::new();
+    let nakadion = Nakadion::start(
+        config,
+        collector.clone(),
+        access_token_provider,
+        DevNullMetricsCollector,
+    ).map_err(|err| RoundtripError::Consume(err.to_string()))?;
+
+    let deadline = Instant::now() + timeout;
+    let missing = loop {
+        let missing = collector.missing(events);
+        if missing.is_empty() || Instant::now() >= deadline || !nakadion.running() {
+            break missing;
+        }
+        thread::sleep(Duration::from_millis(100));
+    };
+    nakadion.stop();
+    collector.check_error()?;
+
+    if missing.is_empty() {
+        Ok(())
+    } else {
+        Err(RoundtripError::Missing {
+            sent: events.len(),
+            received: events.len() - missing.len(),
+        })
+    }
+}
+
+/// Collects the events of all partitions
+struct Collector<T> {
+    received: Arc<Mutex<Vec<T>>>,
+    error: Arc<Mutex<Option<String>>>,
+}
+
+impl<T> Clone for Collector<T> {
+    fn clone(&self) -> Collector<T> {
+        Collector {
+            received: self.received.clone(),
+            error: self.error.clone(),
+        }
+    }
+}
+
+impl<T: PartialEq> Collector<T> {
+    fn new() -> Collector<T> {
+        Collector {
+            received: Arc::new(Mutex::new(Vec::new())),
+            error: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    fn take(&self) -> Vec<T> {
+        match self.received.lock() {
+            Ok(mut received) => ::std::mem::replace(&mut *received, Vec::new()),
+            Err(poisoned) => ::std::mem::replace(&mut *poisoned.into_inner(), Vec::new()),
+        }
+    }
+
+    /// The indexes of the events that did not arrive yet
+    fn missing(&self, events: &[T]) -> Vec<usize> {
+        let received = match self.received.lock() {
+            Ok(received) => received,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        events
+            .iter()
+            .enumerate()
+            .filter(|&(_, event)| !received.contains(event))
+            .map(|(index, _)| index)
+            .collect()
+    }
+
+    fn check_error(&self) -> Result<(), RoundtripError> {
+        let error = match self.error.lock() {
+            Ok(error) => error.clone(),
+            Err(poisoned) => poisoned.into_inner().clone(),
+        };
+        match error {
+            Some(err) => Err(RoundtripError::Deserialize(err)),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<T: DeserializeOwned + Send + 'static> HandlerFactory for Collector<T> {
+    type Handler = CollectingHandler<T>;
+
+    fn create_handler(
+        &self,
+        _partition: &PartitionId,
+    ) -> Result<CollectingHandler<T>, CreateHandlerError> {
+        Ok(CollectingHandler {
+            received: self.received.clone(),
+            error: self.error.clone(),
+        })
+    }
+}
+
+struct CollectingHandler<T> {
+    received: Arc<Mutex<Vec<T>>>,
+    error: Arc<Mutex<Option<String>>>,
+}
+
+impl<T: DeserializeOwned> BatchHandler for CollectingHandler<T> {
+    fn handle(&mut self, event_type: EventType, events: &[u8]) -> ProcessingStatus {
+        match serde_json::from_slice::<Vec<T>>(events) {
+            Ok(events) => {
+                let n = events.len();
+                match self.received.lock() {
+                    Ok(mut received) => received.extend(events),
+                    Err(poisoned) => poisoned.into_inner().extend(events),
+                }
+                ProcessingStatus::processed(n)
+            }
+            Err(err) => {
+                let reason = format!("event type {}: {}", event_type.0, err);
+                match self.error.lock() {
+                    Ok(mut error) => *error = Some(reason.clone()),
+                    Err(poisoned) => *poisoned.into_inner() = Some(reason.clone()),
+                }
+                ProcessingStatus::failed(reason)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct TestOrder {
+    order_number: String,
+    quantity: u32,
+}
+
+#[test]
+fn events_survive_the_roundtrip() {
+    let orders: Vec<TestOrder> = (0..25)
+        .map(|n| TestOrder {
+            order_number: n.to_string(),
+            quantity: n,
+        })
+        .collect();
+    roundtrip_test("order.created", &orders).unwrap();
+}
+
+#[test]
+fn a_drifted_type_fails_the_roundtrip() {
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Order {
+        #[serde(rename(serialize = "order_number", deserialize = "number"))]
+        number: String,
+    }
+
+    let orders = vec![
+        Order {
+            number: "1".to_string(),
+        },
+    ];
+    match roundtrip_test("order.created", &orders) {
+        Err(RoundtripError::Deserialize(_)) => (),
+        other => panic!("expected a deserialization error but got {:?}", other),
+    }
+}
@@ -0,0 +1,201 @@
+//! A signal for autoscalers of consumer deployments
+//!
+//! The scaling pressure is a single number computed from the backlog
+//! of the subscription, the batches waiting for the workers and the
+//! time it takes to process a batch. A value of 1.0 means that the
+//! consumer is working exactly at the configured targets.
+//! Values above suggest adding instances, values well below suggest
+//! removing instances.
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use nakadi::api_client::NakadiApiClient;
+use nakadi::consumer::Consumer;
+use nakadi::metrics::MetricsCollector;
+use nakadi::model::SubscriptionId;
+
+/// The upper bound of the scaling pressure
+pub const MAX_SCALING_PRESSURE: f64 = 10.0;
+
+/// The values at which a consumer is considered to be fully utilized.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct ScalingTargets {
+    /// The number of unconsumed events per partition of the subscription
+    pub unconsumed_events_per_partition: u64,
+    /// The number of batches waiting to be processed by the workers
+    pub queued_batches: u64,
+    /// The average time from receiving a batch until it has been processed
+    pub batch_latency: Duration,
+}
+
+impl Default for ScalingTargets {
+    fn default() -> ScalingTargets {
+        ScalingTargets {
+            unconsumed_events_per_partition: 10_000,
+            queued_batches: 50,
+            batch_latency: Duration::from_secs(5),
+        }
+    }
+}
+
+/// The measured values the scaling pressure is computed from.
+#[derive(Debug, Clone, Copy)]
+pub struct ScalingInputs {
+    pub unconsumed_events: u64,
+    pub num_partitions: usize,
+    pub queued_batches: u64,
+    pub batch_latency: Duration,
+}
+
+/// The scaling pressure together with its inputs
+#[derive(Debug, Clone, Copy)]
+pub struct ScalingPressure {
+    pub score: f64,
+    pub inputs: ScalingInputs,
+}
+
+/// Gets notified whenever the scaling pressure has been computed.
+pub trait ScalingPressureListener {
+    fn on_scaling_pressure(&self, pressure: &ScalingPressure);
+}
+
+/// A `ScalingPressureListener` that can be shared between threads.
+#[derive(Clone)]
+pub struct SharedScalingPressureListener(pub Arc<ScalingPressureListener + Send + Sync>);
+
+impl fmt::Debug for SharedScalingPressureListener {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SharedScalingPressureListener")
+    }
+}
+
+/// Settings for periodically computing the scaling pressure
+#[derive(Debug, Clone)]
+pub struct ScalingConfig {
+    pub targets: ScalingTargets,
+    /// How often the scaling pressure is computed
+    pub interval: Duration,
+    pub listener: Option<SharedScalingPressureListener>,
+}
+
+/// Computes the scaling pressure.
+///
+/// Each input is divided by its target and the largest ratio
+/// is the score since the most utilized resource limits the consumer.
+/// The score is within `[0, MAX_SCALING_PRESSURE]`.
+pub fn scaling_pressure(inputs: &ScalingInputs, targets: &ScalingTargets) -> f64 {
+    let backlog_target = targets.unconsumed_events_per_partition * inputs.num_partitions as u64;
+    let ratios = [
+        ratio(inputs.unconsumed_events as f64, backlog_target as f64),
+        ratio(inputs.queued_batches as f64, targets.queued_batches as f64),
+        ratio(
+            duration_to_secs(inputs.batch_latency),
+            duration_to_secs(targets.batch_latency),
+        ),
+    ];
+
+    ratios
+        .iter()
+        .cloned()
+        .fold(0.0, f64::max)
+        .min(MAX_SCALING_PRESSURE)
+}
+
+fn ratio(value: f64, target: f64) -> f64 {
+    if target <= 0.0 {
+        0.0
+    } else {
+        value / target
+    }
+}
+
+fn duration_to_secs(d: Duration) -> f64 {
+    d.as_secs() as f64 + f64::from(d.subsec_nanos()) / 1_000_000_000.0
+}
+
+/// Periodically computes the scaling pressure while the consumer is running.
+pub fn start_monitor<M>(
+    api_client: NakadiApiClient,
+    subscription_id: SubscriptionId,
+    consumer: Consumer,
+    config: ScalingConfig,
+    metrics_collector: M,
+) where
+    M: MetricsCollector + Send + 'static,
+{
+    let lifecycle = consumer.lifecycle().clone();
+    lifecycle.spawn_named("nakadion-scaling", move |lifecycle| {
+        while !lifecycle.wait_for_abort(config.interval) {
+            let stats = match api_client.subscription_stats(&subscription_id) {
+                Ok(stats) => stats,
+                Err(err) => {
+                    warn!(
+                        "[Scaling, subscription={}] Could not get stats: {}",
+                        subscription_id, err
+                    );
+                    continue;
+                }
+            };
+
+            let (queued_batches, batch_latency) = consumer.load();
+            let inputs = ScalingInputs {
+                unconsumed_events: stats.unconsumed_events() as u64,
+                num_partitions: stats
+                    .event_types
+                    .iter()
+                    .map(|et| et.num_partitions())
+                    .sum(),
+                queued_batches,
+                batch_latency,
+            };
+
+            let pressure = ScalingPressure {
+                score: scaling_pressure(&inputs, &config.targets),
+                inputs,
+            };
+
+            debug!(
+                "[Scaling, subscription={}] Scaling pressure is {:.2}",
+                subscription_id, pressure.score
+            );
+            metrics_collector.consumer_scaling_pressure(pressure.score);
+            if let Some(ref listener) = config.listener {
+                listener.0.on_scaling_pressure(&pressure);
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+fn test_inputs(unconsumed_events: u64, queued_batches: u64, latency_ms: u64) -> ScalingInputs {
+    ScalingInputs {
+        unconsumed_events,
+        num_partitions: 2,
+        queued_batches,
+        batch_latency: Duration::from_millis(latency_ms),
+    }
+}
+
+#[test]
+fn scaling_pressure_is_the_highest_ratio() {
+    let targets = ScalingTargets {
+        unconsumed_events_per_partition: 100,
+        queued_batches: 10,
+        batch_latency: Duration::from_secs(1),
+    };
+
+    assert_eq!(scaling_pressure(&test_inputs(0, 0, 0), &targets), 0.0);
+    assert_eq!(scaling_pressure(&test_inputs(100, 5, 250), &targets), 0.5);
+    assert_eq!(scaling_pressure(&test_inputs(0, 0, 1500), &targets), 1.5);
+}
+
+#[test]
+fn scaling_pressure_is_capped() {
+    let targets = ScalingTargets::default();
+
+    assert_eq!(
+        scaling_pressure(&test_inputs(u64::max_value() / 2, 0, 0), &targets),
+        MAX_SCALING_PRESSURE
+    );
+}
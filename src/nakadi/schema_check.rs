@@ -0,0 +1,246 @@
+//! Checking events against the schema registered for their event type
+//!
+//! When the Rust types of the events change without the schema registered
+//! in `Nakadi` being changed too `Nakadi` rejects the events once they are
+//! published. Checking a sample of each type in the tests of a service
+//! catches the drift before deployment:
+//!
+//! ```rust,ignore
+//! let violations = schema_check::check_event_type(&api_client, "order.created", &sample)?;
+//! assert!(violations.is_empty(), "{:?}", violations);
+//! ```
+//!
+//! Only the structure is checked: `type`, `required`, `properties`,
+//! `additionalProperties: false`, `items` and `enum`. Everything else
+//! the schema says, e.g. formats, patterns or `$ref`s, is ignored.
+//!
+//! The schema of business and data change event types describes the
+//! event without its metadata so the sample must be the event itself
+//! and not an `OutgoingEvent`.
+use std::fmt;
+
+use serde::Serialize;
+use serde_json::{self, Map, Value};
+
+use nakadi::api_client::NakadiApiClient;
+
+/// A place where an event does not match the schema
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SchemaViolation {
+    /// The path to the offending value starting with `$` for the event
+    pub path: String,
+    pub message: String,
+}
+
+impl fmt::Display for SchemaViolation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.path, self.message)
+    }
+}
+
+/// Check the serialized `event` against the schema registered for
+/// `event_type`.
+///
+/// Returns no violations if the event matches the schema.
+pub fn check_event_type<T: Serialize>(
+    api_client: &NakadiApiClient,
+    event_type: &str,
+    event: &T,
+) -> Result<Vec<SchemaViolation>, String> {
+    let definition = api_client
+        .get_event_type(event_type)
+        .map_err(|err| format!("Could not get event type {}: {}", event_type, err))?;
+    check_event(&definition.schema.schema, event)
+}
+
+/// Check the serialized `event` against a JSON schema given as a string
+/// as it is registered in `Nakadi`.
+pub fn check_event<T: Serialize>(schema: &str, event: &T) -> Result<Vec<SchemaViolation>, String> {
+    let schema: Value =
+        serde_json::from_str(schema).map_err(|err| format!("Invalid schema: {}", err))?;
+    let event =
+        serde_json::to_value(event).map_err(|err| format!("Could not serialize event: {}", err))?;
+    Ok(check_value(&schema, &event))
+}
+
+/// Check a JSON value against a JSON schema.
+pub fn check_value(schema: &Value, value: &Value) -> Vec<SchemaViolation> {
+    let mut violations = Vec::new();
+    check(schema, value, "$", &mut violations);
+    violations
+}
+
+fn check(schema: &Value, value: &Value, path: &str, violations: &mut Vec<SchemaViolation>) {
+    // `true` and other schemas that are not objects accept everything
+    let schema = match schema.as_object() {
+        Some(schema) => schema,
+        None => return,
+    };
+
+    let types = allowed_types(schema);
+    if !types.is_empty() && !types.iter().any(|t| has_type(value, t)) {
+        violations.push(violation(
+            path,
+            format!(
+                "expected {} but found {}",
+                types.join(" or "),
+                type_name(value)
+            ),
+        ));
+        return;
+    }
+
+    if let Some(&Value::Array(ref allowed)) = schema.get("enum") {
+        if !allowed.contains(value) {
+            violations.push(violation(
+                path,
+                format!("{} is not one of the allowed values", value),
+            ));
+        }
+    }
+
+    match *value {
+        Value::Object(ref fields) => check_object(schema, fields, path, violations),
+        Value::Array(ref items) => {
+            if let Some(item_schema) = schema.get("items") {
+                for (index, item) in items.iter().enumerate() {
+                    check(
+                        item_schema,
+                        item,
+                        &format!("{}[{}]", path, index),
+                        violations,
+                    );
+                }
+            }
+        }
+        _ => (),
+    }
+}
+
+fn check_object(
+    schema: &Map<String, Value>,
+    fields: &Map<String, Value>,
+    path: &str,
+    violations: &mut Vec<SchemaViolation>,
+) {
+    if let Some(&Value::Array(ref required)) = schema.get("required") {
+        for name in required.iter().filter_map(Value::as_str) {
+            if !fields.contains_key(name) {
+                violations.push(violation(
+                    path,
+                    format!("required field '{}' is missing", name),
+                ));
+            }
+        }
+    }
+
+    let properties = schema.get("properties").and_then(Value::as_object);
+    let closed = schema.get("additionalProperties") == Some(&Value::Bool(false));
+    for (name, field) in fields {
+        let field_path = format!("{}.{}", path, name);
+        match properties.and_then(|properties| properties.get(name)) {
+            Some(field_schema) => check(field_schema, field, &field_path, violations),
+            None if closed => violations.push(violation(
+                &field_path,
+                "the schema does not allow the field".to_string(),
+            )),
+            None => (),
+        }
+    }
+}
+
+fn allowed_types(schema: &Map<String, Value>) -> Vec<&str> {
+    match schema.get("type") {
+        Some(&Value::String(ref t)) => vec![t.as_str()],
+        Some(&Value::Array(ref types)) => types.iter().filter_map(Value::as_str).collect(),
+        _ => Vec::new(),
+    }
+}
+
+fn has_type(value: &Value, json_type: &str) -> bool {
+    match json_type {
+        "object" => value.is_object(),
+        "array" => value.is_array(),
+        "string" => value.is_string(),
+        "boolean" => value.is_boolean(),
+        "null" => value.is_null(),
+        "number" => value.is_number(),
+        "integer" => value.is_i64() || value.is_u64(),
+        // Unknown types are not checked
+        _ => true,
+    }
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match *value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(ref n) if n.is_f64() => "number",
+        Value::Number(_) => "integer",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+fn violation(path: &str, message: String) -> SchemaViolation {
+    SchemaViolation {
+        path: path.to_string(),
+        message,
+    }
+}
+
+#[cfg(test)]
+const TEST_SCHEMA: &'static str = r#"{
+    "type": "object",
+    "required": ["order_number", "items"],
+    "additionalProperties": false,
+    "properties": {
+        "order_number": {"type": "string"},
+        "status": {"type": "string", "enum": ["open", "closed"]},
+        "items": {
+            "type": "array",
+            "items": {
+                "type": "object",
+                "required": ["quantity"],
+                "properties": {"quantity": {"type": "integer"}}
+            }
+        }
+    }
+}"#;
+
+#[test]
+fn a_matching_event_has_no_violations() {
+    let event = json!({
+        "order_number": "123",
+        "status": "open",
+        "items": [{"quantity": 1}, {"quantity": 2, "note": "gift"}]
+    });
+    assert_eq!(check_event(TEST_SCHEMA, &event).unwrap(), Vec::new());
+}
+
+#[test]
+fn drift_is_reported_with_paths() {
+    let event = json!({
+        "order_number": 123,
+        "status": "lost",
+        "items": [{"quantity": 1.5}, {}],
+        "customer": "c"
+    });
+    let violations: Vec<String> = check_event(TEST_SCHEMA, &event)
+        .unwrap()
+        .iter()
+        .map(ToString::to_string)
+        .collect();
+
+    assert_eq!(
+        violations,
+        vec![
+            "$.customer: the schema does not allow the field",
+            "$.items[0].quantity: expected integer but found number",
+            "$.items[1]: required field 'quantity' is missing",
+            "$.order_number: expected string but found integer",
+            "$.status: \"lost\" is not one of the allowed values",
+        ]
+    );
+}
@@ -0,0 +1,369 @@
+//! Keeping events on disk while `Nakadi` is unavailable
+//!
+//! A `NakadiPublisher` with a `Spool` appends events it could not publish
+//! because `Nakadi` was unavailable to a local file instead of failing.
+//! The spooled events are published in the order they were spooled once
+//! `Nakadi` is available again. Events published later wait until the
+//! spool is empty so that the order of events is kept.
+//!
+//! The file consists of records of the event type, the flow id and the
+//! events, each prefixed with its length.
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+
+/// When the spool file is synced to disk
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsyncPolicy {
+    /// After every append. Nothing is lost if the machine crashes.
+    Always,
+    /// Leave it to the operating system. Faster but the latest
+    /// events might be lost if the machine crashes.
+    Never,
+}
+
+/// Events that could not be published
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SpooledEvents {
+    pub event_type: String,
+    pub flow_id: String,
+    /// The events as they would have been sent to `Nakadi`
+    pub events: Vec<u8>,
+}
+
+impl SpooledEvents {
+    fn encoded_len(&self) -> u64 {
+        (3 * 4 + self.event_type.len() + self.flow_id.len() + self.events.len()) as u64
+    }
+}
+
+#[derive(Fail, Debug)]
+pub enum SpoolError {
+    /// The events would exceed the maximum size of the spool
+    #[fail(display = "The spool is full with {} bytes", _0)]
+    Full(u64),
+    #[fail(display = "Spool IO error: {}", _0)]
+    Io(String),
+    #[fail(display = "The spool is corrupt: {}", _0)]
+    Corrupt(String),
+}
+
+impl From<io::Error> for SpoolError {
+    fn from(err: io::Error) -> SpoolError {
+        SpoolError::Io(err.to_string())
+    }
+}
+
+/// A file events are appended to while `Nakadi` is unavailable
+pub struct Spool {
+    path: PathBuf,
+    max_bytes: u64,
+    fsync: FsyncPolicy,
+    state: Mutex<SpoolState>,
+    draining: Mutex<()>,
+}
+
+struct SpoolState {
+    file: File,
+    size: u64,
+}
+
+impl Spool {
+    /// Open the spool at `path` or create it if it does not exist.
+    ///
+    /// Events spooled by a previous process are kept and will be published.
+    /// A record the previous process was appending when it crashed is
+    /// removed.
+    pub fn open<P: AsRef<Path>>(
+        path: P,
+        max_bytes: u64,
+        fsync: FsyncPolicy,
+    ) -> Result<Spool, SpoolError> {
+        let path = path.as_ref().to_path_buf();
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        let mut state = SpoolState { file, size };
+        let (_, valid_len) = read_records(&path)?;
+        truncate_torn_record(&path, &mut state, valid_len)?;
+        Ok(Spool {
+            path,
+            max_bytes,
+            fsync,
+            state: Mutex::new(state),
+            draining: Mutex::new(()),
+        })
+    }
+
+    /// Append events to the end of the spool.
+    pub fn append(&self, events: &SpooledEvents) -> Result<(), SpoolError> {
+        let mut state = self.lock();
+        if state.size + events.encoded_len() > self.max_bytes {
+            return Err(SpoolError::Full(state.size));
+        }
+
+        let mut record = Vec::with_capacity(events.encoded_len() as usize);
+        write_field(&mut record, events.event_type.as_bytes())?;
+        write_field(&mut record, events.flow_id.as_bytes())?;
+        write_field(&mut record, &events.events)?;
+
+        state.file.write_all(&record)?;
+        if self.fsync == FsyncPolicy::Always {
+            state.file.sync_data()?;
+        }
+        state.size += record.len() as u64;
+        Ok(())
+    }
+
+    /// Returns true if there are no spooled events
+    pub fn is_empty(&self) -> bool {
+        self.lock().size == 0
+    }
+
+    /// The size of the spool in bytes
+    pub fn size(&self) -> u64 {
+        self.lock().size
+    }
+
+    /// Pass the spooled events to `publish` in the order they were spooled
+    /// until it returns false. The events passed before are removed.
+    ///
+    /// Events can be appended while `publish` is running. They are kept
+    /// and passed on the next drain. Returns the number of records removed.
+    pub fn drain<F>(&self, mut publish: F) -> Result<usize, SpoolError>
+    where
+        F: FnMut(&SpooledEvents) -> bool,
+    {
+        let _draining = match self.draining.lock() {
+            Ok(draining) => draining,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+
+        let (records, read_len) = {
+            let mut state = self.lock();
+            if state.size == 0 {
+                return Ok(0);
+            }
+            let (records, valid_len) = read_records(&self.path)?;
+            truncate_torn_record(&self.path, &mut state, valid_len)?;
+            (records, valid_len)
+        };
+
+        let published = records.iter().take_while(|events| publish(events)).count();
+        if published == 0 {
+            return Ok(0);
+        }
+
+        let mut state = self.lock();
+        let mut appended = Vec::new();
+        {
+            let mut file = File::open(&self.path)?;
+            file.seek(SeekFrom::Start(read_len))?;
+            file.read_to_end(&mut appended)?;
+        }
+
+        let tmp_path = self.path.with_extension("tmp");
+        {
+            let mut tmp = File::create(&tmp_path)?;
+            for events in &records[published..] {
+                write_field(&mut tmp, events.event_type.as_bytes())?;
+                write_field(&mut tmp, events.flow_id.as_bytes())?;
+                write_field(&mut tmp, &events.events)?;
+            }
+            tmp.write_all(&appended)?;
+            tmp.sync_all()?;
+        }
+        fs::rename(&tmp_path, &self.path)?;
+
+        state.file = OpenOptions::new().append(true).open(&self.path)?;
+        state.size = state.file.metadata()?.len();
+        Ok(published)
+    }
+
+    fn lock(&self) -> MutexGuard<SpoolState> {
+        match self.state.lock() {
+            Ok(state) => state,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+}
+
+fn write_field<W: Write>(w: &mut W, bytes: &[u8]) -> io::Result<()> {
+    let len = bytes.len() as u32;
+    w.write_all(&[
+        (len >> 24) as u8,
+        (len >> 16) as u8,
+        (len >> 8) as u8,
+        len as u8,
+    ])?;
+    w.write_all(bytes)
+}
+
+/// Cuts off a record that was not completely written because
+/// the process crashed while appending it.
+fn truncate_torn_record(
+    path: &Path,
+    state: &mut SpoolState,
+    valid_len: u64,
+) -> Result<(), SpoolError> {
+    if valid_len < state.size {
+        warn!(
+            "Removing {} bytes of an incomplete record from the spool {}",
+            state.size - valid_len,
+            path.display()
+        );
+        state.file.set_len(valid_len)?;
+        state.size = valid_len;
+    }
+    Ok(())
+}
+
+fn read_field<R: Read>(r: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut len = [0u8; 4];
+    match r.read(&mut len[..1])? {
+        0 => return Ok(None),
+        _ => r.read_exact(&mut len[1..])?,
+    }
+    let len = (u32::from(len[0]) << 24) | (u32::from(len[1]) << 16) | (u32::from(len[2]) << 8)
+        | u32::from(len[3]);
+    let mut bytes = vec![0; len as usize];
+    r.read_exact(&mut bytes)?;
+    Ok(Some(bytes))
+}
+
+fn read_record<R: Read>(r: &mut R) -> io::Result<Option<(Vec<u8>, Vec<u8>, Vec<u8>)>> {
+    let event_type = match read_field(r)? {
+        Some(event_type) => event_type,
+        None => return Ok(None),
+    };
+    let torn = || io::Error::new(io::ErrorKind::UnexpectedEof, "incomplete record");
+    let flow_id = read_field(r)?.ok_or_else(torn)?;
+    let events = read_field(r)?.ok_or_else(torn)?;
+    Ok(Some((event_type, flow_id, events)))
+}
+
+/// Reads the complete records of the spool and the number of bytes
+/// they take. A record cut short ends the spool.
+fn read_records(path: &Path) -> Result<(Vec<SpooledEvents>, u64), SpoolError> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut records = Vec::new();
+    let mut valid_len = 0;
+    loop {
+        let (event_type, flow_id, events) = match read_record(&mut reader) {
+            Ok(Some(record)) => record,
+            Ok(None) => break,
+            Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(err) => return Err(err.into()),
+        };
+        let events = SpooledEvents {
+            event_type: String::from_utf8(event_type)
+                .map_err(|err| SpoolError::Corrupt(err.to_string()))?,
+            flow_id: String::from_utf8(flow_id)
+                .map_err(|err| SpoolError::Corrupt(err.to_string()))?,
+            events,
+        };
+        valid_len += events.encoded_len();
+        records.push(events);
+    }
+    Ok((records, valid_len))
+}
+
+#[cfg(test)]
+fn test_spool(name: &str, max_bytes: u64) -> Spool {
+    let path = ::std::env::temp_dir().join(format!("nakadion-spool-{}", name));
+    let _ = fs::remove_file(&path);
+    Spool::open(path, max_bytes, FsyncPolicy::Never).unwrap()
+}
+
+#[cfg(test)]
+fn test_events(n: usize) -> SpooledEvents {
+    SpooledEvents {
+        event_type: "et".to_string(),
+        flow_id: n.to_string(),
+        events: format!("[{}]", n).into_bytes(),
+    }
+}
+
+#[test]
+fn spooled_events_are_drained_in_order() {
+    let spool = test_spool("order", 1024);
+    for n in 0..3 {
+        spool.append(&test_events(n)).unwrap();
+    }
+
+    let mut drained = Vec::new();
+    let removed = spool
+        .drain(|events| {
+            drained.push(events.clone());
+            drained.len() < 2
+        })
+        .unwrap();
+    assert_eq!(removed, 1);
+    assert_eq!(drained, vec![test_events(0), test_events(1)]);
+
+    let mut drained = Vec::new();
+    spool
+        .drain(|events| {
+            drained.push(events.clone());
+            true
+        })
+        .unwrap();
+    assert_eq!(drained, vec![test_events(1), test_events(2)]);
+    assert!(spool.is_empty());
+}
+
+#[test]
+fn a_full_spool_rejects_events() {
+    let spool = test_spool("full", 30);
+    spool.append(&test_events(0)).unwrap();
+    match spool.append(&test_events(1)) {
+        Err(SpoolError::Full(size)) => assert_eq!(size, test_events(0).encoded_len()),
+        other => panic!("expected a full spool but got {:?}", other),
+    }
+}
+
+#[test]
+fn a_torn_record_ends_the_spool() {
+    let spool = test_spool("torn", 1024);
+    for n in 0..2 {
+        spool.append(&test_events(n)).unwrap();
+    }
+    {
+        let mut file = OpenOptions::new().append(true).open(&spool.path).unwrap();
+        write_field(&mut file, b"et").unwrap();
+        file.write_all(&[0, 0, 0, 9, b'4']).unwrap();
+    }
+    let spool = Spool::open(&spool.path, 1024, FsyncPolicy::Never).unwrap();
+    assert_eq!(spool.size(), 2 * test_events(0).encoded_len());
+
+    let mut drained = Vec::new();
+    spool
+        .drain(|events| {
+            drained.push(events.clone());
+            true
+        })
+        .unwrap();
+    assert_eq!(drained, vec![test_events(0), test_events(1)]);
+}
+
+#[test]
+fn events_can_be_appended_while_draining() {
+    let spool = test_spool("append_while_draining", 1024);
+    spool.append(&test_events(0)).unwrap();
+
+    spool
+        .drain(|_| {
+            spool.append(&test_events(1)).unwrap();
+            true
+        })
+        .unwrap();
+
+    let mut drained = Vec::new();
+    spool
+        .drain(|events| {
+            drained.push(events.clone());
+            true
+        })
+        .unwrap();
+    assert_eq!(drained, vec![test_events(1)]);
+}
@@ -0,0 +1,162 @@
+//! Where the time of a batch goes
+//!
+//! A batch passes several stages from being read from the connection
+//! until its cursor was committed. The time spent in each stage is
+//! reported via the `MetricsCollector` and logged on debug level with
+//! the target `nakadion::stage_timing` so that it can be enabled on its
+//! own:
+//!
+//! * `Read`: Reading the line from the connection. This includes
+//! waiting for `Nakadi` to send it.
+//! * `Parse`: Parsing, validating and filtering the line until
+//! it was dispatched to a worker
+//! * `QueueWait`: Waiting in the mailbox of the worker
+//! * `Handler`: Handling the events
+//! * `Commit`: From handing the batch to the committer until its
+//! cursor was committed
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// The log target of the timings
+pub const LOG_TARGET: &'static str = "nakadion::stage_timing";
+
+/// A stage of processing a batch
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+pub enum Stage {
+    Read,
+    Parse,
+    QueueWait,
+    Handler,
+    Commit,
+}
+
+impl Stage {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Stage::Read => "read",
+            Stage::Parse => "parse",
+            Stage::QueueWait => "queue_wait",
+            Stage::Handler => "handler",
+            Stage::Commit => "commit",
+        }
+    }
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// The time a batch spent in each stage it passed so far
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct StageTimings {
+    pub read: Duration,
+    pub parse: Duration,
+    pub queue_wait: Duration,
+    pub handler: Duration,
+    pub commit: Duration,
+}
+
+impl StageTimings {
+    /// The timings of a line read in `read` and
+    /// received at `received_at` which was just dispatched.
+    pub fn dispatched(read: Duration, received_at: Instant) -> StageTimings {
+        StageTimings {
+            read,
+            parse: received_at.elapsed(),
+            ..StageTimings::default()
+        }
+    }
+
+    pub fn get(&self, stage: Stage) -> Duration {
+        match stage {
+            Stage::Read => self.read,
+            Stage::Parse => self.parse,
+            Stage::QueueWait => self.queue_wait,
+            Stage::Handler => self.handler,
+            Stage::Commit => self.commit,
+        }
+    }
+
+    pub fn set(&mut self, stage: Stage, took: Duration) {
+        match stage {
+            Stage::Read => self.read = took,
+            Stage::Parse => self.parse = took,
+            Stage::QueueWait => self.queue_wait = took,
+            Stage::Handler => self.handler = took,
+            Stage::Commit => self.commit = took,
+        }
+    }
+
+    /// The time spent in all stages
+    pub fn total(&self) -> Duration {
+        self.read + self.parse + self.queue_wait + self.handler + self.commit
+    }
+
+    /// The time a batch received at `received_at` waited in the
+    /// mailbox if it is picked up `now`.
+    pub fn queue_wait_until(&self, received_at: Instant, now: Instant) -> Duration {
+        let since_received = if now > received_at {
+            now - received_at
+        } else {
+            Duration::from_secs(0)
+        };
+        since_received
+            .checked_sub(self.parse)
+            .unwrap_or_else(|| Duration::from_secs(0))
+    }
+
+    /// The time from handing a batch received at `received_at`
+    /// to the committer until its cursor was committed `now`.
+    pub fn commit_until(&self, received_at: Instant, now: Instant) -> Duration {
+        self.queue_wait_until(received_at, now)
+            .checked_sub(self.queue_wait + self.handler)
+            .unwrap_or_else(|| Duration::from_secs(0))
+    }
+}
+
+impl fmt::Display for StageTimings {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let stages = [
+            Stage::Read,
+            Stage::Parse,
+            Stage::QueueWait,
+            Stage::Handler,
+            Stage::Commit,
+        ];
+        for (i, stage) in stages.iter().enumerate() {
+            if i > 0 {
+                write!(f, " ")?;
+            }
+            write!(f, "{}={}us", stage, micros(self.get(*stage)))?;
+        }
+        Ok(())
+    }
+}
+
+fn micros(d: Duration) -> u64 {
+    d.as_secs() * 1_000_000 + u64::from(d.subsec_nanos() / 1_000)
+}
+
+#[test]
+fn queue_wait_excludes_parsing() {
+    let received_at = Instant::now();
+    let timings = StageTimings {
+        parse: Duration::from_millis(3),
+        ..StageTimings::default()
+    };
+
+    assert_eq!(
+        timings.queue_wait_until(received_at, received_at + Duration::from_millis(10)),
+        Duration::from_millis(7)
+    );
+    assert_eq!(
+        timings.queue_wait_until(received_at, received_at + Duration::from_millis(1)),
+        Duration::from_secs(0)
+    );
+    assert_eq!(
+        timings.to_string(),
+        "read=0us parse=3000us queue_wait=0us handler=0us commit=0us"
+    );
+}
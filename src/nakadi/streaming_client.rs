@@ -1,44 +1,97 @@
 /// Stream lines from a Nakadi subscription
 use std::sync::Arc;
 use std::env;
+use std::fmt;
 use std::time::{Duration, Instant};
-use std::io::{BufRead, BufReader, Error as IoError, Read, Split};
+use std::io::{BufRead, BufReader, Error as IoError, Read};
 
 use reqwest::{Client as HttpClient, ClientBuilder as HttpClientBuilder, Response};
 use reqwest::StatusCode;
 use reqwest::header::{Authorization, Bearer, Headers};
 use failure::*;
+use url::form_urlencoded;
 
-use auth::{AccessToken, ProvidesAccessToken, TokenError};
+use auth::{send_with_fresh_token_on_401, AccessToken, AsyncProvidesToken, BlockingTokenProvider,
+           PrefetchingTokenProvider, ProvidesAccessToken, TokenError};
+use nakadi::api_client::InitialCursor;
+use nakadi::buffer_pool::{BufferPool, PooledBuffer};
 use nakadi::model::{FlowId, StreamId, SubscriptionId};
+use nakadi::response_headers::{self, CapturedHeaders};
+use nakadi::wire_debug;
 use nakadi::metrics::{DevNullMetricsCollector, MetricsCollector};
+use nakadi::warm_up;
+use nakadi::failover::NakadiHosts;
 
 header! { (XNakadiStreamId, "X-Nakadi-StreamId") => [String] }
 header! { (XFlowId, "X-Flow-Id") => [String] }
+header! { (XNakadiCursors, "X-Nakadi-Cursors") => [String] }
 
 const LINE_SPLIT_BYTE: u8 = b'\n';
 
 /// A line as received from Nakadi plus a timestamp.
 pub struct RawLine {
     /// The bytes reveived as a line from Nakadi
-    pub bytes: Vec<u8>,
+    pub bytes: PooledBuffer,
     /// The timestamp when this line was received
     pub received_at: Instant,
+    /// How long reading the line took including
+    /// waiting for `Nakadi` to send it
+    pub read_took: Duration,
 }
 
 pub type LineResult = ::std::result::Result<RawLine, IoError>;
 
+/// Query parameters and headers added to a request
+/// connecting to a stream
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ConnectExtras {
+    pub query_params: Vec<(String, String)>,
+    pub headers: Vec<(String, String)>,
+}
+
+/// Adds query parameters and headers to the requests connecting
+/// to a stream, e.g. tracing or tenant hints required by a proxy.
+pub trait CustomizesConnect {
+    /// Called on every connect with the URL to connect to.
+    fn customize(&self, connect_url: &str, extras: &mut ConnectExtras);
+}
+
+impl<F> CustomizesConnect for F
+where
+    F: Fn(&str, &mut ConnectExtras),
+{
+    fn customize(&self, connect_url: &str, extras: &mut ConnectExtras) {
+        self(connect_url, extras)
+    }
+}
+
+/// A `CustomizesConnect` that can be shared between threads.
+#[derive(Clone)]
+pub struct SharedConnectCustomizer(pub Arc<CustomizesConnect + Send + Sync>);
+
+impl fmt::Debug for SharedConnectCustomizer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SharedConnectCustomizer")
+    }
+}
+
 /// An iterator over lines received from Nakadi.
 pub struct NakadiLineIterator {
-    lines: Split<BufReader<Response>>,
+    reader: BufReader<Response>,
+    buffer_pool: BufferPool,
 }
 
 /// An iterator over lines `Nakadion` understands.
 impl NakadiLineIterator {
     pub fn new(response: Response) -> Self {
-        let reader = BufReader::with_capacity(1024 * 1024, response);
+        NakadiLineIterator::with_buffer_pool(response, BufferPool::default())
+    }
+
+    /// Read the lines into buffers taken from `buffer_pool`.
+    pub fn with_buffer_pool(response: Response, buffer_pool: BufferPool) -> Self {
         NakadiLineIterator {
-            lines: reader.split(LINE_SPLIT_BYTE),
+            reader: BufReader::with_capacity(1024 * 1024, response),
+            buffer_pool,
         }
     }
 }
@@ -47,12 +100,23 @@ impl Iterator for NakadiLineIterator {
     type Item = LineResult;
 
     fn next(&mut self) -> Option<LineResult> {
-        self.lines.next().map(|r| {
-            r.map(|l| RawLine {
-                bytes: l,
-                received_at: Instant::now(),
-            })
-        })
+        let mut bytes = self.buffer_pool.take();
+        let read_started = Instant::now();
+        match self.reader.read_until(LINE_SPLIT_BYTE, &mut bytes) {
+            Ok(0) => None,
+            Ok(_) => {
+                if bytes.last() == Some(&LINE_SPLIT_BYTE) {
+                    bytes.pop();
+                }
+                let received_at = Instant::now();
+                Some(Ok(RawLine {
+                    bytes,
+                    received_at,
+                    read_took: received_at - read_started,
+                }))
+            }
+            Err(err) => Some(Err(err)),
+        }
     }
 }
 
@@ -65,6 +129,22 @@ pub trait StreamingClient {
         subscription_id: &SubscriptionId,
         flow_id: FlowId,
     ) -> ::std::result::Result<(StreamId, Self::LineIterator), ConnectError>;
+
+    /// Establish a connection with `batch_limit` and
+    /// `max_uncommitted_events` lowered for warming up
+    /// or adapting to the throughput.
+    ///
+    /// Clients that can not change their limits connect
+    /// with the limits they were configured with.
+    fn connect_with_limits(
+        &self,
+        subscription_id: &SubscriptionId,
+        flow_id: FlowId,
+        _batch_limit: usize,
+        _max_uncommitted_events: usize,
+    ) -> ::std::result::Result<(StreamId, Self::LineIterator), ConnectError> {
+        self.connect(subscription_id, flow_id)
+    }
 }
 
 /// Settings for establishing a connection to `Nakadi`.
@@ -322,6 +402,24 @@ impl ConfigBuilder {
         )
     }
 
+    /// Build a `NakadiStreamingClient` from this builder which gets its
+    /// tokens from a `PrefetchingTokenProvider` around `token_provider`.
+    pub fn build_client_with_async_token_provider<P, M>(
+        self,
+        token_provider: P,
+        refresh_interval: Duration,
+        metrics_collector: M,
+    ) -> Result<NakadiStreamingClient<M>, Error>
+    where
+        P: AsyncProvidesToken + Send + Sync + 'static,
+        M: MetricsCollector + Send + 'static,
+    {
+        self.build_client_with_shared_access_token_provider(
+            prefetching_token_provider(token_provider, refresh_interval),
+            metrics_collector,
+        )
+    }
+
     /// Build a `NakadiStreamingClient` from this builder.
     pub fn build_client_with_shared_access_token_provider<M>(
         self,
@@ -341,6 +439,18 @@ impl ConfigBuilder {
     }
 }
 
+/// Wraps an `AsyncProvidesToken` so that the connector gets tokens
+/// fetched ahead of time.
+fn prefetching_token_provider<P: AsyncProvidesToken + Send + Sync + 'static>(
+    token_provider: P,
+    refresh_interval: Duration,
+) -> Arc<ProvidesAccessToken + Send + Sync + 'static> {
+    Arc::new(BlockingTokenProvider(PrefetchingTokenProvider::new(
+        token_provider,
+        refresh_interval,
+    )))
+}
+
 /// Connects to Nakadi via HTTP and creates an iterator of
 /// lines from the data received from Nakadi.
 #[derive(Clone)]
@@ -349,6 +459,9 @@ pub struct NakadiStreamingClient<M> {
     token_provider: Arc<ProvidesAccessToken + Send + Sync + 'static>,
     config: Config,
     metrics_collector: M,
+    buffer_pool: BufferPool,
+    failover: Option<NakadiHosts>,
+    connect_customizer: Option<SharedConnectCustomizer>,
 }
 
 impl<M> NakadiStreamingClient<M>
@@ -380,6 +493,25 @@ where
         )
     }
 
+    /// Create a new `NakadiStreamingClient<M>` which gets its tokens
+    /// from a `PrefetchingTokenProvider` around `token_provider`.
+    ///
+    /// Connecting only waits for a token if there is none yet or
+    /// `Nakadi` rejected the last one. Otherwise the token fetched
+    /// ahead of time is used while the next one is being fetched.
+    pub fn with_async_token_provider<P: AsyncProvidesToken + Send + Sync + 'static>(
+        config: Config,
+        token_provider: P,
+        refresh_interval: Duration,
+        metrics_collector: M,
+    ) -> Result<NakadiStreamingClient<M>, Error> {
+        NakadiStreamingClient::with_shared_access_token_provider(
+            config,
+            prefetching_token_provider(token_provider, refresh_interval),
+            metrics_collector,
+        )
+    }
+
     /// Create a new `NakadiStreamingClient<M>`.
     pub fn with_shared_access_token_provider(
         config: Config,
@@ -391,12 +523,166 @@ where
             .build()
             .context("Could not create HTTP client")?;
 
-        Ok(NakadiStreamingClient {
+        Ok(NakadiStreamingClient::with_http_client(
+            config,
+            http_client,
+            token_provider,
+            metrics_collector,
+        ))
+    }
+
+    /// Create a new `NakadiStreamingClient<M>` that uses an already
+    /// configured HTTP client.
+    ///
+    /// The HTTP client must not have a timeout since streams
+    /// are kept open.
+    pub fn with_http_client(
+        config: Config,
+        http_client: HttpClient,
+        token_provider: Arc<ProvidesAccessToken + Send + Sync + 'static>,
+        metrics_collector: M,
+    ) -> NakadiStreamingClient<M> {
+        NakadiStreamingClient {
             http_client,
             token_provider,
             config,
             metrics_collector,
-        })
+            buffer_pool: BufferPool::default(),
+            failover: None,
+            connect_customizer: None,
+        }
+    }
+
+    /// Connect to the current host of `hosts` instead of
+    /// the configured `nakadi_host` and fail over between them.
+    pub fn with_failover(mut self, hosts: NakadiHosts) -> NakadiStreamingClient<M> {
+        self.failover = Some(hosts);
+        self
+    }
+
+    /// Add the query parameters and headers of `customizer`
+    /// to every request connecting to a stream.
+    pub fn with_connect_customizer(
+        mut self,
+        customizer: SharedConnectCustomizer,
+    ) -> NakadiStreamingClient<M> {
+        self.connect_customizer = Some(customizer);
+        self
+    }
+
+    /// The URL with the query parameters of the customizer
+    /// and the headers to add
+    fn customize_connect(&self, connect_url: String) -> (String, Vec<(String, String)>) {
+        let customizer = match self.connect_customizer {
+            Some(ref customizer) => customizer,
+            None => return (connect_url, Vec::new()),
+        };
+        let mut extras = ConnectExtras::default();
+        customizer.0.customize(&connect_url, &mut extras);
+        (
+            append_query_params(connect_url, &extras.query_params),
+            extras.headers,
+        )
+    }
+}
+
+impl<M> NakadiStreamingClient<M>
+where
+    M: MetricsCollector,
+{
+    /// Stream the events of an event type via the low level API.
+    ///
+    /// Only the partitions of `cursors` are streamed, each starting
+    /// after the offset of its cursor. Nothing gets committed and
+    /// `max_uncommitted_events` is ignored.
+    pub fn stream_event_type(
+        &self,
+        event_type: &str,
+        cursors: &[InitialCursor],
+        flow_id: FlowId,
+    ) -> ::std::result::Result<NakadiLineIterator, ConnectError> {
+        let mut connect_url = String::new();
+        connect_url.push_str(&self.config.nakadi_host);
+        if !connect_url.ends_with("/") {
+            connect_url.push('/');
+        }
+        connect_url.push_str("event-types/");
+        connect_url.push_str(event_type);
+        connect_url.push_str("/events");
+        append_connect_params(&mut connect_url, &self.config, false);
+        let (connect_url, extra_headers) = self.customize_connect(connect_url);
+
+        let cursors: Vec<_> = cursors
+            .iter()
+            .map(|cursor| json!({ "partition": cursor.partition.0, "offset": cursor.offset }))
+            .collect();
+        let cursors = json!(cursors).to_string();
+
+        self.metrics_collector.streaming_connect_attempt();
+
+        let mut response =
+            send_with_fresh_token_on_401(&*self.token_provider, || -> Result<_, ConnectError> {
+                let mut headers = Headers::new();
+                if let Some(AccessToken(token)) = self.token_provider.get_token()? {
+                    headers.set(Authorization(Bearer { token }));
+                }
+
+                headers.set(XFlowId(flow_id.0.clone()));
+                headers.set(XNakadiCursors(cursors.clone()));
+                for &(ref name, ref value) in &extra_headers {
+                    headers.set_raw(name.clone(), value.clone().into_bytes());
+                }
+
+                let mut request_builder = self.http_client.get(&connect_url);
+                request_builder.headers(headers);
+
+                Ok(wire_debug::send(&self.http_client, &mut request_builder, None)?)
+            })?;
+
+        match response.status() {
+            StatusCode::Ok => Ok(NakadiLineIterator::with_buffer_pool(
+                response,
+                self.buffer_pool.clone(),
+            )),
+            StatusCode::Forbidden => {
+                self.metrics_collector.streaming_connect_attempt_failed();
+                Err(ConnectError::Forbidden(
+                    format!("{}: {}", StatusCode::Forbidden, read_response_body(&mut response)),
+                    flow_id,
+                ))
+            }
+            StatusCode::Unauthorized => {
+                self.metrics_collector.streaming_connect_attempt_failed();
+                Err(ConnectError::Unauthorized(
+                    format!(
+                        "{}: {}",
+                        StatusCode::Unauthorized,
+                        read_response_body(&mut response)
+                    ),
+                    flow_id,
+                ))
+            }
+            StatusCode::ServiceUnavailable => {
+                self.metrics_collector.streaming_connect_attempt_failed();
+                let retry_after = CapturedHeaders::from_headers(response.headers()).retry_after();
+                Err(ConnectError::Overloaded(
+                    format!(
+                        "{}: {}",
+                        StatusCode::ServiceUnavailable,
+                        read_response_body(&mut response)
+                    ),
+                    retry_after,
+                    flow_id,
+                ))
+            }
+            other_status => {
+                self.metrics_collector.streaming_connect_attempt_failed();
+                Err(ConnectError::Other(
+                    format!("{}: {}", other_status, read_response_body(&mut response)),
+                    flow_id,
+                ))
+            }
+        }
     }
 }
 
@@ -409,7 +695,12 @@ fn create_connect_url(config: &Config, subscription_id: &SubscriptionId) -> Stri
     connect_url.push_str("subscriptions/");
     connect_url.push_str(&subscription_id.0);
     connect_url.push_str("/events");
+    append_connect_params(&mut connect_url, config, true);
+
+    connect_url
+}
 
+fn append_connect_params(connect_url: &mut String, config: &Config, is_subscription: bool) {
     let mut connect_params = Vec::new();
     if config.stream_keep_alive_limit != 0 {
         connect_params.push(format!(
@@ -435,7 +726,7 @@ fn create_connect_url(config: &Config, subscription_id: &SubscriptionId) -> Stri
     if config.batch_limit != 0 {
         connect_params.push(format!("batch_limit={}", config.batch_limit));
     }
-    if config.max_uncommitted_events != 0 {
+    if is_subscription && config.max_uncommitted_events != 0 {
         connect_params.push(format!(
             "max_uncommitted_events={}",
             config.max_uncommitted_events
@@ -446,7 +737,17 @@ fn create_connect_url(config: &Config, subscription_id: &SubscriptionId) -> Stri
         connect_url.push('?');
         connect_url.push_str(&connect_params.join("&"));
     };
+}
 
+fn append_query_params(mut connect_url: String, params: &[(String, String)]) -> String {
+    if params.is_empty() {
+        return connect_url;
+    }
+    let query = form_urlencoded::Serializer::new(String::new())
+        .extend_pairs(params.iter())
+        .finish();
+    connect_url.push(if connect_url.contains('?') { '&' } else { '?' });
+    connect_url.push_str(&query);
     connect_url
 }
 
@@ -460,21 +761,100 @@ where
         subscription_id: &SubscriptionId,
         flow_id: FlowId,
     ) -> ::std::result::Result<(StreamId, NakadiLineIterator), ConnectError> {
-        let connect_url = create_connect_url(&self.config, &subscription_id);
+        self.connect_with_config(&self.config, subscription_id, flow_id)
+    }
+
+    fn connect_with_limits(
+        &self,
+        subscription_id: &SubscriptionId,
+        flow_id: FlowId,
+        batch_limit: usize,
+        max_uncommitted_events: usize,
+    ) -> ::std::result::Result<(StreamId, NakadiLineIterator), ConnectError> {
+        let mut config = self.config.clone();
+        config.batch_limit = warm_up::reduced_limit(config.batch_limit, batch_limit);
+        config.max_uncommitted_events =
+            warm_up::reduced_limit(config.max_uncommitted_events, max_uncommitted_events);
+        self.connect_with_config(&config, subscription_id, flow_id)
+    }
+}
+
+impl<M> NakadiStreamingClient<M>
+where
+    M: MetricsCollector,
+{
+    fn connect_with_config(
+        &self,
+        config: &Config,
+        subscription_id: &SubscriptionId,
+        flow_id: FlowId,
+    ) -> ::std::result::Result<(StreamId, NakadiLineIterator), ConnectError> {
+        let hosts = match self.failover {
+            Some(ref hosts) => hosts,
+            None => return self.connect_to(config, subscription_id, flow_id),
+        };
 
-        let mut headers = Headers::new();
-        if let Some(AccessToken(token)) = self.token_provider.get_token()? {
-            headers.set(Authorization(Bearer { token }));
+        let mut config = config.clone();
+        config.nakadi_host = hosts.host_for_connect(Instant::now());
+        let result = self.connect_to(&config, subscription_id, flow_id);
+        match result {
+            Ok(_) => hosts.connect_succeeded(),
+            Err(ref err) if err.is_host_failure() => {
+                if let Some(next_host) = hosts.connect_failed(Instant::now()) {
+                    warn!(
+                        "Failing over from Nakadi host {} to {}: {}",
+                        config.nakadi_host, next_host, err
+                    );
+                }
+            }
+            Err(_) => (),
         }
+        result
+    }
 
-        headers.set(XFlowId(flow_id.0.clone()));
+    fn connect_to(
+        &self,
+        config: &Config,
+        subscription_id: &SubscriptionId,
+        flow_id: FlowId,
+    ) -> ::std::result::Result<(StreamId, NakadiLineIterator), ConnectError> {
+        let connect_url = create_connect_url(config, &subscription_id);
+        let (connect_url, extra_headers) = self.customize_connect(connect_url);
 
         self.metrics_collector.streaming_connect_attempt();
 
-        let mut response = self.http_client.get(&connect_url).headers(headers).send()?;
+        let mut response =
+            send_with_fresh_token_on_401(&*self.token_provider, || -> Result<_, ConnectError> {
+                let mut headers = Headers::new();
+                if let Some(AccessToken(token)) = self.token_provider.get_token()? {
+                    headers.set(Authorization(Bearer { token }));
+                }
+
+                headers.set(XFlowId(flow_id.0.clone()));
+                for &(ref name, ref value) in &extra_headers {
+                    headers.set_raw(name.clone(), value.clone().into_bytes());
+                }
+
+                let mut request_builder = self.http_client.get(&connect_url);
+                request_builder.headers(headers);
+
+                Ok(wire_debug::send(&self.http_client, &mut request_builder, None)?)
+            })?;
+
+        let captured_headers = CapturedHeaders::from_headers(response.headers());
+        if let Some(remaining) = captured_headers.rate_limit_remaining() {
+            self.metrics_collector
+                .streaming_rate_limit_remaining(remaining);
+        }
 
         match response.status() {
             StatusCode::Ok => {
+                if !captured_headers.is_empty() {
+                    debug!(
+                        "Connected(FlowId: {}) with response headers {}",
+                        flow_id, captured_headers
+                    );
+                }
                 let stream_id = if let Some(stream_id) = response
                     .headers()
                     .get::<XNakadiStreamId>()
@@ -489,7 +869,10 @@ where
                         flow_id.clone(),
                     ));
                 };
-                Ok((stream_id, NakadiLineIterator::new(response)))
+                Ok((
+                    stream_id,
+                    NakadiLineIterator::with_buffer_pool(response, self.buffer_pool.clone()),
+                ))
             }
             StatusCode::Forbidden => {
                 self.metrics_collector.streaming_connect_attempt_failed();
@@ -546,6 +929,18 @@ where
                     flow_id,
                 ))
             }
+            StatusCode::ServiceUnavailable => {
+                self.metrics_collector.streaming_connect_attempt_failed();
+                Err(ConnectError::Overloaded(
+                    format!(
+                        "{}: {}",
+                        StatusCode::ServiceUnavailable,
+                        read_response_body(&mut response)
+                    ),
+                    captured_headers.retry_after(),
+                    flow_id,
+                ))
+            }
             other_status => {
                 self.metrics_collector.streaming_connect_attempt_failed();
                 Err(ConnectError::Other(
@@ -558,11 +953,14 @@ where
 }
 
 fn read_response_body(response: &mut Response) -> String {
+    let headers = CapturedHeaders::from_headers(response.headers());
     let mut buf = String::new();
-    response
+    let body = response
         .read_to_string(&mut buf)
         .map(|_| buf)
-        .unwrap_or("<Nakadion: Could not read body.>".to_string())
+        .unwrap_or("<Nakadion: Could not read body.>".to_string());
+    wire_debug::log_response_body(&body);
+    response_headers::with_headers(body, &headers)
 }
 
 /// Errors that can happen when connectiong to Nakadi for
@@ -571,6 +969,9 @@ fn read_response_body(response: &mut Response) -> String {
 pub enum ConnectError {
     #[fail(display = "Token Error on connect: {}", _0)]
     Token(String),
+    /// The token provider failed permanently
+    #[fail(display = "Authentication broken: {}", _0)]
+    AuthBroken(String),
     #[fail(display = "Connection Error: {}", _0)]
     Connection(String),
     #[fail(display = "Forbidden: {}", _0)]
@@ -583,6 +984,9 @@ pub enum ConnectError {
     Conflict(String, FlowId),
     #[fail(display = "Subscription not found: {}", _0)]
     SubscriptionNotFound(String, FlowId),
+    /// `Nakadi` responded with 503. It might have said how long to back off.
+    #[fail(display = "Nakadi is overloaded: {}", _0)]
+    Overloaded(String, Option<Duration>, FlowId),
     #[fail(display = "Other error: {}", _0)]
     Other(String, FlowId),
 }
@@ -595,14 +999,38 @@ impl ConnectError {
             ConnectError::Forbidden(_, _) => true,
             ConnectError::BadRequest(_, _) => true,
             ConnectError::SubscriptionNotFound(_, _) => true,
+            ConnectError::AuthBroken(_) => true,
             _ => false,
         }
     }
+
+    /// Returns true if the host could not be reached or was overloaded
+    /// so that another host might do better.
+    pub fn is_host_failure(&self) -> bool {
+        match *self {
+            ConnectError::Connection(_) => true,
+            ConnectError::Overloaded(..) => true,
+            ConnectError::Other(..) => true,
+            _ => false,
+        }
+    }
+
+    /// The time `Nakadi` asked to wait before connecting again
+    pub fn retry_after(&self) -> Option<Duration> {
+        match *self {
+            ConnectError::Overloaded(_, retry_after, _) => retry_after,
+            _ => None,
+        }
+    }
 }
 
 impl From<TokenError> for ConnectError {
     fn from(err: TokenError) -> ConnectError {
-        ConnectError::Token(format!("Could not get token: {}", err))
+        if err.is_permanent() {
+            ConnectError::AuthBroken(format!("Could not get token: {}", err))
+        } else {
+            ConnectError::Token(format!("Could not get token: {}", err))
+        }
     }
 }
 
@@ -0,0 +1,572 @@
+//! Testing the consumer against scripted streams
+//!
+//! A `Scenario` describes what `Nakadi` does: which batches are sent on
+//! which stream, when streams close and when connecting or committing
+//! fails. It provides a `StreamingClient` and an `ApiClient` that follow
+//! the script without any network so that the failure handling of a
+//! consumer configuration can be tested:
+//!
+//! ```rust,ignore
+//! let outcome = Scenario::new()
+//!     .batches("0", 3)
+//!     .close_stream()
+//!     .commit_fails(1)
+//!     .batches("0", 1)
+//!     .run(handler_factory, Duration::from_secs(10));
+//!
+//! assert_eq!(outcome.invocations.len(), 4);
+//! ```
+//!
+//! The clients can also be passed to `Nakadion::start_with` to test
+//! with exactly the settings used in production. Once all streams of
+//! the scenario have ended connecting fails permanently so that the
+//! consumer stops.
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use std::vec;
+
+use nakadi::api_client::{stats, ApiClient, CommitError, CommitStatus,
+                         CreateEventTypeError, CreateSubscriptionError,
+                         CreateSubscriptionRequest, CreateSubscriptionStatus,
+                         DeleteEventTypeError, DeleteSubscriptionError, EventTypeDefinition,
+                         StatsError};
+use nakadi::buffer_pool::PooledBuffer;
+use nakadi::consumer::{Consumer, ConsumerConfig, ConsumerOutcome};
+use nakadi::handler::{BatchHandler, CreateHandlerError, HandlerFactory, ProcessingStatus,
+                      ProgressReporter};
+use nakadi::metrics::DevNullMetricsCollector;
+use nakadi::model::{EventType, FlowId, PartitionId, StreamId, SubscriptionId};
+use nakadi::streaming_client::{ConnectError, LineResult, RawLine, StreamingClient};
+use nakadi::commit_policy::{CommitPolicy, SharedCommitPolicy};
+use nakadi::{CommitStrategy, ShutdownConfig};
+
+/// The event type of the batches if none was set
+pub const DEFAULT_TEST_EVENT_TYPE: &'static str = "test.event";
+
+#[derive(Debug, Clone, Default)]
+struct ScriptedStream {
+    lines: Vec<String>,
+    connect_failures: usize,
+    commit_failures: usize,
+}
+
+/// A script of what `Nakadi` does
+#[derive(Debug, Clone)]
+pub struct Scenario {
+    event_type: String,
+    streams: Vec<ScriptedStream>,
+    offsets: HashMap<String, u64>,
+    commit_policy: SharedCommitPolicy,
+}
+
+impl Scenario {
+    /// An empty scenario with a single stream.
+    pub fn new() -> Scenario {
+        Scenario {
+            event_type: DEFAULT_TEST_EVENT_TYPE.to_string(),
+            streams: vec![ScriptedStream::default()],
+            offsets: HashMap::new(),
+            commit_policy: CommitStrategy::AllBatches.into(),
+        }
+    }
+
+    /// The event type of all following batches.
+    pub fn event_type<T: Into<String>>(mut self, event_type: T) -> Scenario {
+        self.event_type = event_type.into();
+        self
+    }
+
+    /// The `CommitStrategy` used by `run`.
+    pub fn commit_strategy(mut self, commit_strategy: CommitStrategy) -> Scenario {
+        self.commit_policy = commit_strategy.into();
+        self
+    }
+
+    /// The `CommitPolicy` used by `run` instead of a `CommitStrategy`.
+    pub fn commit_policy<P>(mut self, policy: P) -> Scenario
+    where
+        P: CommitPolicy + Send + Sync + 'static,
+    {
+        self.commit_policy = SharedCommitPolicy::new(policy);
+        self
+    }
+
+    /// Send `n` batches with a single event each for the partition.
+    ///
+    /// The events are `{"n": <offset>}`.
+    pub fn batches(mut self, partition: &str, n: usize) -> Scenario {
+        for _ in 0..n {
+            let offset = self.next_offset(partition);
+            let events = format!(r#"[{{"n":{}}}]"#, offset);
+            self = self.push_line(partition, offset, Some(&events));
+        }
+        self
+    }
+
+    /// Send a batch with the given events which must be a JSON array.
+    pub fn batch(mut self, partition: &str, events: &str) -> Scenario {
+        let offset = self.next_offset(partition);
+        self.push_line(partition, offset, Some(events))
+    }
+
+    /// Send a keep alive line for the partition.
+    pub fn keep_alive(self, partition: &str) -> Scenario {
+        let offset = self.offsets.get(partition).cloned().unwrap_or(0);
+        self.push_line(partition, offset, None)
+    }
+
+    /// End the current stream. Following batches are sent on a new stream.
+    pub fn close_stream(mut self) -> Scenario {
+        self.streams.push(ScriptedStream::default());
+        self
+    }
+
+    /// Fail the next `times` attempts to connect to the current stream.
+    pub fn connect_fails(mut self, times: usize) -> Scenario {
+        self.current().connect_failures += times;
+        self
+    }
+
+    /// Fail the first `times` commits on the current stream.
+    pub fn commit_fails(mut self, times: usize) -> Scenario {
+        self.current().commit_failures += times;
+        self
+    }
+
+    /// Create the clients following the script and the log recording
+    /// the commits.
+    pub fn build(self) -> (ScriptedStreamingClient, ScriptedApiClient, ScenarioLog) {
+        let mut streams = self.streams;
+        if streams.len() > 1 && streams.last().map(|s| s.lines.is_empty()) == Some(true) {
+            // Nothing was scripted after the last `close_stream`
+            streams.pop();
+        }
+
+        let state = Arc::new(Mutex::new(ScenarioState {
+            streams: streams.into_iter().collect(),
+            streams_started: 0,
+            commit_failures: HashMap::new(),
+        }));
+        let log = ScenarioLog::default();
+
+        (
+            ScriptedStreamingClient {
+                state: state.clone(),
+            },
+            ScriptedApiClient {
+                state,
+                log: log.clone(),
+            },
+            log,
+        )
+    }
+
+    /// Run a consumer with the handlers of `handler_factory` until all
+    /// streams have ended or `timeout` has elapsed.
+    pub fn run<HF>(self, handler_factory: HF, timeout: Duration) -> ScenarioOutcome
+    where
+        HF: HandlerFactory + Send + Sync + 'static,
+    {
+        let commit_policy = self.commit_policy.clone();
+        let (streaming_client, api_client, log) = self.build();
+        let handler_factory = RecordingHandlerFactory {
+            inner: handler_factory,
+            log: log.clone(),
+        };
+
+        let consumer = Consumer::start_with_config(
+            streaming_client,
+            api_client,
+            SubscriptionId("scenario".to_string()),
+            handler_factory,
+            DevNullMetricsCollector,
+            ConsumerConfig {
+                commit_policy,
+                shutdown: ShutdownConfig::drain(timeout),
+                ..ConsumerConfig::default()
+            },
+        );
+
+        if !consumer.wait_until_stopped_timeout(timeout) {
+            consumer.stop();
+        }
+
+        ScenarioOutcome {
+            invocations: log.invocations(),
+            commits: log.commits(),
+            outcome: consumer.outcome(),
+        }
+    }
+
+    fn current(&mut self) -> &mut ScriptedStream {
+        self.streams.last_mut().expect("a scenario always has a stream")
+    }
+
+    fn next_offset(&mut self, partition: &str) -> u64 {
+        let offset = self.offsets.entry(partition.to_string()).or_insert(0);
+        *offset += 1;
+        *offset
+    }
+
+    fn push_line(mut self, partition: &str, offset: u64, events: Option<&str>) -> Scenario {
+        let cursor = format!(
+            r#"{{"partition":"{}","offset":"{:018}","event_type":"{}","cursor_token":"{}-{}"}}"#,
+            partition, offset, self.event_type, partition, offset
+        );
+        let line = match events {
+            Some(events) => format!(r#"{{"cursor":{},"events":{}}}"#, cursor, events),
+            None => format!(r#"{{"cursor":{}}}"#, cursor),
+        };
+        self.current().lines.push(line);
+        self
+    }
+}
+
+impl Default for Scenario {
+    fn default() -> Scenario {
+        Scenario::new()
+    }
+}
+
+struct ScenarioState {
+    streams: VecDeque<ScriptedStream>,
+    streams_started: usize,
+    commit_failures: HashMap<String, usize>,
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<T> {
+    match mutex.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+/// A `StreamingClient` sending the streams of a `Scenario`
+#[derive(Clone)]
+pub struct ScriptedStreamingClient {
+    state: Arc<Mutex<ScenarioState>>,
+}
+
+impl StreamingClient for ScriptedStreamingClient {
+    type LineIterator = vec::IntoIter<LineResult>;
+
+    fn connect(
+        &self,
+        _subscription_id: &SubscriptionId,
+        flow_id: FlowId,
+    ) -> Result<(StreamId, Self::LineIterator), ConnectError> {
+        let mut state = lock(&self.state);
+
+        let mut stream = match state.streams.pop_front() {
+            Some(stream) => stream,
+            None => {
+                return Err(ConnectError::SubscriptionNotFound(
+                    "The scenario has no more streams".to_string(),
+                    flow_id,
+                ))
+            }
+        };
+
+        if stream.connect_failures > 0 {
+            stream.connect_failures -= 1;
+            state.streams.push_front(stream);
+            return Err(ConnectError::Connection(
+                "Scripted connection failure".to_string(),
+            ));
+        }
+
+        let stream_id = StreamId::new(format!("stream-{}", state.streams_started));
+        state.streams_started += 1;
+        state
+            .commit_failures
+            .insert(stream_id.0.clone(), stream.commit_failures);
+
+        let lines: Vec<LineResult> = stream
+            .lines
+            .into_iter()
+            .map(|line| {
+                Ok(RawLine {
+                    bytes: PooledBuffer::from(line.into_bytes()),
+                    received_at: Instant::now(),
+                    read_took: Duration::from_secs(0),
+                })
+            })
+            .collect();
+
+        Ok((stream_id, lines.into_iter()))
+    }
+}
+
+/// An `ApiClient` recording the commits of a `Scenario`
+///
+/// Only committing is supported.
+#[derive(Clone)]
+pub struct ScriptedApiClient {
+    state: Arc<Mutex<ScenarioState>>,
+    log: ScenarioLog,
+}
+
+impl ApiClient for ScriptedApiClient {
+    fn commit_cursors_budgeted<T: AsRef<[u8]>>(
+        &self,
+        _subscription_id: &SubscriptionId,
+        stream_id: &StreamId,
+        cursors: &[T],
+        flow_id: FlowId,
+        _budget: Duration,
+    ) -> Result<CommitStatus, CommitError> {
+        if cursors.is_empty() {
+            return Ok(CommitStatus::NothingToCommit);
+        }
+
+        {
+            let mut state = lock(&self.state);
+            if let Some(failures) = state.commit_failures.get_mut(&stream_id.0) {
+                if *failures > 0 {
+                    *failures -= 1;
+                    return Err(CommitError::Server(
+                        "Scripted commit failure".to_string(),
+                        flow_id,
+                    ));
+                }
+            }
+        }
+
+        let cursors = cursors
+            .iter()
+            .filter_map(|cursor| ::serde_json::from_slice(cursor.as_ref()).ok())
+            .collect();
+        lock(&self.log.commits).push(RecordedCommit {
+            stream_id: stream_id.clone(),
+            cursors,
+        });
+        Ok(CommitStatus::AllOffsetsIncreased)
+    }
+
+    fn delete_event_type(&self, _event_type_name: &str) -> Result<(), DeleteEventTypeError> {
+        Err(DeleteEventTypeError::Other(
+            "Not supported by the scenario".to_string(),
+        ))
+    }
+
+    fn create_event_type(
+        &self,
+        _event_type: &EventTypeDefinition,
+    ) -> Result<(), CreateEventTypeError> {
+        Err(CreateEventTypeError::Other(
+            "Not supported by the scenario".to_string(),
+        ))
+    }
+
+    fn create_subscription(
+        &self,
+        _request: &CreateSubscriptionRequest,
+    ) -> Result<CreateSubscriptionStatus, CreateSubscriptionError> {
+        Err(CreateSubscriptionError::Other(
+            "Not supported by the scenario".to_string(),
+        ))
+    }
+
+    fn delete_subscription(&self, _id: &SubscriptionId) -> Result<(), DeleteSubscriptionError> {
+        Err(DeleteSubscriptionError::Other(
+            "Not supported by the scenario".to_string(),
+        ))
+    }
+
+    fn stats(
+        &self,
+        _subscription_id: &SubscriptionId,
+    ) -> Result<stats::SubscriptionStats, StatsError> {
+        Err(StatsError::Server(
+            "Not supported by the scenario".to_string(),
+        ))
+    }
+}
+
+/// A cursor as it was committed
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct CommittedCursor {
+    pub partition: PartitionId,
+    pub offset: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct RecordedCommit {
+    pub stream_id: StreamId,
+    pub cursors: Vec<CommittedCursor>,
+}
+
+/// A batch passed to a handler
+#[derive(Debug, Clone)]
+pub struct HandlerInvocation {
+    pub partition: PartitionId,
+    pub event_type: String,
+    pub events: Vec<u8>,
+    /// False if the handler reported a failure
+    pub processed: bool,
+}
+
+/// What happened while a `Scenario` was played
+#[derive(Clone, Default)]
+pub struct ScenarioLog {
+    invocations: Arc<Mutex<Vec<HandlerInvocation>>>,
+    commits: Arc<Mutex<Vec<RecordedCommit>>>,
+}
+
+impl ScenarioLog {
+    pub fn invocations(&self) -> Vec<HandlerInvocation> {
+        lock(&self.invocations).clone()
+    }
+
+    pub fn commits(&self) -> Vec<RecordedCommit> {
+        lock(&self.commits).clone()
+    }
+
+    /// The offset committed last for the partition
+    pub fn last_committed_offset(&self, partition: &str) -> Option<String> {
+        lock(&self.commits)
+            .iter()
+            .flat_map(|commit| commit.cursors.iter())
+            .filter(|cursor| cursor.partition.0 == partition)
+            .map(|cursor| cursor.offset.clone())
+            .last()
+    }
+}
+
+/// The result of `Scenario::run`
+#[derive(Debug, Clone)]
+pub struct ScenarioOutcome {
+    pub invocations: Vec<HandlerInvocation>,
+    pub commits: Vec<RecordedCommit>,
+    /// `None` if the consumer did not stop in time
+    pub outcome: Option<ConsumerOutcome>,
+}
+
+/// Records the invocations of the handlers created by another factory
+pub struct RecordingHandlerFactory<HF> {
+    inner: HF,
+    log: ScenarioLog,
+}
+
+impl<HF> RecordingHandlerFactory<HF> {
+    pub fn new(inner: HF, log: ScenarioLog) -> RecordingHandlerFactory<HF> {
+        RecordingHandlerFactory { inner, log }
+    }
+}
+
+impl<HF: HandlerFactory> HandlerFactory for RecordingHandlerFactory<HF> {
+    type Handler = RecordingHandler<HF::Handler>;
+
+    fn create_handler(&self, partition: &PartitionId) -> Result<Self::Handler, CreateHandlerError> {
+        Ok(RecordingHandler {
+            inner: self.inner.create_handler(partition)?,
+            partition: partition.clone(),
+            log: self.log.clone(),
+        })
+    }
+
+    fn generation(&self) -> usize {
+        self.inner.generation()
+    }
+}
+
+pub struct RecordingHandler<H> {
+    inner: H,
+    partition: PartitionId,
+    log: ScenarioLog,
+}
+
+impl<H: BatchHandler> BatchHandler for RecordingHandler<H> {
+    fn handle(&mut self, event_type: EventType, events: &[u8]) -> ProcessingStatus {
+        let event_type_name = event_type.0.to_string();
+        let status = self.inner.handle(event_type, events);
+        lock(&self.log.invocations).push(HandlerInvocation {
+            partition: self.partition.clone(),
+            event_type: event_type_name,
+            events: events.to_vec(),
+            processed: match status {
+                ProcessingStatus::Processed(_) => true,
+                ProcessingStatus::Failed { .. } => false,
+            },
+        });
+        status
+    }
+
+    fn attach_progress_reporter(&mut self, reporter: ProgressReporter) {
+        self.inner.attach_progress_reporter(reporter)
+    }
+
+    fn on_shutdown(&mut self) {
+        self.inner.on_shutdown()
+    }
+}
+
+#[cfg(test)]
+struct AcceptAll;
+
+#[cfg(test)]
+impl BatchHandler for AcceptAll {
+    fn handle(&mut self, _event_type: EventType, _events: &[u8]) -> ProcessingStatus {
+        ProcessingStatus::processed_no_hint()
+    }
+}
+
+#[cfg(test)]
+impl HandlerFactory for AcceptAll {
+    type Handler = AcceptAll;
+
+    fn create_handler(&self, _partition: &PartitionId) -> Result<AcceptAll, CreateHandlerError> {
+        Ok(AcceptAll)
+    }
+}
+
+#[test]
+fn streams_are_played_in_order() {
+    let outcome = Scenario::new()
+        .batches("0", 3)
+        .keep_alive("0")
+        .close_stream()
+        .connect_fails(1)
+        .batches("0", 1)
+        .run(AcceptAll, Duration::from_secs(10));
+
+    assert_eq!(outcome.invocations.len(), 4);
+    assert!(outcome.invocations.iter().all(|i| i.processed));
+    assert_eq!(outcome.invocations[3].events, br#"[{"n":4}]"#.to_vec());
+    assert_eq!(
+        outcome.outcome,
+        Some(ConsumerOutcome::ConnectFailed(
+            "Subscription not found: The scenario has no more streams".to_string()
+        ))
+    );
+}
+
+#[test]
+fn commits_can_be_made_to_fail() {
+    let (streaming_client, api_client, log) = Scenario::new().commit_fails(1).build();
+    let (stream_id, _) = streaming_client
+        .connect(&SubscriptionId("s".to_string()), FlowId::default())
+        .unwrap();
+
+    let cursor = br#"{"partition":"0","offset":"000000000000000001"}"#;
+    let commit = || {
+        api_client.commit_cursors_budgeted(
+            &SubscriptionId("s".to_string()),
+            &stream_id,
+            &[&cursor[..]],
+            FlowId::default(),
+            Duration::from_millis(10),
+        )
+    };
+
+    assert!(commit().is_err());
+    assert!(commit().is_ok());
+    assert_eq!(
+        log.last_committed_offset("0"),
+        Some("000000000000000001".to_string())
+    );
+    assert!(streaming_client
+        .connect(&SubscriptionId("s".to_string()), FlowId::default())
+        .is_err());
+}
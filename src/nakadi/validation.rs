@@ -0,0 +1,483 @@
+//! Checking a configuration against `Nakadi` without consuming
+//!
+//! Meant to be run when a service starts and before it
+//! declares itself ready. `verify_subscription` additionally
+//! connects to the subscription for a moment, e.g. in a deployment
+//! pipeline before traffic is switched over.
+use std::time::{Duration, Instant};
+
+use url::Url;
+
+use auth::ProvidesAccessToken;
+use nakadi::{millis, CommitStrategy, NakadionConfig, SubscriptionDiscovery};
+use nakadi::api_client::{ApiClient, ListError, NakadiApiClient, StatsError};
+use nakadi::api_client::stats::SubscriptionStats;
+use nakadi::model::{FlowId, StreamId, SubscriptionId};
+use nakadi::streaming_client::StreamingClient;
+
+/// The time after which `Nakadi` considers uncommitted cursors
+/// as timed out and closes the stream
+const NAKADI_COMMIT_TIMEOUT_SECS: u64 = 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum CheckStatus {
+    Passed,
+    Failed,
+    /// The check could not be run because a previous check failed
+    Skipped,
+}
+
+/// The result of a single check
+#[derive(Debug, Clone, Serialize)]
+pub struct Check {
+    pub name: &'static str,
+    pub status: CheckStatus,
+    pub message: Option<String>,
+}
+
+impl Check {
+    fn passed(name: &'static str) -> Check {
+        Check {
+            name,
+            status: CheckStatus::Passed,
+            message: None,
+        }
+    }
+
+    fn failed<T: Into<String>>(name: &'static str, message: T) -> Check {
+        Check {
+            name,
+            status: CheckStatus::Failed,
+            message: Some(message.into()),
+        }
+    }
+
+    fn skipped<T: Into<String>>(name: &'static str, message: T) -> Check {
+        Check {
+            name,
+            status: CheckStatus::Skipped,
+            message: Some(message.into()),
+        }
+    }
+
+    fn with_message<T: Into<String>>(mut self, message: T) -> Check {
+        self.message = Some(message.into());
+        self
+    }
+}
+
+/// The results of validating a configuration
+#[derive(Debug, Clone, Serialize)]
+pub struct ValidationReport {
+    /// The subscription that would be consumed. `None` if it
+    /// does not exist yet or could not be determined.
+    pub subscription_id: Option<String>,
+    pub checks: Vec<Check>,
+}
+
+impl ValidationReport {
+    /// Returns true if no check failed.
+    pub fn is_ok(&self) -> bool {
+        self.failures().is_empty()
+    }
+
+    pub fn failures(&self) -> Vec<&Check> {
+        self.checks
+            .iter()
+            .filter(|check| check.status == CheckStatus::Failed)
+            .collect()
+    }
+
+    /// Log every check with its result.
+    pub fn log(&self) {
+        for check in &self.checks {
+            let message = check.message.as_ref().map(|m| m.as_str()).unwrap_or("");
+            match check.status {
+                CheckStatus::Passed => info!("Validation '{}' passed {}", check.name, message),
+                CheckStatus::Failed => error!("Validation '{}' failed: {}", check.name, message),
+                CheckStatus::Skipped => {
+                    warn!("Validation '{}' skipped: {}", check.name, message)
+                }
+            }
+        }
+    }
+}
+
+/// Check the configuration, the token, the event types, the subscription
+/// and the authorization to read from the subscription.
+pub fn validate(
+    config: &NakadionConfig,
+    api_client: &NakadiApiClient,
+    token_provider: &ProvidesAccessToken,
+) -> ValidationReport {
+    let mut checks = Vec::new();
+
+    let problems = check_settings(config);
+    if problems.is_empty() {
+        checks.push(Check::passed("settings"));
+    } else {
+        checks.push(Check::failed("settings", problems.join("; ")));
+    }
+
+    let token_check = match token_provider.get_token() {
+        Ok(Some(_)) => Check::passed("token"),
+        Ok(None) => Check::passed("token").with_message("(no token configured)"),
+        Err(err) => Check::failed("token", err.to_string()),
+    };
+    let token_ok = token_check.status == CheckStatus::Passed;
+    checks.push(token_check);
+
+    if !token_ok {
+        checks.push(Check::skipped("event_types", "No token"));
+        checks.push(Check::skipped("subscription", "No token"));
+        checks.push(Check::skipped("authorization", "No token"));
+        return ValidationReport {
+            subscription_id: None,
+            checks,
+        };
+    }
+
+    let mut report = preflight_checks(config, api_client);
+    checks.append(&mut report.checks);
+    report.checks = checks;
+    report
+}
+
+/// Check that the event types and the subscription exist and that
+/// the token of the `api_client` can read the stats of the subscription.
+///
+/// These are the checks run on start if `strict_startup` is set.
+pub fn preflight_checks(config: &NakadionConfig, api_client: &NakadiApiClient) -> ValidationReport {
+    let mut checks = vec![check_event_types(config, api_client)];
+
+    let subscription_id = match config.subscription_discovery {
+        SubscriptionDiscovery::Id(ref id) => Some(id.clone()),
+        SubscriptionDiscovery::OwningApplication(ref app, ref event_types) => {
+            let event_types: Vec<&str> = event_types.iter().map(|et| et.as_str()).collect();
+            match api_client
+                .list_subscriptions(Some(app), &event_types)
+                .collect_all()
+            {
+                Ok(subscriptions) => match subscriptions.into_iter().next() {
+                    Some(subscription) => Some(subscription.id),
+                    None => {
+                        checks.push(Check::passed("subscription").with_message(format!(
+                            "(does not exist yet and will be created for {})",
+                            app
+                        )));
+                        checks.push(Check::skipped(
+                            "authorization",
+                            "The subscription does not exist yet",
+                        ));
+                        return ValidationReport {
+                            subscription_id: None,
+                            checks,
+                        };
+                    }
+                },
+                Err(err) => {
+                    checks.push(Check::failed("subscription", err.to_string()));
+                    checks.push(Check::skipped(
+                        "authorization",
+                        "The subscription could not be determined",
+                    ));
+                    return ValidationReport {
+                        subscription_id: None,
+                        checks,
+                    };
+                }
+            }
+        }
+    };
+
+    if let Some(ref subscription_id) = subscription_id {
+        checks.extend(check_subscription(api_client, subscription_id));
+    }
+
+    ValidationReport {
+        subscription_id: subscription_id.map(|id| id.0),
+        checks,
+    }
+}
+
+/// The event types can only be checked if they are configured.
+/// Otherwise they are given by the subscription.
+fn check_event_types(config: &NakadionConfig, api_client: &NakadiApiClient) -> Check {
+    let event_types = match config.subscription_discovery {
+        SubscriptionDiscovery::OwningApplication(_, ref event_types) => event_types,
+        SubscriptionDiscovery::Id(_) => {
+            return Check::passed("event_types").with_message("(given by the subscription)")
+        }
+    };
+
+    let mut missing = Vec::new();
+    for event_type in event_types {
+        match api_client.list_partitions(event_type).collect_all() {
+            Ok(_) => (),
+            Err(ListError::NotFound(_)) => missing.push(event_type.as_str()),
+            Err(err) => {
+                return Check::failed(
+                    "event_types",
+                    format!("Could not read event type {}: {}", event_type, err),
+                )
+            }
+        }
+    }
+
+    if missing.is_empty() {
+        Check::passed("event_types")
+    } else {
+        Check::failed(
+            "event_types",
+            format!("Event types do not exist: {}", missing.join(", ")),
+        )
+    }
+}
+
+/// Uses the stats of the subscription since reading them requires
+/// the same permissions as consuming.
+fn check_subscription(api_client: &NakadiApiClient, subscription_id: &SubscriptionId) -> Vec<Check> {
+    match api_client.subscription_stats(subscription_id) {
+        Ok(_) => vec![
+            Check::passed("subscription"),
+            Check::passed("authorization"),
+        ],
+        Err(StatsError::NotFound(_)) => vec![
+            Check::failed(
+                "subscription",
+                format!("Subscription {} does not exist", subscription_id),
+            ),
+            Check::skipped("authorization", "The subscription does not exist"),
+        ],
+        Err(StatsError::Unauthorized(msg)) | Err(StatsError::Forbidden(msg)) => vec![
+            Check::passed("subscription"),
+            Check::failed("authorization", msg),
+        ],
+        Err(err) => vec![
+            Check::failed("subscription", err.to_string()),
+            Check::skipped("authorization", "The subscription could not be read"),
+        ],
+    }
+}
+
+/// Finds settings that `Nakadi` would reject or that
+/// contradict each other.
+pub fn check_settings(config: &NakadionConfig) -> Vec<String> {
+    let mut problems = Vec::new();
+
+    if Url::parse(&config.nakadi_host).is_err() {
+        problems.push(format!(
+            "'nakadi_host' is not a valid URL: {}",
+            config.nakadi_host
+        ));
+    }
+
+    if config.stream_limit != 0 && config.stream_limit < config.batch_limit {
+        problems.push(format!(
+            "'stream_limit'({}) is lower than 'batch_limit'({})",
+            config.stream_limit, config.batch_limit
+        ));
+    }
+
+    if config.stream_timeout != Duration::from_secs(0)
+        && config.stream_timeout < config.batch_flush_timeout
+    {
+        problems.push(format!(
+            "'stream_timeout'({}s) is lower than 'batch_flush_timeout'({}s)",
+            config.stream_timeout.as_secs(),
+            config.batch_flush_timeout.as_secs()
+        ));
+    }
+
+    if config.max_uncommitted_events != 0 && config.batch_limit > config.max_uncommitted_events {
+        problems.push(format!(
+            "'batch_limit'({}) is greater than 'max_uncommitted_events'({})",
+            config.batch_limit, config.max_uncommitted_events
+        ));
+    }
+
+    let commit_after_seconds = match config.commit_strategy {
+        CommitStrategy::AfterSeconds { seconds } => Some(seconds),
+        CommitStrategy::Batches { after_seconds, .. }
+        | CommitStrategy::Events { after_seconds, .. } => after_seconds,
+        _ => None,
+    };
+    if let Some(seconds) = commit_after_seconds {
+        if u64::from(seconds) >= NAKADI_COMMIT_TIMEOUT_SECS {
+            problems.push(format!(
+                "Committing after {} seconds exceeds the commit timeout of Nakadi({}s)",
+                seconds, NAKADI_COMMIT_TIMEOUT_SECS
+            ));
+        }
+    }
+
+    if config.max_queued_bytes == Some(0) {
+        problems.push("'max_queued_bytes' is 0 so that no batch can be received".to_string());
+    }
+
+    problems
+}
+
+/// The result of connecting to a subscription with `verify_subscription`
+#[derive(Debug, Clone, Serialize)]
+pub struct SubscriptionVerification {
+    pub subscription_id: String,
+    /// `None` if no stream could be opened
+    pub stream_id: Option<String>,
+    /// How long it took to open the stream
+    pub connect_ms: Option<u64>,
+    /// How long it took after connecting until the first
+    /// batch or keep alive line arrived
+    pub first_line_ms: Option<u64>,
+    /// The partitions assigned to the stream as `event_type:partition`
+    pub assigned_partitions: Vec<String>,
+    /// Why the verification failed
+    pub error: Option<String>,
+}
+
+impl SubscriptionVerification {
+    pub fn is_ok(&self) -> bool {
+        self.error.is_none()
+    }
+
+    pub fn log(&self) {
+        match self.error {
+            None => info!(
+                "Subscription {} verified. Connected in {}ms, first line after {}ms, \
+                 assigned partitions: [{}]",
+                self.subscription_id,
+                self.connect_ms.unwrap_or(0),
+                self.first_line_ms.unwrap_or(0),
+                self.assigned_partitions.join(", ")
+            ),
+            Some(ref err) => error!(
+                "Verifying subscription {} failed: {}",
+                self.subscription_id, err
+            ),
+        }
+    }
+}
+
+/// Connect to the subscription, wait for the first batch or keep alive
+/// line and disconnect.
+///
+/// Nothing is committed so the batch will be delivered again. The
+/// assigned partitions are looked up while the stream is open. The
+/// time to wait for the first line depends on `batch_flush_timeout`
+/// of the streaming client.
+pub fn verify_subscription<S>(
+    streaming_client: &S,
+    api_client: &NakadiApiClient,
+    subscription_id: &SubscriptionId,
+) -> SubscriptionVerification
+where
+    S: StreamingClient,
+{
+    let mut verification = SubscriptionVerification {
+        subscription_id: subscription_id.0.clone(),
+        stream_id: None,
+        connect_ms: None,
+        first_line_ms: None,
+        assigned_partitions: Vec::new(),
+        error: None,
+    };
+
+    let started = Instant::now();
+    let (stream_id, mut lines) = match streaming_client.connect(subscription_id, FlowId::default())
+    {
+        Ok(connected) => connected,
+        Err(err) => {
+            verification.error = Some(format!("Could not connect: {}", err));
+            return verification;
+        }
+    };
+    verification.stream_id = Some(stream_id.0.clone());
+    verification.connect_ms = Some(millis(started.elapsed()));
+
+    let connected = Instant::now();
+    match lines.next() {
+        Some(Ok(_)) => verification.first_line_ms = Some(millis(connected.elapsed())),
+        Some(Err(err)) => {
+            verification.error = Some(format!("Could not read from the stream: {}", err));
+            return verification;
+        }
+        None => {
+            verification.error = Some("The stream was closed before a line arrived".to_string());
+            return verification;
+        }
+    }
+
+    match api_client.stats(subscription_id) {
+        Ok(stats) => verification.assigned_partitions = assigned_partitions(&stats, &stream_id),
+        Err(err) => {
+            verification.error = Some(format!("Could not get the assigned partitions: {}", err))
+        }
+    }
+
+    verification
+}
+
+/// The partitions of all event types assigned to the stream
+fn assigned_partitions(stats: &SubscriptionStats, stream_id: &StreamId) -> Vec<String> {
+    stats
+        .event_types
+        .iter()
+        .flat_map(|et| {
+            et.partitions
+                .iter()
+                .filter(|p| p.stream_id == stream_id.0)
+                .map(move |p| format!("{}:{}", et.event_type, p.partition))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+fn test_config() -> NakadionConfig {
+    use nakadi::NakadionBuilder;
+
+    NakadionBuilder::default()
+        .nakadi_host("http://localhost:8080")
+        .subscription_discovery(SubscriptionDiscovery::Id(SubscriptionId("s".into())))
+        .build_config()
+        .unwrap()
+}
+
+#[test]
+fn default_settings_are_consistent() {
+    assert_eq!(check_settings(&test_config()), Vec::<String>::new());
+}
+
+#[test]
+fn contradicting_settings_are_reported() {
+    let mut config = test_config();
+    config.stream_limit = 10;
+    config.batch_limit = 20;
+    config.commit_strategy = CommitStrategy::AfterSeconds { seconds: 90 };
+
+    let problems = check_settings(&config);
+
+    assert_eq!(problems.len(), 2);
+    assert!(problems[0].contains("'stream_limit'(10)"));
+    assert!(problems[1].contains("90 seconds"));
+}
+
+#[test]
+fn only_partitions_of_the_stream_are_assigned() {
+    use serde_json;
+
+    let stats: SubscriptionStats = serde_json::from_str(
+        r#"{"items": [
+            {"event_type": "a", "partitions": [
+                {"partition": "0", "stream_id": "s1"},
+                {"partition": "1", "stream_id": "s2"},
+                {"partition": "2"}
+            ]},
+            {"event_type": "b", "partitions": [{"partition": "0", "stream_id": "s1"}]}
+        ]}"#,
+    ).unwrap();
+
+    assert_eq!(
+        assigned_partitions(&stats, &StreamId::new("s1")),
+        vec!["a:0".to_string(), "b:0".to_string()]
+    );
+}
@@ -0,0 +1,211 @@
+//! Consuming with reduced limits right after connecting
+//!
+//! Right after connecting caches are cold and connection pools are still
+//! empty while `Nakadi` sends the backlog of the subscription in batches of
+//! the configured size. During a warm-up phase the consumer requests smaller
+//! batches and fewer uncommitted events. Once the phase is over it closes
+//! the stream and reconnects with the configured limits.
+//!
+//! Every connection that is not the one following a warm-up phase starts
+//! with a new warm-up phase.
+use std::time::{Duration, Instant};
+
+/// Settings for the warm-up phase
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WarmUpConfig {
+    /// The `batch_limit` while warming up
+    pub batch_limit: usize,
+    /// The `max_uncommitted_events` while warming up
+    pub max_uncommitted_events: usize,
+    /// End the phase after this time
+    pub duration: Option<Duration>,
+    /// End the phase after this many batches
+    pub batches: Option<usize>,
+}
+
+impl WarmUpConfig {
+    /// Warm up for 60 seconds with the given limits.
+    pub fn new(batch_limit: usize, max_uncommitted_events: usize) -> WarmUpConfig {
+        WarmUpConfig {
+            batch_limit,
+            max_uncommitted_events,
+            duration: Some(Duration::from_secs(60)),
+            batches: None,
+        }
+    }
+
+    /// End the phase after `duration`.
+    pub fn duration(mut self, duration: Duration) -> WarmUpConfig {
+        self.duration = Some(duration);
+        self
+    }
+
+    /// End the phase after `batches` batches. If a duration is set
+    /// too the phase ends with whatever comes first.
+    pub fn batches(mut self, batches: usize) -> WarmUpConfig {
+        self.batches = Some(batches);
+        self
+    }
+}
+
+/// The limit to request while warming up.
+///
+/// Warming up never raises a configured limit. 0 means unlimited.
+pub fn reduced_limit(configured: usize, warm_up: usize) -> usize {
+    match (configured, warm_up) {
+        (0, warm_up) => warm_up,
+        (configured, 0) => configured,
+        (configured, warm_up) => ::std::cmp::min(configured, warm_up),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Phase {
+    /// The next connection warms up
+    Pending,
+    WarmingUp { since: Instant, batches: usize },
+    /// The next connection uses the configured limits
+    Over,
+    /// Connected with the configured limits
+    Full,
+}
+
+/// Tracks the warm-up phase over the connections of a consumer
+#[derive(Debug)]
+pub struct WarmUp {
+    config: WarmUpConfig,
+    phase: Phase,
+}
+
+impl WarmUp {
+    pub fn new(config: WarmUpConfig) -> WarmUp {
+        WarmUp {
+            config,
+            phase: Phase::Pending,
+        }
+    }
+
+    pub fn config(&self) -> &WarmUpConfig {
+        &self.config
+    }
+
+    /// Returns true if the next connection must use the reduced limits.
+    pub fn is_pending(&self) -> bool {
+        self.phase == Phase::Pending
+    }
+
+    /// Returns true while connected with the reduced limits.
+    pub fn is_warming_up(&self) -> bool {
+        match self.phase {
+            Phase::WarmingUp { .. } => true,
+            _ => false,
+        }
+    }
+
+    /// A new stream has been connected.
+    pub fn connected(&mut self, now: Instant) {
+        self.phase = match self.phase {
+            Phase::Pending => Phase::WarmingUp {
+                since: now,
+                batches: 0,
+            },
+            _ => Phase::Full,
+        };
+    }
+
+    pub fn batch_received(&mut self) {
+        if let Phase::WarmingUp {
+            ref mut batches, ..
+        } = self.phase
+        {
+            *batches += 1;
+        }
+    }
+
+    /// Returns true once if the warm-up phase just ended and
+    /// the stream should be closed.
+    pub fn check(&mut self, now: Instant) -> bool {
+        let is_over = match self.phase {
+            Phase::WarmingUp { since, batches } => {
+                self.config
+                    .duration
+                    .map(|duration| now - since >= duration)
+                    .unwrap_or(false)
+                    || self.config
+                        .batches
+                        .map(|max_batches| batches >= max_batches)
+                        .unwrap_or(false)
+            }
+            _ => false,
+        };
+        if is_over {
+            self.phase = Phase::Over;
+        }
+        is_over
+    }
+
+    /// The stream has been closed.
+    ///
+    /// Unless the warm-up phase just ended the next connection
+    /// warms up again.
+    pub fn disconnected(&mut self) {
+        if self.phase != Phase::Over {
+            self.phase = Phase::Pending;
+        }
+    }
+}
+
+#[test]
+fn warming_up_never_raises_limits() {
+    assert_eq!(reduced_limit(0, 10), 10);
+    assert_eq!(reduced_limit(100, 0), 100);
+    assert_eq!(reduced_limit(100, 10), 10);
+    assert_eq!(reduced_limit(5, 10), 5);
+}
+
+#[test]
+fn the_phase_ends_after_the_batches() {
+    let mut warm_up = WarmUp::new(WarmUpConfig {
+        batch_limit: 1,
+        max_uncommitted_events: 10,
+        duration: None,
+        batches: Some(2),
+    });
+    let now = Instant::now();
+    assert!(warm_up.is_pending());
+
+    warm_up.connected(now);
+    assert!(warm_up.is_warming_up());
+    warm_up.batch_received();
+    assert!(!warm_up.check(now));
+    warm_up.batch_received();
+    assert!(warm_up.check(now));
+    assert!(!warm_up.check(now));
+
+    warm_up.disconnected();
+    assert!(!warm_up.is_pending());
+    warm_up.connected(now);
+    assert!(!warm_up.is_warming_up());
+
+    warm_up.disconnected();
+    assert!(warm_up.is_pending());
+}
+
+#[test]
+fn the_phase_ends_after_the_duration() {
+    let mut warm_up = WarmUp::new(WarmUpConfig::new(1, 10).duration(Duration::from_secs(5)));
+    let now = Instant::now();
+
+    warm_up.connected(now);
+    assert!(!warm_up.check(now + Duration::from_secs(4)));
+    assert!(warm_up.check(now + Duration::from_secs(5)));
+}
+
+#[test]
+fn a_broken_warm_up_stream_warms_up_again() {
+    let mut warm_up = WarmUp::new(WarmUpConfig::new(1, 10));
+
+    warm_up.connected(Instant::now());
+    warm_up.disconnected();
+    assert!(warm_up.is_pending());
+}
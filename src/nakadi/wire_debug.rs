@@ -0,0 +1,144 @@
+//! Logging of the requests to and the responses from `Nakadi`
+//!
+//! Meant for debugging integration issues. Everything is logged on
+//! the `debug` level. The `Authorization` header is redacted and
+//! bodies are truncated. Logging can be switched on and off
+//! while running.
+use std::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
+
+use reqwest::{Client as HttpClient, RequestBuilder, Response, Result as HttpResult};
+use reqwest::header::Headers;
+use serde::Serialize;
+use serde_json;
+
+/// The maximum number of bytes of a body that get logged
+pub const MAX_LOGGED_BODY_BYTES: usize = 1024;
+
+static ENABLED: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// Start logging requests and responses.
+pub fn enable() {
+    set_enabled(true)
+}
+
+/// Stop logging requests and responses.
+pub fn disable() {
+    set_enabled(false)
+}
+
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+    info!(
+        "Wire debug logging {}",
+        if enabled { "enabled" } else { "disabled" }
+    );
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Send the request and log it together with the response
+/// if wire debug logging is enabled.
+///
+/// `body` is only used for logging and must be the body
+/// already set on the `request_builder`.
+pub fn send(
+    client: &HttpClient,
+    request_builder: &mut RequestBuilder,
+    body: Option<&[u8]>,
+) -> HttpResult<Response> {
+    if !is_enabled() {
+        return request_builder.send();
+    }
+
+    let request = request_builder.build()?;
+    debug!(
+        "[Wire] --> {} {} {}{}",
+        request.method(),
+        request.url(),
+        format_headers(request.headers()),
+        body.map(|b| format!(" {}", truncate(b))).unwrap_or_default()
+    );
+
+    let response = client.execute(request)?;
+    debug!(
+        "[Wire] <-- {} {} {}",
+        response.status(),
+        response.url(),
+        format_headers(response.headers())
+    );
+
+    Ok(response)
+}
+
+/// Set `body` as the JSON body of the request and send it like `send`.
+pub fn send_json<T: Serialize + ?Sized>(
+    client: &HttpClient,
+    request_builder: &mut RequestBuilder,
+    body: &T,
+) -> HttpResult<Response> {
+    request_builder.json(body);
+    if is_enabled() {
+        let bytes = serde_json::to_vec(body).unwrap_or_default();
+        send(client, request_builder, Some(&bytes))
+    } else {
+        send(client, request_builder, None)
+    }
+}
+
+/// Log the body of a response if wire debug logging is enabled.
+pub fn log_response_body(body: &str) {
+    if is_enabled() {
+        debug!("[Wire] <-- {}", truncate(body.as_bytes()));
+    }
+}
+
+fn format_headers(headers: &Headers) -> String {
+    let formatted: Vec<_> = headers
+        .iter()
+        .map(|header| {
+            if header.name().eq_ignore_ascii_case("Authorization") {
+                format!("{}: <redacted>", header.name())
+            } else {
+                format!("{}: {}", header.name(), header.value_string())
+            }
+        })
+        .collect();
+    format!("[{}]", formatted.join(", "))
+}
+
+fn truncate(body: &[u8]) -> String {
+    if body.len() <= MAX_LOGGED_BODY_BYTES {
+        String::from_utf8_lossy(body).into_owned()
+    } else {
+        format!(
+            "{}...({} bytes)",
+            String::from_utf8_lossy(&body[..MAX_LOGGED_BODY_BYTES]),
+            body.len()
+        )
+    }
+}
+
+#[test]
+fn authorization_is_redacted() {
+    let mut headers = Headers::new();
+    headers.set_raw("Authorization", "Bearer secret");
+    headers.set_raw("X-Flow-Id", "abc");
+
+    let formatted = format_headers(&headers);
+
+    assert!(!formatted.contains("secret"));
+    assert!(formatted.contains("Authorization: <redacted>"));
+    assert!(formatted.contains("X-Flow-Id: abc"));
+}
+
+#[test]
+fn long_bodies_are_truncated() {
+    let body = vec![b'a'; MAX_LOGGED_BODY_BYTES + 1];
+
+    let truncated = truncate(&body);
+
+    assert!(truncated.ends_with(&format!("...({} bytes)", MAX_LOGGED_BODY_BYTES + 1)));
+    assert_eq!(truncate(b"short"), "short");
+}
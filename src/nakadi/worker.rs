@@ -1,25 +1,167 @@
 //! Processing a partition
-use std::sync::mpsc;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::thread;
 use std::time::{Duration, Instant};
 
 use failure::*;
+use log::Level;
 
 use nakadi::Lifecycle;
-use nakadi::model::PartitionId;
-use nakadi::handler::{BatchHandler, ProcessingStatus};
+use nakadi::model::{PartitionId, RawCursor, StreamId};
+use nakadi::handler::{BatchHandler, BatchInfo, ProcessingStatus, ProgressReporter};
 use nakadi::batch::Batch;
 use nakadi::model::EventType;
-use nakadi::committer::Committer;
+use nakadi::committer::{self, Committer};
+use nakadi::mailbox::{Delivery, Mailbox, MailboxConfig, ReceiveError};
 use nakadi::metrics::MetricsCollector;
+use nakadi::stage_timing::{self, Stage};
+use nakadi::worker_pool::{Task, TaskStep, WorkerPool};
+
+/// The default interval at which repetitions of an error are summarized
+const DEFAULT_ERROR_SUMMARY_INTERVAL_SECS: u64 = 60;
+
+/// Collapses identical consecutive errors of a partition.
+///
+/// A broken downstream makes every batch fail the same way. The first
+/// occurrence of an error is logged right away while its repetitions are
+/// only counted and logged as a summary at most once per interval.
+/// Errors are identical if they are of the same kind and have the same
+/// details. The state is shared by all workers so that it outlives
+/// workers restarted for a partition.
+#[derive(Clone)]
+pub struct ErrorLog {
+    summary_interval: Duration,
+    inner: Arc<Mutex<HashMap<PartitionId, RepeatedError>>>,
+}
+
+struct RepeatedError {
+    level: Level,
+    kind: &'static str,
+    details: String,
+    repetitions: u64,
+    summarized_at: Instant,
+}
+
+impl RepeatedError {
+    fn summary(&self, partition: &PartitionId) -> String {
+        format!(
+            "[Worker, partition={}] {}: {} (repeated {} times in the last {} seconds)",
+            partition,
+            self.kind,
+            self.details,
+            self.repetitions,
+            self.summarized_at.elapsed().as_secs()
+        )
+    }
+}
+
+impl ErrorLog {
+    pub fn new(summary_interval: Duration) -> ErrorLog {
+        ErrorLog {
+            summary_interval,
+            inner: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Log an error unless it repeats the last error of the partition.
+    pub fn report(
+        &self,
+        level: Level,
+        stream_id: &StreamId,
+        partition: &PartitionId,
+        kind: &'static str,
+        details: &str,
+    ) {
+        let summary_interval = self.summary_interval;
+        self.update(|errors| {
+            if let Some(repeated) = errors.get_mut(partition) {
+                if repeated.kind == kind && repeated.details == details {
+                    repeated.repetitions += 1;
+                    if repeated.summarized_at.elapsed() >= summary_interval {
+                        log!(level, "{}", repeated.summary(partition));
+                        repeated.repetitions = 0;
+                        repeated.summarized_at = Instant::now();
+                    }
+                    return;
+                }
+            }
+
+            flush(errors, partition);
+            log!(
+                level,
+                "[Worker, stream={}, partition={}] {}: {}",
+                stream_id,
+                partition,
+                kind,
+                details
+            );
+            errors.insert(
+                partition.clone(),
+                RepeatedError {
+                    level,
+                    kind,
+                    details: details.to_string(),
+                    repetitions: 0,
+                    summarized_at: Instant::now(),
+                },
+            );
+        })
+    }
+
+    /// The partition recovered. Logs the pending repetitions
+    /// of its last error.
+    pub fn resolved(&self, partition: &PartitionId) {
+        self.update(|errors| flush(errors, partition))
+    }
+
+    /// The number of repetitions of the last error of the partition
+    /// that have not been logged yet
+    pub fn pending_repetitions(&self, partition: &PartitionId) -> u64 {
+        let mut pending = 0;
+        self.update(|errors| {
+            pending = errors.get(partition).map(|e| e.repetitions).unwrap_or(0)
+        });
+        pending
+    }
+
+    fn update<F: FnOnce(&mut HashMap<PartitionId, RepeatedError>)>(&self, f: F) {
+        match self.inner.lock() {
+            Ok(mut errors) => f(&mut errors),
+            Err(poisoned) => f(&mut poisoned.into_inner()),
+        }
+    }
+}
+
+impl Default for ErrorLog {
+    fn default() -> ErrorLog {
+        ErrorLog::new(Duration::from_secs(DEFAULT_ERROR_SUMMARY_INTERVAL_SECS))
+    }
+}
+
+fn flush(errors: &mut HashMap<PartitionId, RepeatedError>, partition: &PartitionId) {
+    if let Some(repeated) = errors.remove(partition) {
+        if repeated.repetitions > 0 {
+            log!(repeated.level, "{}", repeated.summary(partition));
+        }
+    }
+}
 
 /// A worker is responsible to execute a handler on a given
 /// partition. A worker guarantees that its `BatchHandler`
-/// is always executed on the same thread.
+/// is always executed on the same thread even if it runs
+/// on a `WorkerPool`.
 pub struct Worker {
-    /// Send batches with this sender
-    sender: mpsc::Sender<Batch>,
+    /// Deliver batches to this mailbox
+    mailbox: Mailbox,
     lifecycle: Lifecycle,
+    /// The `Lifecycle` of the pool thread the worker runs on
+    pool_thread: Option<Lifecycle>,
+    /// Set when the worker should stop once its queue is empty
+    draining: Arc<AtomicBool>,
+    /// Tracks the progress of the handler
+    progress: ProgressReporter,
     /// The partition this worker is responsible for.
     partition: PartitionId,
 }
@@ -27,36 +169,72 @@ pub struct Worker {
 impl Worker {
     /// Start the worker.
     ///
-    /// It will run until stop is called.
+    /// It will run until stop is called. The worker runs on a thread of
+    /// its own unless a `WorkerPool` is given.
     pub fn start<H, M>(
         handler: H,
         committer: Committer,
         partition: PartitionId,
         metrics_collector: M,
+        error_log: ErrorLog,
+        mailbox_config: MailboxConfig,
+        worker_pool: Option<&WorkerPool>,
+        parent: &Lifecycle,
     ) -> Worker
     where
         H: BatchHandler + Send + 'static,
         M: MetricsCollector + Send + 'static,
     {
-        let (sender, receiver) = mpsc::channel();
+        let mailbox = match worker_pool {
+            Some(pool) => Mailbox::with_readiness(mailbox_config, pool.readiness_for(&partition)),
+            None => Mailbox::new(mailbox_config),
+        };
+
+        let lifecycle = parent.child();
+        let draining = Arc::new(AtomicBool::new(false));
+        let progress = ProgressReporter::default();
 
-        let lifecycle = Lifecycle::default();
+        let mut handler = handler;
+        handler.attach_progress_reporter(progress.clone());
 
         let handle = Worker {
             lifecycle: lifecycle.clone(),
-            sender,
+            pool_thread: worker_pool.map(|pool| pool.lifecycle_for(&partition)),
+            draining: draining.clone(),
+            progress: progress.clone(),
+            mailbox: mailbox.clone(),
             partition: partition.clone(),
         };
 
-        start_handler_loop(
-            receiver,
+        let processor = PartitionProcessor {
+            mailbox,
             lifecycle,
+            draining,
+            progress,
+            stream_id: committer.stream_id().clone(),
             partition,
             handler,
             committer,
             metrics_collector,
+            error_log,
+        };
+
+        info!(
+            "[Worker, stream={}, partition={}] Started.",
+            processor.stream_id, processor.partition
         );
 
+        match worker_pool {
+            Some(pool) => {
+                if let Err(err) = pool.run(&handle.partition, Box::new(processor)) {
+                    error!("[Worker, partition={}] {}", handle.partition, err);
+                    handle.mailbox.close();
+                    handle.lifecycle.stopped();
+                }
+            }
+            None => start_handler_loop(processor),
+        }
+
         handle
     }
 
@@ -65,151 +243,344 @@ impl Worker {
         self.lifecycle.running()
     }
 
+    /// Wait until the worker stopped.
+    pub fn wait_until_stopped(&self) {
+        self.lifecycle.wait()
+    }
+
+    /// Wait at most `timeout` until the worker stopped.
+    ///
+    /// Returns true if it stopped.
+    pub fn wait_until_stopped_timeout(&self, timeout: Duration) -> bool {
+        self.lifecycle.wait_timeout(timeout)
+    }
+
+    /// Stop waiting for a worker whose handler does not return.
+    ///
+    /// The worker is asked to stop but the dispatcher
+    /// and the pool thread do not wait for it anymore.
+    pub fn abandon(&self) {
+        self.stop();
+        self.lifecycle.detach();
+        if let Some(ref pool_thread) = self.pool_thread {
+            pool_thread.detach();
+        }
+    }
+
     /// Request the worker to stop.
     ///
     /// This does not necessarily cause the worker to stop
     /// immediately. Poll `self::running()` until the worker has
     /// stopped if you depend on the fact that the worker reales stopped working.
+    ///
+    /// Batches still queued will not be processed.
     pub fn stop(&self) {
         self.lifecycle.request_abort()
     }
 
+    /// Request the worker to stop once all queued batches
+    /// have been processed.
+    pub fn drain(&self) {
+        self.draining.store(true, Ordering::Relaxed)
+    }
+
     /// Process the batch.
-    pub fn process(&self, batch: Batch) -> Result<(), Error> {
-        Ok(self.sender.send(batch).context(format!(
-            "[Worker, partition={}] Could not process batch. Worker possibly closed.",
-            self.partition
-        ))?)
+    ///
+    /// Depending on the `OverflowStrategy` this blocks or discards
+    /// batches if the mailbox of the worker is full.
+    pub fn process(&self, batch: Batch) -> Result<Delivery, Error> {
+        self.mailbox.deliver(batch).map_err(|err| {
+            format_err!(
+                "[Worker, partition={}] Could not process batch: {}",
+                self.partition,
+                err
+            )
+        })
+    }
+
+    /// The number of batches waiting to be processed
+    pub fn queued_batches(&self) -> usize {
+        self.mailbox.len()
     }
 
     pub fn partition(&self) -> &PartitionId {
         &self.partition
     }
+
+    /// The progress of the handler on the current batch
+    pub fn progress(&self) -> &ProgressReporter {
+        &self.progress
+    }
 }
 
-fn start_handler_loop<H, M>(
-    receiver: mpsc::Receiver<Batch>,
-    lifecycle: Lifecycle,
-    partition: PartitionId,
-    handler: H,
-    committer: Committer,
-    metrics_collector: M,
-) where
+impl Drop for Worker {
+    /// Stops the worker once it is not reachable anymore.
+    fn drop(&mut self) {
+        self.mailbox.close();
+    }
+}
+
+fn start_handler_loop<H, M>(processor: PartitionProcessor<H, M>)
+where
     H: BatchHandler + Send + 'static,
     M: MetricsCollector + Send + 'static,
 {
-    thread::spawn(move || {
-        handler_loop(
-            receiver,
-            &lifecycle,
-            partition,
-            handler,
-            committer,
-            metrics_collector,
-        )
-    });
+    let name = format!("nakadion-worker-{}", processor.partition);
+    thread::Builder::new()
+        .name(name)
+        .spawn(move || {
+            let _stopped = processor.lifecycle.stop_guard();
+            let mut processor = processor;
+            while processor.step(Duration::from_millis(20)) != TaskStep::Done {}
+            processor.shut_down();
+        })
+        .expect("failed to spawn worker thread");
 }
 
-fn handler_loop<H, M>(
-    receiver: mpsc::Receiver<Batch>,
-    lifecycle: &Lifecycle,
+/// Processes the batches of a partition one at a time.
+///
+/// Either runs on a thread of its own or as a `Task` of a `WorkerPool`.
+struct PartitionProcessor<H, M> {
+    mailbox: Mailbox,
+    lifecycle: Lifecycle,
+    draining: Arc<AtomicBool>,
+    progress: ProgressReporter,
+    stream_id: StreamId,
     partition: PartitionId,
     handler: H,
     committer: Committer,
     metrics_collector: M,
-) where
+    error_log: ErrorLog,
+}
+
+impl<H, M> PartitionProcessor<H, M>
+where
     H: BatchHandler,
     M: MetricsCollector,
 {
-    let stream_id = committer.stream_id().clone();
-    let mut handler = handler;
+    /// Process the next batch waiting at most `wait` for it.
+    fn step(&mut self, wait: Duration) -> TaskStep {
+        let stream_id = &self.stream_id;
+        let partition = &self.partition;
 
-    info!(
-        "[Worker, stream={}, partition={}] Started.",
-        stream_id, partition
-    );
-    loop {
-        if lifecycle.abort_requested() {
+        if self.lifecycle.abort_requested() {
             info!(
                 "[Worker, stream={}, partition={}] Stop requested externally.",
                 stream_id, partition
             );
-            break;
+            report_abandoned_batches(self.mailbox.close(), stream_id, partition);
+            return TaskStep::Done;
         }
 
-        let batch = match receiver.recv_timeout(Duration::from_millis(20)) {
-            Ok(batch) => batch,
-            Err(mpsc::RecvTimeoutError::Timeout) => continue,
-            Err(mpsc::RecvTimeoutError::Disconnected) => {
-                info!(
-                    "[Worker, stream={}, partition={}] Channel disconnected. Stopping.",
-                    stream_id, partition
-                );
-                break;
+        let mut batch = if self.draining.load(Ordering::Relaxed) {
+            match self.mailbox.try_receive() {
+                Some(batch) => batch,
+                None => {
+                    info!(
+                        "[Worker, stream={}, partition={}] Queue drained. Stopping.",
+                        stream_id, partition
+                    );
+                    return TaskStep::Done;
+                }
+            }
+        } else {
+            match self.mailbox.receive_timeout(wait) {
+                Ok(batch) => batch,
+                Err(ReceiveError::Timeout) => return TaskStep::Idle,
+                Err(ReceiveError::Closed) => {
+                    info!(
+                        "[Worker, stream={}, partition={}] Mailbox closed. Stopping.",
+                        stream_id, partition
+                    );
+                    return TaskStep::Done;
+                }
             }
         };
 
+        let queue_wait = batch
+            .timings
+            .queue_wait_until(batch.received_at, Instant::now());
+        batch.timings.queue_wait = queue_wait;
+        self.metrics_collector
+            .batch_stage_took(Stage::QueueWait, queue_wait);
+
         let maybe_a_handler_result = {
             let event_type = match batch.batch_line.event_type_str() {
                 Ok(et) => EventType::new(et),
                 Err(err) => {
-                    error!(
-                        "[Worker, stream={}, partition={}] Invalid event type. Stopping: {}",
-                        stream_id, partition, err
+                    self.error_log.report(
+                        Level::Error,
+                        stream_id,
+                        partition,
+                        "Invalid event type. Stopping",
+                        &err,
                     );
-                    break;
+                    return TaskStep::Done;
                 }
             };
 
+            let handler = &mut self.handler;
+            let progress = &self.progress;
+            let metrics_collector = &self.metrics_collector;
             batch.batch_line.events().map(|events| {
                 metrics_collector.worker_batch_size_bytes(events.len());
                 let start = Instant::now();
+                progress.batch_started(BatchInfo {
+                    partition: partition.clone(),
+                    cursor: RawCursor(batch.batch_line.cursor().to_vec()),
+                    received_at: batch.received_at,
+                    deadline: committer::commit_deadline(&batch),
+                });
                 let res = handler.handle(event_type, events);
+                progress.batch_finished();
                 metrics_collector.worker_batch_processed(start);
-                res
+                (res, start.elapsed())
             })
         };
 
-        if let Some(handler_result) = maybe_a_handler_result {
+        if let Some((handler_result, handler_took)) = maybe_a_handler_result {
+            batch.timings.handler = handler_took;
+            self.metrics_collector
+                .batch_stage_took(Stage::Handler, handler_took);
+            debug!(
+                target: stage_timing::LOG_TARGET,
+                "[Worker, stream={}, partition={}] {}", stream_id, partition, batch.timings
+            );
             match handler_result {
                 ProcessingStatus::Processed(num_events_hint) => {
-                    num_events_hint
-                        .iter()
-                        .for_each(|n| metrics_collector.worker_events_in_same_batch_processed(*n));
-                    match committer.commit(batch, num_events_hint) {
-                        Ok(()) => continue,
+                    num_events_hint.iter().for_each(|n| {
+                        self.metrics_collector
+                            .worker_events_in_same_batch_processed(*n)
+                    });
+                    for (key, value) in self.progress.take_annotations() {
+                        self.metrics_collector.handler_annotation(&key, value);
+                    }
+                    match self.committer.commit(batch, num_events_hint) {
+                        Ok(()) => {
+                            self.error_log.resolved(partition);
+                            TaskStep::Processed
+                        }
                         Err(err) => {
-                            warn!(
-                                "[Worker, stream={}, partition={}] \
-                                 Failed to commit. Stopping: {}",
-                                stream_id, partition, err
+                            self.error_log.report(
+                                Level::Warn,
+                                stream_id,
+                                partition,
+                                "Failed to commit. Stopping",
+                                &err,
                             );
-                            break;
+                            TaskStep::Done
                         }
                     }
                 }
                 ProcessingStatus::Failed { reason } => {
-                    warn!(
-                        "[Worker, stream={}, partition={}] Stopping for reason '{}'",
-                        stream_id, partition, reason
+                    self.error_log.report(
+                        Level::Warn,
+                        stream_id,
+                        partition,
+                        "Handler failed. Stopping",
+                        &reason,
                     );
-                    break;
+                    TaskStep::Done
                 }
             }
         } else {
-            warn!(
-                "[Worker, stream={}, partition={}] \
-                 Received batch without events.",
-                stream_id, partition
-            );
-            continue;
+            // All events were filtered. Only the cursor is left to commit.
+            match self.committer.commit(batch, None) {
+                Ok(()) => TaskStep::Processed,
+                Err(err) => {
+                    self.error_log.report(
+                        Level::Warn,
+                        stream_id,
+                        partition,
+                        "Failed to commit. Stopping",
+                        &err,
+                    );
+                    TaskStep::Done
+                }
+            }
         }
     }
 
-    lifecycle.stopped();
+    fn shut_down(mut self) {
+        // Do not let the dispatcher wait for a worker that stopped
+        report_abandoned_batches(self.mailbox.close(), &self.stream_id, &self.partition);
 
-    info!(
-        "[Worker, stream={}, partition={}] Stopped.",
-        stream_id, partition
+        self.handler.on_shutdown();
+
+        self.lifecycle.stopped();
+
+        info!(
+            "[Worker, stream={}, partition={}] Stopped.",
+            self.stream_id, self.partition
+        );
+    }
+}
+
+impl<H, M> Task for PartitionProcessor<H, M>
+where
+    H: BatchHandler + Send,
+    M: MetricsCollector + Send,
+{
+    fn step(&mut self, wait: Duration) -> TaskStep {
+        PartitionProcessor::step(self, wait)
+    }
+
+    fn finish(self: Box<Self>) {
+        self.shut_down()
+    }
+}
+
+/// Logs the cursors of all batches that are still queued and
+/// will therefore never be processed and committed.
+fn report_abandoned_batches(batches: Vec<Batch>, stream_id: &StreamId, partition: &PartitionId) {
+    let abandoned: Vec<String> = batches
+        .iter()
+        .map(|batch| String::from_utf8_lossy(batch.batch_line.cursor()).into_owned())
+        .collect();
+
+    if !abandoned.is_empty() {
+        warn!(
+            "[Worker, stream={}, partition={}] Abandoned {} unprocessed batches. \
+             These cursors will not be committed: {}",
+            stream_id,
+            partition,
+            abandoned.len(),
+            abandoned.join(", ")
+        );
+    }
+}
+
+#[test]
+fn repeated_errors_are_counted() {
+    let error_log = ErrorLog::default();
+    let stream_id = StreamId::new("stream");
+    let partition = PartitionId("0".to_string());
+
+    error_log.report(Level::Warn, &stream_id, &partition, "Failed", "boom");
+    error_log.report(Level::Warn, &stream_id, &partition, "Failed", "boom");
+    error_log.report(Level::Warn, &stream_id, &partition, "Failed", "boom");
+    assert_eq!(error_log.pending_repetitions(&partition), 2);
+    assert_eq!(
+        error_log.pending_repetitions(&PartitionId("1".to_string())),
+        0
     );
+
+    error_log.report(Level::Warn, &stream_id, &partition, "Failed", "other");
+    assert_eq!(error_log.pending_repetitions(&partition), 0);
+
+    error_log.report(Level::Warn, &stream_id, &partition, "Failed", "other");
+    error_log.resolved(&partition);
+    assert_eq!(error_log.pending_repetitions(&partition), 0);
+}
+
+#[test]
+fn repetitions_are_summarized_periodically() {
+    let error_log = ErrorLog::new(Duration::from_secs(0));
+    let stream_id = StreamId::new("stream");
+    let partition = PartitionId("0".to_string());
+
+    error_log.report(Level::Warn, &stream_id, &partition, "Failed", "boom");
+    error_log.report(Level::Warn, &stream_id, &partition, "Failed", "boom");
+
+    assert_eq!(error_log.pending_repetitions(&partition), 0);
 }
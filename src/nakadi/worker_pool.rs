@@ -0,0 +1,224 @@
+//! Running many workers on a few threads
+//!
+//! By default every worker has a thread of its own. For subscriptions
+//! with hundreds of partitions a `WorkerPool` can be used instead. It
+//! runs the workers of all partitions on a fixed number of threads.
+//!
+//! A partition is always processed on the same thread of the pool so that
+//! its batches are still handled in order and its `BatchHandler` never
+//! changes threads. A thread processes one batch of each of its partitions
+//! in turn. A handler blocking its thread therefore delays all other
+//! partitions on that thread.
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::mpsc;
+use std::time::Duration;
+
+use nakadi::Lifecycle;
+use nakadi::mailbox::Readiness;
+use nakadi::model::PartitionId;
+
+/// The time a thread of the pool waits for new batches
+/// before it looks at its tasks again
+const IDLE_WAIT_MS: u64 = 20;
+
+/// The result of advancing a `Task`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskStep {
+    /// A batch was processed
+    Processed,
+    /// There was nothing to do
+    Idle,
+    /// The task finished and must not be stepped again
+    Done,
+}
+
+/// The processing of a partition that can be advanced step by step
+pub trait Task: Send {
+    /// Process at most one batch waiting at most `wait` for it.
+    fn step(&mut self, wait: Duration) -> TaskStep;
+
+    /// Clean up after `step` returned `TaskStep::Done`.
+    fn finish(self: Box<Self>);
+}
+
+struct PoolThread {
+    sender: mpsc::Sender<Box<Task>>,
+    readiness: Readiness,
+    lifecycle: Lifecycle,
+}
+
+/// A fixed number of threads processing the partitions of a stream.
+///
+/// The threads stop once the pool has been dropped and all
+/// of their tasks are done.
+pub struct WorkerPool {
+    threads: Vec<PoolThread>,
+}
+
+impl WorkerPool {
+    /// Start a pool with `num_threads` threads which are children of `parent`.
+    ///
+    /// At least one thread is started.
+    pub fn new(num_threads: usize, parent: &Lifecycle) -> WorkerPool {
+        let threads = (0..num_threads.max(1))
+            .map(|index| {
+                let (sender, receiver) = mpsc::channel();
+                let readiness = Readiness::default();
+                let thread_readiness = readiness.clone();
+                let lifecycle = parent.spawn_named(format!("nakadion-pool-{}", index), move |_| {
+                    pool_thread_loop(index, receiver, thread_readiness)
+                });
+                PoolThread {
+                    sender,
+                    readiness,
+                    lifecycle,
+                }
+            })
+            .collect();
+
+        WorkerPool { threads }
+    }
+
+    /// The number of threads of the pool
+    pub fn num_threads(&self) -> usize {
+        self.threads.len()
+    }
+
+    /// The `Readiness` of the thread that processes the partition.
+    ///
+    /// Mailboxes of the partition must notify it.
+    pub fn readiness_for(&self, partition: &PartitionId) -> Readiness {
+        self.threads[self.thread_index(partition)].readiness.clone()
+    }
+
+    /// The `Lifecycle` of the thread that processes the partition
+    pub fn lifecycle_for(&self, partition: &PartitionId) -> Lifecycle {
+        self.threads[self.thread_index(partition)].lifecycle.clone()
+    }
+
+    /// Run the task on the thread responsible for the partition.
+    pub fn run(&self, partition: &PartitionId, task: Box<Task>) -> Result<(), String> {
+        let thread = &self.threads[self.thread_index(partition)];
+        thread
+            .sender
+            .send(task)
+            .map_err(|_| format!("The pool thread for partition {} stopped", partition))?;
+        thread.readiness.notify();
+        Ok(())
+    }
+
+    /// The index of the thread for the partition
+    pub fn thread_index(&self, partition: &PartitionId) -> usize {
+        let mut hasher = DefaultHasher::new();
+        partition.0.hash(&mut hasher);
+        (hasher.finish() % self.threads.len() as u64) as usize
+    }
+}
+
+impl Drop for WorkerPool {
+    fn drop(&mut self) {
+        // Wake up the threads so that they notice the pool is gone
+        self.threads.iter().for_each(|t| t.readiness.notify());
+    }
+}
+
+fn pool_thread_loop(index: usize, receiver: mpsc::Receiver<Box<Task>>, readiness: Readiness) {
+    info!("[WorkerPool, thread={}] Started.", index);
+
+    let mut tasks: Vec<Box<Task>> = Vec::new();
+    loop {
+        let mut pool_dropped = false;
+        loop {
+            match receiver.try_recv() {
+                Ok(task) => tasks.push(task),
+                Err(mpsc::TryRecvError::Empty) => break,
+                Err(mpsc::TryRecvError::Disconnected) => {
+                    pool_dropped = true;
+                    break;
+                }
+            }
+        }
+
+        if pool_dropped && tasks.is_empty() {
+            break;
+        }
+
+        let mut processed_any = false;
+        let mut i = 0;
+        while i < tasks.len() {
+            match tasks[i].step(Duration::from_millis(0)) {
+                TaskStep::Processed => {
+                    processed_any = true;
+                    i += 1;
+                }
+                TaskStep::Idle => i += 1,
+                TaskStep::Done => tasks.remove(i).finish(),
+            }
+        }
+
+        if !processed_any {
+            readiness.wait_timeout(Duration::from_millis(IDLE_WAIT_MS));
+        }
+    }
+
+    info!("[WorkerPool, thread={}] Stopped.", index);
+}
+
+#[cfg(test)]
+struct CountingTask {
+    remaining: usize,
+    finished: mpsc::Sender<usize>,
+    id: usize,
+}
+
+#[cfg(test)]
+impl Task for CountingTask {
+    fn step(&mut self, _wait: Duration) -> TaskStep {
+        if self.remaining == 0 {
+            TaskStep::Done
+        } else {
+            self.remaining -= 1;
+            TaskStep::Processed
+        }
+    }
+
+    fn finish(self: Box<Self>) {
+        let _ = self.finished.send(self.id);
+    }
+}
+
+#[test]
+fn partitions_stay_on_their_thread() {
+    let pool = WorkerPool::new(4, &Lifecycle::default());
+    assert_eq!(pool.num_threads(), 4);
+
+    let partition = PartitionId("7".to_string());
+    let index = pool.thread_index(&partition);
+    for _ in 0..10 {
+        assert_eq!(pool.thread_index(&partition), index);
+    }
+    assert!(index < 4);
+}
+
+#[test]
+fn tasks_are_run_to_completion() {
+    let pool = WorkerPool::new(2, &Lifecycle::default());
+    let (finished, receiver) = mpsc::channel();
+
+    for id in 0..5 {
+        let task = CountingTask {
+            remaining: 3,
+            finished: finished.clone(),
+            id,
+        };
+        pool.run(&PartitionId(id.to_string()), Box::new(task))
+            .unwrap();
+    }
+
+    let mut ids: Vec<usize> = (0..5)
+        .map(|_| receiver.recv_timeout(Duration::from_secs(10)).unwrap())
+        .collect();
+    ids.sort();
+    assert_eq!(ids, vec![0, 1, 2, 3, 4]);
+}